@@ -0,0 +1,244 @@
+use ndarray::{Array2, Axis};
+use rayon::prelude::*;
+
+use crate::noise::median_of;
+
+// Detection threshold over the background, in noise sigmas
+const STAR_DETECTION_SIGMA: f32 = 5.0;
+// Half size of the window used to compute the centroid of a star
+const CENTROID_RADIUS: usize = 2;
+// Stars used when voting for the offset between two frames
+const MATCH_STARS: usize = 30;
+// Distance, in pixels, under which two stars are considered the same
+const MATCH_TOLERANCE: f32 = 1.5;
+const MIN_MATCHES: usize = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize)]
+pub struct Star {
+    pub x: f32,
+    pub y: f32,
+    // Peak value over the background
+    pub peak: f32,
+}
+
+// Median and normalized MAD of the data
+pub fn background_and_noise(data: &Array2<f32>) -> (f32, f32) {
+    let mut values: Vec<f32> = data.iter().copied().collect();
+    let Some(median) = median_of(&mut values) else {
+        return (0.0, 0.0);
+    };
+    values.iter_mut().for_each(|v| *v = (*v - median).abs());
+    let mad = median_of(&mut values).unwrap_or(0.0);
+    (median, 1.4826 * mad)
+}
+
+// Pixels of at least `threshold` that are the maximum of the square of
+// `radius` around them, as (x, y), the brightest first. Ties go to the
+// first pixel in scan order, so a flat topped star is found once. Pixels
+// closer than `radius` to the border are skipped.
+pub fn local_maxima(data: &Array2<f32>, threshold: f32, radius: usize) -> Vec<(usize, usize)> {
+    let (h, w) = data.dim();
+    let r = radius;
+    if h <= 2 * r || w <= 2 * r {
+        return vec![];
+    }
+    let mut maxima: Vec<(f32, usize, usize)> = (r..h - r)
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            (r..w - r).filter_map(move |x| {
+                let v = data[[y, x]];
+                if v < threshold {
+                    return None;
+                }
+                for yy in y - r..=y + r {
+                    for xx in x - r..=x + r {
+                        let n = data[[yy, xx]];
+                        if n > v || (n == v && (yy, xx) < (y, x)) {
+                            return None;
+                        }
+                    }
+                }
+                Some((v, x, y))
+            })
+        })
+        .collect();
+    maxima.sort_by(|a, b| b.0.total_cmp(&a.0));
+    maxima.into_iter().map(|(_, x, y)| (x, y)).collect()
+}
+
+// Detects stars as local maxima well above the background and measures their
+// intensity weighted centroid. The brightest `max_stars` are returned, in
+// decreasing order of brightness.
+pub fn find_stars(data: &Array2<f32>, max_stars: usize) -> Vec<Star> {
+    let r = CENTROID_RADIUS;
+    let (background, noise) = background_and_noise(data);
+    let threshold = background + STAR_DETECTION_SIGMA * noise.max(f32::EPSILON);
+
+    local_maxima(data, threshold, r)
+        .into_iter()
+        .take(max_stars)
+        .map(|(x, y)| {
+            let (mut sx, mut sy, mut sum) = (0f32, 0f32, 0f32);
+            for yy in y - r..=y + r {
+                for xx in x - r..=x + r {
+                    let weight = (data[[yy, xx]] - background).max(0.0);
+                    sx += weight * xx as f32;
+                    sy += weight * yy as f32;
+                    sum += weight;
+                }
+            }
+            Star {
+                x: sx / sum,
+                y: sy / sum,
+                peak: data[[y, x]] - background,
+            }
+        })
+        .collect()
+}
+
+// Translation of `target` with respect to `reference`: a star at (x, y) in the
+// reference is found at (x + dx, y + dy) in the target. Every pair of bright
+// stars proposes an offset; the one matching the most stars wins and is then
+// refined by averaging its matches. None when fewer than MIN_MATCHES stars
+// agree.
+pub fn estimate_translation(reference: &[Star], target: &[Star]) -> Option<(f32, f32)> {
+    let reference = &reference[..reference.len().min(MATCH_STARS)];
+    let target = &target[..target.len().min(MATCH_STARS)];
+
+    let matches = |dx: f32, dy: f32| -> Vec<(f32, f32)> {
+        reference
+            .iter()
+            .filter_map(|r| {
+                target
+                    .iter()
+                    .map(|t| (t.x - r.x - dx, t.y - r.y - dy, t))
+                    .filter(|(ex, ey, _)| ex.hypot(*ey) < MATCH_TOLERANCE)
+                    .min_by(|a, b| a.0.hypot(a.1).total_cmp(&b.0.hypot(b.1)))
+                    .map(|(_, _, t)| (t.x - r.x, t.y - r.y))
+            })
+            .collect()
+    };
+
+    let best = reference
+        .iter()
+        .flat_map(|r| target.iter().map(move |t| (t.x - r.x, t.y - r.y)))
+        .map(|(dx, dy)| matches(dx, dy))
+        .max_by_key(|m| m.len())?;
+    if best.len() < MIN_MATCHES.min(reference.len()).max(1) {
+        return None;
+    }
+
+    let n = best.len() as f32;
+    let (sx, sy) = best.iter().fold((0f32, 0f32), |(sx, sy), (dx, dy)| (sx + dx, sy + dy));
+    Some((sx / n, sy / n))
+}
+
+// Resamples `data` so that the pixel at (x + dx, y + dy) lands on (x, y),
+// with bilinear interpolation. Pixels coming from outside the image are 0.
+pub fn shift(data: &Array2<f32>, dx: f32, dy: f32) -> Array2<f32> {
+    let (h, w) = data.dim();
+    let mut out = Array2::<f32>::zeros((h, w));
+    out.axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(y, mut row)| {
+            let sy = y as f32 + dy;
+            if sy < 0.0 || sy > (h - 1) as f32 {
+                return;
+            }
+            let y0 = sy.floor() as usize;
+            let y1 = (y0 + 1).min(h - 1);
+            let fy = sy - y0 as f32;
+            for (x, v) in row.iter_mut().enumerate() {
+                let sx = x as f32 + dx;
+                if sx < 0.0 || sx > (w - 1) as f32 {
+                    continue;
+                }
+                let x0 = sx.floor() as usize;
+                let x1 = (x0 + 1).min(w - 1);
+                let fx = sx - x0 as f32;
+                let top = data[[y0, x0]] * (1.0 - fx) + data[[y0, x1]] * fx;
+                let bottom = data[[y1, x0]] * (1.0 - fx) + data[[y1, x1]] * fx;
+                *v = top * (1.0 - fy) + bottom * fy;
+            }
+        });
+    out
+}
+
+// Half flux radius of a star: the flux weighted mean distance to its
+// centroid, over the background, within `radius` pixels
+fn half_flux_radius(data: &Array2<f32>, star: &Star, background: f32, radius: usize) -> Option<f32> {
+    let (h, w) = data.dim();
+    let (cx, cy) = (star.x.round() as usize, star.y.round() as usize);
+    if cx < radius || cy < radius || cx + radius >= w || cy + radius >= h {
+        return None;
+    }
+    let (mut flux, mut weighted) = (0f32, 0f32);
+    for y in cy - radius..=cy + radius {
+        for x in cx - radius..=cx + radius {
+            let d = (x as f32 - star.x).hypot(y as f32 - star.y);
+            if d > radius as f32 {
+                continue;
+            }
+            let v = (data[[y, x]] - background).max(0.0);
+            flux += v;
+            weighted += v * d;
+        }
+    }
+    (flux > 0.0).then(|| weighted / flux)
+}
+
+// Median half flux radius of the detected stars, a measure of focus and
+// seeing. None when no star could be measured.
+pub fn median_hfr(data: &Array2<f32>, max_stars: usize, radius: usize) -> Option<f32> {
+    let (background, _) = background_and_noise(data);
+    let mut hfrs: Vec<f32> = find_stars(data, max_stars)
+        .iter()
+        .filter_map(|s| half_flux_radius(data, s, background, radius))
+        .collect();
+    median_of(&mut hfrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Flat background of 10 with a little noise and two gaussian stars
+    fn field() -> Array2<f32> {
+        Array2::from_shape_fn((40, 60), |(y, x)| {
+            let star = |cx: f32, cy: f32, peak: f32| {
+                peak * (-((x as f32 - cx).powi(2) + (y as f32 - cy).powi(2)) / 2.0).exp()
+            };
+            10.0 + ((x * 7 + y * 13) % 5) as f32 * 0.1 + star(15.0, 12.0, 100.0) + star(40.3, 25.0, 50.0)
+        })
+    }
+
+    #[test]
+    fn local_maxima_brightest_first() {
+        let data = field();
+        assert_eq!(local_maxima(&data, 30.0, 2), vec![(15, 12), (40, 25)]);
+        assert_eq!(local_maxima(&data, 80.0, 2), vec![(15, 12)]);
+
+        // A flat top is found once, at its first pixel
+        let mut plateau = Array2::zeros((7, 7));
+        plateau[[3, 3]] = 1.0;
+        plateau[[3, 4]] = 1.0;
+        assert_eq!(local_maxima(&plateau, 0.5, 1), vec![(3, 3)]);
+        assert!(local_maxima(&plateau, 0.5, 4).is_empty());
+    }
+
+    #[test]
+    fn stars_are_found_at_their_centroid() {
+        let data = field();
+        let (background, noise) = background_and_noise(&data);
+        assert!((background - 10.2).abs() < 0.11, "{}", background);
+        assert!(noise < 0.5, "{}", noise);
+
+        let stars = find_stars(&data, 10);
+        assert_eq!(stars.len(), 2);
+        assert!((stars[0].x - 15.0).abs() < 0.05 && (stars[0].y - 12.0).abs() < 0.05, "{:?}", stars[0]);
+        assert!((stars[1].x - 40.3).abs() < 0.1 && (stars[1].y - 25.0).abs() < 0.05, "{:?}", stars[1]);
+        assert!(stars[0].peak > stars[1].peak);
+        assert_eq!(find_stars(&data, 1), stars[..1]);
+    }
+}
@@ -0,0 +1,559 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use tauri::{AppHandle, Emitter, State};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::{broadcast, oneshot, Mutex},
+    task::JoinHandle,
+};
+
+use crate::asiairdevices::device_set;
+use crate::deviceregistry::DeviceRegistry;
+use crate::devices::DeviceKind;
+
+// See docs/protocols/ASIAIR.md
+pub const COMMAND_PORT: u16 = 4700;
+pub const GUIDER_PORT: u16 = 4400;
+pub const IMAGE_PORT: u16 = 4800;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+// Full frames of large sensors take a while over Wi-Fi
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const EVENT_CAPACITY: usize = 256;
+
+// Unsolicited message from the ASIAIR, such as exposure progress
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct AsiairEvent {
+    // Port the event came from
+    pub port: u16,
+    // "Event" field of the message
+    pub name: String,
+    pub payload: Value,
+}
+
+type Pending = Arc<StdMutex<HashMap<u64, oneshot::Sender<Result<Value, String>>>>>;
+
+// Result of a reply, failed when its code is not 0. The guider port answers
+// like PHD2, with the code and message in an "error" object.
+fn reply_result(reply: &Value) -> Result<Value, String> {
+    if let Some(error) = reply.get("error").filter(|e| e.is_object()) {
+        let code = error.get("code").and_then(|c| c.as_i64()).unwrap_or(0);
+        return Err(match error.get("message").and_then(|m| m.as_str()) {
+            Some(message) => format!("{} (code {})", message, code),
+            None => format!("Error code {}", code),
+        });
+    }
+    match reply.get("code").and_then(|c| c.as_i64()).unwrap_or(0) {
+        0 => Ok(reply.get("result").cloned().unwrap_or(Value::Null)),
+        code => Err(match reply.get("error").and_then(|e| e.as_str()) {
+            Some(error) => format!("{} (code {})", error, code),
+            None => format!("Error code {}", code),
+        }),
+    }
+}
+
+// Line delimited JSON-RPC connection to one port of an ASIAIR. Replies are
+// matched to their request by id, everything else is broadcast as an event.
+pub struct AsiairClient {
+    writer: Mutex<OwnedWriteHalf>,
+    pending: Pending,
+    next_id: AtomicU64,
+    events: broadcast::Sender<AsiairEvent>,
+    connected: Arc<AtomicBool>,
+    reader: JoinHandle<()>,
+    timeout: Duration,
+}
+
+impl AsiairClient {
+    pub async fn connect(address: SocketAddr, timeout: Duration) -> Result<Self, String> {
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+            .await
+            .map_err(|_| format!("Timeout connecting to {}", address))?
+            .map_err(|e| format!("Failed to connect to {}: {}", address, e))?;
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        let (read_half, write_half) = stream.into_split();
+
+        let pending = Pending::default();
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let connected = Arc::new(AtomicBool::new(true));
+        let reader = tokio::spawn(Self::read_loop(
+            address.port(),
+            BufReader::new(read_half),
+            pending.clone(),
+            events.clone(),
+            connected.clone(),
+        ));
+
+        Ok(Self {
+            writer: Mutex::new(write_half),
+            pending,
+            next_id: AtomicU64::new(1),
+            events,
+            connected,
+            reader,
+            timeout,
+        })
+    }
+
+    async fn read_loop(
+        port: u16,
+        mut reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
+        pending: Pending,
+        events: broadcast::Sender<AsiairEvent>,
+        connected: Arc<AtomicBool>,
+    ) {
+        let mut line = String::new();
+        let reason = loop {
+            line.clear();
+            match reader.read_line(&mut line).await {
+                Ok(0) => break "Connection closed".to_string(),
+                Ok(_) => {}
+                Err(e) => break e.to_string(),
+            }
+            let Ok(message) = serde_json::from_str::<Value>(line.trim()) else {
+                log::warn!("Invalid message from ASIAIR port {}: {}", port, line.trim());
+                continue;
+            };
+
+            if let Some(name) = message.get("Event").and_then(|e| e.as_str()) {
+                // Nobody listening is fine
+                let _ = events.send(AsiairEvent {
+                    port,
+                    name: name.to_string(),
+                    payload: message.clone(),
+                });
+            } else if let Some(id) = message.get("id").and_then(|id| id.as_u64()) {
+                let sender = pending.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+                match sender {
+                    Some(sender) => {
+                        let _ = sender.send(reply_result(&message));
+                    }
+                    None => log::debug!("Unexpected reply {} on port {}", id, port),
+                }
+            }
+        };
+
+        connected.store(false, Ordering::SeqCst);
+        for (_, sender) in pending.lock().unwrap_or_else(|e| e.into_inner()).drain() {
+            let _ = sender.send(Err(reason.clone()));
+        }
+        let _ = events.send(AsiairEvent {
+            port,
+            name: "Disconnected".to_string(),
+            payload: json!({ "reason": reason }),
+        });
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AsiairEvent> {
+        self.events.subscribe()
+    }
+
+    // Sends a request and waits for its reply
+    pub async fn call(&self, method: &str, params: Option<Value>) -> Result<Value, String> {
+        if !self.is_connected() {
+            return Err("Not connected".to_string());
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut request = json!({ "id": id, "method": method });
+        if let Some(params) = params {
+            request["params"] = params;
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).insert(id, sender);
+        let line = request.to_string() + "\r\n";
+        if let Err(e) = self.writer.lock().await.write_all(line.as_bytes()).await {
+            self.pending.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+            return Err(e.to_string());
+        }
+
+        match tokio::time::timeout(self.timeout, receiver).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err("Connection closed".to_string()),
+            Err(_) => {
+                self.pending.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+                Err(format!("Timeout waiting for {}", method))
+            }
+        }
+    }
+}
+
+impl Drop for AsiairClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AsiairPorts {
+    pub command: u16,
+    pub guider: u16,
+    pub image: u16,
+}
+
+impl Default for AsiairPorts {
+    fn default() -> Self {
+        Self {
+            command: COMMAND_PORT,
+            guider: GUIDER_PORT,
+            image: IMAGE_PORT,
+        }
+    }
+}
+
+// Description of a frame on the image port, followed by `size` bytes of
+// samples
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ImageHeader {
+    pub width: usize,
+    pub height: usize,
+    // 8 or 16, 16 bit samples are little endian
+    pub bits: u32,
+    pub size: usize,
+    // CFA of color cameras, such as "RGGB"
+    #[serde(default)]
+    pub bayer: Option<String>,
+}
+
+// Fetches the last frame of the camera on the image port. Each download uses
+// its own connection, so a slow one does not hold the command port.
+pub async fn download_image(address: SocketAddr) -> Result<(ImageHeader, Vec<u8>), String> {
+    let download = async {
+        let stream = TcpStream::connect(address)
+            .await
+            .map_err(|e| format!("Failed to connect to {}: {}", address, e))?;
+        let mut reader = BufReader::new(stream);
+        let request = json!({ "id": 1, "method": "get_current_img" }).to_string() + "\r\n";
+        reader.get_mut().write_all(request.as_bytes()).await.map_err(|e| e.to_string())?;
+
+        let mut line = String::new();
+        reader.read_line(&mut line).await.map_err(|e| e.to_string())?;
+        let reply: Value = serde_json::from_str(line.trim()).map_err(|e| format!("Invalid image header: {}", e))?;
+        let header: ImageHeader = serde_json::from_value(reply_result(&reply)?).map_err(|e| e.to_string())?;
+        let expected = header.width * header.height * if header.bits > 8 { 2 } else { 1 };
+        if header.size != expected {
+            return Err(format!("Image of {} bytes, expected {}", header.size, expected));
+        }
+
+        let mut data = vec![0u8; header.size];
+        reader.read_exact(&mut data).await.map_err(|e| e.to_string())?;
+        Ok((header, data))
+    };
+    tokio::time::timeout(DOWNLOAD_TIMEOUT, download)
+        .await
+        .map_err(|_| "Timeout downloading the image".to_string())?
+}
+
+// Connection to an ASIAIR: its command port, and its guider when it
+// accepts connections
+pub struct AsiairSession {
+    pub host: String,
+    ip: IpAddr,
+    ports: AsiairPorts,
+    pub command: AsiairClient,
+    pub guider: Option<AsiairClient>,
+    // Set while a capture runs, the camera takes one exposure at a time
+    pub(crate) exposing: AtomicBool,
+    // Event forwarding and heartbeat, stopped with the session
+    tasks: StdMutex<Vec<JoinHandle<()>>>,
+}
+
+impl AsiairSession {
+    pub async fn connect(host: &str) -> Result<Self, String> {
+        Self::connect_with_ports(host, AsiairPorts::default()).await
+    }
+
+    pub async fn connect_with_ports(host: &str, ports: AsiairPorts) -> Result<Self, String> {
+        let ip = tokio::net::lookup_host((host, ports.command))
+            .await
+            .map_err(|e| format!("Failed to resolve {}: {}", host, e))?
+            .next()
+            .ok_or_else(|| format!("Failed to resolve {}", host))?
+            .ip();
+        let command = AsiairClient::connect(SocketAddr::new(ip, ports.command), CALL_TIMEOUT).await?;
+        let guider = match AsiairClient::connect(SocketAddr::new(ip, ports.guider), CALL_TIMEOUT).await {
+            Ok(guider) => Some(guider),
+            Err(e) => {
+                log::warn!("ASIAIR guider not available: {}", e);
+                None
+            }
+        };
+        Ok(Self {
+            host: host.to_string(),
+            ip,
+            ports,
+            command,
+            guider,
+            exposing: AtomicBool::new(false),
+            tasks: StdMutex::default(),
+        })
+    }
+
+    pub fn image_address(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.ports.image)
+    }
+
+    pub fn clients(&self) -> impl Iterator<Item = &AsiairClient> {
+        std::iter::once(&self.command).chain(self.guider.as_ref())
+    }
+}
+
+impl Drop for AsiairSession {
+    fn drop(&mut self) {
+        for task in self.tasks.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            task.abort();
+        }
+    }
+}
+
+// Forwards the events of every client and the state of the devices to the
+// frontend, and keeps the command connection alive
+fn spawn_session_tasks(
+    app: AppHandle,
+    telescope_index: u32,
+    session: &Arc<AsiairSession>,
+    devices: &[DeviceKind],
+) -> Vec<JoinHandle<()>> {
+    let mut tasks: Vec<JoinHandle<()>> = session
+        .clients()
+        .map(|client| {
+            let mut events = client.subscribe();
+            let app = app.clone();
+            tokio::spawn(async move {
+                loop {
+                    match events.recv().await {
+                        Ok(event) => {
+                            let payload = json!({ "index": telescope_index, "event": event });
+                            if let Err(e) = app.emit("asiair_event", payload) {
+                                log::warn!("Failed to emit ASIAIR event: {}", e);
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(n)) => log::warn!("Dropped {} ASIAIR events", n),
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let mount_app = app.clone();
+    tasks.push(crate::asiairmount::spawn_state_task(session, move |state| {
+        let payload = json!({ "index": telescope_index, "state": state });
+        if let Err(e) = mount_app.emit("mount_state", payload) {
+            log::warn!("Failed to emit mount state: {}", e);
+        }
+    }));
+    tasks.push(crate::asiairdevices::spawn_status_task(app.clone(), telescope_index, session, devices));
+    tasks.push(crate::asiairplan::spawn_autorun_task(app, telescope_index, session));
+
+    let weak = Arc::downgrade(session);
+    tasks.push(tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+            let Some(session) = weak.upgrade() else { break };
+            if !session.command.is_connected() {
+                break;
+            }
+            if let Err(e) = session.command.call("test_connection", None).await {
+                log::warn!("ASIAIR heartbeat failed: {}", e);
+            }
+        }
+    }));
+    tasks
+}
+
+// Open ASIAIR connections by telescope index, kept as Tauri state
+#[derive(Default)]
+pub struct AsiairConnections {
+    sessions: Mutex<HashMap<u32, Arc<AsiairSession>>>,
+}
+
+impl AsiairConnections {
+    pub async fn get(&self, telescope_index: u32) -> Result<Arc<AsiairSession>, String> {
+        self.sessions
+            .lock()
+            .await
+            .get(&telescope_index)
+            .filter(|s| s.command.is_connected())
+            .cloned()
+            .ok_or_else(|| format!("Telescope {} is not connected", telescope_index))
+    }
+}
+
+#[tauri::command]
+pub async fn asiair_connect(
+    app: AppHandle,
+    connections: State<'_, AsiairConnections>,
+    registry: State<'_, DeviceRegistry>,
+    telescope_index: u32,
+    host: String,
+) -> Result<(), String> {
+    log::info!("Connecting to ASIAIR {} for telescope index {}...", host, telescope_index);
+    // A previous connection is replaced
+    registry.unregister(telescope_index).await;
+    connections.sessions.lock().await.remove(&telescope_index);
+
+    let session = Arc::new(AsiairSession::connect(&host).await?);
+    session.command.call("test_connection", None).await?;
+    let devices = device_set(&session).await;
+    *session.tasks.lock().unwrap_or_else(|e| e.into_inner()) =
+        spawn_session_tasks(app, telescope_index, &session, &devices.kinds());
+    registry.register(telescope_index, devices).await;
+    connections.sessions.lock().await.insert(telescope_index, session);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn asiair_disconnect(
+    connections: State<'_, AsiairConnections>,
+    registry: State<'_, DeviceRegistry>,
+    telescope_index: u32,
+) -> Result<(), String> {
+    log::info!("Disconnecting ASIAIR of telescope index {}...", telescope_index);
+    // The registry holds the session too
+    registry.unregister(telescope_index).await;
+    connections.sessions.lock().await.remove(&telescope_index);
+    Ok(())
+}
+
+// Raw call on the command port, for what has no dedicated command yet
+#[tauri::command]
+pub async fn asiair_call(
+    connections: State<'_, AsiairConnections>,
+    telescope_index: u32,
+    method: String,
+    params: Option<Value>,
+) -> Result<Value, String> {
+    connections.get(telescope_index).await?.command.call(&method, params).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mockasiair::{MockCommandServer, MockDevice};
+
+    const WAIT: Duration = Duration::from_secs(2);
+
+    fn mock() -> MockCommandServer {
+        MockCommandServer::start("127.0.0.1:0", "127.0.0.1:0", MockDevice::default()).unwrap()
+    }
+
+    async fn next_event(events: &mut broadcast::Receiver<AsiairEvent>) -> AsiairEvent {
+        tokio::time::timeout(WAIT, events.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn replies_are_matched_by_id() {
+        let mock = mock();
+        let client = AsiairClient::connect(mock.address(), WAIT).await.unwrap();
+
+        // The slow reply comes last, each call still gets its own
+        let (slow, connection, info) = tokio::join!(
+            client.call("mock_delay", Some(json!([200]))),
+            client.call("test_connection", None),
+            client.call("pi_get_info", None),
+        );
+        assert_eq!(slow.unwrap(), json!(0));
+        assert_eq!(connection.unwrap(), json!("server connected!"));
+        assert_eq!(info.unwrap()["guid"], MockDevice::default().guid);
+
+        let error = client.call("no_such_method", None).await.unwrap_err();
+        assert!(error.contains("method not found"), "{}", error);
+    }
+
+    #[test]
+    fn replies_carry_errors_in_both_formats() {
+        assert_eq!(reply_result(&json!({ "id": 1, "code": 0, "result": 5 })), Ok(json!(5)));
+        assert_eq!(reply_result(&json!({ "jsonrpc": "2.0", "id": 2, "result": 0 })), Ok(json!(0)));
+        assert_eq!(
+            reply_result(&json!({ "id": 3, "code": 4, "error": "mount is parked" })),
+            Err("mount is parked (code 4)".to_string())
+        );
+        // PHD2, on the guider port
+        let phd2 = json!({ "jsonrpc": "2.0", "id": 4, "error": { "code": 1, "message": "equipment not connected" } });
+        assert_eq!(reply_result(&phd2), Err("equipment not connected (code 1)".to_string()));
+        let phd2 = json!({ "jsonrpc": "2.0", "id": 5, "error": { "code": 2 } });
+        assert_eq!(reply_result(&phd2), Err("Error code 2".to_string()));
+    }
+
+    #[tokio::test]
+    async fn calls_time_out() {
+        let mock = mock();
+        let client = AsiairClient::connect(mock.address(), Duration::from_millis(100)).await.unwrap();
+        let error = client.call("mock_delay", Some(json!([500]))).await.unwrap_err();
+        assert_eq!(error, "Timeout waiting for mock_delay");
+
+        // The late reply is dropped, the connection stays usable
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(client.is_connected());
+        assert_eq!(client.call("test_connection", None).await.unwrap(), json!("server connected!"));
+    }
+
+    #[tokio::test]
+    async fn events_reach_every_subscriber() {
+        let mock = mock();
+        let client = AsiairClient::connect(mock.address(), WAIT).await.unwrap();
+        let (mut first, mut second) = (client.subscribe(), client.subscribe());
+        // Replies are not events
+        client.call("test_connection", None).await.unwrap();
+
+        mock.send_event("Exposure", json!({ "state": "start" }));
+        for events in [&mut first, &mut second] {
+            let event = next_event(events).await;
+            assert_eq!(event.port, mock.address().port());
+            assert_eq!(event.name, "Exposure");
+            assert_eq!(event.payload["state"], "start");
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnecting_fails_calls_in_flight() {
+        let mock = mock();
+        let client = AsiairClient::connect(mock.address(), WAIT).await.unwrap();
+        let mut events = client.subscribe();
+
+        let call = client.call("mock_delay", Some(json!([1000])));
+        let disconnect = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            mock.disconnect_clients();
+        };
+        let (result, _) = tokio::join!(call, disconnect);
+        assert_eq!(result.unwrap_err(), "Connection closed");
+
+        let event = next_event(&mut events).await;
+        assert_eq!(event.name, "Disconnected");
+        assert!(!client.is_connected());
+        assert_eq!(client.call("test_connection", None).await.unwrap_err(), "Not connected");
+    }
+
+    #[tokio::test]
+    async fn session_uses_the_image_port() {
+        let mock = mock();
+        let ports = AsiairPorts {
+            command: mock.address().port(),
+            guider: mock.address().port(),
+            image: mock.image_address().port(),
+        };
+        let session = AsiairSession::connect_with_ports("127.0.0.1", ports).await.unwrap();
+        assert_eq!(session.image_address(), mock.image_address());
+        assert_eq!(session.clients().count(), 2);
+
+        session.command.call("start_exposure", Some(json!(["light"]))).await.unwrap();
+        assert_eq!(mock.state().camera.state, "exposing");
+    }
+}
@@ -0,0 +1,395 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use ndarray::Array2;
+use serde_json::json;
+use tokio::sync::broadcast;
+
+use crate::asiair::{download_image, AsiairSession, ImageHeader};
+use crate::debayer::BayerPattern;
+use crate::devices::{
+    Camera, CameraCapabilities, CameraSettings, CameraState, ExposureKind, ExposureProgress, ExposureState,
+};
+use crate::metadata::ImageMetadata;
+use crate::rawimage::RawImage;
+
+const DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f";
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+// On top of the exposure time, for the readout and a busy ASIAIR
+const EXPOSURE_GRACE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CameraInfo {
+    pub name: String,
+    // Sensor size in pixels
+    pub chip_size: [u32; 2],
+    pub pixel_size_um: Option<f64>,
+    pub bayer_pattern: Option<String>,
+    pub is_color: bool,
+    pub has_cooler: bool,
+    pub bit_depth: Option<u32>,
+    pub max_bin: Option<u32>,
+}
+
+pub async fn camera_info(session: &AsiairSession) -> Result<CameraInfo, String> {
+    let info = session.command.call("get_camera_info", None).await?;
+    serde_json::from_value(info).map_err(|e| format!("Invalid camera info: {}", e))
+}
+
+pub async fn camera_state(session: &AsiairSession) -> Result<CameraState, String> {
+    let state = session.command.call("get_camera_state", None).await?;
+    serde_json::from_value(state).map_err(|e| format!("Invalid camera state: {}", e))
+}
+
+pub async fn apply_settings(session: &AsiairSession, settings: &CameraSettings) -> Result<(), String> {
+    let command = &session.command;
+    if let Some(exposure) = settings.exposure {
+        if exposure.is_nan() || exposure <= 0.0 {
+            return Err(format!("Invalid exposure time {}", exposure));
+        }
+        // The ASIAIR takes microseconds
+        let exposure = (exposure * 1e6).round() as i64;
+        command.call("set_control_value", Some(json!(["Exposure", exposure]))).await?;
+    }
+    if let Some(gain) = settings.gain {
+        command.call("set_control_value", Some(json!(["Gain", gain]))).await?;
+    }
+    if let Some(binning) = settings.binning {
+        command.call("set_camera_bin", Some(json!([binning]))).await?;
+    }
+    if let Some(roi) = settings.roi {
+        command.call("set_camera_roi", Some(json!(roi))).await?;
+    } else if settings.full_frame {
+        command.call("clear_camera_roi", None).await?;
+    }
+    Ok(())
+}
+
+// Exposure time the camera is set to, in seconds
+async fn current_exposure(session: &AsiairSession) -> Result<f64, String> {
+    let value = session
+        .command
+        .call("get_control_value", Some(json!(["Exposure"])))
+        .await?;
+    value
+        .get("value")
+        .and_then(|v| v.as_f64())
+        .map(|us| us / 1e6)
+        .ok_or_else(|| "Invalid exposure value".to_string())
+}
+
+fn bayer_pattern(value: Option<&str>) -> BayerPattern {
+    match value.map(|v| v.trim().to_uppercase()).as_deref() {
+        Some("RGGB") => BayerPattern::RGGB,
+        Some("BGGR") => BayerPattern::BGGR,
+        Some("GRBG") => BayerPattern::GRBG,
+        Some("GBRG") => BayerPattern::GBRG,
+        _ => BayerPattern::NONE,
+    }
+}
+
+// Samples of the image port as a raw image, kept as they are like the
+// integer samples of FITS and SER files
+pub fn frame_to_raw_image(header: &ImageHeader, data: &[u8]) -> Result<RawImage, String> {
+    let samples: Vec<i32> = if header.bits > 8 {
+        data.chunks_exact(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as i32)
+            .collect()
+    } else {
+        data.iter().map(|&b| b as i32).collect()
+    };
+    let pixels = Array2::from_shape_vec((header.height, header.width), samples).map_err(|e| e.to_string())?;
+    let mut image = RawImage::new(pixels, bayer_pattern(header.bayer.as_deref()));
+    image.saturation_level = (1i32 << header.bits.clamp(1, 16)) - 1;
+    Ok(image)
+}
+
+// Waits for the "Exposure" event that ends the exposure. The ASIAIR reports
+// no progress while exposing, so it is estimated from the clock.
+async fn wait_for_exposure(
+    session: &AsiairSession,
+    events: &mut broadcast::Receiver<crate::asiair::AsiairEvent>,
+    duration: f64,
+    on_progress: &impl Fn(ExposureProgress),
+) -> Result<(), String> {
+    let start = Instant::now();
+    let timeout = Duration::from_secs_f64(duration) + EXPOSURE_GRACE;
+    let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if event.name == "Exposure" => {
+                    match event.payload.get("state").and_then(|s| s.as_str()) {
+                        Some("complete") => return Ok(()),
+                        Some("fail") | Some("cancel") => {
+                            let reason = event.payload.get("error").and_then(|e| e.as_str()).unwrap_or("cancelled");
+                            return Err(format!("Exposure failed: {}", reason));
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(event) if event.name == "Disconnected" => {
+                    return Err("ASIAIR disconnected during the exposure".to_string());
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(n)) => log::warn!("Dropped {} ASIAIR events", n),
+                Err(broadcast::error::RecvError::Closed) => return Err("ASIAIR connection closed".to_string()),
+            },
+            _ = ticker.tick() => {
+                let elapsed = start.elapsed();
+                if elapsed > timeout {
+                    let _ = session.command.call("stop_exposure", None).await;
+                    return Err("Timeout waiting for the exposure".to_string());
+                }
+                // A missed event must not leave the capture waiting until the
+                // timeout, ask the camera once the exposure should be over
+                let elapsed = elapsed.as_secs_f64();
+                if elapsed > duration + 1.0 && camera_state(session).await.is_ok_and(|s| s.state == "idle") {
+                    return Ok(());
+                }
+                let state = if elapsed < duration { ExposureState::Exposing } else { ExposureState::Downloading };
+                on_progress(ExposureProgress { state, elapsed: elapsed.min(duration), duration });
+            }
+        }
+    }
+}
+
+// Clears the exposing flag of the session when dropped, so a cancelled
+// capture future does not leave the camera marked busy
+struct ExposingGuard<'a>(&'a AtomicBool);
+
+impl Drop for ExposingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+// Takes one exposure with `settings` and downloads it
+pub async fn capture(
+    session: &AsiairSession,
+    kind: ExposureKind,
+    settings: &CameraSettings,
+    on_progress: impl Fn(ExposureProgress),
+) -> Result<RawImage, String> {
+    if session.exposing.swap(true, Ordering::SeqCst) {
+        return Err("The camera is already exposing".to_string());
+    }
+    let _guard = ExposingGuard(&session.exposing);
+    capture_exposure(session, kind, settings, &on_progress).await
+}
+
+async fn capture_exposure(
+    session: &AsiairSession,
+    kind: ExposureKind,
+    settings: &CameraSettings,
+    on_progress: &impl Fn(ExposureProgress),
+) -> Result<RawImage, String> {
+    apply_settings(session, settings).await?;
+    let duration = match settings.exposure {
+        Some(exposure) => exposure,
+        None => current_exposure(session).await?,
+    };
+    let info = camera_info(session).await.ok();
+
+    // Subscribed before starting, so the end of a short exposure is not missed
+    let mut events = session.command.subscribe();
+    let date_obs = chrono::Utc::now().naive_utc().format(DATE_FORMAT).to_string();
+    session
+        .command
+        .call("start_exposure", Some(json!([kind.as_str()])))
+        .await?;
+    on_progress(ExposureProgress { state: ExposureState::Exposing, elapsed: 0.0, duration });
+    wait_for_exposure(session, &mut events, duration, on_progress).await?;
+
+    on_progress(ExposureProgress { state: ExposureState::Downloading, elapsed: duration, duration });
+    let start = Instant::now();
+    let (header, data) = download_image(session.image_address()).await?;
+    log::info!("ASIAIR image download took: {:?}", start.elapsed());
+
+    let mut raw_image = frame_to_raw_image(&header, &data)?;
+    raw_image.metadata = ImageMetadata {
+        exposure: Some(duration as f32),
+        date_obs: Some(date_obs),
+        gain: settings.gain.map(|g| g as f32),
+        instrument: info.map(|i| i.name).filter(|n| !n.is_empty()),
+        ..Default::default()
+    };
+    on_progress(ExposureProgress { state: ExposureState::Complete, elapsed: duration, duration });
+    Ok(raw_image)
+}
+
+pub async fn abort_exposure(session: &AsiairSession) -> Result<(), String> {
+    session.command.call("stop_exposure", None).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl Camera for AsiairSession {
+    async fn camera_capabilities(&self) -> Result<CameraCapabilities, String> {
+        let info = camera_info(self).await?;
+        Ok(CameraCapabilities {
+            name: info.name,
+            width: info.chip_size[0],
+            height: info.chip_size[1],
+            pixel_size: info.pixel_size_um,
+            bayer: info.bayer_pattern.filter(|_| info.is_color),
+            bit_depth: info.bit_depth,
+            max_bin: info.max_bin.unwrap_or(1),
+            has_cooler: info.has_cooler,
+        })
+    }
+
+    async fn camera_state(&self) -> Result<CameraState, String> {
+        camera_state(self).await
+    }
+
+    async fn set_camera_settings(&self, settings: &CameraSettings) -> Result<(), String> {
+        apply_settings(self, settings).await
+    }
+
+    async fn capture(
+        &self,
+        kind: ExposureKind,
+        settings: &CameraSettings,
+        on_progress: &(dyn Fn(ExposureProgress) + Send + Sync),
+    ) -> Result<RawImage, String> {
+        capture(self, kind, settings, on_progress).await
+    }
+
+    async fn abort_exposure(&self) -> Result<(), String> {
+        abort_exposure(self).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use crate::asiair::AsiairPorts;
+    use crate::devices::CameraRoi;
+    use crate::mockasiair::{MockCommandServer, MockDevice};
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    async fn session(mock: &MockCommandServer) -> Arc<AsiairSession> {
+        let ports = AsiairPorts {
+            command: mock.address().port(),
+            guider: mock.address().port(),
+            image: mock.image_address().port(),
+        };
+        Arc::new(AsiairSession::connect_with_ports("127.0.0.1", ports).await.unwrap())
+    }
+
+    fn settings(exposure: f64) -> CameraSettings {
+        CameraSettings { exposure: Some(exposure), ..Default::default() }
+    }
+
+    #[test]
+    fn frames_keep_their_samples_and_bit_depth() {
+        let header = |bits, bayer: Option<&str>| ImageHeader {
+            width: 3,
+            height: 2,
+            bits,
+            size: 0,
+            bayer: bayer.map(|b| b.to_string()),
+        };
+        let image = frame_to_raw_image(&header(8, Some("gbrg")), &[0, 1, 2, 3, 4, 255]).unwrap();
+        assert_eq!(image.raw_image, ndarray::arr2(&[[0, 1, 2], [3, 4, 255]]));
+        assert_eq!(image.saturation_level, 255);
+        assert!(image.bayer_pattern == BayerPattern::GBRG);
+
+        let data: Vec<u8> = [0u16, 1, 4095, 256, 65535, 7].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let image = frame_to_raw_image(&header(16, None), &data).unwrap();
+        assert_eq!(image.raw_image, ndarray::arr2(&[[0, 1, 4095], [256, 65535, 7]]));
+        assert_eq!(image.saturation_level, 65535);
+        assert!(image.bayer_pattern == BayerPattern::NONE);
+
+        assert!(frame_to_raw_image(&header(16, None), &data[..10]).is_err());
+    }
+
+    #[tokio::test]
+    async fn capture_downloads_the_frame() {
+        let mock = MockCommandServer::start("127.0.0.1:0", "127.0.0.1:0", MockDevice::default()).unwrap();
+        let session = session(&mock).await;
+        let progress = Mutex::new(vec![]);
+        let settings = CameraSettings { gain: Some(200), binning: Some(2), ..settings(0.2) };
+        let image = capture(&session, ExposureKind::Preview, &settings, |p| progress.lock().unwrap().push(p.state))
+            .await
+            .unwrap();
+
+        // The 1936x1096 sensor binned 2x2, 16 bit RGGB samples
+        assert_eq!(image.dimensions(), (968, 548));
+        assert_eq!(image.saturation_level, 65535);
+        assert!(image.bayer_pattern == BayerPattern::RGGB);
+        assert!(*image.raw_image.iter().max().unwrap() > 3000);
+        assert_eq!(image.metadata.exposure, Some(0.2));
+        assert_eq!(image.metadata.gain, Some(200.0));
+        assert_eq!(image.metadata.instrument.as_deref(), Some("ZWO ASI462MC"));
+        assert!(image.metadata.date_obs.is_some());
+        let progress = progress.into_inner().unwrap();
+        assert_eq!(progress.first(), Some(&ExposureState::Exposing));
+        assert_eq!(progress.last(), Some(&ExposureState::Complete));
+        assert!(progress.contains(&ExposureState::Downloading));
+
+        let camera = mock.state().camera;
+        assert_eq!((camera.exposure_us, camera.gain, camera.binning), (200_000, 200, 2));
+        assert_eq!(camera.state, "idle");
+
+        // Exposure time from the camera, ROI in binned pixels
+        let roi = CameraRoi { x: 10, y: 10, width: 200, height: 100 };
+        let settings = CameraSettings { roi: Some(roi), ..Default::default() };
+        let image = capture(&session, ExposureKind::Light, &settings, |_| {}).await.unwrap();
+        assert_eq!(image.dimensions(), (200, 100));
+        assert_eq!(image.metadata.exposure, Some(0.2));
+        assert_eq!(image.metadata.gain, None);
+    }
+
+    #[tokio::test]
+    async fn one_capture_runs_at_a_time() {
+        let mock = MockCommandServer::start("127.0.0.1:0", "127.0.0.1:0", MockDevice::default()).unwrap();
+        let session = session(&mock).await;
+        let (long, short) = (settings(0.5), settings(0.1));
+        let first = capture(&session, ExposureKind::Preview, &long, |_| {});
+        let second = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            capture(&session, ExposureKind::Preview, &short, |_| {}).await
+        };
+        let (first, second) = tokio::join!(first, second);
+        assert!(first.is_ok());
+        assert_eq!(second.unwrap_err(), "The camera is already exposing");
+        assert!(!session.exposing.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn failed_captures_release_the_camera() {
+        let mock = MockCommandServer::start("127.0.0.1:0", "127.0.0.1:0", MockDevice::default()).unwrap();
+        let session = session(&mock).await;
+        let (long, short) = (settings(5.0), settings(0.1));
+
+        // Refused settings
+        assert!(capture(&session, ExposureKind::Preview, &settings(-1.0), |_| {}).await.is_err());
+        assert!(!session.exposing.load(Ordering::SeqCst));
+
+        // Stopped from elsewhere
+        let stop = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            abort_exposure(&session).await.unwrap();
+        };
+        let (result, _) = tokio::join!(capture(&session, ExposureKind::Preview, &long, |_| {}), stop);
+        assert_eq!(result.unwrap_err(), "Exposure failed: cancelled");
+        assert!(!session.exposing.load(Ordering::SeqCst));
+
+        // Dropped while exposing
+        let cancelled = capture(&session, ExposureKind::Preview, &long, |_| {});
+        assert!(tokio::time::timeout(Duration::from_millis(200), cancelled).await.is_err());
+        assert!(!session.exposing.load(Ordering::SeqCst));
+        abort_exposure(&session).await.unwrap();
+        let image = capture(&session, ExposureKind::Preview, &short, |_| {});
+        assert!(tokio::time::timeout(WAIT, image).await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,363 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use serde_json::json;
+use tauri::{AppHandle, Emitter};
+use tokio::{sync::broadcast, task::JoinHandle};
+
+use crate::asiair::{AsiairClient, AsiairSession};
+use crate::asiaircamera::camera_info;
+use crate::asiairmount::mount_state;
+use crate::deviceregistry::DeviceSet;
+use crate::devices::{
+    Cooler, CoolerState, DeviceKind, FilterWheel, FilterWheelState, Focuser, FocuserState, Guider, GuiderState, GuiderStatus,
+    Settle,
+};
+
+// Focuser and filter wheel are read this often while they move
+const MOTION_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// The sensor temperature changes slowly and sends no event
+const COOLER_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+async fn control_value(session: &AsiairSession, control: &str) -> Result<f64, String> {
+    session
+        .command
+        .call("get_control_value", Some(json!([control])))
+        .await?
+        .get("value")
+        .and_then(|v| v.as_f64())
+        .ok_or_else(|| format!("Invalid {} value", control))
+}
+
+async fn set_control_value(session: &AsiairSession, control: &str, value: i64) -> Result<(), String> {
+    session
+        .command
+        .call("set_control_value", Some(json!([control, value])))
+        .await?;
+    Ok(())
+}
+
+#[async_trait]
+impl Focuser for AsiairSession {
+    async fn focuser_state(&self) -> Result<FocuserState, String> {
+        let state = self.command.call("get_focuser_state", None).await?;
+        let integer = |key: &str| state.get(key).and_then(|v| v.as_i64()).map(|v| v as i32);
+        Ok(FocuserState {
+            position: integer("position").ok_or("Invalid focuser position")?,
+            max_position: integer("max_step"),
+            moving: state.get("is_moving").and_then(|v| v.as_bool()).unwrap_or(false),
+            temperature: state.get("temperature").and_then(|v| v.as_f64()),
+        })
+    }
+
+    async fn move_focuser(&self, position: i32) -> Result<(), String> {
+        self.command.call("move_focuser", Some(json!([position]))).await?;
+        Ok(())
+    }
+
+    async fn halt_focuser(&self) -> Result<(), String> {
+        self.command.call("stop_focuser", None).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FilterWheel for AsiairSession {
+    async fn filter_wheel_state(&self) -> Result<FilterWheelState, String> {
+        let (setting, state) = tokio::try_join!(
+            self.command.call("get_wheel_setting", None),
+            self.command.call("get_wheel_state", None),
+        )?;
+        let names = setting
+            .get("names")
+            .and_then(|n| n.as_array())
+            .ok_or("Invalid filter names")?
+            .iter()
+            .map(|n| n.as_str().unwrap_or_default().to_string())
+            .collect();
+        Ok(FilterWheelState {
+            // -1 while the wheel turns
+            position: state.get("position").and_then(|p| p.as_u64()).map(|p| p as usize),
+            names,
+            moving: state.get("moving").and_then(|m| m.as_bool()).unwrap_or(false),
+        })
+    }
+
+    async fn select_filter(&self, position: usize) -> Result<(), String> {
+        self.command.call("set_wheel_position", Some(json!([position]))).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Cooler for AsiairSession {
+    async fn cooler_state(&self) -> Result<CoolerState, String> {
+        let (temperature, cooler_on, target, power) = tokio::try_join!(
+            control_value(self, "Temperature"),
+            control_value(self, "CoolerOn"),
+            control_value(self, "TargetTemp"),
+            control_value(self, "CoolPowerPerc"),
+        )?;
+        Ok(CoolerState {
+            // Tenths of a degree, as in the ZWO camera SDK
+            temperature: temperature / 10.0,
+            cooler_on: cooler_on != 0.0,
+            target: Some(target),
+            power: Some(power),
+        })
+    }
+
+    async fn set_cooler(&self, on: bool, target: Option<f64>) -> Result<(), String> {
+        if let Some(target) = target {
+            // Whole degrees only
+            set_control_value(self, "TargetTemp", target.round() as i64).await?;
+        }
+        set_control_value(self, "CoolerOn", on as i64).await
+    }
+}
+
+impl AsiairSession {
+    fn guider(&self) -> Result<&AsiairClient, String> {
+        self.guider.as_ref().ok_or_else(|| "The ASIAIR guider is not connected".to_string())
+    }
+}
+
+// The guider port speaks the PHD2 event server protocol
+#[async_trait]
+impl Guider for AsiairSession {
+    async fn guider_state(&self) -> Result<GuiderState, String> {
+        let guider = self.guider()?;
+        let (state, settling) = tokio::try_join!(
+            guider.call("get_app_state", None),
+            guider.call("get_settling", None),
+        )?;
+        let state = match state.as_str() {
+            Some("Stopped") | Some("Selected") => GuiderStatus::Stopped,
+            Some("Looping") => GuiderStatus::Looping,
+            Some("Calibrating") => GuiderStatus::Calibrating,
+            Some("Guiding") => GuiderStatus::Guiding,
+            Some("LostLock") => GuiderStatus::LostLock,
+            Some("Paused") => GuiderStatus::Paused,
+            _ => return Err(format!("Invalid guider state {}", state)),
+        };
+        Ok(GuiderState {
+            state,
+            settling: settling.as_bool().unwrap_or(false),
+        })
+    }
+
+    async fn start_guiding(&self, settle: Settle) -> Result<(), String> {
+        let params = json!({ "settle": settle, "recalibrate": false });
+        self.guider()?.call("guide", Some(params)).await?;
+        Ok(())
+    }
+
+    async fn stop_guiding(&self) -> Result<(), String> {
+        self.guider()?.call("stop_capture", None).await?;
+        Ok(())
+    }
+
+    async fn dither(&self, pixels: f64, ra_only: bool, settle: Settle) -> Result<(), String> {
+        let params = json!({ "amount": pixels, "raOnly": ra_only, "settle": settle });
+        self.guider()?.call("dither", Some(params)).await?;
+        Ok(())
+    }
+}
+
+// Devices of the session for the device registry, those the ASIAIR does
+// not answer for are left out
+pub async fn device_set(session: &Arc<AsiairSession>) -> DeviceSet {
+    let (camera, mount, focuser, wheel) = tokio::join!(
+        camera_info(session),
+        mount_state(session),
+        session.focuser_state(),
+        session.filter_wheel_state(),
+    );
+    let has_cooler = camera.as_ref().is_ok_and(|info| info.has_cooler);
+    DeviceSet {
+        backend: "ASIAIR".to_string(),
+        camera: camera.is_ok().then(|| session.clone() as _),
+        mount: mount.is_ok().then(|| session.clone() as _),
+        focuser: focuser.is_ok().then(|| session.clone() as _),
+        filter_wheel: wheel.is_ok().then(|| session.clone() as _),
+        cooler: has_cooler.then(|| session.clone() as _),
+        guider: session.guider.is_some().then(|| session.clone() as _),
+        rotator: None,
+        dome: None,
+    }
+}
+
+fn emit_state(app: &AppHandle, event: &str, telescope_index: u32, state: &impl serde::Serialize) {
+    let payload = json!({ "index": telescope_index, "state": state });
+    if let Err(e) = app.emit(event, payload) {
+        log::warn!("Failed to emit {}: {}", event, e);
+    }
+}
+
+// Emits the focuser, filter wheel and cooler states when they change. Moves
+// are read on their events and while they last, the cooler on a timer.
+// Only the `devices` of the session are read. Ends with the session.
+pub fn spawn_status_task(
+    app: AppHandle,
+    telescope_index: u32,
+    session: &Arc<AsiairSession>,
+    devices: &[DeviceKind],
+) -> JoinHandle<()> {
+    let mut events = session.command.subscribe();
+    let weak = Arc::downgrade(session);
+    let has_focuser = devices.contains(&DeviceKind::Focuser);
+    let has_wheel = devices.contains(&DeviceKind::FilterWheel);
+    let has_cooler = devices.contains(&DeviceKind::Cooler);
+    tokio::spawn(async move {
+        let mut focuser: Option<FocuserState> = None;
+        let mut wheel: Option<FilterWheelState> = None;
+        let mut cooler: Option<CoolerState> = None;
+        let mut motion = tokio::time::interval(MOTION_POLL_INTERVAL);
+        let mut cooler_timer = tokio::time::interval(COOLER_POLL_INTERVAL);
+        // Everything is read once at the start
+        let (mut read_focuser, mut read_wheel) = (has_focuser, has_wheel);
+        cooler_timer.tick().await;
+        let mut read_cooler = has_cooler;
+        loop {
+            let Some(session) = weak.upgrade() else { break };
+            if read_focuser {
+                match session.focuser_state().await {
+                    Ok(state) if focuser.as_ref() != Some(&state) => {
+                        emit_state(&app, "focuser_state", telescope_index, &state);
+                        focuser = Some(state);
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::debug!("No focuser state: {}", e),
+                }
+            }
+            if read_wheel {
+                match session.filter_wheel_state().await {
+                    Ok(state) if wheel.as_ref() != Some(&state) => {
+                        emit_state(&app, "filter_wheel_state", telescope_index, &state);
+                        wheel = Some(state);
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::debug!("No filter wheel state: {}", e),
+                }
+            }
+            if read_cooler {
+                match session.cooler_state().await {
+                    Ok(state) if cooler.as_ref() != Some(&state) => {
+                        emit_state(&app, "cooler_state", telescope_index, &state);
+                        cooler = Some(state);
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::debug!("No cooler state: {}", e),
+                }
+            }
+            drop(session);
+
+            let focuser_moving = focuser.as_ref().is_some_and(|s| s.moving);
+            let wheel_moving = wheel.as_ref().is_some_and(|s| s.moving);
+            (read_focuser, read_wheel, read_cooler) = (false, false, false);
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        read_focuser = has_focuser && event.name.starts_with("Focuser");
+                        read_wheel = has_wheel && event.name.starts_with("Wheel");
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => (read_focuser, read_wheel) = (has_focuser, has_wheel),
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = motion.tick(), if focuser_moving || wheel_moving => {
+                    (read_focuser, read_wheel) = (focuser_moving, wheel_moving);
+                }
+                _ = cooler_timer.tick(), if has_cooler => read_cooler = true,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asiair::AsiairPorts;
+    use crate::mockasiair::{MockCommandServer, MockDevice};
+
+    async fn session(mock: &MockCommandServer) -> AsiairSession {
+        let ports = AsiairPorts {
+            command: mock.address().port(),
+            guider: mock.address().port(),
+            image: mock.image_address().port(),
+        };
+        AsiairSession::connect_with_ports("127.0.0.1", ports).await.unwrap()
+    }
+
+    fn focuser_target(mock: &MockCommandServer) -> Option<i32> {
+        mock.state().focuser.moving.map(|(target, _, _)| target)
+    }
+
+    #[tokio::test]
+    async fn relative_focuser_moves_stay_in_range() {
+        let mock = MockCommandServer::start("127.0.0.1:0", "127.0.0.1:0", MockDevice::default()).unwrap();
+        let session = session(&mock).await;
+        let state = session.focuser_state().await.unwrap();
+        assert_eq!((state.position, state.max_position), (5000, Some(10000)));
+
+        session.move_focuser_by(-120).await.unwrap();
+        assert_eq!(focuser_target(&mock), Some(4880));
+        session.halt_focuser().await.unwrap();
+        let position = session.focuser_state().await.unwrap().position;
+        assert!((4880..=5000).contains(&position), "{}", position);
+
+        session.move_focuser_by(-20000).await.unwrap();
+        assert_eq!(focuser_target(&mock), Some(0));
+        session.halt_focuser().await.unwrap();
+
+        session.move_focuser_by(i32::MAX).await.unwrap();
+        assert_eq!(focuser_target(&mock), Some(10000));
+        session.halt_focuser().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn filters_are_selected_and_waited_for() {
+        let mock = MockCommandServer::start("127.0.0.1:0", "127.0.0.1:0", MockDevice::default()).unwrap();
+        let session = session(&mock).await;
+
+        let state = session.select_filter_and_wait(2, Duration::from_secs(5)).await.unwrap();
+        assert_eq!((state.position, state.moving), (Some(2), false));
+        assert_eq!(state.names[2], "G");
+        assert_eq!(mock.state().filter_wheel.position, 2);
+
+        // The wheel has 7 slots
+        let error = session.select_filter_and_wait(7, Duration::from_secs(5)).await.unwrap_err();
+        assert_eq!(error, "No filter in slot 7, the wheel has 7");
+        assert_eq!(mock.state().filter_wheel.move_count, 1);
+
+        // 4 slots take 800 ms
+        let error = session.select_filter_and_wait(6, Duration::from_millis(100)).await.unwrap_err();
+        assert_eq!(error, "Timeout waiting for the filter wheel to reach slot 6");
+        let state = session.filter_wheel_state().await.unwrap();
+        assert_eq!((state.position, state.moving), (None, true));
+        let state = session.select_filter_and_wait(6, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(state.position, Some(6));
+    }
+
+    #[tokio::test]
+    async fn cooler_temperatures_are_in_degrees() {
+        let mock = MockCommandServer::start("127.0.0.1:0", "127.0.0.1:0", MockDevice::default()).unwrap();
+        let session = session(&mock).await;
+
+        // The sensor stays at the ambient 20 degrees with the cooler off
+        let state = session.cooler_state().await.unwrap();
+        assert_eq!((state.temperature, state.cooler_on, state.target), (20.0, false, Some(0.0)));
+        assert_eq!(state.power, Some(0.0));
+
+        session.set_cooler(true, Some(-10.4)).await.unwrap();
+        assert_eq!(mock.state().camera.target_temperature, -10);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let state = session.cooler_state().await.unwrap();
+        assert!(state.cooler_on);
+        assert_eq!(state.target, Some(-10.0));
+        // 2 degrees per second, read in tenths
+        assert!(state.temperature < 20.0 && state.temperature > 18.0, "{}", state.temperature);
+        assert_eq!(state.temperature, (state.temperature * 10.0).round() / 10.0);
+        assert!(state.power.unwrap() > 0.0);
+    }
+}
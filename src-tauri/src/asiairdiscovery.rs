@@ -1,92 +1,558 @@
 use std::{
     collections::HashMap,
-    net::UdpSocket,
-    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex},
-    thread,
-    time::Duration,
+    io::ErrorKind,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tauri::{AppHandle, Emitter};
+use tauri::{AppHandle, Emitter, State};
 use serde_json::{json, Value};
 
-static DISCOVERY_RUNNING: AtomicBool = AtomicBool::new(false);
+// The worker checks for cancellation this often, so stopping never waits
+// for a whole reply window
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-#[derive(Debug, Clone, serde::Serialize)]
-struct Device{
-    title: String,
-    value: String,
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveryConfig {
+    // UDP port ASIAIRs listen on for scan_air requests
+    pub port: u16,
+    // Broadcast on every network, manually added hosts are always probed
+    pub broadcast: bool,
+    // Replies are collected for this long after each broadcast
+    pub reply_window: Duration,
+    pub broadcast_interval: Duration,
+    // Devices that have not answered for this long are dropped
+    pub device_timeout: Duration,
 }
 
-#[tauri::command]
-pub fn start_asiair_discovery(app: AppHandle) {
-    if DISCOVERY_RUNNING.swap(true, Ordering::SeqCst) {
-        return; // Already running
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            port: 4720,
+            broadcast: true,
+            reply_window: Duration::from_millis(2000),
+            broadcast_interval: Duration::from_millis(1000),
+            device_timeout: Duration::from_secs(10),
+        }
     }
+}
 
-    let socket = UdpSocket::bind("0.0.0.0:0").expect("Failed to bind UDP socket");
-    socket
-        .set_broadcast(true)
-        .expect("Failed to enable broadcast");
-
-    let app_handle = app.clone();
-    let devices_cache = Arc::new(Mutex::new(HashMap::<String, Device>::new())); // Cache for devices
-
-    log::info!("Starting Asiair discovery...");
-    let _ = thread::Builder::new().name("asiair_discovery".to_string()).spawn(move || {
-        loop {
-            if DISCOVERY_RUNNING.load(Ordering::SeqCst) {
-                let discovery_message = json!({
-                    "id": "132", // ASIAir App uses this value hardcoded
-                    "method": "scan_air",
-                    "name": "iphone"
-                })
-                .to_string() + "\r\n";
-
-                let _ = socket.send_to(discovery_message.as_bytes(), "255.255.255.255:4720");
-
-                let mut buf = [0u8; 4096];
-                socket
-                    .set_read_timeout(Some(Duration::from_millis(2000)))
-                    .expect("Failed to set timeout");
-
-                if let Ok((size, _src)) = socket.recv_from(&mut buf) {
-                    let response = String::from_utf8_lossy(&buf[..size]);
-                    if let Ok(json_response) = serde_json::from_str::<Value>(&response) {
-                        if let Some(result) = json_response.get("result") {
-                            if let Some(guid) = result.get("guid").and_then(|v| v.as_str()) {
-                                if let Some(ip) = result.get("ip").and_then(|v| v.as_str()) {
-                                    if let Some(ssid) = result.get("ssid").and_then(|v| v.as_str()) {
-                                        let device = Device {
-                                            title: ssid.to_string(),
-                                            value: ip.to_string(),
-                                        };
-
-                                        // Assuming each device has a unique 'guid'
-                                        // Store in cache to avoid duplicates
-                                        let mut cache = devices_cache.lock().unwrap();
-                                        cache.insert(guid.to_string(), device);
-                                    }
-                                }
-                            }
-                        }
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Device {
+    // SSID of the ASIAIR, shown in the connection dialog
+    pub title: String,
+    // IP address
+    pub value: String,
+    pub guid: String,
+    pub model: Option<String>,
+    pub firmware: Option<String>,
+    // Milliseconds since the epoch
+    pub last_seen: u64,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Device described by a `scan_air` reply, None for anything else
+fn parse_reply(bytes: &[u8], last_seen: u64) -> Option<Device> {
+    let response: Value = serde_json::from_slice(bytes).ok()?;
+    let result = response.get("result")?;
+    let string = |key: &str| result.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+
+    let ip = string("ip")?;
+    Some(Device {
+        title: string("ssid").or_else(|| string("name")).unwrap_or_else(|| ip.clone()),
+        // Without a guid the address is the best identity there is
+        guid: string("guid").unwrap_or_else(|| ip.clone()),
+        value: ip,
+        model: string("model"),
+        firmware: string("firmware").or_else(|| string("version")),
+        last_seen,
+    })
+}
+
+// A panic while holding the lock does not make the cache unusable
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+type DeviceCache = Arc<Mutex<HashMap<String, Device>>>;
+
+// Manually added hosts and their addresses. Names are resolved on a thread of
+// their own, a slow DNS server never holds up the discovery worker.
+#[derive(Default)]
+struct Hosts {
+    names: Vec<String>,
+    addresses: Vec<SocketAddr>,
+    resolving: bool,
+}
+
+type HostList = Arc<Mutex<Hosts>>;
+
+// Broadcast address of the subnet of an interface, the one it reports or
+// else the address with all the host bits set. Point to point links such as
+// VPNs have none.
+fn broadcast_address(v4: &if_addrs::Ifv4Addr) -> Option<Ipv4Addr> {
+    if v4.prefixlen >= 31 {
+        return None;
+    }
+    Some(v4.broadcast.unwrap_or_else(|| Ipv4Addr::from(u32::from(v4.ip) | !u32::from(v4.netmask))))
+}
+
+// Directed broadcast address of each IPv4 network the machine is on. The
+// limited broadcast 255.255.255.255 only leaves through one interface on
+// machines with several of them (Wi-Fi, Ethernet, VPN...), a directed one
+// is routed through the interface of its subnet.
+fn broadcast_addresses() -> Vec<Ipv4Addr> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            log::warn!("Failed to list network interfaces: {}", e);
+            return vec![];
+        }
+    };
+    let mut addresses: Vec<Ipv4Addr> = interfaces
+        .iter()
+        .filter(|i| !i.is_loopback())
+        .filter_map(|i| match &i.addr {
+            if_addrs::IfAddr::V4(v4) => broadcast_address(v4),
+            _ => None,
+        })
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+// "192.168.1.20", "asiair.local" or either with an explicit port
+fn resolve_host(host: &str, port: u16) -> Option<SocketAddr> {
+    let resolved = if host.contains(':') {
+        host.to_socket_addrs()
+    } else {
+        (host, port).to_socket_addrs()
+    };
+    match resolved {
+        Ok(mut addresses) => addresses.find(|a| a.is_ipv4()),
+        Err(e) => {
+            log::warn!("Failed to resolve ASIAIR host {}: {}", host, e);
+            None
+        }
+    }
+}
+
+// Resolves the host names again in the background. Addresses resolved for a
+// list that has been replaced since are dropped.
+fn refresh_hosts(hosts: &HostList, port: u16) {
+    let names = {
+        let mut hosts = lock(hosts);
+        if hosts.names.is_empty() {
+            hosts.addresses.clear();
+            return;
+        }
+        hosts.resolving = true;
+        hosts.names.clone()
+    };
+    let list = hosts.clone();
+    let spawned = thread::Builder::new()
+        .name("asiair_resolve".to_string())
+        .spawn(move || {
+            let addresses: Vec<SocketAddr> = names.iter().filter_map(|h| resolve_host(h, port)).collect();
+            let mut hosts = lock(&list);
+            if hosts.names == names {
+                hosts.addresses = addresses;
+                hosts.resolving = false;
+            }
+        });
+    if let Err(e) = spawned {
+        log::warn!("Failed to resolve ASIAIR hosts: {}", e);
+        lock(hosts).resolving = false;
+    }
+}
+
+// Where each scan_air request goes, enumerated again every round as
+// interfaces come and go
+fn discovery_targets(config: &DiscoveryConfig, hosts: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut targets = vec![];
+    if config.broadcast {
+        targets.push(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::BROADCAST, config.port)));
+        targets.extend(
+            broadcast_addresses()
+                .into_iter()
+                .map(|a| SocketAddr::V4(SocketAddrV4::new(a, config.port))),
+        );
+    }
+    targets.extend_from_slice(hosts);
+    targets
+}
+
+fn sorted_devices(cache: &DeviceCache) -> Vec<Device> {
+    let mut devices: Vec<Device> = lock(cache).values().cloned().collect();
+    devices.sort_by(|a, b| (&a.title, &a.value).cmp(&(&b.title, &b.value)));
+    devices
+}
+
+// Sleeps for `duration` unless stopped first, returns false when stopped
+fn wait(stop: &AtomicBool, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while !stop.load(Ordering::SeqCst) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        thread::sleep(remaining.min(POLL_INTERVAL));
+    }
+    false
+}
+
+fn discovery_loop(
+    socket: UdpSocket,
+    config: DiscoveryConfig,
+    cache: DeviceCache,
+    hosts: HostList,
+    stop: Arc<AtomicBool>,
+    on_update: impl Fn(Vec<Device>),
+) {
+    let discovery_message = json!({
+        "id": "132", // ASIAir App uses this value hardcoded
+        "method": "scan_air",
+        "name": "iphone"
+    })
+    .to_string() + "\r\n";
+    let mut buf = [0u8; 4096];
+
+    // Devices found by a previous run are shown right away
+    on_update(sorted_devices(&cache));
+
+    while !stop.load(Ordering::SeqCst) {
+        // Addresses from the previous resolution until the new one is done
+        let addresses = {
+            let list = lock(&hosts);
+            (list.addresses.clone(), list.resolving)
+        };
+        if !addresses.1 {
+            refresh_hosts(&hosts, config.port);
+        }
+        for target in discovery_targets(&config, &addresses.0) {
+            if let Err(e) = socket.send_to(discovery_message.as_bytes(), target) {
+                log::warn!("ASIAIR discovery request to {} failed: {}", target, e);
+            }
+        }
+
+        // Every ASIAIR on the network answers, read all the replies of the
+        // window and not only the first one
+        let deadline = Instant::now() + config.reply_window;
+        while !stop.load(Ordering::SeqCst) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || socket.set_read_timeout(Some(remaining.min(POLL_INTERVAL))).is_err() {
+                break;
+            }
+            match socket.recv_from(&mut buf) {
+                Ok((size, _src)) => {
+                    if let Some(device) = parse_reply(&buf[..size], now_millis()) {
+                        lock(&cache).insert(device.guid.clone(), device);
                     }
                 }
-
-                let cache = devices_cache.lock().unwrap();
-                let consolidated_list: Vec<Device> = cache.values().cloned().collect();
-                app_handle
-                    .emit("discovered_device", consolidated_list)
-                    .expect("Failed to emit device list");
-                thread::sleep(Duration::from_millis(1000)); // Wait before sending the next discovery message
-            } else {
-                break; // Exit the loop when discovery is stopped
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+                Err(e) => {
+                    log::warn!("ASIAIR discovery receive failed: {}", e);
+                    break;
+                }
             }
         }
-    });
+
+        let now = now_millis();
+        let timeout = config.device_timeout.as_millis() as u64;
+        lock(&cache).retain(|_, d| now.saturating_sub(d.last_seen) < timeout);
+        on_update(sorted_devices(&cache));
+
+        if !wait(&stop, config.broadcast_interval) {
+            break;
+        }
+    }
+}
+
+fn bind_socket() -> Result<UdpSocket, String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("Failed to bind UDP socket: {}", e))?;
+    socket
+        .set_broadcast(true)
+        .map_err(|e| format!("Failed to enable broadcast: {}", e))?;
+    Ok(socket)
+}
+
+struct Worker {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+// Discovery of the ASIAIRs on the network, kept as Tauri state. At most one
+// worker thread runs at a time, and the devices it found are kept between
+// runs.
+pub struct AsiairDiscovery {
+    config: DiscoveryConfig,
+    devices: DeviceCache,
+    // Probed by unicast on top of the broadcasts
+    hosts: HostList,
+    // Held while starting and while taking the worker out to stop it. The
+    // thread is joined after the lock is released.
+    worker: Mutex<Option<Worker>>,
+}
+
+impl Default for AsiairDiscovery {
+    fn default() -> Self {
+        Self::new(DiscoveryConfig::default())
+    }
+}
+
+impl AsiairDiscovery {
+    pub fn new(config: DiscoveryConfig) -> Self {
+        Self {
+            config,
+            devices: DeviceCache::default(),
+            hosts: HostList::default(),
+            worker: Mutex::new(None),
+        }
+    }
+
+    pub fn devices(&self) -> Vec<Device> {
+        sorted_devices(&self.devices)
+    }
+
+    pub fn hosts(&self) -> Vec<String> {
+        lock(&self.hosts).names.clone()
+    }
+
+    // Takes effect once the names are resolved, in the background
+    pub fn set_hosts(&self, hosts: Vec<String>) {
+        {
+            let mut list = lock(&self.hosts);
+            list.names = hosts
+                .into_iter()
+                .map(|h| h.trim().to_string())
+                .filter(|h| !h.is_empty())
+                .collect();
+            list.addresses.clear();
+        }
+        refresh_hosts(&self.hosts, self.config.port);
+    }
+
+    #[cfg(test)]
+    pub fn is_running(&self) -> bool {
+        lock(&self.worker).as_ref().is_some_and(|w| !w.handle.is_finished())
+    }
+
+    // Starts the worker, `on_update` gets the device list after each
+    // broadcast. Does nothing when already running.
+    pub fn start(&self, on_update: impl Fn(Vec<Device>) + Send + 'static) -> Result<(), String> {
+        let mut worker = lock(&self.worker);
+        if worker.as_ref().is_some_and(|w| !w.handle.is_finished()) {
+            return Ok(());
+        }
+
+        let socket = bind_socket()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let (config, cache, hosts, worker_stop) =
+            (self.config.clone(), self.devices.clone(), self.hosts.clone(), stop.clone());
+        log::info!("Starting Asiair discovery...");
+        let handle = thread::Builder::new()
+            .name("asiair_discovery".to_string())
+            .spawn(move || discovery_loop(socket, config, cache, hosts, worker_stop, on_update))
+            .map_err(|e| format!("Failed to start discovery: {}", e))?;
+        *worker = Some(Worker { stop, handle });
+        Ok(())
+    }
+
+    // Tells the worker to stop, returning its thread to join
+    fn request_stop(&self) -> Option<JoinHandle<()>> {
+        let Worker { stop, handle } = lock(&self.worker).take()?;
+        log::info!("Stopping Asiair discovery...");
+        stop.store(true, Ordering::SeqCst);
+        Some(handle)
+    }
+
+    // Stops the worker and waits for its thread to exit
+    pub fn stop(&self) {
+        if let Some(handle) = self.request_stop() {
+            join_worker(handle);
+        }
+    }
+}
+
+fn join_worker(handle: JoinHandle<()>) {
+    if handle.join().is_err() {
+        log::warn!("ASIAIR discovery thread panicked");
+    }
+}
+
+impl Drop for AsiairDiscovery {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[tauri::command]
+pub fn start_asiair_discovery(app: AppHandle, discovery: State<'_, AsiairDiscovery>) -> Result<(), String> {
+    discovery.start(move |devices| {
+        if let Err(e) = app.emit("discovered_device", devices) {
+            log::warn!("Failed to emit device list: {}", e);
+        }
+    })
+}
+
+// The worker thread is joined off the async runtime
+#[tauri::command]
+pub async fn stop_asiair_discovery(discovery: State<'_, AsiairDiscovery>) -> Result<(), String> {
+    if let Some(handle) = discovery.request_stop() {
+        tauri::async_runtime::spawn_blocking(move || join_worker(handle))
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// Devices found so far, also while discovery is stopped
+#[tauri::command]
+pub fn get_asiair_devices(discovery: State<'_, AsiairDiscovery>) -> Vec<Device> {
+    discovery.devices()
+}
+
+#[tauri::command]
+pub fn get_asiair_hosts(discovery: State<'_, AsiairDiscovery>) -> Vec<String> {
+    discovery.hosts()
 }
 
+// Hosts probed directly, for ASIAIRs that broadcasts do not reach
 #[tauri::command]
-pub fn stop_asiair_discovery() {
+pub fn set_asiair_hosts(discovery: State<'_, AsiairDiscovery>, hosts: Vec<String>) {
+    discovery.set_hosts(hosts);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mockasiair::{MockAsiair, MockDevice};
+
+    fn interface(ip: [u8; 4], prefixlen: u8, broadcast: Option<[u8; 4]>) -> if_addrs::Ifv4Addr {
+        let netmask = u32::MAX.checked_shl(32 - prefixlen as u32).unwrap_or(0);
+        if_addrs::Ifv4Addr {
+            ip: Ipv4Addr::from(ip),
+            netmask: Ipv4Addr::from(netmask),
+            prefixlen,
+            broadcast: broadcast.map(Ipv4Addr::from),
+        }
+    }
+
+    #[test]
+    fn broadcast_addresses_of_subnets() {
+        let broadcast = |ip, prefixlen, broadcast| broadcast_address(&interface(ip, prefixlen, broadcast));
+        // Computed from the netmask when the interface does not report it
+        assert_eq!(broadcast([192, 168, 1, 20], 24, None), Some(Ipv4Addr::new(192, 168, 1, 255)));
+        assert_eq!(broadcast([172, 16, 5, 9], 16, None), Some(Ipv4Addr::new(172, 16, 255, 255)));
+        assert_eq!(broadcast([10, 1, 2, 3], 8, None), Some(Ipv4Addr::new(10, 255, 255, 255)));
+        assert_eq!(
+            broadcast([192, 168, 1, 20], 24, Some([192, 168, 1, 255])),
+            Some(Ipv4Addr::new(192, 168, 1, 255))
+        );
+        assert_eq!(
+            broadcast([172, 16, 5, 9], 16, Some([172, 16, 255, 255])),
+            Some(Ipv4Addr::new(172, 16, 255, 255))
+        );
+        // Point to point links
+        assert_eq!(broadcast([10, 8, 0, 2], 31, None), None);
+        assert_eq!(broadcast([10, 8, 0, 2], 32, None), None);
+        assert_eq!(broadcast([10, 8, 0, 2], 32, Some([10, 8, 0, 2])), None);
+    }
+
+    // Long enough for a few rounds of the config below on a loaded machine
+    const SETTLE: Duration = Duration::from_secs(5);
 
-    log::info!("Stoping Asiair discovery...");
-    DISCOVERY_RUNNING.store(false, Ordering::SeqCst);
-}
\ No newline at end of file
+    // Probes the mock through the manual host list only, so the tests do not
+    // depend on the network the machine is on
+    fn discovery(mock: &MockAsiair) -> AsiairDiscovery {
+        let discovery = AsiairDiscovery::new(DiscoveryConfig {
+            port: mock.address().port(),
+            broadcast: false,
+            reply_window: Duration::from_millis(100),
+            broadcast_interval: Duration::from_millis(50),
+            device_timeout: Duration::from_millis(500),
+        });
+        discovery.set_hosts(vec!["127.0.0.1".to_string()]);
+        discovery
+    }
+
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + SETTLE;
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    #[test]
+    fn finds_and_expires_devices() {
+        let mut mock = MockAsiair::start("127.0.0.1:0", MockDevice::default()).unwrap();
+        let discovery = discovery(&mock);
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let sink = updates.clone();
+        discovery.start(move |devices| lock(&sink).push(devices)).unwrap();
+        assert!(discovery.is_running());
+
+        assert!(wait_for(|| !discovery.devices().is_empty()));
+        let devices = discovery.devices();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].title, "ASIAIR_MOCK");
+        assert_eq!(devices[0].value, "127.0.0.1");
+        assert_eq!(devices[0].model.as_deref(), Some("ASIAIR Plus"));
+        assert!(wait_for(|| lock(&updates).iter().any(|d| d.len() == 1)));
+
+        // Devices are kept while stopped and discovery starts again
+        discovery.stop();
+        assert!(!discovery.is_running());
+        assert_eq!(discovery.devices().len(), 1);
+        discovery.start(|_| {}).unwrap();
+        assert!(discovery.is_running());
+
+        // Dropped once the device stops answering for device_timeout
+        mock.stop();
+        assert!(wait_for(|| discovery.devices().is_empty()));
+        discovery.stop();
+    }
+
+    #[test]
+    fn restarting_leaves_one_worker() {
+        let mock = MockAsiair::start("127.0.0.1:0", MockDevice::default()).unwrap();
+        let discovery = discovery(&mock);
+        let calls = Arc::new(Mutex::new([0usize; 3]));
+        let counter = |worker: usize| {
+            let calls = calls.clone();
+            move |_| lock(&calls)[worker] += 1
+        };
+
+        discovery.start(counter(0)).unwrap();
+        discovery.stop();
+        discovery.start(counter(1)).unwrap();
+        // Already running, the callback is never used
+        discovery.start(counter(2)).unwrap();
+        assert!(discovery.is_running());
+
+        assert!(wait_for(|| lock(&calls)[1] >= 2));
+        let before = *lock(&calls);
+        assert!(wait_for(|| lock(&calls)[1] > before[1]));
+        let after = *lock(&calls);
+        assert_eq!(after[0], before[0]);
+        assert_eq!(after[2], 0);
+
+        discovery.stop();
+        assert!(!discovery.is_running());
+    }
+}
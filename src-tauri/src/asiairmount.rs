@@ -0,0 +1,305 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use tokio::{sync::broadcast, task::JoinHandle};
+
+use crate::asiair::AsiairSession;
+use crate::devices::{Mount, MountCapabilities, MountState, MoveDirection, PierSide, TrackingRate};
+
+// The mount state is read again this often while it slews or moves, the
+// ASIAIR only reports the start and the end of a slew
+const MOTION_POLL_INTERVAL: Duration = Duration::from_millis(1000);
+// Slowest and fastest manual move rates, as in the ASIAIR app
+pub const MIN_MOVE_RATE: u32 = 1;
+pub const MAX_MOVE_RATE: u32 = 9;
+
+fn number(value: &Value, key: &str) -> Result<f64, String> {
+    value
+        .get(key)
+        .and_then(|v| v.as_f64())
+        .ok_or_else(|| format!("Missing {} in the mount reply", key))
+}
+
+fn flag(value: &Value, key: &str) -> bool {
+    value.get(key).and_then(|v| v.as_bool()).unwrap_or(false)
+}
+
+fn check_coordinates(ra: f64, dec: f64) -> Result<(), String> {
+    if !(0.0..24.0).contains(&ra) {
+        return Err(format!("Right ascension {} is not between 0 and 24 hours", ra));
+    }
+    if !(-90.0..=90.0).contains(&dec) {
+        return Err(format!("Declination {} is not between -90 and 90 degrees", dec));
+    }
+    Ok(())
+}
+
+pub async fn mount_state(session: &AsiairSession) -> Result<MountState, String> {
+    let command = &session.command;
+    let (equatorial, horizontal, pier_side, tracking, tracking_rate, state) = tokio::try_join!(
+        command.call("scope_get_equ_coord", None),
+        command.call("scope_get_horiz_coord", None),
+        command.call("scope_get_pierside", None),
+        command.call("scope_get_track_state", None),
+        command.call("scope_get_track_rate", None),
+        command.call("scope_get_state", None),
+    )?;
+    Ok(MountState {
+        ra: number(&equatorial, "ra")?,
+        dec: number(&equatorial, "dec")?,
+        alt: number(&horizontal, "alt")?,
+        az: number(&horizontal, "az")?,
+        pier_side: serde_json::from_value(pier_side).unwrap_or(PierSide::Unknown),
+        tracking: tracking.as_bool().unwrap_or(false),
+        tracking_rate: serde_json::from_value(tracking_rate).map_err(|e| format!("Invalid tracking rate: {}", e))?,
+        slewing: flag(&state, "slewing"),
+        moving: flag(&state, "moving"),
+        parked: flag(&state, "parked"),
+    })
+}
+
+pub async fn goto(session: &AsiairSession, ra: f64, dec: f64) -> Result<(), String> {
+    check_coordinates(ra, dec)?;
+    session.command.call("scope_goto", Some(json!([ra, dec]))).await?;
+    Ok(())
+}
+
+pub async fn sync(session: &AsiairSession, ra: f64, dec: f64) -> Result<(), String> {
+    check_coordinates(ra, dec)?;
+    session.command.call("scope_sync", Some(json!([ra, dec]))).await?;
+    Ok(())
+}
+
+// Stops a goto and any manual move
+pub async fn abort(session: &AsiairSession) -> Result<(), String> {
+    session.command.call("scope_abort_slew", None).await?;
+    session.command.call("scope_stop_move", None).await?;
+    Ok(())
+}
+
+pub async fn park(session: &AsiairSession) -> Result<(), String> {
+    session.command.call("scope_park", None).await?;
+    Ok(())
+}
+
+pub async fn unpark(session: &AsiairSession) -> Result<(), String> {
+    session.command.call("scope_unpark", None).await?;
+    Ok(())
+}
+
+pub async fn set_tracking(session: &AsiairSession, tracking: bool, rate: Option<TrackingRate>) -> Result<(), String> {
+    if let Some(rate) = rate {
+        session.command.call("scope_set_track_rate", Some(json!([rate]))).await?;
+    }
+    session.command.call("scope_set_track_state", Some(json!([tracking]))).await?;
+    Ok(())
+}
+
+// Moves until `stop_move`, at a rate between MIN_MOVE_RATE and MAX_MOVE_RATE
+pub async fn start_move(session: &AsiairSession, direction: MoveDirection, rate: u32) -> Result<(), String> {
+    if !(MIN_MOVE_RATE..=MAX_MOVE_RATE).contains(&rate) {
+        return Err(format!("Move rate {} is not between {} and {}", rate, MIN_MOVE_RATE, MAX_MOVE_RATE));
+    }
+    session.command.call("scope_set_move_speed", Some(json!([rate]))).await?;
+    session.command.call("scope_move", Some(json!([direction.as_str()]))).await?;
+    Ok(())
+}
+
+pub async fn stop_move(session: &AsiairSession) -> Result<(), String> {
+    session.command.call("scope_stop_move", None).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl Mount for AsiairSession {
+    async fn mount_capabilities(&self) -> Result<MountCapabilities, String> {
+        Ok(MountCapabilities {
+            can_park: true,
+            can_sync: true,
+            tracking_rates: vec![TrackingRate::Sidereal, TrackingRate::Lunar, TrackingRate::Solar, TrackingRate::King],
+            move_rates: (MIN_MOVE_RATE, MAX_MOVE_RATE),
+        })
+    }
+
+    async fn mount_state(&self) -> Result<MountState, String> {
+        mount_state(self).await
+    }
+
+    async fn goto(&self, ra: f64, dec: f64) -> Result<(), String> {
+        goto(self, ra, dec).await
+    }
+
+    async fn sync(&self, ra: f64, dec: f64) -> Result<(), String> {
+        sync(self, ra, dec).await
+    }
+
+    async fn abort_slew(&self) -> Result<(), String> {
+        abort(self).await
+    }
+
+    async fn park(&self) -> Result<(), String> {
+        park(self).await
+    }
+
+    async fn unpark(&self) -> Result<(), String> {
+        unpark(self).await
+    }
+
+    async fn set_tracking(&self, tracking: bool, rate: Option<TrackingRate>) -> Result<(), String> {
+        set_tracking(self, tracking, rate).await
+    }
+
+    async fn start_move(&self, direction: MoveDirection, rate: u32) -> Result<(), String> {
+        start_move(self, direction, rate).await
+    }
+
+    async fn stop_move(&self) -> Result<(), String> {
+        stop_move(self).await
+    }
+}
+
+// Reads the mount state when an event of the mount arrives, and every
+// MOTION_POLL_INTERVAL while it slews or moves. `on_state` gets it when it
+// changed. Ends with the session.
+pub fn spawn_state_task(session: &Arc<AsiairSession>, on_state: impl Fn(MountState) + Send + 'static) -> JoinHandle<()> {
+    let mut events = session.command.subscribe();
+    let weak = Arc::downgrade(session);
+    tokio::spawn(async move {
+        let mut last: Option<MountState> = None;
+        let mut ticker = tokio::time::interval(MOTION_POLL_INTERVAL);
+        loop {
+            // The state is read right away, then again after each wait
+            let Some(session) = weak.upgrade() else { break };
+            match mount_state(&session).await {
+                Ok(state) if last.as_ref() != Some(&state) => {
+                    on_state(state.clone());
+                    last = Some(state);
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("Failed to read the mount state: {}", e),
+            }
+            drop(session);
+
+            let in_motion = last.as_ref().is_some_and(|s| s.slewing || s.moving);
+            loop {
+                tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(event) if event.name.starts_with("Scope") => break,
+                        Ok(_) => {}
+                        Err(broadcast::error::RecvError::Lagged(_)) => break,
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    },
+                    _ = ticker.tick(), if in_motion => break,
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asiair::AsiairPorts;
+    use crate::mockasiair::{MockCommandServer, MockDevice};
+    use tokio::sync::mpsc;
+
+    async fn session(mock: &MockCommandServer) -> Arc<AsiairSession> {
+        let ports = AsiairPorts {
+            command: mock.address().port(),
+            guider: mock.address().port(),
+            image: mock.image_address().port(),
+        };
+        Arc::new(AsiairSession::connect_with_ports("127.0.0.1", ports).await.unwrap())
+    }
+
+    fn mock() -> MockCommandServer {
+        MockCommandServer::start("127.0.0.1:0", "127.0.0.1:0", MockDevice::default()).unwrap()
+    }
+
+    async fn next(states: &mut mpsc::UnboundedReceiver<MountState>) -> MountState {
+        tokio::time::timeout(Duration::from_secs(10), states.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn goto_rejects_out_of_range_coordinates() {
+        let mock = mock();
+        let session = session(&mock).await;
+        unpark(&session).await.unwrap();
+
+        for (ra, dec) in [(24.0, 0.0), (-0.5, 0.0), (12.0, 90.5), (12.0, -91.0), (f64::NAN, 0.0)] {
+            assert!(goto(&session, ra, dec).await.is_err(), "{} {}", ra, dec);
+            assert!(sync(&session, ra, dec).await.is_err(), "{} {}", ra, dec);
+        }
+        // Nothing reached the mount
+        assert_eq!(mock.state().mount.slew_count, 0);
+        assert!(!mount_state(&session).await.unwrap().slewing);
+    }
+
+    #[tokio::test]
+    async fn park_and_unpark() {
+        let mock = mock();
+        let session = session(&mock).await;
+        let state = mount_state(&session).await.unwrap();
+        assert!(state.parked && !state.tracking);
+        assert_eq!(goto(&session, 5.0, 20.0).await.unwrap_err(), "mount is parked (code 4)");
+
+        unpark(&session).await.unwrap();
+        set_tracking(&session, true, Some(TrackingRate::Lunar)).await.unwrap();
+        let state = mount_state(&session).await.unwrap();
+        assert!(!state.parked && state.tracking);
+        assert_eq!(state.tracking_rate, TrackingRate::Lunar);
+
+        park(&session).await.unwrap();
+        let state = mount_state(&session).await.unwrap();
+        assert!(state.parked && !state.tracking);
+        assert_eq!(state.dec, 90.0);
+    }
+
+    #[tokio::test]
+    async fn slew_completion_pushes_the_state() {
+        let mock = mock();
+        let session = session(&mock).await;
+        unpark(&session).await.unwrap();
+
+        let (sender, mut states) = mpsc::unbounded_channel();
+        let task = spawn_state_task(&session, move |state| {
+            let _ = sender.send(state);
+        });
+        assert!(!next(&mut states).await.slewing);
+
+        // The mock slews at 30° per second, a few seconds from the pole
+        goto(&session, 5.0, 20.0).await.unwrap();
+        assert!(next(&mut states).await.slewing);
+        // Positions while slewing, then the end of the slew
+        let state = loop {
+            let state = next(&mut states).await;
+            if !state.slewing {
+                break state;
+            }
+        };
+        assert_eq!((state.ra, state.dec), (5.0, 20.0));
+
+        // Stops with the session
+        drop(session);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn start_move_checks_the_rate() {
+        let mock = mock();
+        let session = session(&mock).await;
+        unpark(&session).await.unwrap();
+
+        for rate in [MIN_MOVE_RATE - 1, MAX_MOVE_RATE + 1] {
+            let error = start_move(&session, MoveDirection::North, rate).await.unwrap_err();
+            assert!(error.starts_with(&format!("Move rate {} is not between", rate)), "{}", error);
+        }
+        assert!(!mount_state(&session).await.unwrap().moving);
+
+        start_move(&session, MoveDirection::North, MAX_MOVE_RATE).await.unwrap();
+        assert!(mount_state(&session).await.unwrap().moving);
+        stop_move(&session).await.unwrap();
+        assert!(!mount_state(&session).await.unwrap().moving);
+    }
+}
@@ -0,0 +1,384 @@
+use std::sync::Arc;
+
+use chrono::NaiveTime;
+use serde_json::{json, Map, Value};
+use tauri::{AppHandle, Emitter, State};
+use tokio::{sync::broadcast, task::JoinHandle};
+
+use crate::asiair::{AsiairConnections, AsiairSession};
+
+// Plans are stored on the ASIAIR as JSON. The types below name the fields
+// SkyCtl edits, every other field is kept as it is in `extra`. An update is
+// written into the plan stored on the ASIAIR, see `patch_plan`, so a plan
+// read from the ASIAIR is written back unchanged.
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameType {
+    Light,
+    Dark,
+    Flat,
+    Bias,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ExposureSequence {
+    #[serde(rename = "type")]
+    pub frame_type: FrameType,
+    // Name of the filter, none without a filter wheel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    // Microseconds, like the exposure control of the camera
+    pub exposure: i64,
+    pub gain: i32,
+    pub bin: u32,
+    pub count: u32,
+    // Frames taken so far, counted by the ASIAIR while it runs the plan
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub done: Option<u32>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+// Local time of the site, "HH:MM". The window spans midnight when it ends
+// before it starts.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TimeWindow {
+    pub start: String,
+    pub end: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PlanTarget {
+    pub name: String,
+    // Hours, J2000
+    pub ra: f64,
+    // Degrees
+    pub dec: f64,
+    // Degrees, for a rotator
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<TimeWindow>,
+    // Skipped by autorun when false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    pub sequences: Vec<ExposureSequence>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Plan {
+    pub name: String,
+    pub targets: Vec<PlanTarget>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+fn check_time(time: &str) -> Result<(), String> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map(|_| ())
+        .map_err(|_| format!("Invalid time {}, expected HH:MM", time))
+}
+
+impl Plan {
+    // Catches what the ASIAIR would refuse, before uploading
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("The plan has no name".to_string());
+        }
+        for target in &self.targets {
+            let name = &target.name;
+            if name.trim().is_empty() {
+                return Err("A target of the plan has no name".to_string());
+            }
+            if !(0.0..24.0).contains(&target.ra) || !(-90.0..=90.0).contains(&target.dec) {
+                return Err(format!("Invalid coordinates for {}", name));
+            }
+            if let Some(window) = &target.window {
+                check_time(&window.start)?;
+                check_time(&window.end)?;
+            }
+            for sequence in &target.sequences {
+                if sequence.exposure <= 0 || sequence.count == 0 || !(1..=4).contains(&sequence.bin) {
+                    return Err(format!("Invalid exposure sequence for {}", name));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AutorunStatus {
+    #[default]
+    Idle,
+    Running,
+    Stopped,
+    Complete,
+    Failed,
+}
+
+// Sent to the frontend as `asiair_autorun_state`
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AutorunState {
+    pub state: AutorunStatus,
+    pub plan: Option<String>,
+    pub target: Option<String>,
+    // Index of the sequence in the target
+    pub sequence: Option<usize>,
+    // Frames of the whole plan
+    pub done: u32,
+    pub total: u32,
+    // Why autorun failed or stopped
+    pub message: Option<String>,
+}
+
+pub async fn list_plans(session: &AsiairSession) -> Result<Vec<String>, String> {
+    let names = session.command.call("get_plan_list", None).await?;
+    serde_json::from_value(names).map_err(|e| format!("Invalid plan list: {}", e))
+}
+
+pub async fn get_plan(session: &AsiairSession, name: &str) -> Result<Plan, String> {
+    let plan = session.command.call("get_plan", Some(json!([name]))).await?;
+    serde_json::from_value(plan).map_err(|e| format!("Invalid plan {}: {}", name, e))
+}
+
+// Writes `edited` into `stored`, the plan as the ASIAIR sent it. The keys
+// keep their order, numbers equal to the stored ones keep their type, and
+// explicit nulls stay, since the optional fields serialize None as no key.
+fn patch_plan(stored: &mut Value, edited: Value) {
+    match (stored, edited) {
+        (Value::Object(stored), Value::Object(mut edited)) => {
+            stored.retain(|key, value| value.is_null() || edited.contains_key(key));
+            for (key, value) in stored.iter_mut() {
+                if let Some(edit) = edited.shift_remove(key) {
+                    patch_plan(value, edit);
+                }
+            }
+            stored.extend(edited);
+        }
+        (Value::Array(stored), Value::Array(edited)) => {
+            stored.truncate(edited.len());
+            let mut edited = edited.into_iter();
+            for (value, edit) in stored.iter_mut().zip(edited.by_ref()) {
+                patch_plan(value, edit);
+            }
+            stored.extend(edited);
+        }
+        (Value::Number(stored), Value::Number(edited)) if stored.as_f64() == edited.as_f64() => {}
+        (stored, edited) => *stored = edited,
+    }
+}
+
+// Uploads a new plan, fails when one has the same name
+pub async fn create_plan(session: &AsiairSession, plan: &Plan) -> Result<(), String> {
+    plan.validate()?;
+    session.command.call("create_plan", Some(json!([plan]))).await?;
+    Ok(())
+}
+
+// Replaces the plan of the same name
+pub async fn update_plan(session: &AsiairSession, plan: &Plan) -> Result<(), String> {
+    plan.validate()?;
+    let mut stored = session.command.call("get_plan", Some(json!([plan.name]))).await?;
+    let edited = serde_json::to_value(plan).map_err(|e| format!("Invalid plan {}: {}", plan.name, e))?;
+    patch_plan(&mut stored, edited);
+    session.command.call("update_plan", Some(json!([stored]))).await?;
+    Ok(())
+}
+
+pub async fn delete_plan(session: &AsiairSession, name: &str) -> Result<(), String> {
+    session.command.call("delete_plan", Some(json!([name]))).await?;
+    Ok(())
+}
+
+pub async fn autorun_state(session: &AsiairSession) -> Result<AutorunState, String> {
+    let state = session.command.call("get_autorun_state", None).await?;
+    serde_json::from_value(state).map_err(|e| format!("Invalid autorun state: {}", e))
+}
+
+pub async fn start_autorun(session: &AsiairSession, plan: &str) -> Result<(), String> {
+    session.command.call("start_autorun", Some(json!([plan]))).await?;
+    Ok(())
+}
+
+pub async fn stop_autorun(session: &AsiairSession) -> Result<(), String> {
+    session.command.call("stop_autorun", None).await?;
+    Ok(())
+}
+
+// Emits the autorun state after connecting and on each "Autorun" event.
+// Ends with the session.
+pub fn spawn_autorun_task(app: AppHandle, telescope_index: u32, session: &Arc<AsiairSession>) -> JoinHandle<()> {
+    let mut events = session.command.subscribe();
+    let weak = Arc::downgrade(session);
+    tokio::spawn(async move {
+        let mut last: Option<AutorunState> = None;
+        loop {
+            let Some(session) = weak.upgrade() else { break };
+            match autorun_state(&session).await {
+                Ok(state) if last.as_ref() != Some(&state) => {
+                    let payload = json!({ "index": telescope_index, "state": state });
+                    if let Err(e) = app.emit("asiair_autorun_state", payload) {
+                        log::warn!("Failed to emit autorun state: {}", e);
+                    }
+                    last = Some(state);
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("Failed to read the autorun state: {}", e),
+            }
+            drop(session);
+
+            loop {
+                match events.recv().await {
+                    Ok(event) if event.name == "Autorun" => break,
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(_)) => break,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        }
+    })
+}
+
+#[tauri::command]
+pub async fn asiair_list_plans(
+    connections: State<'_, AsiairConnections>,
+    telescope_index: u32,
+) -> Result<Vec<String>, String> {
+    list_plans(&*connections.get(telescope_index).await?).await
+}
+
+#[tauri::command]
+pub async fn asiair_get_plan(
+    connections: State<'_, AsiairConnections>,
+    telescope_index: u32,
+    name: String,
+) -> Result<Plan, String> {
+    get_plan(&*connections.get(telescope_index).await?, &name).await
+}
+
+#[tauri::command]
+pub async fn asiair_create_plan(
+    connections: State<'_, AsiairConnections>,
+    telescope_index: u32,
+    plan: Plan,
+) -> Result<(), String> {
+    create_plan(&*connections.get(telescope_index).await?, &plan).await
+}
+
+#[tauri::command]
+pub async fn asiair_update_plan(
+    connections: State<'_, AsiairConnections>,
+    telescope_index: u32,
+    plan: Plan,
+) -> Result<(), String> {
+    update_plan(&*connections.get(telescope_index).await?, &plan).await
+}
+
+#[tauri::command]
+pub async fn asiair_delete_plan(
+    connections: State<'_, AsiairConnections>,
+    telescope_index: u32,
+    name: String,
+) -> Result<(), String> {
+    delete_plan(&*connections.get(telescope_index).await?, &name).await
+}
+
+#[tauri::command]
+pub async fn asiair_get_autorun_state(
+    connections: State<'_, AsiairConnections>,
+    telescope_index: u32,
+) -> Result<AutorunState, String> {
+    autorun_state(&*connections.get(telescope_index).await?).await
+}
+
+// Runs the plan named `plan`, progress arrives as `asiair_autorun_state`
+#[tauri::command]
+pub async fn asiair_start_autorun(
+    connections: State<'_, AsiairConnections>,
+    telescope_index: u32,
+    plan: String,
+) -> Result<(), String> {
+    start_autorun(&*connections.get(telescope_index).await?, &plan).await
+}
+
+#[tauri::command]
+pub async fn asiair_stop_autorun(connections: State<'_, AsiairConnections>, telescope_index: u32) -> Result<(), String> {
+    stop_autorun(&*connections.get(telescope_index).await?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asiair::AsiairPorts;
+    use crate::mockasiair::{MockCommandServer, MockDevice};
+
+    // Plan as an ASIAIR stores it, with its own key order, explicit nulls,
+    // whole degrees and fields SkyCtl does not know
+    const STORED_PLAN: &str = r#"{"name":"Double cluster","dither":{"enabled":true,"pixels":5},"targets":[{"sequences":[{"type":"light","filter":"R","exposure":120000000,"gain":100,"bin":1,"count":20,"done":null,"offset":30},{"type":"dark","filter":null,"exposure":120000000,"gain":100,"bin":1,"count":10}],"name":"NGC 869","ra":2.3186,"dec":57,"rotation":null,"window":{"end":"04:00","start":"22:30","moon":false},"enabled":true,"autofocus":{"every_filter":true}}],"version":3}"#;
+
+    fn stored() -> Value {
+        serde_json::from_str(STORED_PLAN).unwrap()
+    }
+
+    fn write_back(plan: &Plan) -> String {
+        let mut stored = stored();
+        patch_plan(&mut stored, serde_json::to_value(plan).unwrap());
+        stored.to_string()
+    }
+
+    #[test]
+    fn plan_round_trips() {
+        let plan: Plan = serde_json::from_value(stored()).unwrap();
+        assert_eq!(plan.targets[0].dec, 57.0);
+        assert_eq!(plan.targets[0].rotation, None);
+        assert_eq!(plan.targets[0].sequences[1].filter, None);
+        assert_eq!(write_back(&plan), STORED_PLAN);
+    }
+
+    #[test]
+    fn plan_edits_are_written_in_place() {
+        let mut plan: Plan = serde_json::from_value(stored()).unwrap();
+        let target = &mut plan.targets[0];
+        target.dec = 57.5;
+        target.enabled = None;
+        target.sequences[0].count = 30;
+        target.sequences[0].filter = None;
+        target.sequences[1].filter = Some("L".to_string());
+        target.sequences.push(target.sequences[1].clone());
+        let expected = STORED_PLAN
+            .replace(r#""dec":57,"#, r#""dec":57.5,"#)
+            .replace(r#","enabled":true,"autofocus""#, r#","autofocus""#)
+            .replace(r#""filter":"R","exposure":120000000,"gain":100,"bin":1,"count":20,"#, r#""exposure":120000000,"gain":100,"bin":1,"count":30,"#)
+            .replace(
+                r#"{"type":"dark","filter":null,"exposure":120000000,"gain":100,"bin":1,"count":10}"#,
+                r#"{"type":"dark","filter":"L","exposure":120000000,"gain":100,"bin":1,"count":10},{"type":"dark","filter":"L","exposure":120000000,"gain":100,"bin":1,"count":10}"#,
+            );
+        assert_eq!(write_back(&plan), expected);
+    }
+
+    #[tokio::test]
+    async fn update_plan_keeps_the_stored_plan() {
+        let mock = MockCommandServer::start("127.0.0.1:0", "127.0.0.1:0", MockDevice::default()).unwrap();
+        let ports = AsiairPorts {
+            command: mock.address().port(),
+            guider: mock.address().port(),
+            image: mock.image_address().port(),
+        };
+        let session = AsiairSession::connect_with_ports("127.0.0.1", ports).await.unwrap();
+        // Stored as it is, as if created by the ASIAIR app
+        session.command.call("create_plan", Some(json!([stored()]))).await.unwrap();
+        let mut plan = get_plan(&session, "Double cluster").await.unwrap();
+        plan.targets[0].sequences[0].count = 30;
+        update_plan(&session, &plan).await.unwrap();
+        let updated = mock.state().plans.into_iter().find(|p| p["name"] == "Double cluster").unwrap();
+        assert_eq!(updated.to_string(), STORED_PLAN.replace(r#""count":20"#, r#""count":30"#));
+    }
+}
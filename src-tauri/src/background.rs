@@ -0,0 +1,354 @@
+use ndarray::{s, Array2, Axis};
+use rayon::prelude::*;
+
+use crate::noise::median_of;
+
+// Step, in pixels, of the grid where the model is evaluated exactly. Values in
+// between are interpolated, the background varies slowly enough for that.
+const MODEL_STEP: usize = 8;
+const MAD_TO_SIGMA: f32 = 1.4826;
+
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackgroundCorrection {
+    // Additive gradients: light pollution, moon glow
+    Subtract,
+    // Multiplicative gradients: vignetting
+    Divide,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum BackgroundFit {
+    // Least squares 2D polynomial of the given degree
+    Polynomial { degree: usize },
+    // Thin plate spline through the samples, `smoothing` relaxes the
+    // interpolation to follow the general trend instead
+    Rbf { smoothing: f32 },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BackgroundParams {
+    // Number of sample boxes along each axis
+    pub grid_size: usize,
+    // Side of each sample box in pixels, 0 to size them from the grid
+    pub box_size: usize,
+    // Clipping, in sigmas, of the stars inside each box
+    pub sigma: f32,
+    // Boxes brighter than the median of all the boxes by more than this many
+    // sigmas are dropped, as they probably sit on nebulosity or a galaxy
+    pub tolerance: f32,
+    pub fit: BackgroundFit,
+    pub correction: BackgroundCorrection,
+}
+
+impl Default for BackgroundParams {
+    fn default() -> Self {
+        Self {
+            grid_size: 16,
+            box_size: 0,
+            sigma: 2.5,
+            tolerance: 2.0,
+            fit: BackgroundFit::Polynomial { degree: 2 },
+            correction: BackgroundCorrection::Subtract,
+        }
+    }
+}
+
+// A background sample, with coordinates normalized to [-1, 1]
+#[derive(Debug, Copy, Clone)]
+struct Sample {
+    x: f64,
+    y: f64,
+    value: f64,
+}
+
+#[derive(Debug, Clone)]
+enum Surface {
+    Polynomial { degree: usize, coefs: Vec<f64> },
+    Rbf { samples: Vec<Sample>, weights: Vec<f64>, affine: [f64; 3] },
+}
+
+// Fitted background, one plane per channel with the size of the image
+#[derive(Debug, Clone)]
+pub struct BackgroundModel {
+    pub channels: Vec<Array2<f32>>,
+}
+
+// Median of the box after iteratively rejecting pixels further than `sigma`
+// from it, which removes the stars falling inside the box.
+fn clipped_median(values: &mut Vec<f32>, sigma: f32) -> Option<f32> {
+    for _ in 0..5 {
+        if values.len() < 3 {
+            break;
+        }
+        let median = median_of(values).unwrap_or_default();
+        let mut devs: Vec<f32> = values.iter().map(|v| (v - median).abs()).collect();
+        let spread = MAD_TO_SIGMA * median_of(&mut devs).unwrap_or_default();
+        let before = values.len();
+        values.retain(|v| (v - median).abs() <= sigma * spread);
+        if values.len() == before || spread == 0.0 {
+            break;
+        }
+    }
+    median_of(values)
+}
+
+fn normalized(pos: f32, size: usize) -> f64 {
+    if size <= 1 {
+        0.0
+    } else {
+        2.0 * pos as f64 / (size - 1) as f64 - 1.0
+    }
+}
+
+fn sample_grid(data: &Array2<f32>, params: &BackgroundParams) -> Vec<Sample> {
+    let (h, w) = data.dim();
+    let grid = params.grid_size.max(2);
+    let box_size = if params.box_size > 0 {
+        params.box_size
+    } else {
+        (w.min(h) / grid / 2).max(3)
+    };
+    let half = box_size / 2;
+
+    let samples: Vec<Sample> = (0..grid * grid)
+        .into_par_iter()
+        .filter_map(|i| {
+            let (gy, gx) = (i / grid, i % grid);
+            let cy = ((gy as f32 + 0.5) * h as f32 / grid as f32) as usize;
+            let cx = ((gx as f32 + 0.5) * w as f32 / grid as f32) as usize;
+            let (y0, y1) = (cy.saturating_sub(half), (cy + half + 1).min(h));
+            let (x0, x1) = (cx.saturating_sub(half), (cx + half + 1).min(w));
+            let mut values: Vec<f32> = data.slice(s![y0..y1, x0..x1]).iter().copied().collect();
+            clipped_median(&mut values, params.sigma).map(|value| Sample {
+                x: normalized(cx as f32, w),
+                y: normalized(cy as f32, h),
+                value: value as f64,
+            })
+        })
+        .collect();
+
+    // Reject the boxes sitting on bright objects
+    let mut values: Vec<f32> = samples.iter().map(|s| s.value as f32).collect();
+    let Some(median) = median_of(&mut values) else {
+        return samples;
+    };
+    let mut devs: Vec<f32> = values.iter().map(|v| (v - median).abs()).collect();
+    let spread = MAD_TO_SIGMA * median_of(&mut devs).unwrap_or_default();
+    let limit = median as f64 + (params.tolerance * spread) as f64;
+    samples.into_iter().filter(|s| s.value <= limit).collect()
+}
+
+// Solves a dense linear system by Gaussian elimination with partial pivoting
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1..n {
+            let f = a[row][col] / a[col][col];
+            if f == 0.0 {
+                continue;
+            }
+            let (upper, lower) = a.split_at_mut(row);
+            for (dst, src) in lower[0][col..].iter_mut().zip(&upper[col][col..]) {
+                *dst -= f * src;
+            }
+            b[row] -= f * b[col];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+fn polynomial_terms(x: f64, y: f64, degree: usize) -> Vec<f64> {
+    let mut terms = Vec::new();
+    for d in 0..=degree {
+        for i in 0..=d {
+            terms.push(x.powi((d - i) as i32) * y.powi(i as i32));
+        }
+    }
+    terms
+}
+
+fn thin_plate(r2: f64) -> f64 {
+    if r2 <= 0.0 {
+        0.0
+    } else {
+        0.5 * r2 * r2.ln()
+    }
+}
+
+impl Surface {
+    fn fit(samples: &[Sample], fit: &BackgroundFit) -> Result<Self, String> {
+        match *fit {
+            BackgroundFit::Polynomial { degree } => {
+                let degree = degree.clamp(1, 4);
+                let n_terms = (degree + 1) * (degree + 2) / 2;
+                if samples.len() < n_terms {
+                    return Err(format!(
+                        "Only {} background samples left, a degree {} polynomial needs {}",
+                        samples.len(), degree, n_terms
+                    ));
+                }
+                // Normal equations of the least squares problem
+                let mut ata = vec![vec![0.0; n_terms]; n_terms];
+                let mut atb = vec![0.0; n_terms];
+                for s in samples {
+                    let t = polynomial_terms(s.x, s.y, degree);
+                    for i in 0..n_terms {
+                        for j in 0..n_terms {
+                            ata[i][j] += t[i] * t[j];
+                        }
+                        atb[i] += t[i] * s.value;
+                    }
+                }
+                let coefs = solve(ata, atb)
+                    .ok_or_else(|| "Background polynomial fit is singular".to_string())?;
+                Ok(Surface::Polynomial { degree, coefs })
+            }
+            BackgroundFit::Rbf { smoothing } => {
+                let n = samples.len();
+                if n < 3 {
+                    return Err(format!("Only {} background samples left, need at least 3", n));
+                }
+                let mut a = vec![vec![0.0; n + 3]; n + 3];
+                let mut b = vec![0.0; n + 3];
+                for i in 0..n {
+                    for j in 0..n {
+                        let (dx, dy) = (samples[i].x - samples[j].x, samples[i].y - samples[j].y);
+                        a[i][j] = thin_plate(dx * dx + dy * dy);
+                    }
+                    a[i][i] += smoothing.max(0.0) as f64;
+                    let p = [1.0, samples[i].x, samples[i].y];
+                    for k in 0..3 {
+                        a[i][n + k] = p[k];
+                        a[n + k][i] = p[k];
+                    }
+                    b[i] = samples[i].value;
+                }
+                let x = solve(a, b)
+                    .ok_or_else(|| "Background RBF fit is singular".to_string())?;
+                Ok(Surface::Rbf {
+                    samples: samples.to_vec(),
+                    weights: x[..n].to_vec(),
+                    affine: [x[n], x[n + 1], x[n + 2]],
+                })
+            }
+        }
+    }
+
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        match self {
+            Surface::Polynomial { degree, coefs } => polynomial_terms(x, y, *degree)
+                .iter()
+                .zip(coefs)
+                .map(|(t, c)| t * c)
+                .sum(),
+            Surface::Rbf { samples, weights, affine } => {
+                let radial: f64 = samples
+                    .iter()
+                    .zip(weights)
+                    .map(|(s, w)| {
+                        let (dx, dy) = (x - s.x, y - s.y);
+                        w * thin_plate(dx * dx + dy * dy)
+                    })
+                    .sum();
+                radial + affine[0] + affine[1] * x + affine[2] * y
+            }
+        }
+    }
+
+    // Evaluates the surface on a coarse grid and interpolates bilinearly
+    fn render(&self, h: usize, w: usize) -> Array2<f32> {
+        let gh = (h - 1) / MODEL_STEP + 2;
+        let gw = (w - 1) / MODEL_STEP + 2;
+        let coarse: Vec<f64> = (0..gh * gw)
+            .into_par_iter()
+            .map(|i| {
+                let py = ((i / gw) * MODEL_STEP).min(h - 1);
+                let px = ((i % gw) * MODEL_STEP).min(w - 1);
+                self.eval(normalized(px as f32, w), normalized(py as f32, h))
+            })
+            .collect();
+
+        let mut model = Array2::<f32>::zeros((h, w));
+        model
+            .axis_iter_mut(Axis(0))
+            .into_par_iter()
+            .enumerate()
+            .for_each(|(y, mut row)| {
+                let gy = (y / MODEL_STEP).min(gh - 2);
+                let y0 = gy * MODEL_STEP;
+                let y1 = ((gy + 1) * MODEL_STEP).min(h - 1);
+                let fy = if y1 > y0 { (y - y0) as f64 / (y1 - y0) as f64 } else { 0.0 };
+                for (x, v) in row.iter_mut().enumerate() {
+                    let gx = (x / MODEL_STEP).min(gw - 2);
+                    let x0 = gx * MODEL_STEP;
+                    let x1 = ((gx + 1) * MODEL_STEP).min(w - 1);
+                    let fx = if x1 > x0 { (x - x0) as f64 / (x1 - x0) as f64 } else { 0.0 };
+                    let top = coarse[gy * gw + gx] * (1.0 - fx) + coarse[gy * gw + gx + 1] * fx;
+                    let bottom =
+                        coarse[(gy + 1) * gw + gx] * (1.0 - fx) + coarse[(gy + 1) * gw + gx + 1] * fx;
+                    *v = (top * (1.0 - fy) + bottom * fy) as f32;
+                }
+            });
+        model
+    }
+}
+
+// Fits a background model to every channel of the image
+pub fn extract_background(
+    channels: &[Array2<f32>],
+    params: &BackgroundParams,
+) -> Result<BackgroundModel, String> {
+    let start_time = std::time::Instant::now();
+    let channels = channels
+        .par_iter()
+        .map(|data| {
+            let (h, w) = data.dim();
+            if h < 2 || w < 2 {
+                return Err("Image too small for background extraction".to_string());
+            }
+            let samples = sample_grid(data, params);
+            Ok(Surface::fit(&samples, &params.fit)?.render(h, w))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    log::info!("Background extraction took: {:?}", start_time.elapsed());
+    Ok(BackgroundModel { channels })
+}
+
+impl BackgroundModel {
+    // Corrects a channel in place. Subtraction adds back the median of the
+    // model so the background level, and the stretch, stay about the same;
+    // division rescales by the model mean.
+    pub fn correct(&self, channel: usize, data: &mut ndarray::ArrayViewMut2<i32>, correction: BackgroundCorrection) {
+        let model = &self.channels[channel];
+        let mut values: Vec<f32> = model.iter().copied().collect();
+        let pedestal = median_of(&mut values).unwrap_or_default();
+        let mean = model.mean().unwrap_or(1.0);
+
+        ndarray::Zip::from(data).and(model).par_for_each(|v, &m| {
+            let corrected = match correction {
+                BackgroundCorrection::Subtract => *v as f32 - m + pedestal,
+                BackgroundCorrection::Divide => {
+                    if m > 0.0 {
+                        *v as f32 * mean / m
+                    } else {
+                        *v as f32
+                    }
+                }
+            };
+            *v = corrected.round().max(0.0) as i32;
+        });
+    }
+}
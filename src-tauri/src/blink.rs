@@ -0,0 +1,103 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use ndarray::Array2;
+
+use crate::align::{estimate_translation, find_stars, shift, Star};
+use crate::display::pack_u8;
+use crate::imagestore::ImageId;
+use crate::stretch::{normalize, Stretch, StretchAlgorithm};
+
+const ALIGNMENT_STARS: usize = 50;
+
+// One frame of a blink sequence, aligned on the first one
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct BlinkFrame {
+    pub id: ImageId,
+    // Translation of the frame with respect to the first one, in display
+    // pixels, already compensated in `pixels`
+    pub offset_x: f32,
+    pub offset_y: f32,
+    // False when not enough stars matched; the frame is then shown as is
+    pub aligned: bool,
+    pub width: usize,
+    pub height: usize,
+    // Base64 encoded RGBA, 8 bits per channel, ready for an ImageData
+    pub pixels: String,
+}
+
+fn luminance(channels: &[Array2<f32>]) -> Array2<f32> {
+    let mut sum = channels[0].clone();
+    for c in &channels[1..] {
+        sum += c;
+    }
+    sum / channels.len() as f32
+}
+
+// Builds a blink sequence from display sized frames. All of them are
+// normalized with the `maxima` of the first frame and stretched with the same
+// parameters, so only real changes (moving objects, focus, clouds) stand out.
+// Without `stretch` the auto stretch of the first frame is used for all.
+pub fn blink_sequence(
+    frames: Vec<(ImageId, Vec<Array2<i32>>)>,
+    maxima: &[f32],
+    stretch: Option<StretchAlgorithm>,
+) -> Result<Vec<BlinkFrame>, String> {
+    let start_time = std::time::Instant::now();
+    let Some((_, first)) = frames.first() else {
+        return Ok(vec![]);
+    };
+    let (dim, planes) = (first[0].dim(), first.len());
+    if frames.iter().any(|(_, f)| f.len() != planes || f[0].dim() != dim) {
+        return Err("All the frames of a blink sequence must have the same size and channels".to_string());
+    }
+
+    let linked = stretch.is_none_or(|s| s.is_linked());
+    let global_max = maxima.iter().copied().fold(f32::MIN, f32::max);
+    let normalized: Vec<(ImageId, Vec<Array2<f32>>)> = frames
+        .into_iter()
+        .map(|(id, planes)| {
+            let channels = planes
+                .iter()
+                .zip(maxima)
+                .map(|(p, &max)| normalize(&p.mapv(|v| v as f32), if linked { global_max } else { max }))
+                .collect();
+            (id, channels)
+        })
+        .collect();
+
+    let stretch = stretch.unwrap_or_else(|| StretchAlgorithm::Mtf {
+        stf: Stretch::default().linked_params(&normalized[0].1),
+    });
+    let reference: Vec<Star> = find_stars(&luminance(&normalized[0].1), ALIGNMENT_STARS);
+
+    let sequence = normalized
+        .into_iter()
+        .enumerate()
+        .map(|(i, (id, mut channels))| {
+            let offset = if i == 0 {
+                Some((0.0, 0.0))
+            } else {
+                let stars = find_stars(&luminance(&channels), ALIGNMENT_STARS);
+                estimate_translation(&reference, &stars)
+            };
+            if let Some((dx, dy)) = offset.filter(|&(dx, dy)| dx != 0.0 || dy != 0.0) {
+                channels = channels.iter().map(|c| shift(c, dx, dy)).collect();
+            }
+            stretch.apply_inplace(&mut channels);
+
+            let (height, width) = channels[0].dim();
+            let (offset_x, offset_y) = offset.unwrap_or((0.0, 0.0));
+            BlinkFrame {
+                id,
+                offset_x,
+                offset_y,
+                aligned: offset.is_some(),
+                width,
+                height,
+                pixels: STANDARD.encode(pack_u8(&channels, true)),
+            }
+        })
+        .collect();
+
+    log::info!("Blink sequence took: {:?}", start_time.elapsed());
+    Ok(sequence)
+}
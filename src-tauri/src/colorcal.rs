@@ -0,0 +1,223 @@
+use ndarray::{Array2, Zip};
+use rayon::prelude::*;
+
+use crate::align::local_maxima;
+use crate::noise::median_of;
+
+// Half size of the box used to measure the flux of each star
+const STAR_RADIUS: usize = 3;
+// Detection threshold over the background, in noise sigmas
+const STAR_DETECTION_SIGMA: f32 = 10.0;
+const MAX_STARS: usize = 500;
+// Stars with any pixel above this fraction of the channel maximum are
+// saturated or non-linear and would bias the white balance
+const STAR_SATURATION: f32 = 0.9;
+
+// A catalogue star matched to a position of a plate-solved frame
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CatalogStar {
+    pub x: f32,
+    pub y: f32,
+    // Johnson B-V color index
+    pub bv: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum WhiteBalance {
+    None,
+    // Makes the average star white
+    Stars,
+    // Matches the measured star colors to the ones expected from their
+    // catalogue color index
+    Photometric { stars: Vec<CatalogStar> },
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ColorCalibrationParams {
+    pub neutralize_background: bool,
+    pub white_balance: WhiteBalance,
+}
+
+impl Default for ColorCalibrationParams {
+    fn default() -> Self {
+        Self {
+            neutralize_background: true,
+            white_balance: WhiteBalance::Stars,
+        }
+    }
+}
+
+// Result of the calibration, reported back to the frontend
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ColorCalibration {
+    // Background level of each channel before the calibration
+    pub background: [f32; 3],
+    // Multiplier applied to each channel, normalized to green
+    pub white_balance: [f32; 3],
+    pub stars_used: usize,
+}
+
+// Effective temperature of a star from its B-V index (Ballesteros 2012)
+fn bv_to_temperature(bv: f32) -> f32 {
+    4600.0 * (1.0 / (0.92 * bv + 1.7) + 1.0 / (0.92 * bv + 0.62))
+}
+
+// Relative blackbody emission at the effective wavelength of the R, G and B
+// filters of a typical OSC sensor, normalized to green
+fn temperature_to_rgb(temperature: f32) -> [f32; 3] {
+    const WAVELENGTHS_NM: [f64; 3] = [600.0, 530.0, 460.0];
+    const HC_K: f64 = 1.438_776_9e-2; // h * c / k in m K
+    let planck = |nm: f64| {
+        let l = nm * 1e-9;
+        1.0 / (l.powi(5) * ((HC_K / (l * temperature as f64)).exp() - 1.0))
+    };
+    let g = planck(WAVELENGTHS_NM[1]);
+    [
+        (planck(WAVELENGTHS_NM[0]) / g) as f32,
+        1.0,
+        (planck(WAVELENGTHS_NM[2]) / g) as f32,
+    ]
+}
+
+// Flux of the star centered at (x, y) over the background on every channel,
+// or None when it is saturated or too close to the border
+fn measure_star(channels: &[Array2<f32>], background: &[f32; 3], limits: &[f32; 3], x: usize, y: usize) -> Option<[f32; 3]> {
+    let (h, w) = channels[0].dim();
+    if x < STAR_RADIUS || y < STAR_RADIUS || x + STAR_RADIUS >= w || y + STAR_RADIUS >= h {
+        return None;
+    }
+    let mut flux = [0f32; 3];
+    for (c, channel) in channels.iter().enumerate() {
+        for yy in y - STAR_RADIUS..=y + STAR_RADIUS {
+            for xx in x - STAR_RADIUS..=x + STAR_RADIUS {
+                let v = channel[[yy, xx]];
+                if v >= limits[c] {
+                    return None;
+                }
+                flux[c] += v - background[c];
+            }
+        }
+    }
+    if flux.iter().any(|&f| f <= 0.0) {
+        return None;
+    }
+    Some(flux)
+}
+
+// Detects stars as local maxima of the luminance well above the background
+fn find_stars(channels: &[Array2<f32>], background: &[f32; 3], noise: &[f32; 3]) -> Vec<[f32; 3]> {
+    let (h, w) = channels[0].dim();
+    let mut luminance = Array2::<f32>::zeros((h, w));
+    Zip::from(&mut luminance)
+        .and(&channels[0])
+        .and(&channels[1])
+        .and(&channels[2])
+        .par_for_each(|l, &r, &g, &b| *l = r + g + b);
+
+    let threshold = background.iter().sum::<f32>()
+        + STAR_DETECTION_SIGMA * noise.iter().map(|n| n * n).sum::<f32>().sqrt();
+    let limits: Vec<f32> = channels
+        .iter()
+        .map(|c| STAR_SATURATION * c.iter().copied().fold(f32::MIN, f32::max))
+        .collect();
+    let limits = [limits[0], limits[1], limits[2]];
+
+    local_maxima(&luminance, threshold, 1)
+        .into_iter()
+        .filter_map(|(x, y)| measure_star(channels, background, &limits, x, y))
+        .take(MAX_STARS)
+        .collect()
+}
+
+// Per channel factors that bring the measured star colors to the expected
+// ones. The median of the per star ratios keeps outliers (variable stars,
+// blends, bad matches) from skewing the result.
+fn balance_factors(measured: &[[f32; 3]], expected: &[[f32; 3]]) -> [f32; 3] {
+    let mut factors = [1f32; 3];
+    for c in [0, 2] {
+        let mut ratios: Vec<f32> = measured
+            .iter()
+            .zip(expected)
+            .map(|(m, e)| (e[c] / e[1]) / (m[c] / m[1]))
+            .filter(|r| r.is_finite() && *r > 0.0)
+            .collect();
+        factors[c] = median_of(&mut ratios).unwrap_or(1.0);
+    }
+    factors
+}
+
+// Calibrates the color of R, G, B planes in place.
+//
+// `background` and `noise` are the per channel medians and noise estimates
+// (see calculate_stats in rawimage.rs). Background neutralization offsets the
+// channels so their backgrounds match; white balance then scales them around
+// that common background.
+pub fn calibrate_color(
+    channels: &mut [Array2<f32>],
+    background: [f32; 3],
+    noise: [f32; 3],
+    params: &ColorCalibrationParams,
+) -> Result<ColorCalibration, String> {
+    if channels.len() != 3 {
+        return Err("Color calibration needs a color image".to_string());
+    }
+    let start_time = std::time::Instant::now();
+    let reference = background.iter().sum::<f32>() / 3.0;
+
+    let mut current_bg = background;
+    if params.neutralize_background {
+        channels.par_iter_mut().enumerate().for_each(|(c, channel)| {
+            let offset = reference - background[c];
+            channel.par_mapv_inplace(|v| (v + offset).max(0.0));
+        });
+        current_bg = [reference; 3];
+    }
+
+    let (white_balance, stars_used) = match &params.white_balance {
+        WhiteBalance::None => ([1.0; 3], 0),
+        WhiteBalance::Stars => {
+            let measured = find_stars(channels, &current_bg, &noise);
+            if measured.is_empty() {
+                return Err("No unsaturated stars found for white balance".to_string());
+            }
+            let expected = vec![[1.0; 3]; measured.len()];
+            (balance_factors(&measured, &expected), measured.len())
+        }
+        WhiteBalance::Photometric { stars } => {
+            let (h, w) = channels[0].dim();
+            let limits: Vec<f32> = channels
+                .iter()
+                .map(|c| STAR_SATURATION * c.iter().copied().fold(f32::MIN, f32::max))
+                .collect();
+            let limits = [limits[0], limits[1], limits[2]];
+            let (measured, expected): (Vec<[f32; 3]>, Vec<[f32; 3]>) = stars
+                .iter()
+                .filter(|s| s.x >= 0.0 && s.y >= 0.0 && (s.x as usize) < w && (s.y as usize) < h)
+                .filter_map(|s| {
+                    measure_star(channels, &current_bg, &limits, s.x.round() as usize, s.y.round() as usize)
+                        .map(|flux| (flux, temperature_to_rgb(bv_to_temperature(s.bv))))
+                })
+                .unzip();
+            if measured.is_empty() {
+                return Err("None of the catalogue stars could be measured".to_string());
+            }
+            let used = measured.len();
+            (balance_factors(&measured, &expected), used)
+        }
+    };
+
+    channels.par_iter_mut().enumerate().for_each(|(c, channel)| {
+        let (k, bg) = (white_balance[c], current_bg[c]);
+        if k != 1.0 {
+            channel.par_mapv_inplace(|v| ((v - bg) * k + bg).max(0.0));
+        }
+    });
+
+    log::info!("Color calibration took: {:?}", start_time.elapsed());
+    Ok(ColorCalibration {
+        background,
+        white_balance,
+        stars_used,
+    })
+}
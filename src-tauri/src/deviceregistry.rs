@@ -0,0 +1,531 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use serde_json::{json, Value};
+use tauri::{AppHandle, Emitter, State};
+use tokio::{sync::RwLock, task::JoinHandle};
+
+use crate::devices::{
+    warm_up, Camera, CameraCapabilities, CameraSettings, CameraState, Cooler, CoolerState, DeviceKind, Dome, DomeState,
+    ExposureKind, FilterWheel, FilterWheelState, Focuser, FocuserState, Guider, GuiderState, Mount, MountCapabilities,
+    MountState, MoveDirection, Rotator, RotatorState, Settle, TrackingRate, WarmUpRamp, FILTER_TIMEOUT,
+};
+use crate::imagestore::ImageStore;
+use crate::stf::show_new_image;
+
+// Devices of a telescope, whatever backend drives them. A backend registers
+// them when it connects, under the telescope index used by
+// `load_fits_image`, and the commands below dispatch to them.
+
+#[derive(Clone, Default)]
+pub struct DeviceSet {
+    // Such as "ASIAIR"
+    pub backend: String,
+    pub camera: Option<Arc<dyn Camera>>,
+    pub mount: Option<Arc<dyn Mount>>,
+    pub focuser: Option<Arc<dyn Focuser>>,
+    pub filter_wheel: Option<Arc<dyn FilterWheel>>,
+    pub cooler: Option<Arc<dyn Cooler>>,
+    pub rotator: Option<Arc<dyn Rotator>>,
+    pub guider: Option<Arc<dyn Guider>>,
+    pub dome: Option<Arc<dyn Dome>>,
+}
+
+impl DeviceSet {
+    pub fn kinds(&self) -> Vec<DeviceKind> {
+        [
+            (DeviceKind::Camera, self.camera.is_some()),
+            (DeviceKind::Mount, self.mount.is_some()),
+            (DeviceKind::Focuser, self.focuser.is_some()),
+            (DeviceKind::FilterWheel, self.filter_wheel.is_some()),
+            (DeviceKind::Cooler, self.cooler.is_some()),
+            (DeviceKind::Rotator, self.rotator.is_some()),
+            (DeviceKind::Guider, self.guider.is_some()),
+            (DeviceKind::Dome, self.dome.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(kind, connected)| connected.then_some(kind))
+        .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ConnectedDevices {
+    pub backend: String,
+    pub devices: Vec<DeviceKind>,
+}
+
+// Registered devices by telescope index, kept as Tauri state
+#[derive(Default)]
+pub struct DeviceRegistry {
+    telescopes: RwLock<HashMap<u32, DeviceSet>>,
+    // Cooler warm ups in progress
+    warm_ups: StdMutex<HashMap<u32, JoinHandle<()>>>,
+}
+
+impl DeviceRegistry {
+    // Replaces the devices registered before for the telescope
+    pub async fn register(&self, telescope_index: u32, devices: DeviceSet) {
+        log::info!(
+            "Telescope index {} has {:?} through {}",
+            telescope_index,
+            devices.kinds(),
+            devices.backend
+        );
+        self.cancel_warm_up(telescope_index);
+        self.telescopes.write().await.insert(telescope_index, devices);
+    }
+
+    pub async fn unregister(&self, telescope_index: u32) {
+        self.cancel_warm_up(telescope_index);
+        self.telescopes.write().await.remove(&telescope_index);
+    }
+
+    pub async fn devices(&self, telescope_index: u32) -> Result<DeviceSet, String> {
+        self.telescopes
+            .read()
+            .await
+            .get(&telescope_index)
+            .cloned()
+            .ok_or_else(|| format!("Telescope {} is not connected", telescope_index))
+    }
+
+    async fn device<T: ?Sized>(
+        &self,
+        telescope_index: u32,
+        kind: DeviceKind,
+        pick: impl FnOnce(DeviceSet) -> Option<Arc<T>>,
+    ) -> Result<Arc<T>, String> {
+        pick(self.devices(telescope_index).await?)
+            .ok_or_else(|| format!("No {} connected to telescope {}", kind.name(), telescope_index))
+    }
+
+    pub async fn camera(&self, telescope_index: u32) -> Result<Arc<dyn Camera>, String> {
+        self.device(telescope_index, DeviceKind::Camera, |d| d.camera).await
+    }
+
+    pub async fn mount(&self, telescope_index: u32) -> Result<Arc<dyn Mount>, String> {
+        self.device(telescope_index, DeviceKind::Mount, |d| d.mount).await
+    }
+
+    pub async fn focuser(&self, telescope_index: u32) -> Result<Arc<dyn Focuser>, String> {
+        self.device(telescope_index, DeviceKind::Focuser, |d| d.focuser).await
+    }
+
+    pub async fn filter_wheel(&self, telescope_index: u32) -> Result<Arc<dyn FilterWheel>, String> {
+        self.device(telescope_index, DeviceKind::FilterWheel, |d| d.filter_wheel).await
+    }
+
+    pub async fn cooler(&self, telescope_index: u32) -> Result<Arc<dyn Cooler>, String> {
+        self.device(telescope_index, DeviceKind::Cooler, |d| d.cooler).await
+    }
+
+    pub async fn rotator(&self, telescope_index: u32) -> Result<Arc<dyn Rotator>, String> {
+        self.device(telescope_index, DeviceKind::Rotator, |d| d.rotator).await
+    }
+
+    pub async fn guider(&self, telescope_index: u32) -> Result<Arc<dyn Guider>, String> {
+        self.device(telescope_index, DeviceKind::Guider, |d| d.guider).await
+    }
+
+    pub async fn dome(&self, telescope_index: u32) -> Result<Arc<dyn Dome>, String> {
+        self.device(telescope_index, DeviceKind::Dome, |d| d.dome).await
+    }
+
+    // Also cancels a warm up in progress
+    pub async fn set_cooler(&self, telescope_index: u32, on: bool, target: Option<f64>) -> Result<(), String> {
+        let cooler = self.cooler(telescope_index).await?;
+        self.cancel_warm_up(telescope_index);
+        cooler.set_cooler(on, target).await
+    }
+
+    // Warms the cooler of the telescope up in the background, replacing the
+    // warm up in progress. It stops when the cooler is set or the devices of
+    // the telescope are replaced. `on_done` gets how it ended.
+    pub async fn warm_up_cooler(
+        &self,
+        telescope_index: u32,
+        ramp: WarmUpRamp,
+        on_step: impl Fn(CoolerState) + Send + Sync + 'static,
+        on_done: impl FnOnce(Result<(), String>) + Send + 'static,
+    ) -> Result<(), String> {
+        let cooler = self.cooler(telescope_index).await?;
+        let task = tokio::spawn(async move { on_done(warm_up(&*cooler, ramp, on_step).await) });
+        let mut warm_ups = self.warm_ups.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(previous) = warm_ups.insert(telescope_index, task) {
+            previous.abort();
+        }
+        Ok(())
+    }
+
+    fn cancel_warm_up(&self, telescope_index: u32) {
+        if let Some(task) = self.warm_ups.lock().unwrap_or_else(|e| e.into_inner()).remove(&telescope_index) {
+            task.abort();
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn get_devices(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<ConnectedDevices, String> {
+    let devices = registry.devices(telescope_index).await?;
+    Ok(ConnectedDevices {
+        devices: devices.kinds(),
+        backend: devices.backend,
+    })
+}
+
+#[tauri::command]
+pub async fn get_camera_capabilities(
+    registry: State<'_, DeviceRegistry>,
+    telescope_index: u32,
+) -> Result<CameraCapabilities, String> {
+    registry.camera(telescope_index).await?.camera_capabilities().await
+}
+
+#[tauri::command]
+pub async fn get_camera_state(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<CameraState, String> {
+    registry.camera(telescope_index).await?.camera_state().await
+}
+
+#[tauri::command]
+pub async fn set_camera_settings(
+    registry: State<'_, DeviceRegistry>,
+    telescope_index: u32,
+    settings: CameraSettings,
+) -> Result<(), String> {
+    registry.camera(telescope_index).await?.set_camera_settings(&settings).await
+}
+
+// Takes an exposure and shows it like an opened file, progress is emitted
+// as `exposure_progress` and the frame as `fits_image_updated`
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn camera_capture(
+    app: AppHandle,
+    registry: State<'_, DeviceRegistry>,
+    store: State<'_, ImageStore>,
+    telescope_index: u32,
+    kind: ExposureKind,
+    settings: CameraSettings,
+    display_width: usize,
+    display_height: usize,
+) -> Result<(), String> {
+    let camera = registry.camera(telescope_index).await?;
+    let progress_app = app.clone();
+    let on_progress = move |progress| {
+        let payload = json!({ "index": telescope_index, "progress": progress });
+        if let Err(e) = progress_app.emit("exposure_progress", payload) {
+            log::warn!("Failed to emit exposure progress: {}", e);
+        }
+    };
+    let raw_image = camera.capture(kind, &settings, &on_progress).await?;
+
+    show_new_image(&app, &store, telescope_index, raw_image, display_width, display_height).await
+}
+
+#[tauri::command]
+pub async fn camera_abort_exposure(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<(), String> {
+    registry.camera(telescope_index).await?.abort_exposure().await
+}
+
+#[tauri::command]
+pub async fn get_mount_capabilities(
+    registry: State<'_, DeviceRegistry>,
+    telescope_index: u32,
+) -> Result<MountCapabilities, String> {
+    registry.mount(telescope_index).await?.mount_capabilities().await
+}
+
+#[tauri::command]
+pub async fn get_mount_state(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<MountState, String> {
+    registry.mount(telescope_index).await?.mount_state().await
+}
+
+#[tauri::command]
+pub async fn mount_goto(registry: State<'_, DeviceRegistry>, telescope_index: u32, ra: f64, dec: f64) -> Result<(), String> {
+    registry.mount(telescope_index).await?.goto(ra, dec).await
+}
+
+#[tauri::command]
+pub async fn mount_sync(registry: State<'_, DeviceRegistry>, telescope_index: u32, ra: f64, dec: f64) -> Result<(), String> {
+    registry.mount(telescope_index).await?.sync(ra, dec).await
+}
+
+#[tauri::command]
+pub async fn mount_abort(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<(), String> {
+    registry.mount(telescope_index).await?.abort_slew().await
+}
+
+#[tauri::command]
+pub async fn mount_park(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<(), String> {
+    registry.mount(telescope_index).await?.park().await
+}
+
+#[tauri::command]
+pub async fn mount_unpark(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<(), String> {
+    registry.mount(telescope_index).await?.unpark().await
+}
+
+#[tauri::command]
+pub async fn mount_set_tracking(
+    registry: State<'_, DeviceRegistry>,
+    telescope_index: u32,
+    tracking: bool,
+    rate: Option<TrackingRate>,
+) -> Result<(), String> {
+    registry.mount(telescope_index).await?.set_tracking(tracking, rate).await
+}
+
+#[tauri::command]
+pub async fn mount_move(
+    registry: State<'_, DeviceRegistry>,
+    telescope_index: u32,
+    direction: MoveDirection,
+    rate: u32,
+) -> Result<(), String> {
+    registry.mount(telescope_index).await?.start_move(direction, rate).await
+}
+
+#[tauri::command]
+pub async fn mount_stop_move(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<(), String> {
+    registry.mount(telescope_index).await?.stop_move().await
+}
+
+#[tauri::command]
+pub async fn get_focuser_state(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<FocuserState, String> {
+    registry.focuser(telescope_index).await?.focuser_state().await
+}
+
+// Starts a move to an absolute `position`, or by `steps` from the current
+// one
+#[tauri::command]
+pub async fn move_focuser(
+    registry: State<'_, DeviceRegistry>,
+    telescope_index: u32,
+    position: Option<i32>,
+    steps: Option<i32>,
+) -> Result<(), String> {
+    let focuser = registry.focuser(telescope_index).await?;
+    match (position, steps) {
+        (Some(position), None) => focuser.move_focuser(position).await,
+        (None, Some(steps)) => focuser.move_focuser_by(steps).await,
+        _ => Err("Give either a position or a number of steps".to_string()),
+    }
+}
+
+#[tauri::command]
+pub async fn halt_focuser(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<(), String> {
+    registry.focuser(telescope_index).await?.halt_focuser().await
+}
+
+#[tauri::command]
+pub async fn get_filter_wheel_state(
+    registry: State<'_, DeviceRegistry>,
+    telescope_index: u32,
+) -> Result<FilterWheelState, String> {
+    registry.filter_wheel(telescope_index).await?.filter_wheel_state().await
+}
+
+// Returns once the wheel has stopped on slot `position`
+#[tauri::command]
+pub async fn select_filter(
+    registry: State<'_, DeviceRegistry>,
+    telescope_index: u32,
+    position: usize,
+) -> Result<FilterWheelState, String> {
+    let wheel = registry.filter_wheel(telescope_index).await?;
+    wheel.select_filter_and_wait(position, FILTER_TIMEOUT).await
+}
+
+#[tauri::command]
+pub async fn get_cooler_state(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<CoolerState, String> {
+    registry.cooler(telescope_index).await?.cooler_state().await
+}
+
+// Also cancels a warm up in progress
+#[tauri::command]
+pub async fn set_cooler(
+    registry: State<'_, DeviceRegistry>,
+    telescope_index: u32,
+    on: bool,
+    target: Option<f64>,
+) -> Result<(), String> {
+    registry.set_cooler(telescope_index, on, target).await
+}
+
+// Starts warming the sensor up, each step is emitted as `cooler_state` and
+// the end as `cooler_warm_up_done`
+#[tauri::command]
+pub async fn warm_up_cooler(
+    app: AppHandle,
+    registry: State<'_, DeviceRegistry>,
+    telescope_index: u32,
+    ramp: WarmUpRamp,
+) -> Result<(), String> {
+    let step_app = app.clone();
+    let on_step = move |state| {
+        let payload = json!({ "index": telescope_index, "state": state });
+        if let Err(e) = step_app.emit("cooler_state", payload) {
+            log::warn!("Failed to emit cooler state: {}", e);
+        }
+    };
+    let on_done = move |result: Result<(), String>| {
+        let error: Value = match result {
+            Ok(()) => Value::Null,
+            Err(e) => {
+                log::warn!("Warm up failed: {}", e);
+                json!(e)
+            }
+        };
+        if let Err(e) = app.emit("cooler_warm_up_done", json!({ "index": telescope_index, "error": error })) {
+            log::warn!("Failed to emit the end of the warm up: {}", e);
+        }
+    };
+    registry.warm_up_cooler(telescope_index, ramp, on_step, on_done).await
+}
+
+#[tauri::command]
+pub async fn get_rotator_state(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<RotatorState, String> {
+    registry.rotator(telescope_index).await?.rotator_state().await
+}
+
+#[tauri::command]
+pub async fn move_rotator(registry: State<'_, DeviceRegistry>, telescope_index: u32, position: f64) -> Result<(), String> {
+    registry.rotator(telescope_index).await?.move_rotator(position).await
+}
+
+#[tauri::command]
+pub async fn halt_rotator(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<(), String> {
+    registry.rotator(telescope_index).await?.halt_rotator().await
+}
+
+#[tauri::command]
+pub async fn get_guider_state(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<GuiderState, String> {
+    registry.guider(telescope_index).await?.guider_state().await
+}
+
+#[tauri::command]
+pub async fn start_guiding(
+    registry: State<'_, DeviceRegistry>,
+    telescope_index: u32,
+    settle: Option<Settle>,
+) -> Result<(), String> {
+    let guider = registry.guider(telescope_index).await?;
+    guider.start_guiding(settle.unwrap_or_default()).await
+}
+
+#[tauri::command]
+pub async fn stop_guiding(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<(), String> {
+    registry.guider(telescope_index).await?.stop_guiding().await
+}
+
+#[tauri::command]
+pub async fn guider_dither(
+    registry: State<'_, DeviceRegistry>,
+    telescope_index: u32,
+    pixels: f64,
+    ra_only: bool,
+    settle: Option<Settle>,
+) -> Result<(), String> {
+    let guider = registry.guider(telescope_index).await?;
+    guider.dither(pixels, ra_only, settle.unwrap_or_default()).await
+}
+
+#[tauri::command]
+pub async fn get_dome_state(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<DomeState, String> {
+    registry.dome(telescope_index).await?.dome_state().await
+}
+
+#[tauri::command]
+pub async fn dome_open_shutter(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<(), String> {
+    registry.dome(telescope_index).await?.open_shutter().await
+}
+
+#[tauri::command]
+pub async fn dome_close_shutter(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<(), String> {
+    registry.dome(telescope_index).await?.close_shutter().await
+}
+
+#[tauri::command]
+pub async fn dome_slew(registry: State<'_, DeviceRegistry>, telescope_index: u32, azimuth: f64) -> Result<(), String> {
+    registry.dome(telescope_index).await?.slew_dome(azimuth).await
+}
+
+#[tauri::command]
+pub async fn dome_park(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<(), String> {
+    registry.dome(telescope_index).await?.park_dome().await
+}
+
+#[tauri::command]
+pub async fn dome_abort(registry: State<'_, DeviceRegistry>, telescope_index: u32) -> Result<(), String> {
+    registry.dome(telescope_index).await?.abort_dome().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::asiair::{AsiairPorts, AsiairSession};
+    use crate::asiairdevices::device_set;
+    use crate::mockasiair::{MockCommandServer, MockDevice};
+    use tokio::sync::mpsc;
+
+    const WAIT: Duration = Duration::from_secs(2);
+
+    async fn registry(mock: &MockCommandServer) -> DeviceRegistry {
+        let ports = AsiairPorts {
+            command: mock.address().port(),
+            guider: mock.address().port(),
+            image: mock.image_address().port(),
+        };
+        let session = Arc::new(AsiairSession::connect_with_ports("127.0.0.1", ports).await.unwrap());
+        let registry = DeviceRegistry::default();
+        registry.register(0, device_set(&session).await).await;
+        registry
+    }
+
+    #[tokio::test]
+    async fn warm_up_ends_with_the_cooler_off() {
+        let mock = MockCommandServer::start("127.0.0.1:0", "127.0.0.1:0", MockDevice::default()).unwrap();
+        let registry = registry(&mock).await;
+        registry.set_cooler(0, true, Some(-10.0)).await.unwrap();
+
+        let (steps, mut step_rx) = mpsc::unbounded_channel();
+        let (done, mut done_rx) = mpsc::unbounded_channel();
+        let ramp = WarmUpRamp { rate: 6000.0, temperature: 5.0 };
+        let on_step = move |state: CoolerState| steps.send(state.target).unwrap();
+        let on_done = move |result| done.send(result).unwrap();
+        registry.warm_up_cooler(0, ramp, on_step, on_done).await.unwrap();
+
+        tokio::time::timeout(WAIT, done_rx.recv()).await.unwrap().unwrap().unwrap();
+        assert_eq!(step_rx.recv().await.unwrap(), Some(-9.0));
+        let camera = mock.state().camera;
+        assert!(!camera.cooler_on);
+        assert_eq!(camera.target_temperature, 5);
+    }
+
+    #[tokio::test]
+    async fn setting_the_cooler_cancels_the_warm_up() {
+        let mock = MockCommandServer::start("127.0.0.1:0", "127.0.0.1:0", MockDevice::default()).unwrap();
+        let registry = registry(&mock).await;
+        registry.set_cooler(0, true, Some(-10.0)).await.unwrap();
+
+        let (steps, mut step_rx) = mpsc::unbounded_channel();
+        let (done, mut done_rx) = mpsc::unbounded_channel::<Result<(), String>>();
+        let ramp = WarmUpRamp { rate: 600.0, temperature: 5.0 };
+        let on_step = move |state: CoolerState| steps.send(state.target).unwrap();
+        let on_done = move |result| done.send(result).unwrap();
+        registry.warm_up_cooler(0, ramp, on_step, on_done).await.unwrap();
+        assert_eq!(tokio::time::timeout(WAIT, step_rx.recv()).await.unwrap(), Some(Some(-9.0)));
+
+        registry.set_cooler(0, true, Some(-15.0)).await.unwrap();
+        // The aborted task drops its callbacks without calling them
+        assert_eq!(tokio::time::timeout(WAIT, done_rx.recv()).await.unwrap(), None);
+        let camera = mock.state().camera;
+        assert!(camera.cooler_on);
+        assert_eq!(camera.target_temperature, -15);
+    }
+}
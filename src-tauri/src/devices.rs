@@ -0,0 +1,448 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::rawimage::RawImage;
+
+// Devices any backend can drive. Each backend implements the traits of the
+// devices it supports, and the generic helpers below work with all of them.
+// deviceregistry.rs finds the devices of a telescope.
+
+// Wheels report their position this often while waiting for a move
+const FILTER_POLL_INTERVAL: Duration = Duration::from_millis(250);
+// Turning a wheel with many slots takes a few seconds
+pub const FILTER_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum DeviceKind {
+    Camera,
+    Mount,
+    Focuser,
+    FilterWheel,
+    Cooler,
+    Rotator,
+    Guider,
+    Dome,
+}
+
+impl DeviceKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Camera => "camera",
+            Self::Mount => "mount",
+            Self::Focuser => "focuser",
+            Self::FilterWheel => "filter wheel",
+            Self::Cooler => "cooler",
+            Self::Rotator => "rotator",
+            Self::Guider => "guider",
+            Self::Dome => "dome",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CameraRoi {
+    // Binned pixels from the top left corner of the sensor
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// Settings to change before an exposure, the others keep their value
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CameraSettings {
+    // Seconds
+    pub exposure: Option<f64>,
+    pub gain: Option<i32>,
+    pub binning: Option<u32>,
+    pub roi: Option<CameraRoi>,
+    // Back to the whole sensor, ignored when `roi` is set
+    pub full_frame: bool,
+}
+
+// Preview frames are only shown, light frames are also saved by the backend
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExposureKind {
+    Preview,
+    Light,
+}
+
+impl ExposureKind {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Preview => "preview",
+            Self::Light => "light",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CameraState {
+    // "idle", "exposing" or "downloading"
+    pub state: String,
+    pub exposure: Option<f64>,
+    pub gain: Option<i32>,
+    pub binning: Option<u32>,
+    pub roi: Option<CameraRoi>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExposureState {
+    Exposing,
+    Downloading,
+    Complete,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ExposureProgress {
+    pub state: ExposureState,
+    // Seconds
+    pub elapsed: f64,
+    pub duration: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CameraCapabilities {
+    pub name: String,
+    // Sensor size in pixels
+    pub width: u32,
+    pub height: u32,
+    // Micrometers
+    pub pixel_size: Option<f64>,
+    // CFA of color cameras, such as "RGGB"
+    pub bayer: Option<String>,
+    pub bit_depth: Option<u32>,
+    pub max_bin: u32,
+    pub has_cooler: bool,
+}
+
+#[async_trait]
+pub trait Camera: Send + Sync {
+    async fn camera_capabilities(&self) -> Result<CameraCapabilities, String>;
+
+    async fn camera_state(&self) -> Result<CameraState, String>;
+
+    async fn set_camera_settings(&self, settings: &CameraSettings) -> Result<(), String>;
+
+    // Takes one exposure with `settings` and returns the frame
+    async fn capture(
+        &self,
+        kind: ExposureKind,
+        settings: &CameraSettings,
+        on_progress: &(dyn Fn(ExposureProgress) + Send + Sync),
+    ) -> Result<RawImage, String>;
+
+    async fn abort_exposure(&self) -> Result<(), String>;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum PierSide {
+    East,
+    West,
+    Unknown,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum TrackingRate {
+    Sidereal,
+    Lunar,
+    Solar,
+    King,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MoveDirection {
+    North,
+    South,
+    East,
+    West,
+}
+
+impl MoveDirection {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::North => "north",
+            Self::South => "south",
+            Self::East => "east",
+            Self::West => "west",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MountState {
+    // Hours, JNow
+    pub ra: f64,
+    // Degrees
+    pub dec: f64,
+    pub alt: f64,
+    pub az: f64,
+    pub pier_side: PierSide,
+    pub tracking: bool,
+    pub tracking_rate: TrackingRate,
+    // Goto in progress
+    pub slewing: bool,
+    // Manual move in progress
+    pub moving: bool,
+    pub parked: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MountCapabilities {
+    pub can_park: bool,
+    pub can_sync: bool,
+    // Empty when the tracking rate is fixed
+    pub tracking_rates: Vec<TrackingRate>,
+    // Slowest and fastest manual move rates
+    pub move_rates: (u32, u32),
+}
+
+#[async_trait]
+pub trait Mount: Send + Sync {
+    async fn mount_capabilities(&self) -> Result<MountCapabilities, String>;
+
+    async fn mount_state(&self) -> Result<MountState, String>;
+
+    // Starts a goto to `ra` hours and `dec` degrees
+    async fn goto(&self, ra: f64, dec: f64) -> Result<(), String>;
+
+    async fn sync(&self, ra: f64, dec: f64) -> Result<(), String>;
+
+    // Stops a goto and any manual move
+    async fn abort_slew(&self) -> Result<(), String>;
+
+    async fn park(&self) -> Result<(), String>;
+
+    async fn unpark(&self) -> Result<(), String>;
+
+    // Turns tracking on or off, changing its rate first when given
+    async fn set_tracking(&self, tracking: bool, rate: Option<TrackingRate>) -> Result<(), String>;
+
+    // Moves until `stop_move`
+    async fn start_move(&self, direction: MoveDirection, rate: u32) -> Result<(), String>;
+
+    async fn stop_move(&self) -> Result<(), String>;
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FocuserState {
+    // Steps
+    pub position: i32,
+    pub max_position: Option<i32>,
+    pub moving: bool,
+    // Celsius, from the probe of the focuser
+    pub temperature: Option<f64>,
+}
+
+#[async_trait]
+pub trait Focuser: Send + Sync {
+    async fn focuser_state(&self) -> Result<FocuserState, String>;
+
+    // Starts a move to `position`, the state reports when it ends
+    async fn move_focuser(&self, position: i32) -> Result<(), String>;
+
+    async fn halt_focuser(&self) -> Result<(), String>;
+
+    // Starts a move by `steps` from the current position, kept within the
+    // range of the focuser
+    async fn move_focuser_by(&self, steps: i32) -> Result<(), String> {
+        let state = self.focuser_state().await?;
+        let position = state.position.saturating_add(steps).max(0);
+        let position = state.max_position.map_or(position, |max| position.min(max));
+        self.move_focuser(position).await
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FilterWheelState {
+    // Slot, from 0. None while the wheel turns.
+    pub position: Option<usize>,
+    // Filter of each slot
+    pub names: Vec<String>,
+    pub moving: bool,
+}
+
+#[async_trait]
+pub trait FilterWheel: Send + Sync {
+    async fn filter_wheel_state(&self) -> Result<FilterWheelState, String>;
+
+    // Starts turning to slot `position`
+    async fn select_filter(&self, position: usize) -> Result<(), String>;
+
+    // Turns to slot `position` and waits until the wheel stops there
+    async fn select_filter_and_wait(&self, position: usize, timeout: Duration) -> Result<FilterWheelState, String> {
+        let names = self.filter_wheel_state().await?.names;
+        if position >= names.len() {
+            return Err(format!("No filter in slot {}, the wheel has {}", position, names.len()));
+        }
+        self.select_filter(position).await?;
+        let start = Instant::now();
+        loop {
+            let state = self.filter_wheel_state().await?;
+            if !state.moving && state.position == Some(position) {
+                return Ok(state);
+            }
+            if start.elapsed() > timeout {
+                return Err(format!("Timeout waiting for the filter wheel to reach slot {}", position));
+            }
+            tokio::time::sleep(FILTER_POLL_INTERVAL).await;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CoolerState {
+    // Sensor temperature in Celsius
+    pub temperature: f64,
+    pub cooler_on: bool,
+    pub target: Option<f64>,
+    // Percent
+    pub power: Option<f64>,
+}
+
+#[async_trait]
+pub trait Cooler: Send + Sync {
+    async fn cooler_state(&self) -> Result<CoolerState, String>;
+
+    // Turns the cooler on or off, with a new target temperature when given
+    async fn set_cooler(&self, on: bool, target: Option<f64>) -> Result<(), String>;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct WarmUpRamp {
+    // Celsius per minute
+    pub rate: f64,
+    // The cooler is turned off once the target reaches this temperature
+    pub temperature: f64,
+}
+
+// Raises the target temperature by one degree at a time, at the rate of the
+// ramp, then turns the cooler off. Warming a cold sensor at once can fog or
+// stress it. `on_step` gets the state after each step.
+pub async fn warm_up(cooler: &dyn Cooler, ramp: WarmUpRamp, on_step: impl Fn(CoolerState) + Send) -> Result<(), String> {
+    if ramp.rate.is_nan() || ramp.rate <= 0.0 {
+        return Err(format!("Invalid warm up rate {}", ramp.rate));
+    }
+    let step_interval = Duration::from_secs_f64(60.0 / ramp.rate);
+    let state = cooler.cooler_state().await?;
+    let mut target = state.target.unwrap_or(state.temperature).round();
+    while state.cooler_on && target < ramp.temperature {
+        target = (target + 1.0).min(ramp.temperature);
+        cooler.set_cooler(true, Some(target)).await?;
+        on_step(cooler.cooler_state().await?);
+        tokio::time::sleep(step_interval).await;
+    }
+    cooler.set_cooler(false, None).await?;
+    on_step(cooler.cooler_state().await?);
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RotatorState {
+    // Sky position angle in degrees, east of north
+    pub position: f64,
+    // Angle of the rotator itself, when it differs from the sky angle
+    pub mechanical_position: Option<f64>,
+    pub moving: bool,
+}
+
+#[async_trait]
+pub trait Rotator: Send + Sync {
+    async fn rotator_state(&self) -> Result<RotatorState, String>;
+
+    // Starts turning to sky angle `position`
+    async fn move_rotator(&self, position: f64) -> Result<(), String>;
+
+    async fn halt_rotator(&self) -> Result<(), String>;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum GuiderStatus {
+    Stopped,
+    Looping,
+    Calibrating,
+    Guiding,
+    LostLock,
+    Paused,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct GuiderState {
+    pub state: GuiderStatus,
+    // Waiting for the guide star to settle after starting or dithering
+    pub settling: bool,
+}
+
+// When guiding counts as settled: within `pixels` for `time` seconds, given
+// up after `timeout` seconds
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Settle {
+    pub pixels: f64,
+    pub time: f64,
+    pub timeout: f64,
+}
+
+impl Default for Settle {
+    fn default() -> Self {
+        Self {
+            pixels: 1.5,
+            time: 10.0,
+            timeout: 60.0,
+        }
+    }
+}
+
+#[async_trait]
+pub trait Guider: Send + Sync {
+    async fn guider_state(&self) -> Result<GuiderState, String>;
+
+    // Calibrates when needed, then guides
+    async fn start_guiding(&self, settle: Settle) -> Result<(), String>;
+
+    async fn stop_guiding(&self) -> Result<(), String>;
+
+    // Shifts the guide star by up to `pixels`, in right ascension only when
+    // `ra_only`
+    async fn dither(&self, pixels: f64, ra_only: bool, settle: Settle) -> Result<(), String>;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ShutterState {
+    Open,
+    Closed,
+    Opening,
+    Closing,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DomeState {
+    // Degrees from the north through the east, None for roll off roofs
+    pub azimuth: Option<f64>,
+    pub shutter: ShutterState,
+    pub slewing: bool,
+    pub parked: bool,
+}
+
+#[async_trait]
+pub trait Dome: Send + Sync {
+    async fn dome_state(&self) -> Result<DomeState, String>;
+
+    async fn open_shutter(&self) -> Result<(), String>;
+
+    async fn close_shutter(&self) -> Result<(), String>;
+
+    // Starts turning to `azimuth` degrees
+    async fn slew_dome(&self, azimuth: f64) -> Result<(), String>;
+
+    async fn park_dome(&self) -> Result<(), String>;
+
+    // Stops the dome and the shutter
+    async fn abort_dome(&self) -> Result<(), String>;
+}
@@ -0,0 +1,137 @@
+use ndarray::Array2;
+use rayon::prelude::*;
+
+use crate::resize::Fit;
+use crate::stretch::{normalize, StretchAlgorithm};
+
+// Display buffers kept per image. Each one is small next to the source data,
+// but the viewer only ever flips between a few sizes and stretches.
+const MAX_DISPLAY_BUFFERS: usize = 4;
+
+// What a display buffer was rendered for
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DisplayKey {
+    // Box the image was fitted in, as requested by the frontend
+    pub width: usize,
+    pub height: usize,
+    // None for the linear data, scaled as the frontend expects it
+    pub stretch: Option<StretchAlgorithm>,
+    pub fit: Fit,
+}
+
+// Interleaved RGB pixels, derived from the source data for display only
+#[derive(Debug)]
+pub struct DisplayBuffer {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u16>,
+}
+
+impl DisplayBuffer {
+    // Linear buffer from one (mono) or three (R, G, B) planes
+    pub fn from_planes(planes: &[Array2<i32>]) -> Self {
+        Self::interleave(planes, |v: &i32| (*v).clamp(0, u16::MAX as i32) as u16)
+    }
+
+    // Stretched buffer. `maxima` are the maxima of the full resolution
+    // channels, so every size is normalized the same way.
+    pub fn stretched(planes: &[Array2<i32>], maxima: &[f32], stretch: &StretchAlgorithm) -> Self {
+        let global_max = maxima.iter().copied().fold(f32::MIN, f32::max);
+        let mut channels: Vec<Array2<f32>> = planes
+            .iter()
+            .zip(maxima)
+            .map(|(p, &max)| normalize(&p.mapv(|v| v as f32), if stretch.is_linked() { global_max } else { max }))
+            .collect();
+        stretch.apply_inplace(&mut channels);
+        Self::interleave(&channels, |v: &f32| (v.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16)
+    }
+
+    fn interleave<T: Sync>(planes: &[Array2<T>], to_u16: impl Fn(&T) -> u16 + Sync) -> Self {
+        let (height, width) = planes.first().map(|p| p.dim()).unwrap_or((0, 0));
+        let mut pixels = vec![0u16; width * height * 3];
+        if width > 0 {
+            pixels.par_chunks_mut(width * 3).enumerate().for_each(|(y, row)| {
+                for (x, pixel) in row.chunks_mut(3).enumerate() {
+                    for (c, p) in pixel.iter_mut().enumerate() {
+                        *p = to_u16(&planes[c.min(planes.len() - 1)][[y, x]]);
+                    }
+                }
+            });
+        }
+        Self { width, height, pixels }
+    }
+}
+
+// Packs stretched [0.0, 1.0] planes into 8 bit RGB, or RGBA when `alpha` is
+// set, replicating mono planes on every component
+pub fn pack_u8(channels: &[Array2<f32>], alpha: bool) -> Vec<u8> {
+    let (h, w) = channels.first().map(|c| c.dim()).unwrap_or((0, 0));
+    let components = if alpha { 4 } else { 3 };
+    let mut out = vec![255u8; w * h * components];
+    if w > 0 {
+        out.par_chunks_mut(w * components).enumerate().for_each(|(y, row)| {
+            for (x, pixel) in row.chunks_mut(components).enumerate() {
+                for (c, p) in pixel.iter_mut().take(3).enumerate() {
+                    let channel = &channels[c.min(channels.len() - 1)];
+                    *p = (channel[[y, x]].clamp(0.0, 1.0) * 255.0).round() as u8;
+                }
+            }
+        });
+    }
+    out
+}
+
+// Most recently used display buffers of an image, newest first
+#[derive(Debug, Default)]
+pub struct DisplayCache {
+    entries: Vec<(DisplayKey, DisplayBuffer)>,
+    // Survives clear() so the display can be refreshed after the source
+    // data changes
+    last_size: Option<(usize, usize)>,
+}
+
+impl DisplayCache {
+    pub fn get_or_insert_with(
+        &mut self,
+        key: DisplayKey,
+        render: impl FnOnce() -> DisplayBuffer,
+    ) -> &DisplayBuffer {
+        self.last_size = Some((key.width, key.height));
+        match self.entries.iter().position(|(k, _)| *k == key) {
+            Some(i) => {
+                let entry = self.entries.remove(i);
+                self.entries.insert(0, entry);
+            }
+            None => {
+                self.entries.insert(0, (key, render()));
+                self.entries.truncate(MAX_DISPLAY_BUFFERS);
+            }
+        }
+        &self.entries[0].1
+    }
+
+    // Size the viewer asked for last
+    pub fn last_size(&self) -> Option<(usize, usize)> {
+        self.last_size
+    }
+
+    // Bytes held by the cached buffers
+    pub fn memory_size(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(_, b)| b.pixels.len() * std::mem::size_of::<u16>())
+            .sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    // Empty cache that remembers the last size, for a copy of the image
+    pub fn without_buffers(&self) -> DisplayCache {
+        DisplayCache {
+            entries: Vec::new(),
+            last_size: self.last_size,
+        }
+    }
+}
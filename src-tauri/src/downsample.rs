@@ -1,80 +1,61 @@
 use ndarray::{Array2, Array3};
-use rayon::prelude::*;
+use crate::resize::{fit_size, resize, resize_rgb, Fit};
 
-pub fn downsample(data: &Array2<i32>, max_width: usize, max_height: usize) -> Array2<i32> {
+// Fits the image in `max_width` x `max_height` with the filter of `fit`,
+// growing it only when `fit.upscale` is set
+pub fn downsample(data: &Array2<i32>, max_width: usize, max_height: usize, fit: Fit) -> Array2<i32> {
     let (h, w) = data.dim();
-    let aspect = w as f32 / h as f32;
-    let (target_width, target_height) = if (max_width as f32 / max_height as f32) > aspect {
-        let th = max_height.min(h);
-        let tw = ((th as f32) * aspect).round() as usize;
-        (tw, th)
-    } else {
-        let tw = max_width.min(w);
-        let th = ((tw as f32) / aspect).round() as usize;
-        (tw, th)
-    };
-    let scale_x = w as f32 / target_width as f32;
-    let scale_y = h as f32 / target_height as f32;
-    let pixels: Vec<i32> = (0..target_height)
-        .into_par_iter()
-        .flat_map_iter(|y| {
-            (0..target_width).map(move |x| {
-                let start_x = (x as f32 * scale_x).floor() as usize;
-                let start_y = (y as f32 * scale_y).floor() as usize;
-                let end_x = ((x as f32 + 1.0) * scale_x).ceil() as usize;
-                let end_y = ((y as f32 + 1.0) * scale_y).ceil() as usize;
-                let mut sum = 0i64;
-                let mut count = 0i64;
-                for yy in start_y..end_y.min(h) {
-                    for xx in start_x..end_x.min(w) {
-                        sum += data[[yy, xx]] as i64;
-                        count += 1;
-                    }
-                }
-                if count > 0 { (sum / count) as i32 } else { 0 }
-            })
-        })
-        .collect();
-    Array2::from_shape_vec((target_height, target_width), pixels)
-        .expect("Failed to reshape downsampled array")
+    let (target_width, target_height) = fit_size(w, h, max_width, max_height, fit.upscale);
+    resize(data, target_width, target_height, fit.filter)
 }
 
-pub fn downsample_rgb(data: &Array3<i32>, max_width: usize, max_height: usize) -> Array3<i32> {
+pub fn downsample_rgb(data: &Array3<i32>, max_width: usize, max_height: usize, fit: Fit) -> Array3<i32> {
     let (h, w, _) = data.dim();
-    let aspect = w as f32 / h as f32;
-    let (target_width, target_height) = if (max_width as f32 / max_height as f32) > aspect {
-        let th = max_height.min(h);
-        let tw = ((th as f32) * aspect).round() as usize;
-        (tw, th)
-    } else {
-        let tw = max_width.min(w);
-        let th = ((tw as f32) / aspect).round() as usize;
-        (tw, th)
-    };
-    let scale_x = w as f32 / target_width as f32;
-    let scale_y = h as f32 / target_height as f32;
-    let pixels: Vec<i32> = (0..target_height)
-        .into_par_iter()
-        .flat_map_iter(|y| {
-            (0..target_width).flat_map(move |x| {
-                (0..3).map(move |c| {
-                    let start_x = (x as f32 * scale_x).floor() as usize;
-                    let start_y = (y as f32 * scale_y).floor() as usize;
-                    let end_x = ((x as f32 + 1.0) * scale_x).ceil() as usize;
-                    let end_y = ((y as f32 + 1.0) * scale_y).ceil() as usize;
-                    let mut sum = 0i64;
-                    let mut count = 0i64;
-                    for yy in start_y..end_y.min(h) {
-                        for xx in start_x..end_x.min(w) {
-                            sum += data[[yy, xx, c]] as i64;
-                            count += 1;
-                        }
-                    }
-                    if count > 0 { (sum / count) as i32 } else { 0 }
-                })
-            })
-        })
-        .collect();
-    Array3::from_shape_vec((target_height, target_width, 3), pixels)
-        .expect("Failed to reshape RGB downsampled array")
-}
\ No newline at end of file
+    let (target_width, target_height) = fit_size(w, h, max_width, max_height, fit.upscale);
+    resize_rgb(data, target_width, target_height, fit.filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resize::ResizeFilter;
+
+    const FILTERS: [ResizeFilter; 4] =
+        [ResizeFilter::Box, ResizeFilter::Bilinear, ResizeFilter::Bicubic, ResizeFilter::Lanczos3];
+
+    #[test]
+    fn downscaling_fits_the_box() {
+        let data = Array2::from_elem((50, 100), 1000);
+        for filter in FILTERS {
+            let fit = Fit { filter, upscale: false };
+            let out = downsample(&data, 30, 30, fit);
+            assert_eq!(out.dim(), (15, 30));
+            assert!(out.iter().all(|&v| v == 1000), "{:?}", filter);
+            assert_eq!(downsample(&data, 40, 10, fit).dim(), (10, 20));
+        }
+    }
+
+    #[test]
+    fn upscaling_is_opt_in() {
+        let data = Array2::from_elem((50, 100), 1000);
+        assert_eq!(downsample(&data, 300, 300, Fit::default()).dim(), (50, 100));
+        for filter in FILTERS {
+            let out = downsample(&data, 300, 300, Fit { filter, upscale: true });
+            assert_eq!(out.dim(), (150, 300));
+            assert!(out.iter().all(|&v| v == 1000), "{:?}", filter);
+        }
+        // Odd sizes round to the nearest pixel
+        let data = Array2::from_elem((7, 9), 1);
+        assert_eq!(downsample(&data, 20, 20, Fit { filter: ResizeFilter::Lanczos3, upscale: true }).dim(), (16, 20));
+    }
+
+    #[test]
+    fn rgb_planes_keep_their_channels() {
+        let data = Array3::from_shape_fn((40, 60, 3), |(_, _, c)| 100 * (c as i32 + 1));
+        let fit = Fit { filter: ResizeFilter::Bicubic, upscale: true };
+        let out = downsample_rgb(&data, 120, 120, fit);
+        assert_eq!(out.dim(), (80, 120, 3));
+        assert_eq!((out[[10, 10, 0]], out[[10, 10, 1]], out[[10, 10, 2]]), (100, 200, 300));
+        assert_eq!(downsample_rgb(&data, 30, 30, fit).dim(), (20, 30, 3));
+    }
+}
@@ -0,0 +1,353 @@
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Seek, Write};
+
+use chrono::NaiveDateTime;
+use exif::{Field, In, Tag, Value};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::{ExtendedColorType, ImageEncoder};
+use ndarray::Array2;
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::tags::Tag as TiffTag;
+
+use crate::display::pack_u8;
+use crate::metadata::ImageMetadata;
+use crate::stretch::StretchAlgorithm;
+
+const SOFTWARE: &str = "SkyCtl";
+
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "format", rename_all = "lowercase")]
+pub enum ExportFormat {
+    // 8 bits, quality from 1 to 100
+    Jpeg { quality: u8 },
+    Png { sixteen_bits: bool },
+    // Always 16 bits
+    Tiff,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ExportParams {
+    pub path: String,
+    pub format: ExportFormat,
+    pub stretch: StretchAlgorithm,
+    // Box the image is fitted in, full resolution when not given
+    pub max_width: Option<usize>,
+    pub max_height: Option<usize>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ExportResult {
+    pub path: String,
+    pub width: usize,
+    pub height: usize,
+}
+
+// "M 42 | Ha | 300 s | 2024-01-10T03:12:00", skipping what is unknown
+fn description(metadata: &ImageMetadata) -> String {
+    [
+        metadata.object.clone(),
+        metadata.filter.clone(),
+        metadata.exposure.map(|e| format!("{} s", e)),
+        metadata.date_obs.clone(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>()
+    .join(" | ")
+}
+
+// EXIF and TIFF dates: "YYYY:MM:DD HH:MM:SS"
+fn exif_date(metadata: &ImageMetadata) -> Option<String> {
+    let date = metadata.date_obs.as_ref()?;
+    ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(date, f).ok())
+        .map(|t| t.format("%Y:%m:%d %H:%M:%S").to_string())
+}
+
+// 16 bit samples, one per channel
+fn samples_u16(channels: &[Array2<f32>]) -> Vec<u16> {
+    let (h, w) = channels[0].dim();
+    let mut out = Vec::with_capacity(w * h * channels.len());
+    for y in 0..h {
+        for x in 0..w {
+            for c in channels {
+                out.push((c[[y, x]].clamp(0.0, 1.0) * u16::MAX as f32).round() as u16);
+            }
+        }
+    }
+    out
+}
+
+fn pack_u16(channels: &[Array2<f32>]) -> Vec<u8> {
+    // The image encoders take 16 bit samples in native byte order
+    samples_u16(channels).iter().flat_map(|v| v.to_ne_bytes()).collect()
+}
+
+// 8 bit samples, one per channel
+fn pack_u8_planes(channels: &[Array2<f32>]) -> Vec<u8> {
+    let rgb = pack_u8(channels, false);
+    if channels.len() == 1 {
+        rgb.into_iter().step_by(3).collect()
+    } else {
+        rgb
+    }
+}
+
+fn encode(encoder: impl ImageEncoder, data: &[u8], channels: &[Array2<f32>], color: ExtendedColorType) -> Result<(), String> {
+    let (h, w) = channels[0].dim();
+    encoder
+        .write_image(data, w as u32, h as u32, color)
+        .map_err(|e| e.to_string())
+}
+
+// TIFF structure of an APP1 segment with the description, software and date
+// in IFD0 and the exposure in the EXIF IFD
+fn exif_tiff(metadata: &ImageMetadata) -> Result<Vec<u8>, String> {
+    let ascii = |tag: Tag, ifd_num: In, s: String| Field {
+        tag,
+        ifd_num,
+        value: Value::Ascii(vec![s.into_bytes()]),
+    };
+    let date = exif_date(metadata);
+    let mut fields = vec![
+        ascii(Tag::ImageDescription, In::PRIMARY, description(metadata)),
+        ascii(Tag::Software, In::PRIMARY, SOFTWARE.to_string()),
+    ];
+    if let Some(date) = &date {
+        fields.push(ascii(Tag::DateTime, In::PRIMARY, date.clone()));
+        fields.push(ascii(Tag::DateTimeOriginal, In::PRIMARY, date.clone()));
+    }
+    if let Some(exposure) = metadata.exposure.filter(|e| *e > 0.0) {
+        fields.push(Field {
+            tag: Tag::ExposureTime,
+            ifd_num: In::PRIMARY,
+            value: Value::Rational(vec![((exposure * 10000.0).round() as u32, 10000).into()]),
+        });
+    }
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in &fields {
+        writer.push_field(field);
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, true).map_err(|e| e.to_string())?;
+    Ok(tiff.into_inner())
+}
+
+// Adds the EXIF segment to a JFIF stream. It must follow the APP0 segment
+// written by the encoder, right after the start of image marker otherwise.
+fn insert_exif(jpeg: Vec<u8>, tiff: &[u8]) -> Result<Vec<u8>, String> {
+    if jpeg.len() < 4 || jpeg[..2] != [0xff, 0xd8] {
+        return Err("Invalid JPEG stream".to_string());
+    }
+    let at = if jpeg[2..4] == [0xff, 0xe0] && jpeg.len() >= 6 {
+        4 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize
+    } else {
+        2
+    };
+    let length = tiff.len() + 8;
+    if length > u16::MAX as usize || at > jpeg.len() {
+        return Err("EXIF data does not fit in a JPEG segment".to_string());
+    }
+
+    let mut out = Vec::with_capacity(jpeg.len() + length + 2);
+    out.extend_from_slice(&jpeg[..at]);
+    out.extend_from_slice(&[0xff, 0xe1]);
+    out.extend_from_slice(&(length as u16).to_be_bytes());
+    out.extend_from_slice(b"Exif\0\0");
+    out.extend_from_slice(tiff);
+    out.extend_from_slice(&jpeg[at..]);
+    Ok(out)
+}
+
+// tEXt chunk, the keyword and text must be Latin-1
+fn png_text_chunk(keyword: &str, text: &str) -> Vec<u8> {
+    let mut body = b"tEXt".to_vec();
+    body.extend(keyword.chars().chain(std::iter::once('\0')).chain(text.chars()).map(|c| u8::try_from(c).unwrap_or(b'?')));
+    let mut chunk = ((body.len() - 4) as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(&body);
+    chunk.extend_from_slice(&crc32fast::hash(&body).to_be_bytes());
+    chunk
+}
+
+// Adds text chunks after the IHDR chunk, which always comes first
+fn insert_png_text(png: Vec<u8>, metadata: &ImageMetadata) -> Result<Vec<u8>, String> {
+    // Signature, then length, type, 13 bytes of data and CRC
+    const IHDR_END: usize = 8 + 4 + 4 + 13 + 4;
+    if png.len() < IHDR_END || &png[12..16] != b"IHDR" {
+        return Err("Invalid PNG stream".to_string());
+    }
+    let texts = [
+        ("Title", metadata.object.clone()),
+        ("Description", Some(description(metadata))),
+        ("Creation Time", metadata.date_obs.clone()),
+        ("Software", Some(SOFTWARE.to_string())),
+        ("Filter", metadata.filter.clone()),
+        ("Exposure", metadata.exposure.map(|e| format!("{} s", e))),
+    ];
+    let mut out = png[..IHDR_END].to_vec();
+    for (keyword, text) in texts {
+        if let Some(text) = text {
+            out.extend_from_slice(&png_text_chunk(keyword, &text));
+        }
+    }
+    out.extend_from_slice(&png[IHDR_END..]);
+    Ok(out)
+}
+
+fn write_jpeg(channels: &[Array2<f32>], metadata: &ImageMetadata, path: &str, quality: u8) -> Result<(), String> {
+    let mut jpeg = Vec::new();
+    // Mono planes are replicated, JPEG viewers handle RGB best
+    encode(
+        JpegEncoder::new_with_quality(&mut jpeg, quality.clamp(1, 100)),
+        &pack_u8(channels, false),
+        channels,
+        ExtendedColorType::Rgb8,
+    )?;
+    let out = insert_exif(jpeg, &exif_tiff(metadata)?)?;
+    std::fs::write(path, out).map_err(|e| e.to_string())
+}
+
+fn write_png(channels: &[Array2<f32>], metadata: &ImageMetadata, path: &str, sixteen_bits: bool) -> Result<(), String> {
+    let mono = channels.len() == 1;
+    let (data, color) = match (sixteen_bits, mono) {
+        (true, true) => (pack_u16(channels), ExtendedColorType::L16),
+        (true, false) => (pack_u16(channels), ExtendedColorType::Rgb16),
+        (false, true) => (pack_u8_planes(channels), ExtendedColorType::L8),
+        (false, false) => (pack_u8_planes(channels), ExtendedColorType::Rgb8),
+    };
+    let mut png = Vec::new();
+    encode(PngEncoder::new(&mut png), &data, channels, color)?;
+    let out = insert_png_text(png, metadata)?;
+    std::fs::write(path, out).map_err(|e| e.to_string())
+}
+
+// Description, software and date in the tags of the image, as in IFD0 of
+// the EXIF written to JPEG files
+fn write_tiff_image<C: colortype::ColorType<Inner = u16>>(
+    tiff: &mut TiffEncoder<impl Write + Seek>,
+    channels: &[Array2<f32>],
+    metadata: &ImageMetadata,
+) -> tiff::TiffResult<()> {
+    let (h, w) = channels[0].dim();
+    let mut image = tiff.new_image::<C>(w as u32, h as u32)?;
+    let tags = image.encoder();
+    tags.write_tag(TiffTag::ImageDescription, description(metadata).as_str())?;
+    tags.write_tag(TiffTag::Software, SOFTWARE)?;
+    if let Some(date) = exif_date(metadata) {
+        tags.write_tag(TiffTag::DateTime, date.as_str())?;
+    }
+    image.write_data(&samples_u16(channels))
+}
+
+// Written with the tiff crate, the TIFF encoder of the image crate has no
+// way to add tags
+fn write_tiff(channels: &[Array2<f32>], metadata: &ImageMetadata, path: &str) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let mut tiff = TiffEncoder::new(BufWriter::new(file)).map_err(|e| e.to_string())?;
+    match channels.len() {
+        1 => write_tiff_image::<colortype::Gray16>(&mut tiff, channels, metadata),
+        _ => write_tiff_image::<colortype::RGB16>(&mut tiff, channels, metadata),
+    }
+    .map_err(|e| e.to_string())
+}
+
+// Writes stretched [0.0, 1.0] planes to `params.path`
+pub fn export(channels: &[Array2<f32>], metadata: &ImageMetadata, params: &ExportParams) -> Result<ExportResult, String> {
+    let start_time = std::time::Instant::now();
+    let Some(first) = channels.first() else {
+        return Err("Nothing to export".to_string());
+    };
+    let (height, width) = first.dim();
+
+    match params.format {
+        ExportFormat::Jpeg { quality } => write_jpeg(channels, metadata, &params.path, quality)?,
+        ExportFormat::Png { sixteen_bits } => write_png(channels, metadata, &params.path, sixteen_bits)?,
+        ExportFormat::Tiff => write_tiff(channels, metadata, &params.path)?,
+    }
+
+    log::info!("Export took: {:?}", start_time.elapsed());
+    Ok(ExportResult {
+        path: params.path.clone(),
+        width,
+        height,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiff::decoder::{Decoder, DecodingResult};
+    use tiff::ColorType;
+
+    use crate::stretch::{ChannelStretch, StretchParams};
+
+    fn params(path: &std::path::Path) -> ExportParams {
+        ExportParams {
+            path: path.to_string_lossy().to_string(),
+            format: ExportFormat::Tiff,
+            stretch: StretchAlgorithm::Mtf {
+                stf: ChannelStretch::Linked(StretchParams::default()),
+            },
+            max_width: None,
+            max_height: None,
+        }
+    }
+
+    #[test]
+    fn tiff_tags_read_back() {
+        let path = std::env::temp_dir().join("skyctl-export-rgb.tiff");
+        let metadata = ImageMetadata {
+            object: Some("M 42".to_string()),
+            filter: Some("Ha".to_string()),
+            exposure: Some(300.0),
+            date_obs: Some("2024-01-10T03:12:00.250".to_string()),
+            ..Default::default()
+        };
+        let channels: Vec<Array2<f32>> = (0..3)
+            .map(|c| Array2::from_shape_fn((2, 3), |(y, x)| (c * 6 + y * 3 + x) as f32 / 17.0))
+            .collect();
+        let result = export(&channels, &metadata, &params(&path)).unwrap();
+        assert_eq!((result.width, result.height), (3, 2));
+
+        let mut tiff = Decoder::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(tiff.dimensions().unwrap(), (3, 2));
+        assert_eq!(tiff.colortype().unwrap(), ColorType::RGB(16));
+        assert_eq!(
+            tiff.get_tag_ascii_string(TiffTag::ImageDescription).unwrap(),
+            "M 42 | Ha | 300 s | 2024-01-10T03:12:00.250"
+        );
+        assert_eq!(tiff.get_tag_ascii_string(TiffTag::DateTime).unwrap(), "2024:01:10 03:12:00");
+        assert_eq!(tiff.get_tag_ascii_string(TiffTag::Software).unwrap(), SOFTWARE);
+        let DecodingResult::U16(samples) = tiff.read_image().unwrap() else {
+            panic!("Expected 16 bit samples");
+        };
+        // R, G and B of the first and last pixels
+        assert_eq!(samples[..3], [0, 23130, 46260]);
+        assert_eq!(samples[15..], [19275, 42405, 65535]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mono_tiff_without_date() {
+        let path = std::env::temp_dir().join("skyctl-export-mono.tiff");
+        let metadata = ImageMetadata {
+            object: Some("NGC 7000".to_string()),
+            ..Default::default()
+        };
+        let channels = vec![Array2::from_elem((4, 5), 0.5)];
+        export(&channels, &metadata, &params(&path)).unwrap();
+
+        let mut tiff = Decoder::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(tiff.colortype().unwrap(), ColorType::Gray(16));
+        assert_eq!(tiff.get_tag_ascii_string(TiffTag::ImageDescription).unwrap(), "NGC 7000");
+        assert!(tiff.find_tag(TiffTag::DateTime).unwrap().is_none());
+        let DecodingResult::U16(samples) = tiff.read_image().unwrap() else {
+            panic!("Expected 16 bit samples");
+        };
+        assert_eq!(samples, vec![32768; 20]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
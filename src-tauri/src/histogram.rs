@@ -0,0 +1,92 @@
+use ndarray::{Array2, Axis};
+use rayon::prelude::*;
+
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistogramScale {
+    Linear,
+    Log,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Histogram {
+    pub bins: usize,
+    pub scale: HistogramScale,
+    pub stretched: bool,
+    // Raw pixel counts per bin, one vector per channel
+    pub counts: Vec<Vec<u32>>,
+    // Counts mapped through `scale` and divided by the highest bin of all the
+    // channels, so they can be drawn directly as heights in [0.0, 1.0]
+    pub values: Vec<Vec<f32>>,
+}
+
+fn bin_index(x: f32, bins: usize) -> usize {
+    ((x.clamp(0.0, 1.0) * bins as f32) as usize).min(bins - 1)
+}
+
+// Histogram of a channel normalized to [0.0, 1.0]. Rows are binned in
+// parallel and the partial histograms merged at the end.
+pub fn channel_histogram(data: &Array2<f32>, bins: usize) -> Vec<u32> {
+    let bins = bins.max(1);
+    data.axis_iter(Axis(0))
+        .into_par_iter()
+        .fold(
+            || vec![0u32; bins],
+            |mut hist, row| {
+                for &x in row.iter() {
+                    hist[bin_index(x, bins)] += 1;
+                }
+                hist
+            },
+        )
+        .reduce(
+            || vec![0u32; bins],
+            |mut a, b| {
+                for (a, b) in a.iter_mut().zip(b) {
+                    *a += b;
+                }
+                a
+            },
+        )
+}
+
+impl Histogram {
+    pub fn from_channels(
+        channels: &[Array2<f32>],
+        bins: usize,
+        scale: HistogramScale,
+        stretched: bool,
+    ) -> Self {
+        let bins = bins.max(1);
+        let counts: Vec<Vec<u32>> = channels
+            .par_iter()
+            .map(|channel| channel_histogram(channel, bins))
+            .collect();
+
+        let map = |c: u32| match scale {
+            HistogramScale::Linear => c as f32,
+            HistogramScale::Log => (c as f32).ln_1p(),
+        };
+        let peak = counts
+            .iter()
+            .flat_map(|c| c.iter())
+            .copied()
+            .max()
+            .map(map)
+            .filter(|&p| p > 0.0)
+            .unwrap_or(1.0);
+
+        let values = counts
+            .iter()
+            .map(|c| c.iter().map(|&v| map(v) / peak).collect())
+            .collect();
+
+        Self {
+            bins,
+            scale,
+            stretched,
+            counts,
+            values,
+        }
+    }
+}
@@ -0,0 +1,441 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+use crate::metadata::ImageMetadata;
+use crate::rawimage::RawImage;
+
+const DEFAULT_MEMORY_BUDGET: usize = 2 * 1024 * 1024 * 1024;
+const DEFAULT_HISTORY_LENGTH: usize = 10;
+const THUMBNAIL_SIZE: usize = 128;
+
+pub type ImageId = u64;
+
+struct StoredImage {
+    telescope_index: u32,
+    image: RawImage,
+    // Memory used by the image when it was last modified
+    bytes: usize,
+    // Milliseconds since the epoch
+    loaded_at: u64,
+    // Base64 encoded JPEG
+    thumbnail: String,
+    // Value of the store clock when the image was last used
+    last_used: AtomicU64,
+    // Counts the changes made by `modify`
+    version: u64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ImageEntry {
+    pub id: ImageId,
+    pub telescope_index: u32,
+    pub width: usize,
+    pub height: usize,
+    pub bytes: usize,
+    pub loaded_at: u64,
+    // Whether this is the image shown for its telescope
+    pub current: bool,
+    pub metadata: ImageMetadata,
+    // Base64 encoded JPEG, empty if it could not be generated
+    pub thumbnail: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MemoryUsage {
+    pub budget: usize,
+    pub used: usize,
+    pub images: Vec<ImageEntry>,
+}
+
+struct Inner {
+    images: HashMap<ImageId, StoredImage>,
+    // Images of each telescope in capture order, the newest first
+    history: HashMap<u32, VecDeque<ImageId>>,
+    // Image shown for each telescope, the newest unless another was selected
+    current: HashMap<u32, ImageId>,
+    next_id: ImageId,
+    budget: usize,
+    history_length: usize,
+}
+
+// Every image loaded by the application, managed as Tauri state.
+//
+// Each telescope keeps its last `history_length` images; the newest is its
+// current image until another one is selected. When the images use more than the memory budget the least
+// recently used ones are dropped, but the current image of a telescope is
+// never evicted, only explicitly released.
+pub struct ImageStore {
+    inner: RwLock<Inner>,
+    clock: AtomicU64,
+}
+
+impl Default for ImageStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_MEMORY_BUDGET, DEFAULT_HISTORY_LENGTH)
+    }
+}
+
+fn not_found(telescope_index: u32) -> String {
+    format!("No RawImage found in cache for telescope index {}", telescope_index)
+}
+
+impl Inner {
+    fn is_current(&self, id: ImageId, telescope_index: u32) -> bool {
+        self.current.get(&telescope_index) == Some(&id)
+    }
+
+    fn current_id(&self, telescope_index: u32) -> Result<ImageId, String> {
+        self.current
+            .get(&telescope_index)
+            .copied()
+            .ok_or_else(|| not_found(telescope_index))
+    }
+
+    fn current(&self, telescope_index: u32) -> Result<&StoredImage, String> {
+        self.images
+            .get(&self.current_id(telescope_index)?)
+            .ok_or_else(|| not_found(telescope_index))
+    }
+
+    fn used(&self) -> usize {
+        self.images.values().map(|i| i.bytes).sum()
+    }
+
+    fn entry(&self, id: ImageId, stored: &StoredImage) -> ImageEntry {
+        let (width, height) = stored.image.dimensions();
+        ImageEntry {
+            id,
+            telescope_index: stored.telescope_index,
+            width,
+            height,
+            bytes: stored.bytes,
+            loaded_at: stored.loaded_at,
+            current: self.is_current(id, stored.telescope_index),
+            metadata: stored.image.metadata.clone(),
+            thumbnail: stored.thumbnail.clone(),
+        }
+    }
+
+    fn remove(&mut self, id: ImageId) -> Option<StoredImage> {
+        let stored = self.images.remove(&id)?;
+        let telescope_index = stored.telescope_index;
+        if let Some(history) = self.history.get_mut(&telescope_index) {
+            history.retain(|&i| i != id);
+            // Releasing the current image falls back to the newest one
+            if self.current.get(&telescope_index) == Some(&id) {
+                match history.front() {
+                    Some(&newest) => self.current.insert(telescope_index, newest),
+                    None => self.current.remove(&telescope_index),
+                };
+            }
+            if history.is_empty() {
+                self.history.remove(&telescope_index);
+            }
+        }
+        Some(stored)
+    }
+
+    // Drops the least recently used images until the budget is met
+    fn evict(&mut self) {
+        let mut used = self.used();
+        while used > self.budget {
+            let candidate = self
+                .images
+                .iter()
+                .filter(|(&id, s)| !self.is_current(id, s.telescope_index))
+                .min_by_key(|(_, s)| s.last_used.load(Ordering::Relaxed))
+                .map(|(&id, _)| id);
+            let Some(id) = candidate else {
+                log::warn!(
+                    "Current images use {} bytes, over the {} bytes budget",
+                    used,
+                    self.budget
+                );
+                break;
+            };
+            if let Some(stored) = self.remove(id) {
+                log::info!("Evicted image {} ({} bytes)", id, stored.bytes);
+                used -= stored.bytes;
+            }
+        }
+    }
+}
+
+impl ImageStore {
+    pub fn new(budget: usize, history_length: usize) -> Self {
+        Self {
+            inner: RwLock::new(Inner {
+                images: HashMap::new(),
+                history: HashMap::new(),
+                current: HashMap::new(),
+                next_id: 1,
+                budget,
+                history_length: history_length.max(1),
+            }),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self, stored: &StoredImage) {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        stored.last_used.store(now, Ordering::Relaxed);
+    }
+
+    // Adds an image and makes it the current one of its telescope
+    pub fn insert(&self, telescope_index: u32, image: RawImage) -> Result<ImageId, String> {
+        // Rendered before taking the lock, it needs the whole image
+        let thumbnail = image
+            .thumbnail_jpeg(THUMBNAIL_SIZE)
+            .map(|jpeg| STANDARD.encode(jpeg))
+            .unwrap_or_else(|e| {
+                log::warn!("Could not generate thumbnail: {}", e);
+                String::new()
+            });
+
+        let mut inner = self.inner.write().map_err(|e| e.to_string())?;
+        let id = inner.next_id;
+        inner.next_id += 1;
+
+        let loaded_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let stored = StoredImage {
+            telescope_index,
+            bytes: image.memory_size(),
+            image,
+            loaded_at,
+            thumbnail,
+            last_used: AtomicU64::new(0),
+            version: 0,
+        };
+        self.touch(&stored);
+        inner.images.insert(id, stored);
+
+        let history_length = inner.history_length;
+        let history = inner.history.entry(telescope_index).or_default();
+        history.push_front(id);
+        let expired: Vec<ImageId> = history.drain(history_length.min(history.len())..).collect();
+        for old in expired {
+            inner.images.remove(&old);
+        }
+        inner.current.insert(telescope_index, id);
+
+        inner.evict();
+        Ok(id)
+    }
+
+    // Runs `f` on the current image of the telescope
+    pub fn read<R>(&self, telescope_index: u32, f: impl FnOnce(&RawImage) -> R) -> Result<R, String> {
+        let inner = self.inner.read().map_err(|e| e.to_string())?;
+        let stored = inner.current(telescope_index)?;
+        self.touch(stored);
+        Ok(f(&stored.image))
+    }
+
+    // Runs `f` on any stored image
+    pub fn read_image<R>(&self, id: ImageId, f: impl FnOnce(&RawImage) -> R) -> Result<R, String> {
+        let inner = self.inner.read().map_err(|e| e.to_string())?;
+        let stored = inner
+            .images
+            .get(&id)
+            .ok_or_else(|| format!("No image with id {}", id))?;
+        self.touch(stored);
+        Ok(f(&stored.image))
+    }
+
+    // Makes a stored image the current one of its telescope again, returning
+    // the telescope index. The history keeps its capture order.
+    pub fn select(&self, id: ImageId) -> Result<u32, String> {
+        let mut inner = self.inner.write().map_err(|e| e.to_string())?;
+        let stored = inner
+            .images
+            .get(&id)
+            .ok_or_else(|| format!("No image with id {}", id))?;
+        let telescope_index = stored.telescope_index;
+        self.touch(stored);
+        inner.current.insert(telescope_index, id);
+        Ok(telescope_index)
+    }
+
+    // Runs `f` on the current image of the telescope, which may change its
+    // size (display buffers, pyramid), so the budget is enforced afterwards.
+    pub fn write<R>(
+        &self,
+        telescope_index: u32,
+        f: impl FnOnce(&mut RawImage) -> R,
+    ) -> Result<R, String> {
+        let mut inner = self.inner.write().map_err(|e| e.to_string())?;
+        let id = inner.current_id(telescope_index)?;
+        let stored = inner.images.get_mut(&id).ok_or_else(|| not_found(telescope_index))?;
+        let result = f(&mut stored.image);
+        stored.bytes = stored.image.memory_size();
+        let stored = &inner.images[&id];
+        self.touch(stored);
+
+        inner.evict();
+        Ok(result)
+    }
+
+    // Runs `f` on a copy of the current image of the telescope without
+    // holding the lock, so long processing does not block the viewer, then
+    // stores the copy in place of the image. Nothing is stored when `f`
+    // fails, or when the image was released or modified in the meantime.
+    pub fn modify<R>(
+        &self,
+        telescope_index: u32,
+        f: impl FnOnce(&mut RawImage) -> Result<R, String>,
+    ) -> Result<R, String> {
+        let (id, version, mut image, had_pyramid) = {
+            let inner = self.inner.read().map_err(|e| e.to_string())?;
+            let id = inner.current_id(telescope_index)?;
+            let stored = inner.current(telescope_index)?;
+            let image = stored.image.source_copy();
+            (id, stored.version, image, stored.image.pyramid.is_some())
+        };
+        let result = f(&mut image)?;
+        if had_pyramid {
+            image.build_pyramid();
+        }
+
+        let mut inner = self.inner.write().map_err(|e| e.to_string())?;
+        let stored = inner
+            .images
+            .get_mut(&id)
+            .ok_or_else(|| format!("Image {} was released while it was processed", id))?;
+        if stored.version != version {
+            return Err(format!("Image {} was modified while it was processed", id));
+        }
+        stored.bytes = image.memory_size();
+        stored.image = image;
+        stored.version += 1;
+        let stored = &inner.images[&id];
+        self.touch(stored);
+
+        inner.evict();
+        Ok(result)
+    }
+
+    pub fn release(&self, id: ImageId) -> Result<(), String> {
+        let mut inner = self.inner.write().map_err(|e| e.to_string())?;
+        inner
+            .remove(id)
+            .map(|_| ())
+            .ok_or_else(|| format!("No image with id {}", id))
+    }
+
+    // Releases every image of the telescope, returning how many there were
+    pub fn release_telescope(&self, telescope_index: u32) -> Result<usize, String> {
+        let mut inner = self.inner.write().map_err(|e| e.to_string())?;
+        let ids: Vec<ImageId> = inner
+            .history
+            .remove(&telescope_index)
+            .map(Vec::from)
+            .unwrap_or_default();
+        inner.current.remove(&telescope_index);
+        for id in &ids {
+            inner.images.remove(id);
+        }
+        Ok(ids.len())
+    }
+
+    pub fn set_budget(&self, budget: usize) -> Result<(), String> {
+        let mut inner = self.inner.write().map_err(|e| e.to_string())?;
+        inner.budget = budget;
+        inner.evict();
+        Ok(())
+    }
+
+    // Images of the telescope, the newest first
+    pub fn history(&self, telescope_index: u32) -> Result<Vec<ImageEntry>, String> {
+        let inner = self.inner.read().map_err(|e| e.to_string())?;
+        Ok(inner
+            .history
+            .get(&telescope_index)
+            .map(|h| {
+                h.iter()
+                    .filter_map(|id| inner.images.get(id).map(|s| inner.entry(*id, s)))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    pub fn memory_usage(&self) -> Result<MemoryUsage, String> {
+        let inner = self.inner.read().map_err(|e| e.to_string())?;
+        let mut images: Vec<ImageEntry> = inner
+            .images
+            .iter()
+            .map(|(&id, s)| inner.entry(id, s))
+            .collect();
+        images.sort_by_key(|e| e.id);
+        Ok(MemoryUsage {
+            budget: inner.budget,
+            used: inner.used(),
+            images,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array2;
+
+    use crate::debayer::BayerPattern;
+
+    fn image(value: i32) -> RawImage {
+        RawImage::new(Array2::from_elem((4, 4), value), BayerPattern::NONE)
+    }
+
+    fn value(store: &ImageStore) -> i32 {
+        store.read(0, |image| image.raw_image[[0, 0]]).unwrap()
+    }
+
+    #[test]
+    fn modify_works_on_a_copy() {
+        let store = ImageStore::new(usize::MAX, 5);
+        let mut original = image(1);
+        original.build_pyramid();
+        store.insert(0, original).unwrap();
+
+        let result = store.modify(0, |image| {
+            image.raw_image.fill(2);
+            // The store stays readable meanwhile
+            assert_eq!(value(&store), 1);
+            Ok(7)
+        });
+        assert_eq!(result, Ok(7));
+        assert_eq!(value(&store), 2);
+        assert!(store.read(0, |image| image.pyramid.is_some()).unwrap());
+
+        let result: Result<(), String> = store.modify(0, |image| {
+            image.raw_image.fill(3);
+            Err("Failed".to_string())
+        });
+        assert_eq!(result, Err("Failed".to_string()));
+        assert_eq!(value(&store), 2);
+    }
+
+    #[test]
+    fn changes_made_meanwhile_are_kept() {
+        let store = ImageStore::new(usize::MAX, 5);
+        let id = store.insert(0, image(1)).unwrap();
+        let result = store.modify(0, |outer| {
+            store.modify(0, |inner| {
+                inner.raw_image.fill(2);
+                Ok(())
+            })?;
+            outer.raw_image.fill(3);
+            Ok(())
+        });
+        assert_eq!(result, Err(format!("Image {} was modified while it was processed", id)));
+        assert_eq!(value(&store), 2);
+
+        let result = store.modify(0, |_| store.release(id));
+        assert_eq!(result, Err(format!("Image {} was released while it was processed", id)));
+    }
+}
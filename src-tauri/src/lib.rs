@@ -1,23 +1,56 @@
 #[cfg(target_os = "macos")]
 mod corelocation;
 
+mod align;
+mod asiair;
+mod asiaircamera;
+mod asiairdevices;
 mod asiairdiscovery;
+mod asiairmount;
+mod asiairplan;
+mod background;
+mod blink;
+mod cameraraw;
+mod colorcal;
 mod stf;
 mod debayer;
+mod deviceregistry;
+mod devices;
+mod display;
 mod downsample;
+mod export;
+mod histogram;
+mod imagestore;
+mod library;
+mod metadata;
+#[cfg(test)]
+mod mockasiair;
+mod noise;
+mod planetary;
+mod pyramid;
 mod rawimage;
+mod resize;
+mod ser;
+mod stretch;
+mod xisf;
+
+use tauri::Manager;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
-        .setup(|_app| {
+        .setup(|app| {
             #[cfg(target_os = "macos")]
             {
                 log::info!("Starting CoreLocation...");
                 // Must run on main thread!
                 let main = objc2_foundation::MainThreadMarker::new().unwrap();
-                corelocation::start_location_manager(_app.handle().clone(), main);
+                corelocation::start_location_manager(app.handle().clone(), main);
             }
+            app.manage(library::ImageLibrary::open(
+                app.path().app_data_dir()?,
+                app.path().app_cache_dir()?,
+            ));
             Ok(())
         })
         .plugin(
@@ -29,10 +62,90 @@ pub fn run() {
         )
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_store::Builder::default().build())
+        .manage(imagestore::ImageStore::default())
+        .manage(asiairdiscovery::AsiairDiscovery::default())
+        .manage(asiair::AsiairConnections::default())
+        .manage(deviceregistry::DeviceRegistry::default())
         .invoke_handler(tauri::generate_handler![
             asiairdiscovery::start_asiair_discovery,
             asiairdiscovery::stop_asiair_discovery,
+            asiairdiscovery::get_asiair_devices,
+            asiairdiscovery::get_asiair_hosts,
+            asiairdiscovery::set_asiair_hosts,
+            asiair::asiair_connect,
+            asiair::asiair_disconnect,
+            asiair::asiair_call,
+            asiairplan::asiair_list_plans,
+            asiairplan::asiair_get_plan,
+            asiairplan::asiair_create_plan,
+            asiairplan::asiair_update_plan,
+            asiairplan::asiair_delete_plan,
+            asiairplan::asiair_get_autorun_state,
+            asiairplan::asiair_start_autorun,
+            asiairplan::asiair_stop_autorun,
+            deviceregistry::get_devices,
+            deviceregistry::get_camera_capabilities,
+            deviceregistry::get_camera_state,
+            deviceregistry::set_camera_settings,
+            deviceregistry::camera_capture,
+            deviceregistry::camera_abort_exposure,
+            deviceregistry::get_mount_capabilities,
+            deviceregistry::get_mount_state,
+            deviceregistry::mount_goto,
+            deviceregistry::mount_sync,
+            deviceregistry::mount_abort,
+            deviceregistry::mount_park,
+            deviceregistry::mount_unpark,
+            deviceregistry::mount_set_tracking,
+            deviceregistry::mount_move,
+            deviceregistry::mount_stop_move,
+            deviceregistry::get_focuser_state,
+            deviceregistry::move_focuser,
+            deviceregistry::halt_focuser,
+            deviceregistry::get_filter_wheel_state,
+            deviceregistry::select_filter,
+            deviceregistry::get_cooler_state,
+            deviceregistry::set_cooler,
+            deviceregistry::warm_up_cooler,
+            deviceregistry::get_rotator_state,
+            deviceregistry::move_rotator,
+            deviceregistry::halt_rotator,
+            deviceregistry::get_guider_state,
+            deviceregistry::start_guiding,
+            deviceregistry::stop_guiding,
+            deviceregistry::guider_dither,
+            deviceregistry::get_dome_state,
+            deviceregistry::dome_open_shutter,
+            deviceregistry::dome_close_shutter,
+            deviceregistry::dome_slew,
+            deviceregistry::dome_park,
+            deviceregistry::dome_abort,
             stf::load_fits_image,
+            stf::load_image_file,
+            stf::get_ser_header,
+            stf::load_ser_frame,
+            planetary::rank_ser_frames,
+            planetary::stack_ser_frames,
+            stf::get_display_image,
+            stf::get_image_histogram,
+            stf::extract_background,
+            stf::calibrate_color,
+            stf::export_image,
+            stf::get_image_pyramid,
+            stf::get_image_tile,
+            stf::get_image_history,
+            stf::show_history_image,
+            stf::get_blink_sequence,
+            stf::release_image,
+            stf::release_telescope_images,
+            stf::get_image_memory_usage,
+            stf::set_image_memory_budget,
+            library::get_library_directories,
+            library::set_library_directories,
+            library::scan_library,
+            library::query_library,
+            library::get_library_facets,
+            library::get_library_thumbnail,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
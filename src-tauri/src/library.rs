@@ -0,0 +1,410 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::UNIX_EPOCH;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use tauri::{command, AppHandle, Emitter, State};
+use walkdir::WalkDir;
+
+use crate::cameraraw::read_camera_raw;
+use crate::metadata::ImageMetadata;
+use crate::rawimage::RawImage;
+use crate::xisf::read_xisf;
+
+// Bumped when LibraryEntry changes, so old indexes are rebuilt
+const INDEX_VERSION: u32 = 1;
+const INDEX_FILE: &str = "library.json";
+const THUMBNAIL_DIR: &str = "thumbnails";
+const THUMBNAIL_SIZE: usize = 256;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Fits,
+    Xisf,
+    // DSLR and mirrorless raw files
+    Raw,
+}
+
+impl ImageFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "fit" | "fits" | "fts" => Some(Self::Fits),
+            "xisf" => Some(Self::Xisf),
+            "cr2" | "cr3" | "nef" | "arw" | "dng" => Some(Self::Raw),
+            _ => None,
+        }
+    }
+
+    pub fn load(&self, path: &Path) -> Result<RawImage, String> {
+        match self {
+            Self::Fits => RawImage::from_reader(BufReader::new(File::open(path).map_err(|e| e.to_string())?)),
+            Self::Xisf => read_xisf(path),
+            Self::Raw => read_camera_raw(path),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LibraryEntry {
+    pub path: String,
+    pub format: ImageFormat,
+    // Size and modification time, in milliseconds since the epoch, of the
+    // file when it was indexed
+    pub file_size: u64,
+    pub modified: u64,
+    pub width: usize,
+    pub height: usize,
+    pub metadata: ImageMetadata,
+    // Median half flux radius of the stars, in sensor pixels
+    pub hfr: Option<f32>,
+    // Observing night, named after the date on which the evening started
+    pub night: Option<String>,
+    // Stretched JPEG in the cache directory
+    pub thumbnail: Option<String>,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct LibraryIndex {
+    version: u32,
+    directories: Vec<String>,
+    // By path
+    entries: BTreeMap<String, LibraryEntry>,
+}
+
+// Every field is optional; entries must match all the given ones
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct LibraryQuery {
+    // Case insensitive substring of the object name
+    pub target: Option<String>,
+    pub filter: Option<String>,
+    pub night: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct ScanSummary {
+    pub indexed: usize,
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    // "path: error" for the files that could not be read
+    pub failed: Vec<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Facet {
+    pub value: String,
+    pub count: usize,
+}
+
+// Distinct values found in the library, to populate the query filters
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LibraryFacets {
+    pub targets: Vec<Facet>,
+    pub filters: Vec<Facet>,
+    pub nights: Vec<Facet>,
+}
+
+// Index of the FITS and XISF files found in the configured directories,
+// managed as Tauri state and persisted as JSON in the app data directory.
+// Files are only read again when their size or modification time change.
+pub struct ImageLibrary {
+    index: RwLock<LibraryIndex>,
+    index_path: PathBuf,
+    thumbnail_dir: PathBuf,
+}
+
+fn modified_millis(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Frames taken after midnight belong to the night that started the previous
+// evening. The local date is used when the capture software wrote it; UTC is
+// close enough for most sites otherwise.
+fn observing_night(metadata: &ImageMetadata) -> Option<String> {
+    let date = metadata.date_loc.as_ref().or(metadata.date_obs.as_ref())?;
+    let night = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(date, f).ok())
+        .map(|t| (t - Duration::hours(12)).date())
+        .or_else(|| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())?;
+    Some(night.format("%Y-%m-%d").to_string())
+}
+
+fn facet<'a>(values: impl Iterator<Item = &'a String>) -> Vec<Facet> {
+    let mut counts: BTreeMap<&String, usize> = BTreeMap::new();
+    for v in values {
+        *counts.entry(v).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .map(|(value, count)| Facet { value: value.clone(), count })
+        .collect()
+}
+
+impl ImageLibrary {
+    pub fn open(data_dir: PathBuf, cache_dir: PathBuf) -> Self {
+        let index_path = data_dir.join(INDEX_FILE);
+        let index = fs::read_to_string(&index_path)
+            .ok()
+            .and_then(|json| serde_json::from_str::<LibraryIndex>(&json).ok())
+            .map(|index| {
+                if index.version == INDEX_VERSION {
+                    index
+                } else {
+                    // Keep the configuration, reindex the files
+                    LibraryIndex {
+                        version: INDEX_VERSION,
+                        directories: index.directories,
+                        entries: BTreeMap::new(),
+                    }
+                }
+            })
+            .unwrap_or(LibraryIndex {
+                version: INDEX_VERSION,
+                ..Default::default()
+            });
+
+        Self {
+            index: RwLock::new(index),
+            index_path,
+            thumbnail_dir: cache_dir.join(THUMBNAIL_DIR),
+        }
+    }
+
+    fn save(&self, index: &LibraryIndex) -> Result<(), String> {
+        if let Some(dir) = self.index_path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let json = serde_json::to_string(index).map_err(|e| e.to_string())?;
+        // Written aside and renamed so a crash never leaves half an index
+        let tmp = self.index_path.with_extension("json.tmp");
+        fs::write(&tmp, json).map_err(|e| e.to_string())?;
+        fs::rename(&tmp, &self.index_path).map_err(|e| e.to_string())
+    }
+
+    // Named after the file and its modification time, with a checksum that
+    // stays the same across Rust releases so the cache outlives updates
+    fn thumbnail_path(&self, path: &str, modified: u64) -> PathBuf {
+        let name = format!("{:08x}-{:x}.jpg", crc32fast::hash(path.as_bytes()), modified);
+        self.thumbnail_dir.join(name)
+    }
+
+    pub fn directories(&self) -> Result<Vec<String>, String> {
+        Ok(self.index.read().map_err(|e| e.to_string())?.directories.clone())
+    }
+
+    // Entries outside of the new directories are dropped on the next scan
+    pub fn set_directories(&self, directories: Vec<String>) -> Result<(), String> {
+        let mut index = self.index.write().map_err(|e| e.to_string())?;
+        index.directories = directories;
+        self.save(&index)
+    }
+
+    fn index_file(&self, path: &Path, format: ImageFormat, file_size: u64, modified: u64) -> Result<LibraryEntry, String> {
+        let mut image = format.load(path)?;
+        image.debayer()?;
+        let (width, height) = image.dimensions();
+        let key = path.to_string_lossy().to_string();
+
+        let thumbnail_path = self.thumbnail_path(&key, modified);
+        fs::create_dir_all(&self.thumbnail_dir).map_err(|e| e.to_string())?;
+        let thumbnail = image
+            .thumbnail_jpeg(THUMBNAIL_SIZE)
+            .and_then(|jpeg| fs::write(&thumbnail_path, jpeg).map_err(|e| e.to_string()))
+            .map(|_| thumbnail_path.to_string_lossy().to_string())
+            .map_err(|e| log::warn!("No thumbnail for {}: {}", key, e))
+            .ok();
+
+        Ok(LibraryEntry {
+            path: key,
+            format,
+            file_size,
+            modified,
+            width,
+            height,
+            night: observing_night(&image.metadata),
+            hfr: image.hfr(),
+            metadata: image.metadata,
+            thumbnail,
+        })
+    }
+
+    // Walks the configured directories, indexing new and modified files and
+    // dropping the ones that disappeared. `progress` is called after each file
+    // with the number of files done and found.
+    pub fn scan(&self, progress: impl Fn(usize, usize)) -> Result<ScanSummary, String> {
+        let start_time = std::time::Instant::now();
+        let directories = self.directories()?;
+        let files: Vec<(PathBuf, ImageFormat)> = directories
+            .iter()
+            .flat_map(|dir| WalkDir::new(dir).into_iter().filter_map(|e| e.ok()))
+            .filter(|e| e.file_type().is_file())
+            .filter_map(|e| ImageFormat::from_path(e.path()).map(|f| (e.into_path(), f)))
+            .collect();
+
+        let mut summary = ScanSummary::default();
+        let mut found = HashSet::new();
+        for (i, (path, format)) in files.iter().enumerate() {
+            let key = path.to_string_lossy().to_string();
+            found.insert(key.clone());
+
+            let Ok(file) = fs::metadata(path) else { continue };
+            let (file_size, modified) = (file.len(), modified_millis(&file));
+            let existing = self.index.read().map_err(|e| e.to_string())?.entries.get(&key).cloned();
+            let up_to_date = existing.as_ref().is_some_and(|e| {
+                e.file_size == file_size
+                    && e.modified == modified
+                    && e.thumbnail.as_ref().is_some_and(|t| Path::new(t).exists())
+            });
+
+            if !up_to_date {
+                match self.index_file(path, *format, file_size, modified) {
+                    Ok(entry) => {
+                        // The thumbnail of the previous version has another name
+                        let old_thumbnail = existing.as_ref().and_then(|e| e.thumbnail.as_ref());
+                        if let Some(old) = old_thumbnail.filter(|&old| Some(old) != entry.thumbnail.as_ref()) {
+                            let _ = fs::remove_file(old);
+                        }
+                        if existing.is_some() {
+                            summary.updated += 1;
+                        } else {
+                            summary.added += 1;
+                        }
+                        self.index.write().map_err(|e| e.to_string())?.entries.insert(key, entry);
+                    }
+                    Err(e) => summary.failed.push(format!("{}: {}", key, e)),
+                }
+            }
+            progress(i + 1, files.len());
+        }
+
+        let mut index = self.index.write().map_err(|e| e.to_string())?;
+        let removed: Vec<String> = index.entries.keys().filter(|k| !found.contains(*k)).cloned().collect();
+        for key in &removed {
+            if let Some(thumbnail) = index.entries.remove(key).and_then(|e| e.thumbnail) {
+                let _ = fs::remove_file(thumbnail);
+            }
+        }
+        summary.removed = removed.len();
+        summary.indexed = index.entries.len();
+        self.save(&index)?;
+
+        log::info!("Library scan took: {:?}", start_time.elapsed());
+        Ok(summary)
+    }
+
+    // Matching entries, in the order they were taken
+    pub fn query(&self, query: &LibraryQuery) -> Result<Vec<LibraryEntry>, String> {
+        let index = self.index.read().map_err(|e| e.to_string())?;
+        let target = query.target.as_ref().map(|t| t.to_lowercase());
+        let mut entries: Vec<LibraryEntry> = index
+            .entries
+            .values()
+            .filter(|e| {
+                target.as_ref().is_none_or(|t| {
+                    e.metadata.object.as_ref().is_some_and(|o| o.to_lowercase().contains(t))
+                })
+            })
+            .filter(|e| {
+                query.filter.as_ref().is_none_or(|f| {
+                    e.metadata.filter.as_ref().is_some_and(|ef| ef.eq_ignore_ascii_case(f))
+                })
+            })
+            .filter(|e| query.night.is_none() || e.night == query.night)
+            .cloned()
+            .collect();
+        // Camera raw files only know the local time
+        let date = |e: &LibraryEntry| e.metadata.date_obs.clone().or_else(|| e.metadata.date_loc.clone());
+        entries.sort_by(|a, b| (date(a), &a.path).cmp(&(date(b), &b.path)));
+        Ok(entries)
+    }
+
+    pub fn facets(&self) -> Result<LibraryFacets, String> {
+        let index = self.index.read().map_err(|e| e.to_string())?;
+        let entries = || index.entries.values();
+        Ok(LibraryFacets {
+            targets: facet(entries().filter_map(|e| e.metadata.object.as_ref())),
+            filters: facet(entries().filter_map(|e| e.metadata.filter.as_ref())),
+            nights: facet(entries().filter_map(|e| e.night.as_ref())),
+        })
+    }
+
+    // Base64 encoded JPEG thumbnail of an indexed file
+    pub fn thumbnail(&self, path: &str) -> Result<String, String> {
+        let index = self.index.read().map_err(|e| e.to_string())?;
+        let thumbnail = index
+            .entries
+            .get(path)
+            .and_then(|e| e.thumbnail.as_ref())
+            .ok_or_else(|| format!("No thumbnail for {}", path))?;
+        fs::read(thumbnail).map(|jpeg| STANDARD.encode(jpeg)).map_err(|e| e.to_string())
+    }
+}
+
+#[command]
+pub async fn get_library_directories(library: State<'_, ImageLibrary>) -> Result<Vec<String>, String> {
+    library.directories()
+}
+
+#[command]
+pub async fn set_library_directories(
+    library: State<'_, ImageLibrary>,
+    directories: Vec<String>,
+) -> Result<(), String> {
+    library.set_directories(directories)
+}
+
+// Updates the index, emitting `library_scan_progress` as files are read
+#[command]
+pub async fn scan_library(app: AppHandle, library: State<'_, ImageLibrary>) -> Result<ScanSummary, String> {
+    library.scan(|done, total| {
+        let payload = serde_json::json!({ "done": done, "total": total });
+        if let Err(e) = app.emit("library_scan_progress", payload) {
+            log::warn!("Failed to emit scan progress: {}", e);
+        }
+    })
+}
+
+#[command]
+pub async fn query_library(
+    library: State<'_, ImageLibrary>,
+    query: LibraryQuery,
+) -> Result<Vec<LibraryEntry>, String> {
+    library.query(&query)
+}
+
+#[command]
+pub async fn get_library_facets(library: State<'_, ImageLibrary>) -> Result<LibraryFacets, String> {
+    library.facets()
+}
+
+#[command]
+pub async fn get_library_thumbnail(library: State<'_, ImageLibrary>, path: String) -> Result<String, String> {
+    library.thumbnail(&path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thumbnail_names_are_stable() {
+        let library = ImageLibrary::open(PathBuf::from("/nonexistent"), PathBuf::from("/cache"));
+        let path = library.thumbnail_path("/data/m31.fits", 1_700_000_000_000);
+        let expected = Path::new("/cache").join(THUMBNAIL_DIR).join("a2c6adc7-18bcfe56800.jpg");
+        assert_eq!(path, expected);
+        assert_ne!(library.thumbnail_path("/data/m31.fits", 1_700_000_000_001), path);
+        assert_ne!(library.thumbnail_path("/data/m33.fits", 1_700_000_000_000), path);
+    }
+}
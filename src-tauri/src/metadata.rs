@@ -0,0 +1,78 @@
+use fitsrs::card::Value;
+
+// Acquisition details of a frame, as found in its headers
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ImageMetadata {
+    pub object: Option<String>,
+    pub filter: Option<String>,
+    // Seconds
+    pub exposure: Option<f32>,
+    // Start of the exposure, ISO 8601 as written by the capture software. UTC
+    // for DATE-OBS, local time of the site for DATE-LOC.
+    pub date_obs: Option<String>,
+    pub date_loc: Option<String>,
+    pub gain: Option<f32>,
+    // Sensor temperature in Celsius
+    pub ccd_temp: Option<f32>,
+    pub instrument: Option<String>,
+    pub telescope: Option<String>,
+    pub iso: Option<u32>,
+    // Sensor levels and as shot R, G, B multipliers (normalized to green) of
+    // camera raw files. The pixel data is already rescaled with the levels;
+    // the multipliers are not applied.
+    pub black_level: Option<f32>,
+    pub white_level: Option<f32>,
+    pub white_balance: Option<[f32; 3]>,
+}
+
+fn string_value(value: Option<&Value>) -> Option<String> {
+    match value {
+        Some(Value::String { value, .. }) => clean_string(value),
+        _ => None,
+    }
+}
+
+fn float_value(value: Option<&Value>) -> Option<f32> {
+    match value {
+        Some(Value::Float { value, .. }) => Some(*value as f32),
+        Some(Value::Integer { value, .. }) => Some(*value as f32),
+        _ => None,
+    }
+}
+
+// Strips the quotes and padding of a FITS string value
+fn clean_string(value: &str) -> Option<String> {
+    let value = value.trim().trim_matches('\'').trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+impl ImageMetadata {
+    fn from_lookup(string: impl Fn(&str) -> Option<String>, float: impl Fn(&str) -> Option<f32>) -> Self {
+        Self {
+            object: string("OBJECT"),
+            filter: string("FILTER"),
+            exposure: float("EXPTIME").or_else(|| float("EXPOSURE")),
+            date_obs: string("DATE-OBS"),
+            date_loc: string("DATE-LOC"),
+            gain: float("GAIN"),
+            ccd_temp: float("CCD-TEMP"),
+            instrument: string("INSTRUME"),
+            telescope: string("TELESCOP"),
+            iso: float("ISOSPEED").map(|iso| iso as u32),
+            ..Default::default()
+        }
+    }
+
+    // Reads the usual keywords from a FITS header, `get` looks up one card
+    pub fn from_fits_header<'a>(get: impl Fn(&str) -> Option<&'a Value>) -> Self {
+        Self::from_lookup(|k| string_value(get(k)), |k| float_value(get(k)))
+    }
+
+    // Same keywords from their raw text values, as XISF files store them
+    pub fn from_keywords(get: impl Fn(&str) -> Option<String>) -> Self {
+        Self::from_lookup(
+            |k| get(k).and_then(|v| clean_string(&v)),
+            |k| get(k).and_then(|v| clean_string(&v)).and_then(|v| v.parse().ok()),
+        )
+    }
+}
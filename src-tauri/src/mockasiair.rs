@@ -0,0 +1,1204 @@
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use serde_json::{json, Value};
+
+// Threads check for shutdown this often
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockDevice {
+    pub guid: String,
+    pub ssid: String,
+    // Address announced in the reply
+    pub ip: String,
+    pub model: String,
+    pub firmware: String,
+}
+
+impl Default for MockDevice {
+    fn default() -> Self {
+        Self {
+            guid: "00000000-0000-0000-0000-000000000000".to_string(),
+            ssid: "ASIAIR_MOCK".to_string(),
+            ip: "127.0.0.1".to_string(),
+            model: "ASIAIR Plus".to_string(),
+            firmware: "2.2".to_string(),
+        }
+    }
+}
+
+// Fake ASIAIR for the tests, built with them only. It answers the
+// scan_air discovery request the way a real one does, MockCommandServer
+// plays its command port.
+pub struct MockAsiair {
+    address: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+fn scan_air_reply(device: &MockDevice, id: &Value) -> String {
+    json!({
+        "id": id,
+        "code": 0,
+        "method": "scan_air",
+        "result": {
+            "guid": device.guid,
+            "ssid": device.ssid,
+            "name": device.ssid,
+            "ip": device.ip,
+            "model": device.model,
+            "firmware": device.firmware,
+        }
+    })
+    .to_string() + "\r\n"
+}
+
+fn discovery_responder(socket: UdpSocket, device: MockDevice, stop: Arc<AtomicBool>) {
+    let mut buf = [0u8; 4096];
+    while !stop.load(Ordering::SeqCst) {
+        let (size, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                log::warn!("Mock ASIAIR receive failed: {}", e);
+                continue;
+            }
+        };
+        let Ok(request) = serde_json::from_slice::<Value>(&buf[..size]) else {
+            continue;
+        };
+        if request.get("method").and_then(|m| m.as_str()) == Some("scan_air") {
+            let reply = scan_air_reply(&device, request.get("id").unwrap_or(&Value::Null));
+            if let Err(e) = socket.send_to(reply.as_bytes(), src) {
+                log::warn!("Mock ASIAIR reply failed: {}", e);
+            }
+        }
+    }
+}
+
+impl MockAsiair {
+    // Listens for discovery requests on `address`, port 0 picks a free one
+    pub fn start(address: &str, device: MockDevice) -> Result<Self, String> {
+        let socket = UdpSocket::bind(address).map_err(|e| format!("Failed to bind mock ASIAIR: {}", e))?;
+        socket
+            .set_read_timeout(Some(POLL_INTERVAL))
+            .map_err(|e| e.to_string())?;
+        let address = socket.local_addr().map_err(|e| e.to_string())?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let responder_stop = stop.clone();
+        let handle = thread::Builder::new()
+            .name("mock_asiair".to_string())
+            .spawn(move || discovery_responder(socket, device, responder_stop))
+            .map_err(|e| e.to_string())?;
+        log::info!("Mock ASIAIR listening on {}", address);
+        Ok(Self {
+            address,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for MockAsiair {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{:.6}", now.as_secs_f64())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockCamera {
+    pub name: String,
+    // Sensor size in pixels
+    pub width: u32,
+    pub height: u32,
+    pub bayer: Option<String>,
+    pub exposure_us: i64,
+    pub gain: i32,
+    pub binning: u32,
+    // x, y, width and height in binned pixels
+    pub roi: Option<[u32; 4]>,
+    // "idle", "exposing" or "downloading"
+    pub state: String,
+    // Counts the exposures started, a stopped one is not completed by its
+    // timer afterwards
+    pub exposure_count: u64,
+    // Celsius, the sensor heads for its target at MOCK_COOLING_SPEED
+    pub temperature: f64,
+    pub ambient: f64,
+    pub cooler_on: bool,
+    pub target_temperature: i64,
+    pub temperature_updated: Instant,
+}
+
+impl Default for MockCamera {
+    fn default() -> Self {
+        Self {
+            name: "ZWO ASI462MC".to_string(),
+            width: 1936,
+            height: 1096,
+            bayer: Some("RGGB".to_string()),
+            exposure_us: 1_000_000,
+            gain: 100,
+            binning: 1,
+            roi: None,
+            state: "idle".to_string(),
+            exposure_count: 0,
+            temperature: 20.0,
+            ambient: 20.0,
+            cooler_on: false,
+            target_temperature: 0,
+            temperature_updated: Instant::now(),
+        }
+    }
+}
+
+// Celsius per second
+const MOCK_COOLING_SPEED: f64 = 2.0;
+// Steps per second
+const MOCK_FOCUSER_SPEED: f64 = 2000.0;
+// Time for the wheel to turn by one slot
+const MOCK_WHEEL_SLOT_TIME: Duration = Duration::from_millis(200);
+
+impl MockCamera {
+    // The cooler brings the sensor at most 35 degrees below the ambient
+    fn update_temperature(&mut self) {
+        let goal = if self.cooler_on {
+            (self.target_temperature as f64).max(self.ambient - 35.0)
+        } else {
+            self.ambient
+        };
+        let step = self.temperature_updated.elapsed().as_secs_f64() * MOCK_COOLING_SPEED;
+        self.temperature_updated = Instant::now();
+        self.temperature = if self.temperature > goal {
+            (self.temperature - step).max(goal)
+        } else {
+            (self.temperature + step).min(goal)
+        };
+    }
+
+    fn cooler_power(&self) -> f64 {
+        if self.cooler_on {
+            ((self.ambient - self.temperature) / 40.0 * 100.0).clamp(0.0, 100.0).round()
+        } else {
+            0.0
+        }
+    }
+
+    // Size of the frames, binned and cropped to the ROI
+    fn frame_size(&self) -> (u32, u32) {
+        match self.roi {
+            Some([_, _, width, height]) => (width, height),
+            None => (self.width / self.binning.max(1), self.height / self.binning.max(1)),
+        }
+    }
+
+    // Noisy sky background with a few stars, 16 bit little endian
+    fn frame(&self) -> (Value, Vec<u8>) {
+        let (width, height) = self.frame_size();
+        let (w, h) = (width as usize, height as usize);
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64 ^ self.exposure_count;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let mut pixels: Vec<f64> = (0..w * h).map(|_| 1000.0 + 50.0 * random()).collect();
+        for _ in 0..40 {
+            let (cx, cy) = (random() * w as f64, random() * h as f64);
+            let peak = 2000.0 + 50000.0 * random().powi(3);
+            let sigma = 1.2 + random();
+            let radius = (4.0 * sigma).ceil() as i64;
+            for y in (cy as i64 - radius).max(0)..(cy as i64 + radius).min(h as i64) {
+                for x in (cx as i64 - radius).max(0)..(cx as i64 + radius).min(w as i64) {
+                    let d2 = (x as f64 - cx).powi(2) + (y as f64 - cy).powi(2);
+                    pixels[y as usize * w + x as usize] += peak * (-d2 / (2.0 * sigma * sigma)).exp();
+                }
+            }
+        }
+        let data: Vec<u8> = pixels
+            .iter()
+            .flat_map(|&p| (p.min(65535.0) as u16).to_le_bytes())
+            .collect();
+        let header = json!({
+            "width": width,
+            "height": height,
+            "bits": 16,
+            "size": data.len(),
+            "bayer": self.bayer,
+        });
+        (header, data)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockMount {
+    // Site, for the horizontal coordinates. Degrees, east positive.
+    pub latitude: f64,
+    pub longitude: f64,
+    // Hours and degrees. The mount always follows the sky, whether tracking
+    // or not.
+    pub ra: f64,
+    pub dec: f64,
+    pub tracking: bool,
+    pub tracking_rate: String,
+    pub slewing: bool,
+    // Direction and start of the manual move in progress
+    pub moving: Option<(String, Instant)>,
+    pub move_rate: u32,
+    pub parked: bool,
+    // Counts the gotos started, an aborted one is not completed by its
+    // timer afterwards
+    pub slew_count: u64,
+}
+
+impl Default for MockMount {
+    fn default() -> Self {
+        Self {
+            latitude: 48.0,
+            longitude: 2.0,
+            ra: 0.0,
+            dec: 90.0,
+            tracking: false,
+            tracking_rate: "Sidereal".to_string(),
+            slewing: false,
+            moving: None,
+            move_rate: 5,
+            parked: true,
+            slew_count: 0,
+        }
+    }
+}
+
+// Degrees per second of gotos
+const MOCK_SLEW_SPEED: f64 = 30.0;
+
+impl MockMount {
+    // Local sidereal time in hours
+    fn sidereal_time(&self) -> f64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        // Days since J2000.0, 2000-01-01 12:00 UTC
+        let days = now.as_secs_f64() / 86400.0 - 10957.5;
+        (18.697374558 + 24.06570982441908 * days + self.longitude / 15.0).rem_euclid(24.0)
+    }
+
+    fn hour_angle(&self) -> f64 {
+        (self.sidereal_time() - self.ra).rem_euclid(24.0)
+    }
+
+    // Altitude and azimuth in degrees, azimuth from the north through the east
+    fn horizontal(&self) -> (f64, f64) {
+        let (ha, dec, lat) = ((self.hour_angle() * 15.0).to_radians(), self.dec.to_radians(), self.latitude.to_radians());
+        let alt = (dec.sin() * lat.sin() + dec.cos() * lat.cos() * ha.cos()).asin();
+        let az = (-ha.sin() * dec.cos()).atan2(lat.cos() * dec.sin() - lat.sin() * dec.cos() * ha.cos());
+        (alt.to_degrees(), az.to_degrees().rem_euclid(360.0))
+    }
+
+    // A german equatorial sits east of the pier while pointing west of the
+    // meridian
+    fn pier_side(&self) -> &'static str {
+        if self.hour_angle() < 12.0 { "East" } else { "West" }
+    }
+
+    // Angular distance to `ra`, `dec` in degrees
+    fn distance(&self, ra: f64, dec: f64) -> f64 {
+        let (ra1, dec1, ra2, dec2) = ((self.ra * 15.0).to_radians(), self.dec.to_radians(), (ra * 15.0).to_radians(), dec.to_radians());
+        let cos = dec1.sin() * dec2.sin() + dec1.cos() * dec2.cos() * (ra1 - ra2).cos();
+        cos.clamp(-1.0, 1.0).acos().to_degrees()
+    }
+
+    // Applies the manual move in progress and ends it
+    fn finish_move(&mut self) -> bool {
+        let Some((direction, start)) = self.moving.take() else {
+            return false;
+        };
+        // Rate 1 is twice sidereal, each step doubles it
+        let degrees = start.elapsed().as_secs_f64() * 15.0 / 3600.0 * 2f64.powi(self.move_rate as i32);
+        match direction.as_str() {
+            "north" => self.dec = (self.dec + degrees).min(90.0),
+            "south" => self.dec = (self.dec - degrees).max(-90.0),
+            "east" => self.ra = (self.ra + degrees / 15.0).rem_euclid(24.0),
+            _ => self.ra = (self.ra - degrees / 15.0).rem_euclid(24.0),
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockFocuser {
+    pub position: i32,
+    pub max_step: i32,
+    pub temperature: f64,
+    // Target, start position and start time of the move in progress
+    pub moving: Option<(i32, i32, Instant)>,
+    // Counts the moves started, a stopped one is not completed by its
+    // timer afterwards
+    pub move_count: u64,
+}
+
+impl Default for MockFocuser {
+    fn default() -> Self {
+        Self {
+            position: 5000,
+            max_step: 10000,
+            temperature: 12.5,
+            moving: None,
+            move_count: 0,
+        }
+    }
+}
+
+impl MockFocuser {
+    fn current_position(&self) -> i32 {
+        match self.moving {
+            Some((target, from, start)) => {
+                let travelled = (start.elapsed().as_secs_f64() * MOCK_FOCUSER_SPEED) as i32;
+                from + (target - from).signum() * travelled.min((target - from).abs())
+            }
+            None => self.position,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockFilterWheel {
+    pub names: Vec<String>,
+    pub position: usize,
+    pub moving: bool,
+    pub move_count: u64,
+}
+
+impl Default for MockFilterWheel {
+    fn default() -> Self {
+        Self {
+            names: ["L", "R", "G", "B", "Ha", "OIII", "SII"].iter().map(|n| n.to_string()).collect(),
+            position: 0,
+            moving: false,
+            move_count: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MockAutorun {
+    // "idle", "running", "stopped" or "complete"
+    pub state: String,
+    pub plan: Option<String>,
+    pub target: Option<String>,
+    pub sequence: Option<usize>,
+    pub done: u32,
+    pub total: u32,
+    // Counts the runs started, a stopped one does not go on afterwards
+    pub run_count: u64,
+}
+
+// Time of each frame of a mock autorun, whatever its exposure
+const MOCK_AUTORUN_FRAME_TIME: Duration = Duration::from_millis(100);
+
+// Plan in the format of the ASIAIR, with a field SkyCtl does not know to
+// check that it is kept
+fn mock_plan() -> Value {
+    let sequence = |filter: &str, count: u32| {
+        json!({ "type": "light", "filter": filter, "exposure": 60_000_000, "gain": 100, "bin": 1, "count": count, "done": 0 })
+    };
+    json!({
+        "name": "Mock plan",
+        "targets": [{
+            "name": "M 31",
+            "ra": 0.7123,
+            "dec": 41.269,
+            "window": { "start": "21:00", "end": "03:30" },
+            "enabled": true,
+            "sequences": [sequence("L", 3), sequence("Ha", 2)],
+            "autofocus": { "every_filter": true },
+        }],
+        "dither": { "enabled": true, "pixels": 5, "every": 1 },
+    })
+}
+
+fn plan_name(plan: &Value) -> Option<&str> {
+    plan.get("name").and_then(|n| n.as_str())
+}
+
+// Sequences still to run, as target index, sequence index and frames left
+fn remaining_frames(plan: &Value) -> Vec<(usize, usize, u32)> {
+    let mut frames = vec![];
+    let targets = plan.get("targets").and_then(|t| t.as_array()).cloned().unwrap_or_default();
+    for (t, target) in targets.iter().enumerate() {
+        if target.get("enabled").and_then(|e| e.as_bool()) == Some(false) {
+            continue;
+        }
+        let sequences = target.get("sequences").and_then(|s| s.as_array()).cloned().unwrap_or_default();
+        for (i, sequence) in sequences.iter().enumerate() {
+            let count = sequence.get("count").and_then(|c| c.as_u64()).unwrap_or(0) as u32;
+            let done = sequence.get("done").and_then(|d| d.as_u64()).unwrap_or(0) as u32;
+            if count > done {
+                frames.push((t, i, count - done));
+            }
+        }
+    }
+    frames
+}
+
+// Takes the frames of plan `name` one by one, counting them in the plan
+fn run_autorun(shared: Arc<Shared>, count: u64, name: String) {
+    let autorun_event = |state: &MockState| {
+        let autorun = &state.autorun;
+        json!({
+            "state": autorun.state,
+            "plan": autorun.plan,
+            "target": autorun.target,
+            "sequence": autorun.sequence,
+            "done": autorun.done,
+            "total": autorun.total,
+        })
+    };
+    loop {
+        thread::sleep(MOCK_AUTORUN_FRAME_TIME);
+        let event = {
+            let mut guard = lock(&shared.state);
+            let state = &mut *guard;
+            if state.autorun.run_count != count || state.autorun.state != "running" {
+                return;
+            }
+            let Some(plan) = state.plans.iter_mut().find(|p| plan_name(p) == Some(name.as_str())) else {
+                state.autorun.state = "failed".to_string();
+                return;
+            };
+            match remaining_frames(plan).first() {
+                Some(&(t, i, _)) => {
+                    let sequence = &mut plan["targets"][t]["sequences"][i];
+                    sequence["done"] = json!(sequence.get("done").and_then(|d| d.as_u64()).unwrap_or(0) + 1);
+                    state.autorun.target = plan["targets"][t]["name"].as_str().map(|n| n.to_string());
+                    state.autorun.sequence = Some(i);
+                    state.autorun.done += 1;
+                }
+                None => state.autorun.state = "complete".to_string(),
+            }
+            autorun_event(state)
+        };
+        let complete = event["state"] == "complete";
+        shared.send_event("Autorun", event);
+        if complete {
+            return;
+        }
+    }
+}
+
+// What the mock command server knows about itself, changed by the requests
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockState {
+    pub device: MockDevice,
+    pub camera: MockCamera,
+    pub mount: MockMount,
+    pub focuser: MockFocuser,
+    pub filter_wheel: MockFilterWheel,
+    // As stored on the ASIAIR
+    pub plans: Vec<Value>,
+    pub autorun: MockAutorun,
+}
+
+type Client = Arc<Mutex<TcpStream>>;
+
+// State and clients, shared by the connection threads and the exposure timers
+struct Shared {
+    state: Mutex<MockState>,
+    clients: Mutex<Vec<Client>>,
+}
+
+fn write_line(client: &Client, message: &Value) -> std::io::Result<()> {
+    lock(client).write_all((message.to_string() + "\r\n").as_bytes())
+}
+
+impl Shared {
+    fn send_event(&self, name: &str, fields: Value) {
+        let mut event = json!({ "Event": name, "Timestamp": timestamp() });
+        if let (Some(event), Value::Object(fields)) = (event.as_object_mut(), fields) {
+            event.extend(fields);
+        }
+        lock(&self.clients).retain(|client| write_line(client, &event).is_ok());
+    }
+}
+
+// Ends exposure number `count` after its exposure time, unless stopped or
+// replaced by another one before
+fn run_exposure(shared: Arc<Shared>, count: u64, duration: Duration) {
+    thread::sleep(duration);
+    {
+        let mut state = lock(&shared.state);
+        if state.camera.exposure_count != count || state.camera.state != "exposing" {
+            return;
+        }
+        state.camera.state = "idle".to_string();
+    }
+    shared.send_event("Exposure", json!({ "state": "complete" }));
+}
+
+// Ends goto number `count` at `ra`, `dec` after `duration`, unless aborted
+// or replaced by another one before
+fn run_slew(shared: Arc<Shared>, count: u64, ra: f64, dec: f64, duration: Duration) {
+    thread::sleep(duration);
+    {
+        let mut state = lock(&shared.state);
+        if state.mount.slew_count != count || !state.mount.slewing {
+            return;
+        }
+        state.mount.slewing = false;
+        state.mount.ra = ra;
+        state.mount.dec = dec;
+    }
+    shared.send_event("ScopeGoto", json!({ "state": "complete" }));
+}
+
+// Ends focuser move number `count` after `duration`, unless stopped or
+// replaced by another one before
+fn run_focuser_move(shared: Arc<Shared>, count: u64, duration: Duration) {
+    thread::sleep(duration);
+    let position = {
+        let mut state = lock(&shared.state);
+        let focuser = &mut state.focuser;
+        let Some((target, _, _)) = focuser.moving.filter(|_| focuser.move_count == count) else {
+            return;
+        };
+        focuser.position = target;
+        focuser.moving = None;
+        target
+    };
+    shared.send_event("FocuserMove", json!({ "state": "complete", "position": position }));
+}
+
+fn run_wheel_move(shared: Arc<Shared>, count: u64, position: usize, duration: Duration) {
+    thread::sleep(duration);
+    {
+        let mut state = lock(&shared.state);
+        let wheel = &mut state.filter_wheel;
+        if wheel.move_count != count || !wheel.moving {
+            return;
+        }
+        wheel.position = position;
+        wheel.moving = false;
+    }
+    shared.send_event("WheelMove", json!({ "state": "complete", "position": position }));
+}
+
+fn coordinates_param(params: &Value) -> Option<(f64, f64)> {
+    let (ra, dec) = (params.get(0)?.as_f64()?, params.get(1)?.as_f64()?);
+    ((0.0..24.0).contains(&ra) && (-90.0..=90.0).contains(&dec)).then_some((ra, dec))
+}
+
+fn u32_param(params: &Value, index: usize) -> Option<u32> {
+    params.get(index).and_then(|v| v.as_u64()).map(|v| v as u32)
+}
+
+// Answer to a request, or its error code and message
+fn handle_request(shared: &Arc<Shared>, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+    let invalid = || (2, format!("invalid params for {}", method));
+    let mut guard = lock(&shared.state);
+    let state = &mut *guard;
+    let camera = &mut state.camera;
+    match method {
+        "test_connection" => Ok(json!("server connected!")),
+        "pi_get_info" => Ok(json!({
+            "guid": state.device.guid,
+            "model": state.device.model,
+            "firmware": state.device.firmware,
+        })),
+        "get_app_state" => Ok(json!({ "page": "preview" })),
+        "mock_delay" => Ok(json!(0)),
+        "get_camera_info" => Ok(json!({
+            "name": camera.name,
+            "chip_size": [camera.width, camera.height],
+            "pixel_size_um": 2.9,
+            "bayer_pattern": camera.bayer,
+            "is_color": camera.bayer.is_some(),
+            "has_cooler": true,
+            "bit_depth": 12,
+            "max_bin": 4,
+        })),
+        "get_camera_state" => Ok(json!({
+            "state": camera.state,
+            "exposure": camera.exposure_us as f64 / 1e6,
+            "gain": camera.gain,
+            "binning": camera.binning,
+            "roi": camera.roi.map(|[x, y, width, height]| json!({ "x": x, "y": y, "width": width, "height": height })),
+        })),
+        "get_control_value" => {
+            camera.update_temperature();
+            match params.get(0).and_then(|v| v.as_str()) {
+                Some("Exposure") => Ok(json!({ "value": camera.exposure_us })),
+                Some("Gain") => Ok(json!({ "value": camera.gain })),
+                // Tenths of a degree
+                Some("Temperature") => Ok(json!({ "value": (camera.temperature * 10.0).round() })),
+                Some("CoolerOn") => Ok(json!({ "value": camera.cooler_on as i64 })),
+                Some("TargetTemp") => Ok(json!({ "value": camera.target_temperature })),
+                Some("CoolPowerPerc") => Ok(json!({ "value": camera.cooler_power() })),
+                _ => Err(invalid()),
+            }
+        }
+        "set_control_value" => {
+            let value = params.get(1).and_then(|v| v.as_i64()).ok_or_else(invalid)?;
+            camera.update_temperature();
+            match params.get(0).and_then(|v| v.as_str()) {
+                Some("Exposure") if value > 0 => camera.exposure_us = value,
+                Some("Gain") => camera.gain = value as i32,
+                Some("CoolerOn") => camera.cooler_on = value != 0,
+                Some("TargetTemp") if (-50..=50).contains(&value) => camera.target_temperature = value,
+                _ => return Err(invalid()),
+            }
+            Ok(json!(0))
+        }
+        "set_camera_bin" => {
+            camera.binning = u32_param(params, 0).filter(|b| (1..=4).contains(b)).ok_or_else(invalid)?;
+            camera.roi = None;
+            Ok(json!(0))
+        }
+        "set_camera_roi" => {
+            let field = |key: &str| params.get(key).and_then(|v| v.as_u64()).map(|v| v as u32);
+            let roi = [field("x"), field("y"), field("width"), field("height")];
+            let [Some(x), Some(y), Some(width), Some(height)] = roi else {
+                return Err(invalid());
+            };
+            let binning = camera.binning.max(1);
+            if width == 0 || height == 0 || x + width > camera.width / binning || y + height > camera.height / binning {
+                return Err(invalid());
+            }
+            camera.roi = Some([x, y, width, height]);
+            Ok(json!(0))
+        }
+        "clear_camera_roi" => {
+            camera.roi = None;
+            Ok(json!(0))
+        }
+        "start_exposure" => {
+            if camera.state != "idle" {
+                return Err((3, "camera busy".to_string()));
+            }
+            camera.state = "exposing".to_string();
+            camera.exposure_count += 1;
+            let (count, duration) = (camera.exposure_count, Duration::from_micros(camera.exposure_us as u64));
+            drop(guard);
+            shared.send_event("Exposure", json!({ "state": "start" }));
+            let shared = shared.clone();
+            thread::spawn(move || run_exposure(shared, count, duration));
+            Ok(json!(0))
+        }
+        "stop_exposure" => {
+            let exposing = camera.state == "exposing";
+            camera.state = "idle".to_string();
+            drop(guard);
+            if exposing {
+                shared.send_event("Exposure", json!({ "state": "cancel" }));
+            }
+            Ok(json!(0))
+        }
+        _ => handle_mount_request(shared, guard, method, params),
+    }
+}
+
+fn handle_mount_request(
+    shared: &Arc<Shared>,
+    mut guard: MutexGuard<'_, MockState>,
+    method: &str,
+    params: &Value,
+) -> Result<Value, (i64, String)> {
+    let invalid = || (2, format!("invalid params for {}", method));
+    let parked = || (4, "mount is parked".to_string());
+    let mount = &mut guard.mount;
+    // Event sent once the state is unlocked
+    let event: (&str, Value);
+    match method {
+        "scope_get_equ_coord" => return Ok(json!({ "ra": mount.ra, "dec": mount.dec })),
+        "scope_get_horiz_coord" => {
+            let (alt, az) = mount.horizontal();
+            return Ok(json!({ "alt": alt, "az": az }));
+        }
+        "scope_get_pierside" => return Ok(json!(mount.pier_side())),
+        "scope_get_track_state" => return Ok(json!(mount.tracking)),
+        "scope_get_track_rate" => return Ok(json!(mount.tracking_rate)),
+        "scope_get_state" => {
+            return Ok(json!({
+                "slewing": mount.slewing,
+                "moving": mount.moving.is_some(),
+                "parked": mount.parked,
+            }))
+        }
+        "scope_goto" => {
+            let (ra, dec) = coordinates_param(params).ok_or_else(invalid)?;
+            if mount.parked {
+                return Err(parked());
+            }
+            mount.finish_move();
+            mount.slewing = true;
+            mount.slew_count += 1;
+            let duration = Duration::from_secs_f64((mount.distance(ra, dec) / MOCK_SLEW_SPEED).max(0.2));
+            let (shared, count) = (shared.clone(), mount.slew_count);
+            thread::spawn(move || run_slew(shared, count, ra, dec, duration));
+            event = ("ScopeGoto", json!({ "state": "start" }));
+        }
+        "scope_sync" => {
+            let (ra, dec) = coordinates_param(params).ok_or_else(invalid)?;
+            if mount.parked {
+                return Err(parked());
+            }
+            (mount.ra, mount.dec) = (ra, dec);
+            event = ("ScopeSync", json!({ "ra": ra, "dec": dec }));
+        }
+        "scope_abort_slew" => {
+            if !mount.slewing {
+                return Ok(json!(0));
+            }
+            mount.slewing = false;
+            event = ("ScopeGoto", json!({ "state": "cancel" }));
+        }
+        "scope_park" => {
+            mount.finish_move();
+            mount.slewing = false;
+            mount.parked = true;
+            mount.tracking = false;
+            (mount.ra, mount.dec) = (mount.sidereal_time(), 90.0);
+            event = ("ScopePark", json!({ "state": "parked" }));
+        }
+        "scope_unpark" => {
+            mount.parked = false;
+            event = ("ScopePark", json!({ "state": "unparked" }));
+        }
+        "scope_set_track_state" => {
+            let tracking = params.get(0).and_then(|v| v.as_bool()).ok_or_else(invalid)?;
+            if tracking && mount.parked {
+                return Err(parked());
+            }
+            mount.tracking = tracking;
+            event = ("ScopeTrack", json!({ "state": tracking }));
+        }
+        "scope_set_track_rate" => {
+            let rate = params.get(0).and_then(|v| v.as_str()).ok_or_else(invalid)?;
+            if !["Sidereal", "Lunar", "Solar", "King"].contains(&rate) {
+                return Err(invalid());
+            }
+            mount.tracking_rate = rate.to_string();
+            event = ("ScopeTrack", json!({ "state": mount.tracking, "rate": rate }));
+        }
+        "scope_set_move_speed" => {
+            mount.move_rate = u32_param(params, 0).filter(|r| (1..=9).contains(r)).ok_or_else(invalid)?;
+            return Ok(json!(0));
+        }
+        "scope_move" => {
+            let direction = params.get(0).and_then(|v| v.as_str()).ok_or_else(invalid)?;
+            if !["north", "south", "east", "west"].contains(&direction) {
+                return Err(invalid());
+            }
+            if mount.parked {
+                return Err(parked());
+            }
+            mount.finish_move();
+            mount.moving = Some((direction.to_string(), Instant::now()));
+            event = ("ScopeMove", json!({ "state": "start", "direction": direction }));
+        }
+        "scope_stop_move" => {
+            if !mount.finish_move() {
+                return Ok(json!(0));
+            }
+            event = ("ScopeMove", json!({ "state": "stop" }));
+        }
+        _ => return handle_device_request(shared, guard, method, params),
+    }
+    drop(guard);
+    shared.send_event(event.0, event.1);
+    Ok(json!(0))
+}
+
+// Plans and autorun
+fn handle_plan_request(
+    shared: &Arc<Shared>,
+    mut guard: MutexGuard<'_, MockState>,
+    method: &str,
+    params: &Value,
+) -> Result<Value, (i64, String)> {
+    let invalid = || (2, format!("invalid params for {}", method));
+    let state = &mut *guard;
+    let index_of = |plans: &[Value], name: Option<&str>| plans.iter().position(|p| plan_name(p) == name);
+    match method {
+        "get_plan_list" => Ok(json!(state.plans.iter().filter_map(plan_name).collect::<Vec<_>>())),
+        "get_plan" => {
+            let name = params.get(0).and_then(|n| n.as_str()).ok_or_else(invalid)?;
+            let index = index_of(&state.plans, Some(name)).ok_or((5, format!("no plan named {}", name)))?;
+            Ok(state.plans[index].clone())
+        }
+        "create_plan" | "update_plan" => {
+            let plan = params.get(0).filter(|p| p.is_object()).ok_or_else(invalid)?;
+            let name = plan_name(plan).ok_or_else(invalid)?;
+            match (method, index_of(&state.plans, Some(name))) {
+                ("create_plan", None) => state.plans.push(plan.clone()),
+                ("update_plan", Some(index)) => state.plans[index] = plan.clone(),
+                ("create_plan", Some(_)) => return Err((6, format!("plan {} already exists", name))),
+                _ => return Err((5, format!("no plan named {}", name))),
+            }
+            Ok(json!(0))
+        }
+        "delete_plan" => {
+            let name = params.get(0).and_then(|n| n.as_str()).ok_or_else(invalid)?;
+            let index = index_of(&state.plans, Some(name)).ok_or((5, format!("no plan named {}", name)))?;
+            state.plans.remove(index);
+            Ok(json!(0))
+        }
+        "get_autorun_state" => {
+            let autorun = &state.autorun;
+            Ok(json!({
+                "state": autorun.state,
+                "plan": autorun.plan,
+                "target": autorun.target,
+                "sequence": autorun.sequence,
+                "done": autorun.done,
+                "total": autorun.total,
+            }))
+        }
+        "start_autorun" => {
+            let name = params.get(0).and_then(|n| n.as_str()).ok_or_else(invalid)?;
+            let index = index_of(&state.plans, Some(name)).ok_or((5, format!("no plan named {}", name)))?;
+            if state.autorun.state == "running" {
+                return Err((3, "autorun already running".to_string()));
+            }
+            let total = remaining_frames(&state.plans[index]).iter().map(|f| f.2).sum();
+            state.autorun = MockAutorun {
+                state: "running".to_string(),
+                plan: Some(name.to_string()),
+                target: None,
+                sequence: None,
+                done: 0,
+                total,
+                run_count: state.autorun.run_count + 1,
+            };
+            let (shared, count, name) = (shared.clone(), state.autorun.run_count, name.to_string());
+            drop(guard);
+            shared.send_event("Autorun", json!({ "state": "running", "plan": name }));
+            thread::spawn(move || run_autorun(shared, count, name));
+            Ok(json!(0))
+        }
+        "stop_autorun" => {
+            if state.autorun.state != "running" {
+                return Ok(json!(0));
+            }
+            state.autorun.state = "stopped".to_string();
+            drop(guard);
+            shared.send_event("Autorun", json!({ "state": "stopped" }));
+            Ok(json!(0))
+        }
+        _ => Err((1, format!("method not found: {}", method))),
+    }
+}
+
+// Focuser and filter wheel
+fn handle_device_request(
+    shared: &Arc<Shared>,
+    mut guard: MutexGuard<'_, MockState>,
+    method: &str,
+    params: &Value,
+) -> Result<Value, (i64, String)> {
+    let invalid = || (2, format!("invalid params for {}", method));
+    let state = &mut *guard;
+    let (focuser, wheel) = (&mut state.focuser, &mut state.filter_wheel);
+    // Event sent once the state is unlocked
+    let event: (&str, Value);
+    match method {
+        "get_focuser_state" => {
+            return Ok(json!({
+                "position": focuser.current_position(),
+                "max_step": focuser.max_step,
+                "is_moving": focuser.moving.is_some(),
+                "temperature": focuser.temperature,
+            }))
+        }
+        "move_focuser" => {
+            let target = params.get(0).and_then(|v| v.as_i64()).ok_or_else(invalid)?;
+            if !(0..=focuser.max_step as i64).contains(&target) {
+                return Err(invalid());
+            }
+            let (target, from) = (target as i32, focuser.current_position());
+            focuser.position = from;
+            focuser.moving = Some((target, from, Instant::now()));
+            focuser.move_count += 1;
+            let duration = Duration::from_secs_f64((target - from).abs() as f64 / MOCK_FOCUSER_SPEED);
+            let (shared, count) = (shared.clone(), focuser.move_count);
+            thread::spawn(move || run_focuser_move(shared, count, duration));
+            event = ("FocuserMove", json!({ "state": "start", "position": from }));
+        }
+        "stop_focuser" => {
+            if focuser.moving.is_none() {
+                return Ok(json!(0));
+            }
+            focuser.position = focuser.current_position();
+            focuser.moving = None;
+            event = ("FocuserMove", json!({ "state": "stop", "position": focuser.position }));
+        }
+        "get_wheel_setting" => return Ok(json!({ "names": wheel.names })),
+        "get_wheel_state" => {
+            let position = if wheel.moving { -1 } else { wheel.position as i64 };
+            return Ok(json!({ "position": position, "moving": wheel.moving }));
+        }
+        "set_wheel_position" => {
+            let position = params.get(0).and_then(|v| v.as_u64()).map(|p| p as usize);
+            let position = position.filter(|&p| p < wheel.names.len()).ok_or_else(invalid)?;
+            let slots = position.abs_diff(wheel.position).max(1) as u32;
+            wheel.moving = true;
+            wheel.move_count += 1;
+            let (shared, count) = (shared.clone(), wheel.move_count);
+            thread::spawn(move || run_wheel_move(shared, count, position, MOCK_WHEEL_SLOT_TIME * slots));
+            event = ("WheelMove", json!({ "state": "start" }));
+        }
+        _ => return handle_plan_request(shared, guard, method, params),
+    }
+    drop(guard);
+    shared.send_event(event.0, event.1);
+    Ok(json!(0))
+}
+
+fn serve_client(client: Client, shared: Arc<Shared>, stop: Arc<AtomicBool>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(lock(&client).try_clone()?);
+    let mut line = String::new();
+    while !stop.load(Ordering::SeqCst) {
+        match reader.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            // A partial line stays in `line` until the rest arrives
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        }
+        let request = serde_json::from_str::<Value>(line.trim());
+        line.clear();
+        let Ok(request) = request else {
+            continue;
+        };
+
+        let method = request.get("method").and_then(|m| m.as_str()).unwrap_or_default().to_string();
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+        let mut reply = json!({
+            "jsonrpc": "2.0",
+            "Timestamp": timestamp(),
+            "method": method,
+            "id": request.get("id").cloned().unwrap_or(Value::Null),
+        });
+        // Replies late, so tests can have calls in flight
+        if method == "mock_delay" {
+            thread::sleep(Duration::from_millis(params.get(0).and_then(|v| v.as_u64()).unwrap_or(0)));
+        }
+        match handle_request(&shared, &method, &params) {
+            Ok(result) => {
+                reply["result"] = result;
+                reply["code"] = json!(0);
+            }
+            Err((code, error)) => {
+                reply["code"] = json!(code);
+                reply["error"] = json!(error);
+            }
+        }
+        write_line(&client, &reply)?;
+    }
+    Ok(())
+}
+
+// Image port: one get_current_img request per connection, answered with a
+// header line and the samples
+fn serve_image(mut stream: TcpStream, shared: Arc<Shared>) -> std::io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(stream.try_clone()?).read_line(&mut line)?;
+    let request: Value = serde_json::from_str(line.trim()).unwrap_or(Value::Null);
+    let mut reply = json!({
+        "jsonrpc": "2.0",
+        "Timestamp": timestamp(),
+        "method": request.get("method").cloned().unwrap_or(Value::Null),
+        "id": request.get("id").cloned().unwrap_or(Value::Null),
+    });
+    if request.get("method").and_then(|m| m.as_str()) != Some("get_current_img") {
+        reply["code"] = json!(1);
+        reply["error"] = json!("method not found");
+        return stream.write_all((reply.to_string() + "\r\n").as_bytes());
+    }
+    let camera = lock(&shared.state).camera.clone();
+    let (header, data) = camera.frame();
+    reply["code"] = json!(0);
+    reply["result"] = header;
+    stream.write_all((reply.to_string() + "\r\n").as_bytes())?;
+    stream.write_all(&data)
+}
+
+// Accepts connections until stopped, each one served on its own thread
+fn accept_loop(
+    listener: TcpListener,
+    stop: Arc<AtomicBool>,
+    mut serve: impl FnMut(TcpStream) -> Option<JoinHandle<()>>,
+) {
+    let mut threads = vec![];
+    while !stop.load(Ordering::SeqCst) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                log::warn!("Mock ASIAIR accept failed: {}", e);
+                continue;
+            }
+        };
+        if stream.set_nonblocking(false).and_then(|_| stream.set_read_timeout(Some(POLL_INTERVAL))).is_err() {
+            continue;
+        }
+        threads.extend(serve(stream));
+        threads.retain(|t| !t.is_finished());
+    }
+    for thread in threads {
+        let _ = thread.join();
+    }
+}
+
+fn bind_listener(address: &str) -> Result<TcpListener, String> {
+    let listener = TcpListener::bind(address).map_err(|e| format!("Failed to bind mock ASIAIR: {}", e))?;
+    listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+    Ok(listener)
+}
+
+// Fake ASIAIR command and image ports: line delimited JSON-RPC answered from
+// a `MockState`, events pushed to every client, and synthetic frames served
+// after each exposure
+pub struct MockCommandServer {
+    address: SocketAddr,
+    image_address: SocketAddr,
+    shared: Arc<Shared>,
+    stop: Arc<AtomicBool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl MockCommandServer {
+    // Listens on `address` and `image_address`, port 0 picks a free one
+    pub fn start(address: &str, image_address: &str, device: MockDevice) -> Result<Self, String> {
+        let listener = bind_listener(address)?;
+        let image_listener = bind_listener(image_address)?;
+        let address = listener.local_addr().map_err(|e| e.to_string())?;
+        let image_address = image_listener.local_addr().map_err(|e| e.to_string())?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(MockState {
+                device,
+                camera: MockCamera::default(),
+                mount: MockMount::default(),
+                focuser: MockFocuser::default(),
+                filter_wheel: MockFilterWheel::default(),
+                plans: vec![mock_plan()],
+                autorun: MockAutorun {
+                    state: "idle".to_string(),
+                    ..Default::default()
+                },
+            }),
+            clients: Mutex::new(vec![]),
+        });
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let (command_shared, command_stop) = (shared.clone(), stop.clone());
+        let commands = thread::Builder::new()
+            .name("mock_asiair_commands".to_string())
+            .spawn(move || {
+                let stop = command_stop.clone();
+                accept_loop(listener, command_stop, |stream| {
+                    let client = Arc::new(Mutex::new(stream));
+                    lock(&command_shared.clients).push(client.clone());
+                    let (shared, stop) = (command_shared.clone(), stop.clone());
+                    Some(thread::spawn(move || {
+                        if let Err(e) = serve_client(client, shared, stop) {
+                            log::debug!("Mock ASIAIR client closed: {}", e);
+                        }
+                    }))
+                })
+            })
+            .map_err(|e| e.to_string())?;
+
+        let (image_shared, image_stop) = (shared.clone(), stop.clone());
+        let images = thread::Builder::new()
+            .name("mock_asiair_images".to_string())
+            .spawn(move || {
+                accept_loop(image_listener, image_stop, |stream| {
+                    let shared = image_shared.clone();
+                    Some(thread::spawn(move || {
+                        if let Err(e) = serve_image(stream, shared) {
+                            log::debug!("Mock ASIAIR image transfer failed: {}", e);
+                        }
+                    }))
+                })
+            })
+            .map_err(|e| e.to_string())?;
+        log::info!("Mock ASIAIR commands on {}, images on {}", address, image_address);
+
+        Ok(Self {
+            address,
+            image_address,
+            shared,
+            stop,
+            handles: vec![commands, images],
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn image_address(&self) -> SocketAddr {
+        self.image_address
+    }
+
+    pub fn state(&self) -> MockState {
+        lock(&self.shared.state).clone()
+    }
+
+    // Pushes an event to every connected client, the way the ASIAIR reports
+    // exposures, slews and the like
+    pub fn send_event(&self, name: &str, fields: Value) {
+        self.shared.send_event(name, fields);
+    }
+
+    // Drops every client connection, as when the ASIAIR goes away
+    pub fn disconnect_clients(&self) {
+        for client in lock(&self.shared.clients).drain(..) {
+            let _ = lock(&client).shutdown(std::net::Shutdown::Both);
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.disconnect_clients();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for MockCommandServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
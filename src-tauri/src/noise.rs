@@ -0,0 +1,153 @@
+use ndarray::{Array2, ArrayView2, Axis, Zip};
+use rayon::prelude::*;
+
+// B3 spline scaling function used by the "à trous" wavelet transform
+const B3_SPLINE: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+
+// Standard deviation of unit gaussian noise on each wavelet layer of the B3
+// spline transform (Starck & Murtagh, Astronomical Image and Data Analysis)
+const B3_NOISE_SIGMA: [f32; 6] = [0.8907, 0.2007, 0.0856, 0.0413, 0.0205, 0.0103];
+
+const MRS_CLIP_SIGMA: f32 = 3.0;
+const MRS_MAX_ITERATIONS: usize = 10;
+const MRS_TOLERANCE: f32 = 0.001;
+
+// Median of the values, reordered in place. None when there are no values.
+pub fn median_of(values: &mut [f32]) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    let mid = values.len() / 2;
+    let (_, median, _) = values.select_nth_unstable_by(mid, |a, b| a.total_cmp(b));
+    Some(*median)
+}
+
+fn mirror(i: isize, n: usize) -> usize {
+    let n = n as isize;
+    let i = if i < 0 { -i } else { i };
+    let i = if i >= n { 2 * (n - 1) - i } else { i };
+    i.clamp(0, n - 1) as usize
+}
+
+// One smoothing step of the "à trous" algorithm: separable convolution with
+// the B3 spline, with holes of 2^scale pixels between the kernel taps.
+// Integer samples are converted as they are read, so the source plane is
+// never copied.
+fn smooth<T: Copy + Sync + Into<f64>>(data: ArrayView2<T>, scale: usize) -> Array2<f32> {
+    let (h, w) = data.dim();
+    let step = 1isize << scale;
+
+    let mut rows = Array2::<f32>::zeros((h, w));
+    rows.axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .zip(data.axis_iter(Axis(0)).into_par_iter())
+        .for_each(|(mut out, src)| {
+            for x in 0..w {
+                let mut acc = 0.0;
+                for (k, coef) in B3_SPLINE.iter().enumerate() {
+                    let xx = mirror(x as isize + (k as isize - 2) * step, w);
+                    acc += coef * src[xx].into() as f32;
+                }
+                out[x] = acc;
+            }
+        });
+
+    let mut out = Array2::<f32>::zeros((h, w));
+    out.axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(y, mut row)| {
+            for (k, coef) in B3_SPLINE.iter().enumerate() {
+                let yy = mirror(y as isize + (k as isize - 2) * step, h);
+                row.scaled_add(*coef, &rows.row(yy));
+            }
+        });
+    out
+}
+
+// Mean and standard deviation of the values passing the filter
+fn masked_stddev<'a>(values: impl Iterator<Item = &'a f32>) -> (f32, f32) {
+    let (mut n, mut sum, mut sum2) = (0f64, 0f64, 0f64);
+    for &v in values {
+        n += 1.0;
+        sum += v as f64;
+        sum2 += (v as f64) * (v as f64);
+    }
+    if n < 2.0 {
+        return (0.0, 0.0);
+    }
+    let mean = sum / n;
+    let var = (sum2 / n - mean * mean).max(0.0);
+    (mean as f32, var.sqrt() as f32)
+}
+
+// Standard deviation of the first wavelet layer after iterative k-sigma
+// clipping, used as the starting point of the MRS iterations.
+fn clipped_layer_sigma(layer: &Array2<f32>) -> f32 {
+    let (mut mean, mut sigma) = masked_stddev(layer.iter());
+    for _ in 0..MRS_MAX_ITERATIONS {
+        let (lo, hi) = (mean - MRS_CLIP_SIGMA * sigma, mean + MRS_CLIP_SIGMA * sigma);
+        let (m, s) = masked_stddev(layer.iter().filter(|&&v| v >= lo && v <= hi));
+        if s == 0.0 || (s - sigma).abs() / s < MRS_TOLERANCE {
+            return s;
+        }
+        mean = m;
+        sigma = s;
+    }
+    sigma
+}
+
+// Multiresolution support noise estimate (Starck & Murtagh 1998), the same
+// estimator PixInsight uses for its NOISE keywords.
+//
+// The image is decomposed in `layers` wavelet layers. Pixels with a
+// significant coefficient on any layer belong to the multiresolution support
+// (stars, nebulosity) and are excluded; the noise is the standard deviation of
+// the remaining pixels of I - c_J, iterated until it converges.
+pub fn mrs_noise<T: Copy + Sync + Into<f64>>(data: ArrayView2<T>, layers: usize) -> f32 {
+    let (h, w) = data.dim();
+    let layers = layers.clamp(1, B3_NOISE_SIGMA.len());
+    if h < 2 || w < 2 {
+        return 0.0;
+    }
+
+    let mut current = smooth(data, 0);
+    let first_layer = Zip::from(data).and(&current).par_map_collect(|&d, &c| d.into() as f32 - c);
+    let mut sigma = clipped_layer_sigma(&first_layer) / B3_NOISE_SIGMA[0];
+
+    // Largest normalized wavelet coefficient of every pixel across layers
+    let mut significance = first_layer;
+    significance.par_mapv_inplace(|v| v.abs() / B3_NOISE_SIGMA[0]);
+
+    for (j, layer_sigma) in B3_NOISE_SIGMA.iter().enumerate().take(layers).skip(1) {
+        let next = smooth(current.view(), j);
+        Zip::from(&mut significance)
+            .and(&current)
+            .and(&next)
+            .par_for_each(|s, &c, &n| *s = s.max((c - n).abs() / layer_sigma));
+        current = next;
+    }
+
+    // I - c_J holds every wavelet layer
+    let residual = Zip::from(data).and(&current).par_map_collect(|&d, &c| d.into() as f32 - c);
+
+    for _ in 0..MRS_MAX_ITERATIONS {
+        if sigma <= 0.0 {
+            return 0.0;
+        }
+        let threshold = MRS_CLIP_SIGMA * sigma;
+        let (_, s) = masked_stddev(
+            residual
+                .iter()
+                .zip(significance.iter())
+                .filter(|(_, &sig)| sig < threshold)
+                .map(|(r, _)| r),
+        );
+        let converged = s == 0.0 || (s - sigma).abs() / s < MRS_TOLERANCE;
+        sigma = s;
+        if converged {
+            break;
+        }
+    }
+    sigma
+}
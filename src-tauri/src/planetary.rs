@@ -0,0 +1,392 @@
+use std::path::Path;
+
+use ndarray::{s, Array2, Axis, Zip};
+use rayon::prelude::*;
+use rustfft::num_complex::Complex32;
+use rustfft::FftPlanner;
+use tauri::{command, AppHandle, State};
+
+use crate::align::shift;
+use crate::imagestore::ImageStore;
+use crate::rawimage::RawImage;
+use crate::ser::SerReader;
+use crate::stf::show_new_image;
+
+// Side of the blocks the local contrast is measured on
+const CONTRAST_BLOCK: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QualityMetric {
+    // Variance of the Laplacian, sensitive to the finest details
+    LaplacianVariance,
+    // Mean squared Sobel gradient
+    GradientEnergy,
+    // Mean standard deviation of small blocks
+    LocalContrast,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlignMethod {
+    // Centroid of the bright pixels, for a planet on a dark sky
+    CenterOfMass,
+    // Peak of the phase correlation, for lunar and solar surface details
+    PhaseCorrelation,
+}
+
+// Region of the (debayered) frame, in pixels
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Roi {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct QualityParams {
+    pub metric: QualityMetric,
+    // Whole frame when not given
+    pub roi: Option<Roi>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct StackParams {
+    pub quality: QualityParams,
+    // Share of the best frames that are stacked, 0 to 100
+    pub keep_percent: f32,
+    pub align: AlignMethod,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct FrameQuality {
+    pub index: usize,
+    pub score: f32,
+    // 0 for the best frame
+    pub rank: usize,
+    pub timestamp: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct StackResult {
+    // In frame order
+    pub frames: Vec<FrameQuality>,
+    // Indexes of the stacked frames, best first. The first one is the
+    // reference the others are aligned on.
+    pub stacked: Vec<usize>,
+    // Shift of each stacked frame from the reference, in pixels
+    pub offsets: Vec<(f32, f32)>,
+}
+
+// Mean of the color channels. Bayer frames are debayered first, so scores
+// and offsets are in debayered pixels.
+fn luminance(frame: &mut RawImage) -> Result<Array2<f32>, String> {
+    frame.debayer()?;
+    let channels = frame.channels_f32();
+    let mut sum = channels[0].clone();
+    for channel in &channels[1..] {
+        sum += channel;
+    }
+    Ok(sum / channels.len() as f32)
+}
+
+fn crop(data: &Array2<f32>, roi: Option<Roi>) -> Array2<f32> {
+    let Some(roi) = roi else {
+        return data.clone();
+    };
+    let (h, w) = data.dim();
+    if h == 0 || w == 0 {
+        return data.clone();
+    }
+    let (x, y) = (roi.x.min(w - 1), roi.y.min(h - 1));
+    let (x1, y1) = ((x + roi.width).min(w), (y + roi.height).min(h));
+    data.slice(s![y..y1.max(y + 1), x..x1.max(x + 1)]).to_owned()
+}
+
+// Counts the pixels `shift` fills for an offset of (dx, dy); the others are
+// outside of the shifted frame and left at 0
+fn add_coverage(coverage: &mut Array2<f32>, dx: f32, dy: f32) {
+    let (h, w) = coverage.dim();
+    let inside = |p: usize, d: f32, n: usize| {
+        let sp = p as f32 + d;
+        sp >= 0.0 && sp <= (n - 1) as f32
+    };
+    coverage.indexed_iter_mut().for_each(|((y, x), c)| {
+        if inside(y, dy, h) && inside(x, dx, w) {
+            *c += 1.0;
+        }
+    });
+}
+
+fn variance(values: impl Iterator<Item = f32>) -> f32 {
+    let (n, sum, sum2) = values.fold((0f32, 0f32, 0f32), |(n, s, s2), v| (n + 1.0, s + v, s2 + v * v));
+    if n == 0.0 {
+        return 0.0;
+    }
+    (sum2 / n - (sum / n).powi(2)).max(0.0)
+}
+
+// Sharpness of a frame, higher is better. The scores are divided by the
+// brightness so that passing clouds do not change the ranking.
+pub fn score(data: &Array2<f32>, metric: QualityMetric) -> f32 {
+    let (h, w) = data.dim();
+    let mean = data.mean().unwrap_or(0.0);
+    if h < 3 || w < 3 || mean <= 0.0 {
+        return 0.0;
+    }
+    let d = |y: usize, x: usize| data[[y, x]];
+
+    match metric {
+        QualityMetric::LaplacianVariance => {
+            let laplacian = (1..h - 1).flat_map(|y| {
+                (1..w - 1).map(move |x| 4.0 * d(y, x) - d(y - 1, x) - d(y + 1, x) - d(y, x - 1) - d(y, x + 1))
+            });
+            variance(laplacian) / (mean * mean)
+        }
+        QualityMetric::GradientEnergy => {
+            let energy: f32 = (1..h - 1)
+                .into_par_iter()
+                .map(|y| {
+                    (1..w - 1)
+                        .map(|x| {
+                            let gx = d(y - 1, x + 1) + 2.0 * d(y, x + 1) + d(y + 1, x + 1)
+                                - d(y - 1, x - 1) - 2.0 * d(y, x - 1) - d(y + 1, x - 1);
+                            let gy = d(y + 1, x - 1) + 2.0 * d(y + 1, x) + d(y + 1, x + 1)
+                                - d(y - 1, x - 1) - 2.0 * d(y - 1, x) - d(y - 1, x + 1);
+                            gx * gx + gy * gy
+                        })
+                        .sum::<f32>()
+                })
+                .sum();
+            energy / ((h - 2) * (w - 2)) as f32 / (mean * mean)
+        }
+        QualityMetric::LocalContrast => {
+            let blocks: Vec<f32> = data
+                .exact_chunks((CONTRAST_BLOCK.min(h), CONTRAST_BLOCK.min(w)))
+                .into_iter()
+                .map(|block| variance(block.iter().copied()).sqrt())
+                .collect();
+            blocks.iter().sum::<f32>() / blocks.len() as f32 / mean
+        }
+    }
+}
+
+// Scores every frame of a SER file, the frames are read in parallel
+pub fn rank_frames(path: &Path, params: &QualityParams) -> Result<Vec<FrameQuality>, String> {
+    let start_time = std::time::Instant::now();
+    let frame_count = SerReader::open(path)?.header().frame_count;
+    let mut frames: Vec<FrameQuality> = (0..frame_count)
+        .into_par_iter()
+        .map_init(
+            || SerReader::open(path),
+            |reader, index| {
+                let reader = reader.as_mut().map_err(|e| e.clone())?;
+                let luminance = luminance(&mut reader.read_frame(index)?)?;
+                Ok(FrameQuality {
+                    index,
+                    score: score(&crop(&luminance, params.roi), params.metric),
+                    rank: 0,
+                    timestamp: reader.timestamp(index),
+                })
+            },
+        )
+        .collect::<Result<_, String>>()?;
+
+    let mut order: Vec<usize> = (0..frames.len()).collect();
+    order.sort_by(|&a, &b| frames[b].score.total_cmp(&frames[a].score));
+    for (rank, index) in order.into_iter().enumerate() {
+        frames[index].rank = rank;
+    }
+    log::info!("Ranking {} frames took: {:?}", frame_count, start_time.elapsed());
+    Ok(frames)
+}
+
+fn center_of_mass(data: &Array2<f32>) -> (f32, f32) {
+    // Only what is brighter than the mean counts, the sky around the planet
+    // would pull the centroid to the middle of the frame
+    let mean = data.mean().unwrap_or(0.0);
+    let (mut sum, mut sx, mut sy) = (0f32, 0f32, 0f32);
+    for ((y, x), &v) in data.indexed_iter() {
+        let v = (v - mean).max(0.0);
+        sum += v;
+        sx += v * x as f32;
+        sy += v * y as f32;
+    }
+    if sum > 0.0 {
+        (sx / sum, sy / sum)
+    } else {
+        (0.0, 0.0)
+    }
+}
+
+fn fft_2d(data: &mut Array2<Complex32>, forward: bool) {
+    let (h, w) = data.dim();
+    let mut planner = FftPlanner::<f32>::new();
+    let (row_fft, column_fft) = if forward {
+        (planner.plan_fft_forward(w), planner.plan_fft_forward(h))
+    } else {
+        (planner.plan_fft_inverse(w), planner.plan_fft_inverse(h))
+    };
+    data.axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .for_each(|mut row| row_fft.process(row.as_slice_mut().unwrap()));
+    let mut columns = data.t().as_standard_layout().into_owned();
+    columns
+        .axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .for_each(|mut column| column_fft.process(column.as_slice_mut().unwrap()));
+    data.assign(&columns.t());
+}
+
+// Spectrum of the data minus its mean, with a Hann window so the borders do
+// not correlate
+fn windowed_spectrum(data: &Array2<f32>) -> Array2<Complex32> {
+    let (h, w) = data.dim();
+    let mean = data.mean().unwrap_or(0.0);
+    let hann = |i: usize, n: usize| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / n as f32).cos();
+    let mut spectrum = Array2::from_shape_fn((h, w), |(y, x)| {
+        Complex32::new((data[[y, x]] - mean) * hann(y, h) * hann(x, w), 0.0)
+    });
+    fft_2d(&mut spectrum, true);
+    spectrum
+}
+
+// Vertex of the parabola through three samples, relative to the middle one
+fn parabola_peak(left: f32, center: f32, right: f32) -> f32 {
+    let denominator = left - 2.0 * center + right;
+    if denominator.abs() < f32::EPSILON {
+        0.0
+    } else {
+        (0.5 * (left - right) / denominator).clamp(-0.5, 0.5)
+    }
+}
+
+// Shift of the frame from the reference, from their spectra
+fn phase_correlation(reference: &Array2<Complex32>, frame: &Array2<Complex32>) -> (f32, f32) {
+    let (h, w) = reference.dim();
+    let mut cross = Array2::<Complex32>::zeros((h, w));
+    Zip::from(&mut cross).and(reference).and(frame).for_each(|c, r, f| {
+        let product = f * r.conj();
+        let norm = product.norm();
+        *c = if norm > 0.0 { product / norm } else { Complex32::new(0.0, 0.0) };
+    });
+    fft_2d(&mut cross, false);
+
+    let correlation = cross.mapv(|c| c.re);
+    let ((py, px), _) = correlation
+        .indexed_iter()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .unwrap();
+    let at = |y: isize, x: isize| correlation[[y.rem_euclid(h as isize) as usize, x.rem_euclid(w as isize) as usize]];
+    let (y, x) = (py as isize, px as isize);
+    let fy = parabola_peak(at(y - 1, x), at(y, x), at(y + 1, x));
+    let fx = parabola_peak(at(y, x - 1), at(y, x), at(y, x + 1));
+    // Peaks past the middle are negative shifts
+    let wrap = |p: usize, n: usize| if p > n / 2 { p as f32 - n as f32 } else { p as f32 };
+    (wrap(px, w) + fx, wrap(py, h) + fy)
+}
+
+// Stacks the best frames of a SER file, aligned on the best one
+pub fn stack_frames(path: &Path, params: &StackParams) -> Result<(RawImage, StackResult), String> {
+    let frames = rank_frames(path, &params.quality)?;
+    if frames.is_empty() {
+        return Err("No frame to stack".to_string());
+    }
+    let start_time = std::time::Instant::now();
+    let keep = ((frames.len() as f32 * params.keep_percent / 100.0).ceil() as usize).clamp(1, frames.len());
+    let mut stacked: Vec<usize> = (0..frames.len()).collect();
+    stacked.sort_by_key(|&i| frames[i].rank);
+    stacked.truncate(keep);
+
+    let mut reference = SerReader::open(path)?.read_frame(stacked[0])?;
+    let reference_luminance = crop(&luminance(&mut reference)?, params.quality.roi);
+    let reference_center = center_of_mass(&reference_luminance);
+    let reference_spectrum = match params.align {
+        AlignMethod::PhaseCorrelation => Some(windowed_spectrum(&reference_luminance)),
+        AlignMethod::CenterOfMass => None,
+    };
+
+    let align = |reader: &mut Result<SerReader, String>, index: usize| -> Result<_, String> {
+        let reader = reader.as_mut().map_err(|e| e.clone())?;
+        let mut frame = reader.read_frame(index)?;
+        let luminance = crop(&luminance(&mut frame)?, params.quality.roi);
+        let offset = match &reference_spectrum {
+            Some(spectrum) => {
+                // The window pulls large shifts toward zero, the residual of
+                // the shifted frame corrects most of it
+                let (x, y) = phase_correlation(spectrum, &windowed_spectrum(&luminance));
+                let shifted = shift(&luminance, x, y);
+                let (rx, ry) = phase_correlation(spectrum, &windowed_spectrum(&shifted));
+                (x + rx, y + ry)
+            }
+            None => {
+                let (x, y) = center_of_mass(&luminance);
+                (x - reference_center.0, y - reference_center.1)
+            }
+        };
+        let channels: Vec<Array2<f32>> = frame.channels_f32().iter().map(|c| shift(c, offset.0, offset.1)).collect();
+        Ok((channels, offset))
+    };
+
+    // A few frames per thread at a time, all the selected frames would not
+    // fit in memory
+    let mut sum: Option<Vec<Array2<f32>>> = None;
+    // Frames covering each pixel, the borders get fewer once shifted
+    let mut coverage: Option<Array2<f32>> = None;
+    let mut offsets = Vec::with_capacity(keep);
+    for chunk in stacked.chunks(rayon::current_num_threads() * 2) {
+        let aligned: Vec<_> = chunk
+            .par_iter()
+            .map_init(|| SerReader::open(path), |reader, &index| align(reader, index))
+            .collect::<Result<_, String>>()?;
+        for (channels, offset) in aligned {
+            add_coverage(coverage.get_or_insert_with(|| Array2::zeros(channels[0].dim())), offset.0, offset.1);
+            offsets.push(offset);
+            match &mut sum {
+                Some(sum) => sum.iter_mut().zip(&channels).for_each(|(s, c)| *s += c),
+                None => sum = Some(channels),
+            }
+        }
+    }
+
+    let coverage = coverage.unwrap_or_default();
+    let mean: Vec<Array2<f32>> = sum
+        .unwrap_or_default()
+        .into_iter()
+        .map(|s| Zip::from(&s).and(&coverage).par_map_collect(|&v, &c| if c > 0.0 { v / c } else { 0.0 }))
+        .collect();
+    let image = reference.with_channels(&mean);
+    log::info!("Stacking {} frames took: {:?}", keep, start_time.elapsed());
+
+    Ok((
+        image,
+        StackResult {
+            frames,
+            stacked,
+            offsets,
+        },
+    ))
+}
+
+#[command]
+pub async fn rank_ser_frames(path: String, params: QualityParams) -> Result<Vec<FrameQuality>, String> {
+    rank_frames(Path::new(&path), &params)
+}
+
+// Stacks the best frames and shows the result as the current image of the
+// telescope
+#[command]
+pub async fn stack_ser_frames(
+    app: AppHandle,
+    store: State<'_, ImageStore>,
+    telescope_index: u32,
+    path: String,
+    params: StackParams,
+    display_width: usize,
+    display_height: usize,
+) -> Result<StackResult, String> {
+    let (image, result) = stack_frames(Path::new(&path), &params)?;
+    show_new_image(&app, &store, telescope_index, image, display_width, display_height).await?;
+    Ok(result)
+}
@@ -0,0 +1,322 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use ndarray::{s, Array2, ArrayView2, Axis};
+use rayon::prelude::*;
+
+use crate::display::pack_u8;
+use crate::resize::{resize_f32, ResizeFilter};
+use crate::stretch::{normalize, StretchAlgorithm};
+
+// Levels are halved until they fit in a tile of this size
+const MIN_LEVEL_SIZE: usize = 256;
+
+#[derive(Debug)]
+pub struct PyramidLevel {
+    // Source pixels per pixel of this level: 1, 2, 4, ...
+    pub scale: usize,
+    pub width: usize,
+    pub height: usize,
+    pub channels: Vec<Array2<f32>>,
+}
+
+// Successive 2x2 averaged levels of an image, used to render only the
+// visible part of the image at the zoom of the viewer. Level 0 is the full
+// resolution source itself, which the pyramid does not copy: its tiles are
+// read from the source planes passed to `render_tile`.
+#[derive(Debug)]
+pub struct ImagePyramid {
+    // Size of the source planes
+    pub width: usize,
+    pub height: usize,
+    // Levels 1 and up
+    pub levels: Vec<PyramidLevel>,
+    // Maximum of each channel at full resolution, used to normalize
+    pub maxima: Vec<f32>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Viewport {
+    // Rectangle in full resolution pixels
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LevelInfo {
+    pub level: usize,
+    pub scale: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ImageTile {
+    pub level: usize,
+    pub scale: usize,
+    // Area covered by the tile, in full resolution pixels
+    pub viewport: Viewport,
+    // Pixels of the tile, more than the level has when it was upscaled
+    pub width: usize,
+    pub height: usize,
+    // Base64 encoded RGBA, 8 bits per channel, ready for an ImageData
+    pub pixels: String,
+}
+
+fn halve<T: Copy + Sync>(data: ArrayView2<T>, load: impl Fn(T) -> f32 + Sync) -> Array2<f32> {
+    let (h, w) = data.dim();
+    let (nh, nw) = (h.div_ceil(2), w.div_ceil(2));
+    let mut out = Array2::<f32>::zeros((nh, nw));
+    out.axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(y, mut row)| {
+            let y0 = y * 2;
+            let y1 = (y0 + 1).min(h - 1);
+            for (x, v) in row.iter_mut().enumerate() {
+                let x0 = x * 2;
+                let x1 = (x0 + 1).min(w - 1);
+                *v = (load(data[[y0, x0]]) + load(data[[y0, x1]]) + load(data[[y1, x0]]) + load(data[[y1, x1]])) * 0.25;
+            }
+        });
+    out
+}
+
+impl ImagePyramid {
+    // Builds the levels of `planes`, one for mono images, R, G and B for
+    // debayered ones
+    pub fn new(planes: &[ArrayView2<i32>]) -> Self {
+        let start_time = std::time::Instant::now();
+        let maxima = planes
+            .iter()
+            .map(|p| p.fold(i32::MIN, |a, &b| a.max(b)) as f32)
+            .collect();
+        let (height, width) = planes.first().map(|p| p.dim()).unwrap_or((0, 0));
+
+        let mut levels: Vec<PyramidLevel> = Vec::new();
+        let (mut level_width, mut level_height) = (width, height);
+        while level_width.max(level_height) > MIN_LEVEL_SIZE {
+            let channels: Vec<Array2<f32>> = match levels.last() {
+                Some(last) => last.channels.par_iter().map(|c| halve(c.view(), |v| v)).collect(),
+                None => planes.par_iter().map(|p| halve(p.view(), |v| v as f32)).collect(),
+            };
+            (level_height, level_width) = channels[0].dim();
+            let scale = 1 << (levels.len() + 1);
+            levels.push(PyramidLevel {
+                scale,
+                width: level_width,
+                height: level_height,
+                channels,
+            });
+        }
+        log::info!("Pyramid with {} levels took: {:?}", levels.len() + 1, start_time.elapsed());
+
+        Self {
+            width,
+            height,
+            levels,
+            maxima,
+        }
+    }
+
+    // Bytes held by the levels, the source is not counted
+    pub fn memory_size(&self) -> usize {
+        self.levels
+            .iter()
+            .flat_map(|l| &l.channels)
+            .map(|c| c.len() * std::mem::size_of::<f32>())
+            .sum()
+    }
+
+    pub fn info(&self) -> Vec<LevelInfo> {
+        let source = LevelInfo {
+            level: 0,
+            scale: 1,
+            width: self.width,
+            height: self.height,
+        };
+        std::iter::once(source)
+            .chain(self.levels.iter().enumerate().map(|(i, l)| LevelInfo {
+                level: i + 1,
+                scale: l.scale,
+                width: l.width,
+                height: l.height,
+            }))
+            .collect()
+    }
+
+    // Coarsest level that still has at least one pixel per screen pixel at
+    // the given zoom (screen pixels per full resolution pixel)
+    pub fn level_for_zoom(&self, zoom: f32) -> usize {
+        if zoom <= 0.0 {
+            return self.levels.len();
+        }
+        let level = (1.0 / zoom).log2().floor().max(0.0) as usize;
+        level.min(self.levels.len())
+    }
+
+    // Renders the part of `level` under `viewport`, normalized and stretched.
+    // `source` holds the planes the pyramid was built from. With `upscale`, the zoom of the viewer and a filter, a level coarser
+    // than the screen is resampled to the screen size rather than left to
+    // the browser to enlarge.
+    pub fn render_tile(
+        &self,
+        source: &[ArrayView2<i32>],
+        level: usize,
+        viewport: Viewport,
+        stretch: &StretchAlgorithm,
+        upscale: Option<(f32, ResizeFilter)>,
+    ) -> Result<ImageTile, String> {
+        let (level_scale, width, height) = match level {
+            0 => (1, self.width, self.height),
+            _ => {
+                let l = self
+                    .levels
+                    .get(level - 1)
+                    .ok_or_else(|| format!("Pyramid has no level {}", level))?;
+                (l.scale, l.width, l.height)
+            }
+        };
+        let scale = level_scale as f32;
+
+        let x0 = ((viewport.x / scale).floor().max(0.0) as usize).min(width);
+        let y0 = ((viewport.y / scale).floor().max(0.0) as usize).min(height);
+        let x1 = (((viewport.x + viewport.width) / scale).ceil().max(0.0) as usize).min(width);
+        let y1 = (((viewport.y + viewport.height) / scale).ceil().max(0.0) as usize).min(height);
+        if x1 <= x0 || y1 <= y0 {
+            return Err("Viewport is outside of the image".to_string());
+        }
+
+        let crops: Vec<Array2<f32>> = match level {
+            0 => source.iter().map(|p| p.slice(s![y0..y1, x0..x1]).mapv(|v| v as f32)).collect(),
+            _ => self.levels[level - 1]
+                .channels
+                .iter()
+                .map(|c| c.slice(s![y0..y1, x0..x1]).to_owned())
+                .collect(),
+        };
+        let global_max = self.maxima.iter().copied().fold(f32::MIN, f32::max);
+        let mut channels: Vec<Array2<f32>> = crops
+            .iter()
+            .zip(&self.maxima)
+            .map(|(c, &max)| normalize(c, if stretch.is_linked() { global_max } else { max }))
+            .collect();
+        stretch.apply_inplace(&mut channels);
+
+        let (mut h, mut w) = (y1 - y0, x1 - x0);
+        if let Some((zoom, filter)) = upscale {
+            let screen_scale = zoom * scale;
+            if screen_scale > 1.0 {
+                (w, h) = ((w as f32 * screen_scale).round() as usize, (h as f32 * screen_scale).round() as usize);
+                channels = channels.iter().map(|c| resize_f32(c.view(), w, h, filter)).collect();
+            }
+        }
+        let rgba = pack_u8(&channels, true);
+
+        Ok(ImageTile {
+            level,
+            scale: level_scale,
+            viewport: Viewport {
+                x: (x0 * level_scale) as f32,
+                y: (y0 * level_scale) as f32,
+                width: ((x1 - x0) * level_scale) as f32,
+                height: ((y1 - y0) * level_scale) as f32,
+            },
+            width: w,
+            height: h,
+            pixels: STANDARD.encode(&rgba),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stretch::{ArcsinhStretch, ChannelStretch, StretchParams};
+
+    fn gradient(width: usize, height: usize) -> Array2<i32> {
+        Array2::from_shape_fn((height, width), |(y, x)| (x + y) as i32)
+    }
+
+    fn viewport(x: f32, y: f32, width: f32, height: f32) -> Viewport {
+        Viewport { x, y, width, height }
+    }
+
+    fn stretch() -> StretchAlgorithm {
+        StretchAlgorithm::Arcsinh(ArcsinhStretch::new(10.0, 0.0).unwrap())
+    }
+
+    #[test]
+    fn levels_do_not_copy_the_source() {
+        let source = gradient(1024, 512);
+        let pyramid = ImagePyramid::new(&[source.view()]);
+        assert_eq!(pyramid.maxima, vec![1534.0]);
+        let sizes: Vec<_> = pyramid.info().iter().map(|l| (l.level, l.scale, l.width, l.height)).collect();
+        assert_eq!(sizes, vec![(0, 1, 1024, 512), (1, 2, 512, 256), (2, 4, 256, 128)]);
+        assert_eq!(pyramid.memory_size(), (512 * 256 + 256 * 128) * 4);
+        assert_eq!(pyramid.levels[0].channels[0][[0, 0]], 1.0);
+        assert_eq!((pyramid.level_for_zoom(1.0), pyramid.level_for_zoom(0.3), pyramid.level_for_zoom(0.01)), (0, 1, 2));
+
+        // Small images are their own only level
+        let source = gradient(100, 50);
+        let pyramid = ImagePyramid::new(&[source.view()]);
+        assert_eq!((pyramid.levels.len(), pyramid.memory_size(), pyramid.info().len()), (0, 0, 1));
+        assert_eq!(pyramid.level_for_zoom(0.1), 0);
+    }
+
+    #[test]
+    fn full_resolution_tiles_read_the_source() {
+        let source = gradient(1024, 512);
+        let pyramid = ImagePyramid::new(&[source.view()]);
+        let linear = StretchAlgorithm::Mtf {
+            stf: ChannelStretch::Linked(StretchParams::default()),
+        };
+        let tile = pyramid.render_tile(&[source.view()], 0, viewport(1020.0, 0.0, 8.0, 2.0), &linear, None).unwrap();
+        assert_eq!((tile.level, tile.scale, tile.width, tile.height), (0, 1, 4, 2));
+        assert_eq!(tile.viewport, viewport(1020.0, 0.0, 4.0, 2.0));
+        let rgba = STANDARD.decode(&tile.pixels).unwrap();
+        for (x, y) in [(1020, 0), (1023, 0), (1021, 1)] {
+            let expected = ((x + y) as f32 / 1534.0 * 255.0).round() as u8;
+            let i = (y * 4 + x - 1020) * 4;
+            assert!(rgba[i].abs_diff(expected) <= 1, "{} at {}, {}", rgba[i], x, y);
+            assert_eq!(rgba[i + 3], 255);
+        }
+    }
+
+    #[test]
+    fn zoomed_in_tiles_are_resampled_to_the_screen() {
+        let source = gradient(64, 64);
+        let source = [source.view()];
+        let pyramid = ImagePyramid::new(&source);
+        let stretch = stretch();
+        let area = viewport(8.0, 8.0, 16.0, 16.0);
+
+        let tile = pyramid.render_tile(&source, 0, area, &stretch, None).unwrap();
+        assert_eq!((tile.width, tile.height), (16, 16));
+        let tile = pyramid.render_tile(&source, 0, area, &stretch, Some((4.0, ResizeFilter::Bicubic))).unwrap();
+        assert_eq!((tile.width, tile.height), (64, 64));
+        assert_eq!(tile.viewport, area);
+        assert_eq!(STANDARD.decode(&tile.pixels).unwrap().len(), 64 * 64 * 4);
+        let area = viewport(0.0, 0.0, 10.0, 5.0);
+        let tile = pyramid.render_tile(&source, 0, area, &stretch, Some((2.5, ResizeFilter::Lanczos3))).unwrap();
+        assert_eq!((tile.width, tile.height), (25, 13));
+        // Zoomed out the level already has a pixel per screen pixel
+        let area = viewport(8.0, 8.0, 16.0, 16.0);
+        let tile = pyramid.render_tile(&source, 0, area, &stretch, Some((0.75, ResizeFilter::Bicubic))).unwrap();
+        assert_eq!((tile.width, tile.height), (16, 16));
+    }
+
+    #[test]
+    fn coarse_levels_are_resampled_by_their_scale() {
+        let source = gradient(1024, 512);
+        let source = [source.view()];
+        let pyramid = ImagePyramid::new(&source);
+        let area = viewport(0.0, 0.0, 400.0, 200.0);
+        // Level 2 is a quarter of the size, shown at a third of the source
+        let upscale = Some((1.0 / 3.0, ResizeFilter::Bilinear));
+        let tile = pyramid.render_tile(&source, 2, area, &stretch(), upscale).unwrap();
+        assert_eq!((tile.scale, tile.width, tile.height), (4, 133, 67));
+        assert_eq!(tile.viewport, area);
+        assert!(pyramid.render_tile(&source, 3, area, &stretch(), None).is_err());
+    }
+}
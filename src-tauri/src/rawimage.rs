@@ -1,21 +1,44 @@
 use std::vec;
 
 use crate::{
+    align::median_hfr,
+    background::{extract_background, BackgroundModel, BackgroundParams},
+    colorcal::{calibrate_color, ColorCalibration, ColorCalibrationParams},
     debayer::{debayer_image, BayerPattern},
+    display::{pack_u8, DisplayBuffer, DisplayCache, DisplayKey},
     downsample::{downsample, downsample_rgb},
+    histogram::{Histogram, HistogramScale},
+    metadata::ImageMetadata,
+    noise::mrs_noise,
+    pyramid::ImagePyramid,
+    resize::Fit,
+    stretch::{normalize, ChannelStretch, Stretch, StretchAlgorithm},
 };
 use fitsrs::{Fits, HDU, Pixels, card::Value}; // Updated imports for fitsrs
-use ndarray::{s, Array, Array2, Ix2, Ix3};
+use ndarray::{s, Array, Array2, Array3, ArrayView2, Axis, Ix2, Ix3};
+use once_cell::sync::OnceCell;
 use rayon::join;
+use rayon::prelude::*;
 use std::io::BufReader;
 
-#[derive(serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct Stat{
     min: f32,
     max: f32,
     avg: f32,
     median: f32,
     avg_dev: f32,
+    // Median absolute deviation from the median
+    mad: f32,
+    // Mean and standard deviation after iterative sigma clipping
+    clipped_avg: f32,
+    clipped_stddev: f32,
+    // Background noise, multiresolution support estimate
+    noise: f32,
+    saturated: u64,
+    // Average deviation over background noise, the square root of the
+    // PixInsight SNRWeight
+    snr: f32,
 }
 
 #[derive(serde::Serialize)]
@@ -24,59 +47,246 @@ pub struct RawRGBImage {
     pub height: u32,
     pub pixels: Vec<u16>,
     pub stats: Vec<Stat>,
+    pub histogram: Histogram,
 }
 
+// `raw_image` and `debayered_image` always hold the full resolution source
+// data. Everything shown on screen is derived from them and cached, so the
+// source is never degraded by a preview.
 #[derive(Debug)]
 pub struct RawImage {
     pub bayer_pattern: BayerPattern,
     pub raw_image: Array<i32, Ix2>,
     pub debayered_image: Option<Array<i32, Ix3>>,
-    pub downsampled: bool,
-    pub downsampled_width: usize,
-    pub downsampled_height: usize,
+    pub metadata: ImageMetadata,
+    // Reduced levels for the viewer to zoom in and out of, full resolution
+    // tiles are read from the source planes
+    pub pyramid: Option<ImagePyramid>,
+    // Downsampled, optionally stretched, buffers sent to the frontend
+    display_cache: DisplayCache,
+    // Pixels at or above this value are counted as saturated. It depends on
+    // the bit depth of the source, 16 bit unless the reader knows better.
+    pub saturation_level: i32,
+    // Statistics of the source data, computed on first use
+    stats: OnceCell<Vec<Stat>>,
+    // Linear histogram sent with every display image, computed on first use
+    display_histogram: OnceCell<Histogram>,
+}
+
+const CLIP_SIGMA: f32 = 3.0;
+const CLIP_ITERATIONS: usize = 10;
+const NOISE_LAYERS: usize = 4;
+const HFR_STARS: usize = 200;
+const HFR_RADIUS: usize = 10;
+
+// Histogram of the integer pixel values of a channel. Pixel data is 16 bit so
+// there is one bucket per value and the statistics derived from it are exact;
+// wider ranges are grouped in buckets of `width` values.
+struct ValueHistogram {
+    min: i32,
+    width: i64,
+    counts: Vec<u64>,
+    total: u64,
 }
 
-fn median(data: &Array2<i32>) -> f32 {
-    let len = data.len();
-    if len == 0 {
-        return 0.0;
+impl ValueHistogram {
+    const MAX_BUCKETS: i64 = 1 << 16;
+
+    fn new(data: &ArrayView2<i32>, min: i32, max: i32) -> Self {
+        let range = max as i64 - min as i64 + 1;
+        let width = (range + Self::MAX_BUCKETS - 1) / Self::MAX_BUCKETS;
+        let buckets = ((range + width - 1) / width) as usize;
+
+        let counts = data
+            .axis_iter(Axis(0))
+            .into_par_iter()
+            .fold(
+                || vec![0u64; buckets],
+                |mut counts, row| {
+                    for &v in row.iter() {
+                        counts[((v as i64 - min as i64) / width) as usize] += 1;
+                    }
+                    counts
+                },
+            )
+            .reduce(
+                || vec![0u64; buckets],
+                |mut a, b| {
+                    a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+                    a
+                },
+            );
+
+        Self {
+            min,
+            width,
+            counts,
+            total: data.len() as u64,
+        }
+    }
+
+    fn value(&self, bucket: usize) -> f32 {
+        (self.min as i64 + bucket as i64 * self.width) as f32
+    }
+
+    // Value of the k-th smallest pixel
+    fn nth(&self, k: u64) -> f32 {
+        let mut acc = 0;
+        for (i, &c) in self.counts.iter().enumerate() {
+            acc += c;
+            if acc > k {
+                return self.value(i);
+            }
+        }
+        self.value(self.counts.len() - 1)
+    }
+
+    fn median(&self) -> f32 {
+        self.nth(self.total / 2)
+    }
+
+    // Median of |x - median|: walks the buckets outwards from the median,
+    // always taking the closest side, until half of the pixels are covered.
+    fn mad(&self, median: f32) -> f32 {
+        let center = ((median as i64 - self.min as i64) / self.width) as usize;
+        let target = self.total / 2;
+        let (mut lo, mut hi) = (center as isize, center + 1);
+        let mut acc = 0;
+        loop {
+            let dlo = if lo >= 0 { Some(median - self.value(lo as usize)) } else { None };
+            let dhi = if hi < self.counts.len() { Some(self.value(hi) - median) } else { None };
+            let (dev, count) = match (dlo, dhi) {
+                (Some(l), Some(h)) if l <= h => {
+                    lo -= 1;
+                    (l, self.counts[(lo + 1) as usize])
+                }
+                (_, Some(h)) => {
+                    hi += 1;
+                    (h, self.counts[hi - 1])
+                }
+                (Some(l), None) => {
+                    lo -= 1;
+                    (l, self.counts[(lo + 1) as usize])
+                }
+                (None, None) => return 0.0,
+            };
+            acc += count;
+            if acc > target {
+                return dev;
+            }
+        }
+    }
+
+    // Mean and standard deviation rejecting pixels further than CLIP_SIGMA
+    // sigmas from the mean, starting from the median and normalized MAD.
+    fn clipped_mean_stddev(&self, median: f32, mad: f32) -> (f32, f32) {
+        let (mut mean, mut sigma) = (median as f64, 1.4826 * mad as f64);
+        for _ in 0..CLIP_ITERATIONS {
+            let lo = mean - CLIP_SIGMA as f64 * sigma;
+            let hi = mean + CLIP_SIGMA as f64 * sigma;
+            let (mut n, mut sum, mut sum2) = (0f64, 0f64, 0f64);
+            for (i, &c) in self.counts.iter().enumerate() {
+                let v = self.value(i) as f64;
+                if c == 0 || v < lo || v > hi {
+                    continue;
+                }
+                n += c as f64;
+                sum += c as f64 * v;
+                sum2 += c as f64 * v * v;
+            }
+            if n == 0.0 {
+                break;
+            }
+            let new_mean = sum / n;
+            let new_sigma = (sum2 / n - new_mean * new_mean).max(0.0).sqrt();
+            let converged = (new_sigma - sigma).abs() <= 1e-3 * new_sigma.max(f64::EPSILON);
+            mean = new_mean;
+            sigma = new_sigma;
+            if converged {
+                break;
+            }
+        }
+        (mean as f32, sigma as f32)
     }
-    let mid = len / 2;
-    let mut v: Vec<i32> = data.iter().copied().collect();
-    let (_, median, _) = v.select_nth_unstable(mid);
-    *median as f32
 }
 
-fn calc_channel_stats(data: &Array2<i32>) -> Stat {
-    let median_val = median(data);
-    let len = data.len();
-    let n = len as f32;
-    let data_flat = data.as_slice().unwrap();
-    let mut sum = 0.0f32;
-    let mut min_val = f32::MAX;
-    let mut max_val = f32::MIN;
-    let mut sum_val = 0.0f32;
-
-    for &v in data_flat {
-        let v_f = v as f32;
-        sum += (v_f - median_val).abs();
-        min_val = min_val.min(v_f);
-        max_val = max_val.max(v_f);
-        sum_val += v_f;
+fn calc_channel_stats(data: ArrayView2<i32>, saturation_level: i32) -> Stat {
+    let n = data.len();
+    if n == 0 {
+        return Stat {
+            min: 0.0, max: 0.0, avg: 0.0, median: 0.0, avg_dev: 0.0, mad: 0.0,
+            clipped_avg: 0.0, clipped_stddev: 0.0, noise: 0.0, saturated: 0, snr: 0.0,
+        };
     }
 
-    let avg_dev = sum / n;
-    let avg = sum_val / n;
+    let (min_val, max_val, sum_val, saturated) = data
+        .axis_iter(Axis(0))
+        .into_par_iter()
+        .map(|row| {
+            row.iter().fold((i32::MAX, i32::MIN, 0i64, 0u64), |(mn, mx, sum, sat), &v| {
+                (mn.min(v), mx.max(v), sum + v as i64, sat + (v >= saturation_level) as u64)
+            })
+        })
+        .reduce(
+            || (i32::MAX, i32::MIN, 0i64, 0u64),
+            |a, b| (a.0.min(b.0), a.1.max(b.1), a.2 + b.2, a.3 + b.3),
+        );
+
+    let histogram = ValueHistogram::new(&data, min_val, max_val);
+    let median_val = histogram.median();
+    let mad = histogram.mad(median_val);
+    let (clipped_avg, clipped_stddev) = histogram.clipped_mean_stddev(median_val, mad);
+
+    let dev_sum: f64 = histogram
+        .counts
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| c as f64 * (histogram.value(i) - median_val).abs() as f64)
+        .sum();
+    let avg_dev = (dev_sum / n as f64) as f32;
+    let avg = (sum_val as f64 / n as f64) as f32;
+
+    let noise = mrs_noise(data, NOISE_LAYERS);
+    let snr = if noise > 0.0 { avg_dev / noise } else { 0.0 };
+
     Stat {
         median: median_val,
         avg_dev,
-        min: min_val.round(),
-        max: max_val.round(),
+        min: min_val as f32,
+        max: max_val as f32,
         avg,
+        mad,
+        clipped_avg,
+        clipped_stddev,
+        noise,
+        saturated,
+        snr,
     }
 }
 
 impl RawImage {
+    pub fn new(raw_image: Array2<i32>, bayer_pattern: BayerPattern) -> Self {
+        Self {
+            bayer_pattern,
+            raw_image,
+            debayered_image: None,
+            metadata: ImageMetadata::default(),
+            pyramid: None,
+            display_cache: DisplayCache::default(),
+            saturation_level: u16::MAX as i32,
+            stats: OnceCell::new(),
+            display_histogram: OnceCell::new(),
+        }
+    }
+
+    // Image that is already in color, such as a processed RGB master. There is
+    // no CFA data, so `raw_image` stays empty.
+    pub fn from_rgb(rgb: Array3<i32>) -> Self {
+        let mut image = Self::new(Array2::zeros((0, 0)), BayerPattern::NONE);
+        image.debayered_image = Some(rgb);
+        image
+    }
+
     pub fn from_reader(reader: BufReader<std::fs::File>) -> Result<Self, String> {
         let mut hdu_list = Fits::from_reader(reader);
 
@@ -92,6 +302,8 @@ impl RawImage {
                     .map_err(|_| "Failed to convert to 2D array".to_string())?;
 
                 let raw_image_i32;
+                // Top of the BITPIX 16 range once BZERO and BSCALE are applied
+                let saturation_level;
 
                 if let Some(Value::Integer{ value, ..}) = hdu.get_header().get("BZERO") {
                     let bzero = value;
@@ -102,8 +314,10 @@ impl RawImage {
                     }
 
                     raw_image_i32 = raw_image_i16.mapv(|x| ((x as i64 + bzero) * bscale) as i32);
+                    saturation_level = ((i16::MAX as i64 + bzero) * bscale) as i32;
                 } else {
                     raw_image_i32 = raw_image_i16.mapv(|x| x as i32);
+                    saturation_level = i16::MAX as i32;
                 }
 
                 let bayer_pattern = match hdu.get_header().get("BAYERPAT") {
@@ -117,14 +331,11 @@ impl RawImage {
                     _ => BayerPattern::NONE,
                 };
 
-                return Ok(Self {
-                    raw_image: raw_image_i32,
-                    bayer_pattern,
-                    debayered_image: None,
-                    downsampled: false,
-                    downsampled_width: 0,
-                    downsampled_height: 0,
-                });
+                let header = hdu.get_header();
+                let mut raw_image = Self::new(raw_image_i32, bayer_pattern);
+                raw_image.metadata = ImageMetadata::from_fits_header(|key| header.get(key));
+                raw_image.saturation_level = saturation_level;
+                return Ok(raw_image);
             } else {
                 return Err("Expected I16 pixel data".to_string());
             }
@@ -148,115 +359,370 @@ impl RawImage {
         log::info!("Debayering took: {:?}", elapsed_time);
 
         self.debayered_image = Some(debayered);
+        self.source_changed();
         Ok(())
     }
 
-    pub fn downsample(&mut self, target_width: usize, target_height: usize) -> Result<(), String> {
-        if self.downsampled
-            && self.downsampled_width == target_width
-            && self.downsampled_height == target_height
-        {
-            return Ok(());
-        }
-
+    // Source planes fitted in `max_width` x `max_height`: one for mono
+    // images, R, G and B for debayered ones
+    pub fn display_planes(&self, max_width: usize, max_height: usize, fit: Fit) -> Vec<Array2<i32>> {
         let start_time = std::time::Instant::now();
-        if let Some(debayered_image) = &self.debayered_image {
-            let downsampled = downsample_rgb(debayered_image, target_width, target_height);
-            self.debayered_image = Some(downsampled);
-            self.downsampled = true;
-            self.downsampled_width = target_width;
-            self.downsampled_height = target_height;
-        } else {
-            let downsampled = downsample(&self.raw_image, target_width, target_height);
-            self.raw_image = downsampled;
-            self.downsampled = true;
-            self.downsampled_width = target_width;
-            self.downsampled_height = target_height;
-        }
+        let planes = match &self.debayered_image {
+            Some(debayered_image) => {
+                let rgb = downsample_rgb(debayered_image, max_width, max_height, fit);
+                (0..3).map(|c| rgb.slice(s![.., .., c]).to_owned()).collect()
+            }
+            _ => vec![downsample(&self.raw_image, max_width, max_height, fit)],
+        };
         let elapsed_time = start_time.elapsed();
         log::info!("Downsampling took: {:?}", elapsed_time);
+        planes
+    }
 
-        Ok(())
+    // Image to show on a `max_width` x `max_height` display, linear when
+    // `stretch` is None. Buffers are cached by size, stretch and fit;
+    // statistics and histogram always describe the full resolution data.
+    pub fn display_image(
+        &mut self,
+        max_width: usize,
+        max_height: usize,
+        stretch: Option<&StretchAlgorithm>,
+        fit: Fit,
+    ) -> RawRGBImage {
+        let key = DisplayKey {
+            width: max_width,
+            height: max_height,
+            stretch: stretch.copied(),
+            fit,
+        };
+        let maxima = self.source_maxima();
+        let mut cache = std::mem::take(&mut self.display_cache);
+        let buffer = cache.get_or_insert_with(key, || {
+            let planes = self.display_planes(max_width, max_height, fit);
+            match stretch {
+                Some(stretch) => DisplayBuffer::stretched(&planes, &maxima, stretch),
+                None => DisplayBuffer::from_planes(&planes),
+            }
+        });
+
+        let image = RawRGBImage {
+            width: buffer.width.try_into().unwrap(),
+            height: buffer.height.try_into().unwrap(),
+            pixels: buffer.pixels.clone(),
+            stats: self.stats().to_vec(),
+            histogram: self
+                .display_histogram
+                .get_or_init(|| self.histogram(256, HistogramScale::Linear, None))
+                .clone(),
+        };
+        self.display_cache = cache;
+        image
     }
 
-    pub fn get_raw_image(&self) -> RawRGBImage {
-        if self.bayer_pattern == BayerPattern::NONE {
-            let (height, width) = self.raw_image.dim();
-            let mut rgb: Vec<u16> = Vec::with_capacity(width * height * 3);
-            let stats = self.calculate_stats();
+    // Linear image at the size the viewer asked for last, full resolution if
+    // it never asked
+    pub fn preview(&mut self) -> RawRGBImage {
+        let (width, height) = self.display_size();
+        self.display_image(width, height, None, Fit::default())
+    }
 
-            for &v in self.raw_image.iter() {
-                let v16 = v.min(u16::MAX as i32) as u16;
-                rgb.extend_from_slice(&[v16, v16, v16]);
+    pub fn display_size(&self) -> (usize, usize) {
+        self.display_cache.last_size().unwrap_or_else(|| self.dimensions())
+    }
+
+    // Width and height of the image planes
+    pub fn dimensions(&self) -> (usize, usize) {
+        let (height, width) = match &self.debayered_image {
+            Some(debayered_image) => {
+                let (h, w, _) = debayered_image.dim();
+                (h, w)
             }
-    
-            return RawRGBImage {
-                width: width.try_into().unwrap(),
-                height: height.try_into().unwrap(),
-                pixels: rgb,
-                stats: stats
-            };
+            _ => self.raw_image.dim(),
+        };
+        (width, height)
+    }
+
+    // Channels normalized and stretched to [0.0, 1.0], fitted in `max_size`
+    // or at full resolution
+    pub fn stretched_channels(&self, max_size: Option<(usize, usize)>, stretch: &StretchAlgorithm) -> Vec<Array2<f32>> {
+        let maxima = self.source_maxima();
+        let global_max = maxima.iter().copied().fold(f32::MIN, f32::max);
+        let planes = match max_size {
+            Some((width, height)) => self
+                .display_planes(width, height, Fit::default())
+                .iter()
+                .map(|p| p.mapv(|v| v as f32))
+                .collect(),
+            None => self.channels_f32(),
+        };
+        let mut channels: Vec<Array2<f32>> = planes
+            .iter()
+            .zip(maxima)
+            .map(|(p, max)| normalize(p, if stretch.is_linked() { global_max } else { max }))
+            .collect();
+        stretch.apply_inplace(&mut channels);
+        channels
+    }
+
+    // Auto stretched JPEG fitting in a `size` x `size` box, for lists of
+    // images where only the general look matters
+    pub fn thumbnail_jpeg(&self, size: usize) -> Result<Vec<u8>, String> {
+        let maxima = self.source_maxima();
+        let global_max = maxima.iter().copied().fold(f32::MIN, f32::max);
+        let mut channels: Vec<Array2<f32>> = self
+            .display_planes(size, size, Fit::default())
+            .iter()
+            .map(|p| normalize(&p.mapv(|v| v as f32), global_max))
+            .collect();
+        Stretch::default().linked_params(&channels).apply_inplace(&mut channels);
+
+        let (height, width) = channels[0].dim();
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 85)
+            .encode(
+                &pack_u8(&channels, false),
+                width as u32,
+                height as u32,
+                image::ExtendedColorType::Rgb8,
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(jpeg)
+    }
+
+    // Median half flux radius of the stars in sensor pixels, measured on the
+    // luminance. Debayered planes are half the size of the sensor.
+    pub fn hfr(&self) -> Option<f32> {
+        let channels = self.channels_f32();
+        let mut luminance = channels[0].clone();
+        for c in &channels[1..] {
+            luminance += c;
+        }
+        let (width, _) = self.dimensions();
+        let (_, sensor_width) = self.raw_image.dim();
+        let scale = if sensor_width > width { sensor_width as f32 / width as f32 } else { 1.0 };
+        median_hfr(&luminance, HFR_STARS, HFR_RADIUS).map(|hfr| hfr * scale)
+    }
+
+    // Approximate bytes held by the image: source data, pyramid and display
+    // buffers
+    pub fn memory_size(&self) -> usize {
+        let pixel = std::mem::size_of::<i32>();
+        self.raw_image.len() * pixel
+            + self.debayered_image.as_ref().map_or(0, |d| d.len() * pixel)
+            + self.pyramid.as_ref().map_or(0, |p| p.memory_size())
+            + self.display_cache.memory_size()
+    }
+
+    // Drops everything derived from the source data after it was modified
+    fn source_changed(&mut self) {
+        self.display_cache.clear();
+        self.stats = OnceCell::new();
+        self.display_histogram = OnceCell::new();
+        if self.pyramid.is_some() {
+            self.pyramid = None;
+            self.build_pyramid();
+        }
+    }
+
+    fn stats(&self) -> &[Stat] {
+        self.stats.get_or_init(|| self.calculate_stats())
+    }
+
+     // Image planes converted to f32: one for mono images, R, G and B for
+     // debayered ones.
+     pub fn channels_f32(&self) -> Vec<Array2<f32>> {
+        match &self.debayered_image {
+            Some(debayered_image) => (0..3)
+                .map(|c| debayered_image.slice(s![.., .., c]).mapv(|v| v as f32))
+                .collect(),
+            _ => vec![self.raw_image.mapv(|v| v as f32)],
+        }
+     }
+
+     // Channels normalized to [0.0, 1.0]. Linked stretches need a common scale,
+     // so all channels are divided by the global maximum; otherwise each channel
+     // is divided by its own maximum, matching what ImageViewer.vue does.
+     pub fn normalized_channels(&self, linked: bool) -> Vec<Array2<f32>> {
+        let maxs = self.source_maxima();
+        let global_max = maxs.iter().copied().fold(f32::MIN, f32::max);
+
+        self.channels_f32()
+            .iter()
+            .zip(maxs)
+            .map(|(c, max)| normalize(c, if linked { global_max } else { max }))
+            .collect()
+     }
+
+     // Maximum of each full resolution channel, used to normalize the data so
+     // previews, tiles and histograms are stretched alike.
+     pub fn source_maxima(&self) -> Vec<f32> {
+        if let Some(pyramid) = &self.pyramid {
+            return pyramid.maxima.clone();
+        }
+        match &self.debayered_image {
+            Some(debayered_image) => (0..3)
+                .map(|c| debayered_image.slice(s![.., .., c]).fold(i32::MIN, |a, &b| a.max(b)) as f32)
+                .collect(),
+            _ => vec![self.raw_image.fold(i32::MIN, |a, &b| a.max(b)) as f32],
+        }
+     }
+
+     // Full resolution source planes, without a copy: one for mono images,
+     // R, G and B for debayered ones
+     pub fn planes(&self) -> Vec<ArrayView2<'_, i32>> {
+        match &self.debayered_image {
+            Some(debayered_image) => (0..3).map(|c| debayered_image.slice(s![.., .., c])).collect(),
+            _ => vec![self.raw_image.view()],
+        }
+     }
+
+     // Copy of the source data, to be modified while the original stays
+     // readable. Statistics are kept, display buffers and pyramid are not.
+     pub fn source_copy(&self) -> RawImage {
+        RawImage {
+            bayer_pattern: self.bayer_pattern,
+            raw_image: self.raw_image.clone(),
+            debayered_image: self.debayered_image.clone(),
+            metadata: self.metadata.clone(),
+            pyramid: None,
+            display_cache: self.display_cache.without_buffers(),
+            saturation_level: self.saturation_level,
+            stats: self.stats.clone(),
+            display_histogram: self.display_histogram.clone(),
+        }
+     }
+
+     // Builds the multi-resolution pyramid from the full resolution data
+     pub fn build_pyramid(&mut self) {
+        if self.pyramid.is_none() {
+            self.pyramid = Some(ImagePyramid::new(&self.planes()));
+        }
+     }
+
+     pub fn auto_stretch(&self, stretch: &Stretch, linked: bool) -> ChannelStretch {
+        let channels = self.normalized_channels(linked);
+        if linked {
+            stretch.linked_params(&channels)
         } else {
-            if let Some(debayered_image) = self.debayered_image.as_ref() {
-                let (height, width, _) = debayered_image.dim();
-                let mut rgb: Vec<u16>  = Vec::with_capacity(width * height * 3);
-                let stats = self.calculate_stats();
-
-                for pixel in debayered_image.outer_iter() {
-                    for rgb_triplet in pixel.outer_iter() {
-                        let r = rgb_triplet[0].min(u16::MAX as i32) as u16;
-                        let g = rgb_triplet[1].min(u16::MAX as i32) as u16;
-                        let b = rgb_triplet[2].min(u16::MAX as i32) as u16;
-                        rgb.extend_from_slice(&[r, g, b]);
-                    }
+            stretch.unlinked_params(&channels)
+        }
+     }
+
+     // Builds an image with the same layout as this one from f32 planes, used
+     // to display derived data such as background models.
+     pub fn with_channels(&self, channels: &[Array2<f32>]) -> RawImage {
+        let to_i32 = |v: f32| v.round().max(0.0) as i32;
+        let (raw_image, debayered_image) = if channels.len() == 3 {
+            let (h, w) = channels[0].dim();
+            let mut rgb = Array3::<i32>::zeros((h, w, 3));
+            for (c, channel) in channels.iter().enumerate() {
+                rgb.slice_mut(s![.., .., c]).assign(&channel.mapv(to_i32));
+            }
+            (self.raw_image.clone(), Some(rgb))
+        } else {
+            (channels[0].mapv(to_i32), None)
+        };
+
+        RawImage {
+            bayer_pattern: if debayered_image.is_some() { self.bayer_pattern } else { BayerPattern::NONE },
+            raw_image,
+            debayered_image,
+            metadata: self.metadata.clone(),
+            pyramid: None,
+            display_cache: DisplayCache::default(),
+            saturation_level: self.saturation_level,
+            stats: OnceCell::new(),
+            display_histogram: OnceCell::new(),
+        }
+     }
+
+     // Fits the background of the image and removes it in place. The model is
+     // returned so it can be displayed.
+     pub fn extract_background(&mut self, params: &BackgroundParams) -> Result<BackgroundModel, String> {
+        // Gradients must be fitted on each color, not on the CFA mosaic
+        self.debayer()?;
+
+        let model = extract_background(&self.channels_f32(), params)?;
+        match self.debayered_image.as_mut() {
+            Some(debayered_image) => {
+                for c in 0..3 {
+                    model.correct(c, &mut debayered_image.slice_mut(s![.., .., c]), params.correction);
                 }
+            }
+            _ => model.correct(0, &mut self.raw_image.view_mut(), params.correction),
+        }
+        self.source_changed();
+        Ok(model)
+     }
 
-                return RawRGBImage {
-                    width: width.try_into().unwrap(),
-                    height: height.try_into().unwrap(),
-                    pixels: rgb,
-                    stats: stats
-                };
-            } else {
-                let (height, width) = self.raw_image.dim();
-                let mut rgb: Vec<u16>  = Vec::with_capacity(width * height * 3);
-                let stats = self.calculate_stats();
-    
-                for &v in self.raw_image.iter() {
-                    let v16 = v.min(u16::MAX as i32) as u16;
-                    rgb.extend_from_slice(&[v16, v16, v16]);
+     // Writes back f32 planes, as returned by channels_f32, into the image
+     fn assign_channels(&mut self, channels: &[Array2<f32>]) {
+        let to_i32 = |v: &f32| v.round().max(0.0) as i32;
+        match self.debayered_image.as_mut() {
+            Some(debayered_image) => {
+                for (c, channel) in channels.iter().enumerate().take(3) {
+                    debayered_image.slice_mut(s![.., .., c]).assign(&channel.map(to_i32));
                 }
-        
-                return RawRGBImage {
-                    width: width.try_into().unwrap(),
-                    height: height.try_into().unwrap(),
-                    pixels: rgb,
-                    stats: stats
-                };
             }
+            _ => self.raw_image.assign(&channels[0].map(to_i32)),
+        }
+        self.source_changed();
+     }
+
+     // Neutralizes the background and white balances a color image in place
+     pub fn calibrate_color(&mut self, params: &ColorCalibrationParams) -> Result<ColorCalibration, String> {
+        self.debayer()?;
+        if self.debayered_image.is_none() {
+            return Err("Color calibration needs a color image".to_string());
         }
+
+        let stats = self.stats();
+        let background = [stats[0].median, stats[1].median, stats[2].median];
+        let noise = [stats[0].noise, stats[1].noise, stats[2].noise];
+
+        let mut channels = self.channels_f32();
+        let calibration = calibrate_color(&mut channels, background, noise, params)?;
+        self.assign_channels(&channels);
+        Ok(calibration)
+     }
+
+     // Per channel histogram of the image, before stretching when `stretch` is
+     // None or after applying it otherwise.
+     pub fn histogram(
+        &self,
+        bins: usize,
+        scale: HistogramScale,
+        stretch: Option<&StretchAlgorithm>,
+     ) -> Histogram {
+        let start_time = std::time::Instant::now();
+        let linked = stretch.is_some_and(|s| s.is_linked());
+        let mut channels = self.normalized_channels(linked);
+        if let Some(stretch) = stretch {
+            stretch.apply_inplace(&mut channels);
+        }
+        let histogram = Histogram::from_channels(&channels, bins, scale, stretch.is_some());
+        log::info!("Histogram took: {:?}", start_time.elapsed());
+        histogram
      }
 
      fn calculate_stats(&self) -> Vec<Stat> {
         let mut results = vec![];
         let start_time = std::time::Instant::now();
-        if self.bayer_pattern == BayerPattern::NONE {
-            results.push(calc_channel_stats(&self.raw_image));
+        if let Some(debayered_image) = &self.debayered_image {
+            let (r, (g, b)) = join(
+                || calc_channel_stats(debayered_image.slice(s![.., .., 0]), self.saturation_level),
+                || {
+                    join(
+                        || calc_channel_stats(debayered_image.slice(s![.., .., 1]), self.saturation_level),
+                        || calc_channel_stats(debayered_image.slice(s![.., .., 2]), self.saturation_level),
+                    )
+                },
+            );
+            results.push(r);
+            results.push(g);
+            results.push(b);
         } else {
-            if let Some(debayered_image) = &self.debayered_image {
-                let (r, (g, b)) = join(
-                    || calc_channel_stats(&debayered_image.slice(s![.., .., 0]).to_owned()),
-                    || {
-                        join(
-                            || calc_channel_stats(&debayered_image.slice(s![.., .., 1]).to_owned()),
-                            || calc_channel_stats(&debayered_image.slice(s![.., .., 2]).to_owned()),
-                        )
-                    },
-                );
-                results.push(r);
-                results.push(g);
-                results.push(b);
-            }
+            results.push(calc_channel_stats(self.raw_image.view(), self.saturation_level));
         }
         let elapsed_time = start_time.elapsed();
         log::info!("Stats took: {:?}", elapsed_time);
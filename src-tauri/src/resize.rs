@@ -0,0 +1,224 @@
+use ndarray::{s, Array2, Array3, ArrayView2, Axis};
+use rayon::prelude::*;
+use std::f32::consts::PI;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    // Area average, the fastest and what the previews always used
+    #[default]
+    Box,
+    Bilinear,
+    // Catmull-Rom cubic
+    Bicubic,
+    // Windowed sinc with 3 lobes, the sharpest
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    // Radius of the kernel in source pixels when upscaling
+    fn support(&self) -> f32 {
+        match self {
+            ResizeFilter::Box => 0.5,
+            ResizeFilter::Bilinear => 1.0,
+            ResizeFilter::Bicubic => 2.0,
+            ResizeFilter::Lanczos3 => 3.0,
+        }
+    }
+
+    fn kernel(&self, x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            ResizeFilter::Box => {
+                if x <= 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            ResizeFilter::Bilinear => (1.0 - x).max(0.0),
+            ResizeFilter::Bicubic => {
+                // Keys cubic with a = -0.5
+                const A: f32 = -0.5;
+                if x < 1.0 {
+                    ((A + 2.0) * x - (A + 3.0)) * x * x + 1.0
+                } else if x < 2.0 {
+                    (((x - 5.0) * x + 8.0) * x - 4.0) * A
+                } else {
+                    0.0
+                }
+            }
+            ResizeFilter::Lanczos3 => {
+                if x < 1e-6 {
+                    1.0
+                } else if x < 3.0 {
+                    let px = PI * x;
+                    3.0 * px.sin() * (px / 3.0).sin() / (px * px)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+// How an image is fitted in the box of the display. Box and no upscaling by
+// default, a sharper filter and upscaling keep zoomed-in previews smooth.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Fit {
+    pub filter: ResizeFilter,
+    // Lets images smaller than the box grow to fill it
+    pub upscale: bool,
+}
+
+// Source pixels, and their weights, contributing to one output pixel
+struct Contribution {
+    start: usize,
+    weights: Vec<f32>,
+}
+
+// Precomputes the contributions for every output position along one axis.
+// When downscaling the kernel is stretched so every source pixel is taken
+// into account.
+fn contributions(src: usize, dst: usize, filter: ResizeFilter) -> Vec<Contribution> {
+    let scale = dst as f32 / src as f32;
+    let filter_scale = scale.min(1.0);
+    let support = filter.support() / filter_scale;
+
+    (0..dst)
+        .map(|i| {
+            let center = (i as f32 + 0.5) / scale;
+            let start = ((center - support).floor().max(0.0)) as usize;
+            let end = ((center + support).ceil() as usize).min(src);
+            let mut weights: Vec<f32> = (start..end)
+                .map(|j| filter.kernel((j as f32 + 0.5 - center) * filter_scale))
+                .collect();
+            let sum: f32 = weights.iter().sum();
+            if sum.abs() > f32::EPSILON {
+                weights.iter_mut().for_each(|w| *w /= sum);
+            } else {
+                // Kernel fell between samples, take the nearest one
+                let nearest = (center as usize).min(src - 1);
+                return Contribution { start: nearest, weights: vec![1.0] };
+            }
+            Contribution { start, weights }
+        })
+        .collect()
+}
+
+// Largest size with the aspect ratio of the image that fits in the given box.
+// Unless `allow_upscale` is set the image is never made bigger than it is.
+pub fn fit_size(
+    width: usize,
+    height: usize,
+    max_width: usize,
+    max_height: usize,
+    allow_upscale: bool,
+) -> (usize, usize) {
+    if width == 0 || height == 0 {
+        return (0, 0);
+    }
+    let mut scale = (max_width as f64 / width as f64).min(max_height as f64 / height as f64);
+    if !allow_upscale {
+        scale = scale.min(1.0);
+    }
+    let w = ((width as f64 * scale).round() as usize).clamp(1, max_width.max(1));
+    let h = ((height as f64 * scale).round() as usize).clamp(1, max_height.max(1));
+    (w, h)
+}
+
+// Resizes one plane, reading samples with `load` and writing them with
+// `store`, so integer and float planes share the filter code
+fn resize_plane<T, U>(
+    data: ArrayView2<T>,
+    width: usize,
+    height: usize,
+    filter: ResizeFilter,
+    load: impl Fn(T) -> f32 + Sync,
+    store: impl Fn(f32) -> U + Sync,
+) -> Array2<U>
+where
+    T: Copy + Sync,
+    U: Copy + Default + Send + Sync,
+{
+    let (h, w) = data.dim();
+    let cols = contributions(w, width, filter);
+    let rows = contributions(h, height, filter);
+
+    // Horizontal pass
+    let mut tmp = Array2::<f32>::zeros((h, width));
+    tmp.axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .zip(data.axis_iter(Axis(0)).into_par_iter())
+        .for_each(|(mut out, src)| {
+            for (o, c) in out.iter_mut().zip(&cols) {
+                *o = c
+                    .weights
+                    .iter()
+                    .enumerate()
+                    .map(|(k, w)| w * load(src[c.start + k]))
+                    .sum();
+            }
+        });
+
+    // Vertical pass
+    let mut out = Array2::<U>::default((height, width));
+    out.axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .zip(rows.par_iter())
+        .for_each(|(mut row, c)| {
+            let mut acc = vec![0f32; width];
+            for (k, w) in c.weights.iter().enumerate() {
+                for (a, v) in acc.iter_mut().zip(tmp.row(c.start + k)) {
+                    *a += w * v;
+                }
+            }
+            for (o, a) in row.iter_mut().zip(acc) {
+                *o = store(a);
+            }
+        });
+    out
+}
+
+// Sharp kernels overshoot around stars, samples are kept positive
+fn load_i32(v: i32) -> f32 {
+    v as f32
+}
+
+fn store_i32(v: f32) -> i32 {
+    v.round().max(0.0) as i32
+}
+
+// Resizes a mono image to exactly `width` x `height`
+pub fn resize(data: &Array2<i32>, width: usize, height: usize, filter: ResizeFilter) -> Array2<i32> {
+    if data.is_empty() || width == 0 || height == 0 {
+        return Array2::zeros((height, width));
+    }
+    resize_plane(data.view(), width, height, filter, load_i32, store_i32)
+}
+
+// Resizes a float plane, such as normalized display data, to exactly
+// `width` x `height`
+pub fn resize_f32(data: ArrayView2<f32>, width: usize, height: usize, filter: ResizeFilter) -> Array2<f32> {
+    if data.is_empty() || width == 0 || height == 0 {
+        return Array2::zeros((height, width));
+    }
+    resize_plane(data, width, height, filter, |v| v, |v| v.max(0.0))
+}
+
+// Resizes an RGB image to exactly `width` x `height`
+pub fn resize_rgb(data: &Array3<i32>, width: usize, height: usize, filter: ResizeFilter) -> Array3<i32> {
+    let mut out = Array3::<i32>::zeros((height, width, 3));
+    if data.is_empty() || width == 0 || height == 0 {
+        return out;
+    }
+    let planes: Vec<Array2<i32>> = (0..3)
+        .into_par_iter()
+        .map(|c| resize_plane(data.slice(s![.., .., c]), width, height, filter, load_i32, store_i32))
+        .collect();
+    for (c, plane) in planes.iter().enumerate() {
+        out.slice_mut(s![.., .., c]).assign(plane);
+    }
+    out
+}
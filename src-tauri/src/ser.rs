@@ -0,0 +1,457 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use ndarray::{Array2, Array3};
+
+use crate::debayer::BayerPattern;
+use crate::metadata::ImageMetadata;
+use crate::rawimage::RawImage;
+
+// SER files: a 178 bytes header, the frames one after the other and an
+// optional table of UTC timestamps, one per frame.
+// See http://www.grischa-hahn.homepage.t-online.de/astro/ser/
+const FILE_ID: &[u8; 14] = b"LUCAM-RECORDER";
+const HEADER_SIZE: u64 = 178;
+const STRING_SIZE: usize = 40;
+// The specification says 1 means little endian, but capture software has
+// always written 0 for little endian data and readers follow it
+const LITTLE_ENDIAN: i32 = 0;
+const DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SerColor {
+    Mono,
+    Rggb,
+    Grbg,
+    Gbrg,
+    Bggr,
+    Rgb,
+    Bgr,
+}
+
+impl SerColor {
+    fn from_id(id: i32) -> Result<Self, String> {
+        match id {
+            0 => Ok(Self::Mono),
+            8 => Ok(Self::Rggb),
+            9 => Ok(Self::Grbg),
+            10 => Ok(Self::Gbrg),
+            11 => Ok(Self::Bggr),
+            100 => Ok(Self::Rgb),
+            101 => Ok(Self::Bgr),
+            _ => Err(format!("Unsupported SER color {}", id)),
+        }
+    }
+
+    fn id(&self) -> i32 {
+        match self {
+            Self::Mono => 0,
+            Self::Rggb => 8,
+            Self::Grbg => 9,
+            Self::Gbrg => 10,
+            Self::Bggr => 11,
+            Self::Rgb => 100,
+            Self::Bgr => 101,
+        }
+    }
+
+    fn planes(&self) -> usize {
+        match self {
+            Self::Rgb | Self::Bgr => 3,
+            _ => 1,
+        }
+    }
+
+    fn bayer_pattern(&self) -> BayerPattern {
+        match self {
+            Self::Rggb => BayerPattern::RGGB,
+            Self::Grbg => BayerPattern::GRBG,
+            Self::Gbrg => BayerPattern::GBRG,
+            Self::Bggr => BayerPattern::BGGR,
+            _ => BayerPattern::NONE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SerHeader {
+    pub color: SerColor,
+    // Byte order of 16 bit samples
+    pub little_endian: bool,
+    pub width: usize,
+    pub height: usize,
+    // Significant bits per sample, samples deeper than 8 bits take two bytes
+    pub pixel_depth: u32,
+    pub frame_count: usize,
+    pub observer: String,
+    pub instrument: String,
+    pub telescope: String,
+    // Start of the capture, ISO 8601, local time and UTC
+    pub date_time: Option<String>,
+    pub date_time_utc: Option<String>,
+}
+
+impl SerHeader {
+    fn bytes_per_sample(&self) -> usize {
+        if self.pixel_depth > 8 { 2 } else { 1 }
+    }
+
+    fn frame_size(&self) -> usize {
+        self.width * self.height * self.color.planes() * self.bytes_per_sample()
+    }
+}
+
+// Dates are stored as .NET ticks, 100 ns intervals since 0001-01-01
+fn epoch() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(1, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap()
+}
+
+fn ticks_to_date(ticks: i64) -> Option<NaiveDateTime> {
+    (ticks > 0).then(|| epoch() + Duration::microseconds(ticks / 10))
+}
+
+fn date_to_ticks(date: &NaiveDateTime) -> i64 {
+    (*date - epoch()).num_microseconds().unwrap_or(0) * 10
+}
+
+fn format_ticks(ticks: i64) -> Option<String> {
+    ticks_to_date(ticks).map(|d| d.format(DATE_FORMAT).to_string())
+}
+
+fn parse_date(date: &str) -> Option<NaiveDateTime> {
+    ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(date, f).ok())
+}
+
+fn i32_at(bytes: &[u8], offset: usize) -> i32 {
+    i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn i64_at(bytes: &[u8], offset: usize) -> i64 {
+    i64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn string_at(bytes: &[u8], offset: usize) -> String {
+    let field = &bytes[offset..offset + STRING_SIZE];
+    let end = field.iter().position(|&b| b == 0).unwrap_or(STRING_SIZE);
+    String::from_utf8_lossy(&field[..end]).trim().to_string()
+}
+
+fn string_field(value: &str) -> [u8; STRING_SIZE] {
+    let mut field = [0u8; STRING_SIZE];
+    let bytes = value.as_bytes();
+    let len = bytes.len().min(STRING_SIZE);
+    field[..len].copy_from_slice(&bytes[..len]);
+    field
+}
+
+pub struct SerReader {
+    file: BufReader<File>,
+    header: SerHeader,
+    // Raw ticks, empty when the file has no timestamp table
+    timestamps: Vec<i64>,
+}
+
+impl SerReader {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let mut file = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+        let file_size = file.get_ref().metadata().map_err(|e| e.to_string())?.len();
+        let mut bytes = [0u8; HEADER_SIZE as usize];
+        file.read_exact(&mut bytes).map_err(|_| "Truncated SER header".to_string())?;
+        if &bytes[..14] != FILE_ID {
+            return Err("Not a SER file".to_string());
+        }
+
+        // Fields are little endian, whatever the byte order of the samples
+        let mut header = SerHeader {
+            color: SerColor::from_id(i32_at(&bytes, 18))?,
+            little_endian: i32_at(&bytes, 22) == LITTLE_ENDIAN,
+            width: i32_at(&bytes, 26).max(0) as usize,
+            height: i32_at(&bytes, 30).max(0) as usize,
+            pixel_depth: i32_at(&bytes, 34).clamp(0, 16) as u32,
+            frame_count: i32_at(&bytes, 38).max(0) as usize,
+            observer: string_at(&bytes, 42),
+            instrument: string_at(&bytes, 82),
+            telescope: string_at(&bytes, 122),
+            date_time: format_ticks(i64_at(&bytes, 162)),
+            date_time_utc: format_ticks(i64_at(&bytes, 170)),
+        };
+        if header.width == 0 || header.height == 0 || header.pixel_depth == 0 {
+            return Err("Invalid SER frame size".to_string());
+        }
+
+        // An interrupted capture leaves a partial last frame and no count update
+        let frame_size = header.frame_size() as u64;
+        let complete = ((file_size - HEADER_SIZE) / frame_size) as usize;
+        if complete < header.frame_count {
+            log::warn!("SER file has {} of {} frames", complete, header.frame_count);
+            header.frame_count = complete;
+        }
+        if header.frame_count == 0 {
+            return Err("SER file has no frames".to_string());
+        }
+
+        let table_offset = HEADER_SIZE + header.frame_count as u64 * frame_size;
+        let mut timestamps = Vec::new();
+        if file_size >= table_offset + header.frame_count as u64 * 8 {
+            file.seek(SeekFrom::Start(table_offset)).map_err(|e| e.to_string())?;
+            let mut table = vec![0u8; header.frame_count * 8];
+            file.read_exact(&mut table).map_err(|e| e.to_string())?;
+            timestamps = table.chunks_exact(8).map(|t| i64_at(t, 0)).collect();
+        }
+
+        Ok(Self { file, header, timestamps })
+    }
+
+    pub fn header(&self) -> &SerHeader {
+        &self.header
+    }
+
+    // UTC time of a frame, ISO 8601
+    pub fn timestamp(&self, index: usize) -> Option<String> {
+        self.timestamps.get(index).and_then(|&t| format_ticks(t))
+    }
+
+    pub fn read_frame(&mut self, index: usize) -> Result<RawImage, String> {
+        let header = &self.header;
+        if index >= header.frame_count {
+            return Err(format!("Frame {} out of {}", index, header.frame_count));
+        }
+        let frame_size = header.frame_size();
+        let mut bytes = vec![0u8; frame_size];
+        self.file
+            .seek(SeekFrom::Start(HEADER_SIZE + (index * frame_size) as u64))
+            .map_err(|e| e.to_string())?;
+        self.file.read_exact(&mut bytes).map_err(|e| e.to_string())?;
+
+        let samples: Vec<i32> = match (header.bytes_per_sample(), header.little_endian) {
+            (1, _) => bytes.iter().map(|&b| b as i32).collect(),
+            (_, true) => bytes.chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]]) as i32).collect(),
+            (_, false) => bytes.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as i32).collect(),
+        };
+
+        let (w, h) = (header.width, header.height);
+        let mut image = match header.color {
+            SerColor::Rgb | SerColor::Bgr => {
+                let mut rgb = Array3::from_shape_vec((h, w, 3), samples).map_err(|e| e.to_string())?;
+                if header.color == SerColor::Bgr {
+                    rgb.invert_axis(ndarray::Axis(2));
+                }
+                RawImage::from_rgb(rgb.as_standard_layout().into_owned())
+            }
+            color => {
+                let data = Array2::from_shape_vec((h, w), samples).map_err(|e| e.to_string())?;
+                RawImage::new(data, color.bayer_pattern())
+            }
+        };
+
+        image.saturation_level = (1i32 << header.pixel_depth) - 1;
+        image.metadata = ImageMetadata {
+            instrument: Some(header.instrument.clone()).filter(|s| !s.is_empty()),
+            telescope: Some(header.telescope.clone()).filter(|s| !s.is_empty()),
+            date_obs: self.timestamp(index).or_else(|| header.date_time_utc.clone()),
+            date_loc: header.date_time.clone(),
+            ..Default::default()
+        };
+        Ok(image)
+    }
+}
+
+// Records frames to a SER file. The frame count, start date and timestamp
+// table are written by `finish`.
+pub struct SerWriter {
+    file: BufWriter<File>,
+    header: SerHeader,
+    timestamps: Vec<i64>,
+}
+
+impl SerWriter {
+    pub fn create(path: &Path, header: SerHeader) -> Result<Self, String> {
+        if header.width == 0 || header.height == 0 || !(1..=16).contains(&header.pixel_depth) {
+            return Err("Invalid SER frame size".to_string());
+        }
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path).map_err(|e| e.to_string())?),
+            header: SerHeader {
+                little_endian: true,
+                frame_count: 0,
+                ..header
+            },
+            timestamps: Vec::new(),
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> Result<(), String> {
+        let header = &self.header;
+        let ticks = |date: &Option<String>| date.as_deref().and_then(parse_date).map_or(0, |d| date_to_ticks(&d));
+        let mut bytes = Vec::with_capacity(HEADER_SIZE as usize);
+        bytes.extend_from_slice(FILE_ID);
+        for value in [
+            0,
+            header.color.id(),
+            LITTLE_ENDIAN,
+            header.width as i32,
+            header.height as i32,
+            header.pixel_depth as i32,
+            header.frame_count as i32,
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [&header.observer, &header.instrument, &header.telescope] {
+            bytes.extend_from_slice(&string_field(value));
+        }
+        bytes.extend_from_slice(&ticks(&header.date_time).to_le_bytes());
+        bytes.extend_from_slice(&ticks(&header.date_time_utc).to_le_bytes());
+        self.file.write_all(&bytes).map_err(|e| e.to_string())
+    }
+
+    // Appends a frame taken at `timestamp` (UTC). Mono and Bayer files take
+    // the raw data of the image, RGB ones its color data.
+    pub fn write_frame(&mut self, image: &RawImage, timestamp: NaiveDateTime) -> Result<(), String> {
+        let header = &self.header;
+        let (w, h) = (header.width, header.height);
+        if image.dimensions() != (w, h) {
+            return Err(format!("Frame is {:?}, the file is {}x{}", image.dimensions(), w, h));
+        }
+        let max = (1i32 << header.pixel_depth) - 1;
+        let samples: Vec<i32> = match header.color {
+            SerColor::Rgb | SerColor::Bgr => {
+                let Some(rgb) = &image.debayered_image else {
+                    return Err("Frame has no color data".to_string());
+                };
+                let order = if header.color == SerColor::Rgb { [0, 1, 2] } else { [2, 1, 0] };
+                rgb.rows().into_iter().flat_map(|p| order.map(|c| p[c])).collect()
+            }
+            _ => image.raw_image.iter().copied().collect(),
+        };
+
+        let bytes: Vec<u8> = if header.bytes_per_sample() == 1 {
+            samples.iter().map(|&v| v.clamp(0, max) as u8).collect()
+        } else {
+            samples.iter().flat_map(|&v| (v.clamp(0, max) as u16).to_le_bytes()).collect()
+        };
+        self.file.write_all(&bytes).map_err(|e| e.to_string())?;
+        self.header.frame_count += 1;
+        self.timestamps.push(date_to_ticks(&timestamp));
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<SerHeader, String> {
+        if self.header.date_time_utc.is_none() {
+            self.header.date_time_utc = self.timestamps.first().and_then(|&t| format_ticks(t));
+        }
+        let table: Vec<u8> = self.timestamps.iter().flat_map(|t| t.to_le_bytes()).collect();
+        self.file.write_all(&table).map_err(|e| e.to_string())?;
+
+        // Patch the count and dates now that they are known
+        let header = self.header.clone();
+        self.file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+        self.write_header()?;
+        self.file.flush().map_err(|e| e.to_string())?;
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array3;
+
+    fn header(color: SerColor, pixel_depth: u32) -> SerHeader {
+        SerHeader {
+            color,
+            little_endian: false,
+            width: 5,
+            height: 4,
+            pixel_depth,
+            // Counted by the writer
+            frame_count: 99,
+            observer: "Observer".to_string(),
+            instrument: "ZWO ASI462MC".to_string(),
+            telescope: "C8".to_string(),
+            date_time: None,
+            date_time_utc: None,
+        }
+    }
+
+    fn time(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%.f").unwrap()
+    }
+
+    #[test]
+    fn bayer_frames_read_back() {
+        let path = std::env::temp_dir().join("skyctl-ser-bayer.ser");
+        let start = time("2024-05-01T22:00:00.125");
+        let mut writer = SerWriter::create(&path, header(SerColor::Rggb, 12)).unwrap();
+        for i in 0..3 {
+            let data = Array2::from_shape_fn((4, 5), |(y, x)| (y * 5 + x) as i32 * 100 + i);
+            let timestamp = start + Duration::milliseconds(10 * i as i64);
+            writer.write_frame(&RawImage::new(data, BayerPattern::RGGB), timestamp).unwrap();
+        }
+        // Out of range samples are clipped to the pixel depth
+        let data = Array2::from_elem((4, 5), 5000);
+        writer.write_frame(&RawImage::new(data, BayerPattern::RGGB), start).unwrap();
+        let written = writer.finish().unwrap();
+        assert_eq!(written.frame_count, 4);
+        // Header, 16 bit samples and the timestamp table
+        let size = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(size, HEADER_SIZE + 4 * 40 + 4 * 8);
+
+        let mut reader = SerReader::open(&path).unwrap();
+        let header = reader.header().clone();
+        assert_eq!(header, written);
+        assert_eq!(header.color, SerColor::Rggb);
+        assert!(header.little_endian);
+        assert_eq!((header.width, header.height, header.pixel_depth), (5, 4, 12));
+        assert_eq!((header.observer.as_str(), header.telescope.as_str()), ("Observer", "C8"));
+        assert_eq!(header.date_time_utc.as_deref(), Some("2024-05-01T22:00:00.125"));
+        assert_eq!(reader.timestamp(2).as_deref(), Some("2024-05-01T22:00:00.145"));
+
+        let frame = reader.read_frame(2).unwrap();
+        assert!(frame.bayer_pattern == BayerPattern::RGGB);
+        assert_eq!(frame.raw_image[[0, 0]], 2);
+        assert_eq!(frame.raw_image[[3, 4]], 1902);
+        assert_eq!(frame.saturation_level, 4095);
+        assert_eq!(frame.metadata.instrument.as_deref(), Some("ZWO ASI462MC"));
+        assert_eq!(frame.metadata.date_obs.as_deref(), Some("2024-05-01T22:00:00.145"));
+        assert!(reader.read_frame(3).unwrap().raw_image.iter().all(|&v| v == 4095));
+        assert!(reader.read_frame(4).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bgr_frames_read_back() {
+        let path = std::env::temp_dir().join("skyctl-ser-bgr.ser");
+        let mut writer = SerWriter::create(&path, header(SerColor::Bgr, 8)).unwrap();
+        let rgb = Array3::from_shape_fn((4, 5, 3), |(y, x, c)| (y * 5 + x) as i32 * 3 + c as i32);
+        writer.write_frame(&RawImage::from_rgb(rgb.clone()), time("2024-05-01T22:00:00")).unwrap();
+        // Frames must match the size of the file
+        let small = RawImage::from_rgb(Array3::zeros((2, 2, 3)));
+        assert!(writer.write_frame(&small, time("2024-05-01T22:00:01")).is_err());
+        writer.finish().unwrap();
+
+        // Blue first on disk
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[HEADER_SIZE as usize..HEADER_SIZE as usize + 3], &[2, 1, 0]);
+        let mut reader = SerReader::open(&path).unwrap();
+        assert_eq!(reader.header().frame_count, 1);
+        let frame = reader.read_frame(0).unwrap();
+        assert_eq!(frame.debayered_image.unwrap(), rgb);
+        assert_eq!(frame.saturation_level, 255);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_frame_sizes_are_refused() {
+        let path = std::env::temp_dir().join("skyctl-ser-invalid.ser");
+        assert!(SerWriter::create(&path, header(SerColor::Mono, 17)).is_err());
+        let empty = SerHeader { width: 0, ..header(SerColor::Mono, 8) };
+        assert!(SerWriter::create(&path, empty).is_err());
+    }
+}
@@ -1,18 +1,24 @@
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use tauri::{command, AppHandle, Emitter};
+use tauri::{command, AppHandle, Emitter, State};
+use crate::background::BackgroundParams;
+use crate::blink::{blink_sequence, BlinkFrame};
+use crate::colorcal::{ColorCalibration, ColorCalibrationParams};
+use crate::export::{export, ExportParams, ExportResult};
+use crate::histogram::{Histogram, HistogramScale};
+use crate::imagestore::{ImageEntry, ImageId, ImageStore, MemoryUsage};
+use crate::library::ImageFormat;
+use crate::pyramid::{ImageTile, LevelInfo, Viewport};
+use crate::resize::{Fit, ResizeFilter};
 use crate::rawimage::{RawImage, RawRGBImage};
-use once_cell::sync::Lazy;
+use crate::ser::{SerHeader, SerReader};
+use crate::stretch::StretchAlgorithm;
 use std::fs::File;
 use std::io::BufReader;
-
-// Global hash table for RawImage objects
-type RawImageMap = Arc<RwLock<HashMap<u32, Box<RawImage>>>>;
-static RAW_IMAGE_TABLE: Lazy<RawImageMap> = Lazy::new(|| Arc::new(RwLock::new(HashMap::new())));
+use std::path::Path;
 
 #[command]
 pub async fn load_fits_image(
     app: AppHandle,
+    store: State<'_, ImageStore>,
     telescope_index: u32,
     display_width: usize,
     display_height: usize,
@@ -24,36 +30,303 @@ pub async fn load_fits_image(
     let f = File::open(path).unwrap();
     let reader = BufReader::new(f);
     
-    let mut raw_image = RawImage::from_reader(reader).map_err(|e| e.to_string())?;
+    let raw_image = RawImage::from_reader(reader).map_err(|e| e.to_string())?;
+    show_new_image(&app, &store, telescope_index, raw_image, display_width, display_height).await
+}
 
+// Debayers and stores a freshly loaded image, then sends it to the frontend.
+// The processing takes seconds on a full frame, so it runs on a blocking
+// thread rather than on an async worker.
+pub(crate) async fn show_new_image(
+    app: &AppHandle,
+    store: &ImageStore,
+    telescope_index: u32,
+    mut raw_image: RawImage,
+    display_width: usize,
+    display_height: usize,
+) -> Result<(), String> {
+    let (raw_image, image_data): (RawImage, RawRGBImage) = tauri::async_runtime::spawn_blocking(move || {
+        raw_image.debayer().map_err(|e| e.to_string())?;
+        raw_image.build_pyramid();
+        let image_data = raw_image.display_image(display_width, display_height, None, Fit::default());
+        Ok::<_, String>((raw_image, image_data))
+    })
+    .await
+    .map_err(|e| e.to_string())??;
 
-    raw_image.debayer().map_err(|e| e.to_string())?;
-    raw_image.downsample(display_width, display_height).map_err(|e| e.to_string())?;
+    // The new image becomes the current one of the telescope, the previous
+    // ones stay in its history
+    let image_id = store.insert(telescope_index, raw_image)?;
 
-    // Override the existing RawImage in the hash table
-    {
-        let mut raw_image_map = RAW_IMAGE_TABLE.write().map_err(|e| e.to_string())?;
-        raw_image_map.insert(telescope_index, Box::new(raw_image));
-    }
+    // Send the raw arrays to the frontend
+    let payload = serde_json::json!({
+        "index": telescope_index,
+        "image_id": image_id,
+        "image_data": image_data,
+    });
+
+    app.emit("fits_image_updated", payload)
+        .map_err(|e| e.to_string())?;
 
-    // Retrieve the raw arrays and determine if the image is mono or OSC
-    let raw_image_map = RAW_IMAGE_TABLE.read().map_err(|e| e.to_string())?;
-    let raw_image = raw_image_map
-        .get(&telescope_index)
-        .ok_or_else(|| format!("No RawImage found in cache for telescope index {}", telescope_index))?;
+    Ok(())
+}
 
-    let image_data : RawRGBImage = raw_image.get_raw_image();
+// Opens a FITS, XISF or camera raw file from disk
+#[command]
+pub async fn load_image_file(
+    app: AppHandle,
+    store: State<'_, ImageStore>,
+    telescope_index: u32,
+    path: String,
+    display_width: usize,
+    display_height: usize,
+) -> Result<(), String> {
+    log::info!("Loading {} for telescope index {}...", path, telescope_index);
+    let path = Path::new(&path);
+    let format = ImageFormat::from_path(path).ok_or_else(|| format!("Unsupported file {}", path.display()))?;
+    let raw_image = format.load(path)?;
+    show_new_image(&app, &store, telescope_index, raw_image, display_width, display_height).await
+}
 
-    //assert!(image_data.width * image_data.height * 3 == image_data.pixels.len() as u32);
+// Header of a SER video, to browse its frames
+#[command]
+pub async fn get_ser_header(path: String) -> Result<SerHeader, String> {
+    Ok(SerReader::open(Path::new(&path))?.header().clone())
+}
+
+// Shows one frame of a SER video like any other image
+#[command]
+pub async fn load_ser_frame(
+    app: AppHandle,
+    store: State<'_, ImageStore>,
+    telescope_index: u32,
+    path: String,
+    frame: usize,
+    display_width: usize,
+    display_height: usize,
+) -> Result<(), String> {
+    let raw_image = SerReader::open(Path::new(&path))?.read_frame(frame)?;
+    show_new_image(&app, &store, telescope_index, raw_image, display_width, display_height).await
+}
+
+// Image fitted in `display_width` x `display_height`, stretched on the backend
+// when `stretch` is given. The full resolution data is kept, so any size can be
+// requested at any time. `fit` picks the resize filter and lets small images
+// grow to the display, box filtered and never upscaled by default.
+#[command]
+pub async fn get_display_image(
+    store: State<'_, ImageStore>,
+    telescope_index: u32,
+    display_width: usize,
+    display_height: usize,
+    stretch: Option<StretchAlgorithm>,
+    fit: Option<Fit>,
+) -> Result<RawRGBImage, String> {
+    store.write(telescope_index, |raw_image| {
+        raw_image.display_image(display_width, display_height, stretch.as_ref(), fit.unwrap_or_default())
+    })
+}
+
+#[command]
+pub async fn get_image_histogram(
+    store: State<'_, ImageStore>,
+    telescope_index: u32,
+    bins: usize,
+    scale: HistogramScale,
+    stretch: Option<StretchAlgorithm>,
+) -> Result<Histogram, String> {
+    store.read(telescope_index, |raw_image| {
+        raw_image.histogram(bins, scale, stretch.as_ref())
+    })
+}
+
+// Removes the background gradient of the image, sends the corrected image as a
+// regular update and returns the fitted model so it can be shown.
+#[command]
+pub async fn extract_background(
+    app: AppHandle,
+    store: State<'_, ImageStore>,
+    telescope_index: u32,
+    params: BackgroundParams,
+) -> Result<RawRGBImage, String> {
+    let (image_data, model_data) = store.modify(telescope_index, |raw_image| {
+        let model = raw_image.extract_background(&params)?;
+        let (width, height) = raw_image.display_size();
+        let model_data = raw_image
+            .with_channels(&model.channels)
+            .display_image(width, height, None, Fit::default());
+        Ok((raw_image.preview(), model_data))
+    })?;
+
+    let payload = serde_json::json!({
+        "index": telescope_index,
+        "image_data": image_data,
+    });
+    app.emit("fits_image_updated", payload)
+        .map_err(|e| e.to_string())?;
+
+    Ok(model_data)
+}
+
+#[command]
+pub async fn calibrate_color(
+    app: AppHandle,
+    store: State<'_, ImageStore>,
+    telescope_index: u32,
+    params: ColorCalibrationParams,
+) -> Result<ColorCalibration, String> {
+    let (image_data, calibration) = store.modify(telescope_index, |raw_image| {
+        let calibration = raw_image.calibrate_color(&params)?;
+        Ok((raw_image.preview(), calibration))
+    })?;
 
-    // Send the raw arrays to the frontend
     let payload = serde_json::json!({
         "index": telescope_index,
         "image_data": image_data,
     });
+    app.emit("fits_image_updated", payload)
+        .map_err(|e| e.to_string())?;
+
+    Ok(calibration)
+}
+
+// Writes the current image of the telescope, stretched, as JPEG, PNG or TIFF
+#[command]
+pub async fn export_image(
+    store: State<'_, ImageStore>,
+    telescope_index: u32,
+    params: ExportParams,
+) -> Result<ExportResult, String> {
+    store.read(telescope_index, |raw_image| {
+        let max_size = match (params.max_width, params.max_height) {
+            (None, None) => None,
+            (w, h) => Some((w.unwrap_or(usize::MAX), h.unwrap_or(usize::MAX))),
+        };
+        let channels = raw_image.stretched_channels(max_size, &params.stretch);
+        export(&channels, &raw_image.metadata, &params)
+    })?
+}
+
+#[command]
+pub async fn get_image_pyramid(
+    store: State<'_, ImageStore>,
+    telescope_index: u32,
+) -> Result<Vec<LevelInfo>, String> {
+    store.read(telescope_index, |raw_image| {
+        raw_image.pyramid.as_ref().map(|p| p.info()).unwrap_or_default()
+    })
+}
+
+// Stretched tile covering `viewport` (in full resolution pixels) from the
+// pyramid level that best matches `zoom` (screen pixels per image pixel).
+// Zoomed in past 1:1, a `filter` resamples the tile to the screen size.
+#[command]
+pub async fn get_image_tile(
+    store: State<'_, ImageStore>,
+    telescope_index: u32,
+    zoom: f32,
+    viewport: Viewport,
+    stretch: StretchAlgorithm,
+    filter: Option<ResizeFilter>,
+) -> Result<ImageTile, String> {
+    store.read(telescope_index, |raw_image| {
+        let pyramid = raw_image
+            .pyramid
+            .as_ref()
+            .ok_or_else(|| format!("No full resolution data for telescope index {}", telescope_index))?;
+        let upscale = filter.map(|filter| (zoom, filter));
+        let level = pyramid.level_for_zoom(zoom);
+        pyramid.render_tile(&raw_image.planes(), level, viewport, &stretch, upscale)
+    })?
+}
+
+#[command]
+pub async fn get_image_history(
+    store: State<'_, ImageStore>,
+    telescope_index: u32,
+) -> Result<Vec<ImageEntry>, String> {
+    store.history(telescope_index)
+}
+
+// Makes an image of the history the current one again and sends it to the
+// viewer like a freshly loaded one
+#[command]
+pub async fn show_history_image(
+    app: AppHandle,
+    store: State<'_, ImageStore>,
+    image_id: ImageId,
+    display_width: usize,
+    display_height: usize,
+) -> Result<(), String> {
+    let telescope_index = store.select(image_id)?;
+    let image_data = store.write(telescope_index, |raw_image| {
+        raw_image.display_image(display_width, display_height, None, Fit::default())
+    })?;
 
+    let payload = serde_json::json!({
+        "index": telescope_index,
+        "image_id": image_id,
+        "image_data": image_data,
+    });
     app.emit("fits_image_updated", payload)
         .map_err(|e| e.to_string())?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Aligned, identically stretched frames to blink, oldest first. Defaults to
+// the whole history of the telescope when no `image_ids` are given.
+#[command]
+pub async fn get_blink_sequence(
+    store: State<'_, ImageStore>,
+    telescope_index: u32,
+    image_ids: Option<Vec<ImageId>>,
+    display_width: usize,
+    display_height: usize,
+    stretch: Option<StretchAlgorithm>,
+) -> Result<Vec<BlinkFrame>, String> {
+    let image_ids = match image_ids {
+        Some(ids) => ids,
+        None => store.history(telescope_index)?.iter().rev().map(|e| e.id).collect(),
+    };
+
+    let mut maxima = vec![];
+    let mut frames = Vec::with_capacity(image_ids.len());
+    for &id in &image_ids {
+        let planes = store.read_image(id, |raw_image| {
+            if maxima.is_empty() {
+                maxima = raw_image.source_maxima();
+            }
+            raw_image.display_planes(display_width, display_height, Fit::default())
+        })?;
+        frames.push((id, planes));
+    }
+
+    blink_sequence(frames, &maxima, stretch)
+}
+
+#[command]
+pub async fn release_image(store: State<'_, ImageStore>, image_id: ImageId) -> Result<(), String> {
+    store.release(image_id)
+}
+
+// Frees every image of the telescope, returning how many were released
+#[command]
+pub async fn release_telescope_images(
+    store: State<'_, ImageStore>,
+    telescope_index: u32,
+) -> Result<usize, String> {
+    store.release_telescope(telescope_index)
+}
+
+#[command]
+pub async fn get_image_memory_usage(store: State<'_, ImageStore>) -> Result<MemoryUsage, String> {
+    store.memory_usage()
+}
+
+// Sets the memory the images may use, in bytes. Images over the budget are
+// released right away, least recently used first.
+#[command]
+pub async fn set_image_memory_budget(store: State<'_, ImageStore>, bytes: usize) -> Result<(), String> {
+    store.set_budget(bytes)
+}
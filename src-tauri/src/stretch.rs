@@ -1,15 +1,43 @@
-use std::simd::f32x8;
-use std::simd::cmp::SimdPartialOrd;
-use std::simd::num::SimdFloat;
+use std::borrow::Cow;
 
 use ndarray::Array2;
+use rayon::prelude::*;
 
-const SIMD_WIDTH: usize = 8; // Number of elements in a SIMD vector
+// Number of elements processed together by calc_channel_stats. Fixed size
+// lanes are vectorized by the compiler on stable Rust, std::simd needs a
+// nightly toolchain.
+const SIMD_WIDTH: usize = 8;
+
+// Screen transfer function parameters for a single channel, expressed on data
+// normalised to [0.0, 1.0]. These are the same c0/c1/m values driven by the
+// sliders of ImageViewer.vue.
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct StretchParams {
+    // Shadows clipping point: everything below is mapped to 0.0
+    pub c0: f32,
+    // Highlights clipping point: everything above is mapped to 1.0
+    pub c1: f32,
+    // Midtones balance
+    pub m: f32,
+}
+
+// How the stretch is applied to the channels of a color image.
+//   Linked: the same parameters are used for R, G and B, preserving the
+//           color balance of the data.
+//   Unlinked: each channel gets its own parameters, which neutralizes
+//           the background color cast at the cost of altering the hues.
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "mode", content = "params", rename_all = "lowercase")]
+pub enum ChannelStretch {
+    Linked(StretchParams),
+    Unlinked([StretchParams; 3]),
+}
 
 #[derive(Debug, Copy, Clone)]
 pub struct Stretch {
     pub target_bkg: f32,
     pub shadows_clip: f32,
+    pub highlights_clip: f32,
 }
 
 fn median(data: &Array2<f32>) -> f32 {
@@ -20,11 +48,10 @@ fn median(data: &Array2<f32>) -> f32 {
 
     let mid = len / 2;
 
-    // Use a slice reference directly for the data
     let mut v: Vec<f32> = data.iter().copied().collect();
-    
+
     // Perform a partial sort to find the median without fully sorting
-    let (_, median, _) = v.select_nth_unstable_by(mid, |a, b| a.partial_cmp(b).unwrap());
+    let (_, median, _) = v.select_nth_unstable_by(mid, |a, b| a.total_cmp(b));
 
     *median
 }
@@ -32,39 +59,42 @@ fn median(data: &Array2<f32>) -> f32 {
 pub fn calc_channel_stats(data: &Array2<f32>) -> (f32, f32, f32, f32, f32) {
     let median_val = median(data);  // Calculate median only once
 
-    let len = data.len();
-    let n = len as f32;
-   
-    // Flatten the data to 1D for SIMD processing
-    let data_flat: Vec<f32> = data.iter().copied().collect();
+    let n = data.len() as f32;
 
-    let mut sum = 0.0;
-    let mut min_val = f32::MAX;
-    let mut max_val = f32::MIN;
-    let mut sum_val = 0.0;
+    // Flatten the data to 1D for the SIMD loop, without a copy when possible
+    let data_flat: Cow<[f32]> = match data.as_slice() {
+        Some(slice) => Cow::Borrowed(slice),
+        None => Cow::Owned(data.iter().copied().collect()),
+    };
 
-    // Process data in chunks of SIMD width
-    let mut i = 0;
+    // One accumulator per lane, so the compiler keeps each in a SIMD register
+    let mut sum = [0.0f32; SIMD_WIDTH];
+    let mut min_val = [f32::MAX; SIMD_WIDTH];
+    let mut max_val = [f32::MIN; SIMD_WIDTH];
+    let mut sum_val = [0.0f32; SIMD_WIDTH];
 
-    // Iterate over chunks of SIMD width
-    while i + SIMD_WIDTH <= len {
-        let chunk = f32x8::from_slice(&data_flat[i..i + SIMD_WIDTH]);
-        let dev = (chunk - f32x8::splat(median_val)).abs();
-        sum += dev.reduce_sum();
-        min_val = min_val.min(chunk.reduce_min());
-        max_val = max_val.max(chunk.reduce_max());
-        sum_val += chunk.reduce_sum();
-        i += SIMD_WIDTH;
+    // Process data in chunks of SIMD width
+    let mut chunks = data_flat.chunks_exact(SIMD_WIDTH);
+    for chunk in &mut chunks {
+        for (lane, &v) in chunk.iter().enumerate() {
+            sum[lane] += (v - median_val).abs();
+            min_val[lane] = min_val[lane].min(v);
+            max_val[lane] = max_val[lane].max(v);
+            sum_val[lane] += v;
+        }
     }
 
+    let mut sum: f32 = sum.iter().sum();
+    let mut min_val = min_val.iter().copied().fold(f32::MAX, f32::min);
+    let mut max_val = max_val.iter().copied().fold(f32::MIN, f32::max);
+    let mut sum_val: f32 = sum_val.iter().sum();
+
     // Handle remaining elements if len is not a multiple of SIMD width
-    while i < len {
-        let v = data_flat[i];
+    for &v in chunks.remainder() {
         sum += (v - median_val).abs();
         min_val = min_val.min(v);
         max_val = max_val.max(v);
         sum_val += v;
-        i += 1;
     }
 
     // Calculate the average absolute deviation
@@ -74,10 +104,30 @@ pub fn calc_channel_stats(data: &Array2<f32>) -> (f32, f32, f32, f32, f32) {
     (median_val, avg_dev, min_val, max_val, avg)
 }
 
-fn mtf(m: f32, x: f32) -> f32 {
-    if x == 0.0 {
+// Midtones Transfer Function
+
+//     MTF(m, x) = {
+//         0                for x == 0,
+//         1/2              for x == m,
+//         1                for x == 1,
+
+//         (m - 1)x
+//         --------------   otherwise.
+//         (2m - 1)x - m
+//     }
+
+//     See the section "Midtones Balance" from
+//     https://pixinsight.com/doc/tools/HistogramTransformation/HistogramTransformation.html
+
+//     Args:
+//         m (float): midtones balance parameter
+//                    a value below 0.5 darkens the midtones
+//                    a value above 0.5 lightens the midtones
+//         x (np.array): the data that we want to copy and transform.
+pub fn mtf(m: f32, x: f32) -> f32 {
+    if x <= 0.0 {
         0.0
-    } else if x == 1.0 {
+    } else if x >= 1.0 {
         1.0
     } else if x == m {
         0.5
@@ -88,101 +138,890 @@ fn mtf(m: f32, x: f32) -> f32 {
     }
 }
 
+// Normalizes the data to [0.0, 1.0] dividing by `max_val`. Use the same
+// `max_val` on all channels when the stretch is going to be linked.
+pub fn normalize(data: &Array2<f32>, max_val: f32) -> Array2<f32> {
+    let scale = if max_val > 0.0 { 1.0 / max_val } else { 0.0 };
+    let mut out = data.to_owned();
+    out.par_mapv_inplace(|x| (x * scale).clamp(0.0, 1.0));
+    out
+}
+
+impl Default for StretchParams {
+    fn default() -> Self {
+        Self { c0: 0.0, c1: 1.0, m: 0.5 }
+    }
+}
+
+impl StretchParams {
+    pub fn new(c0: f32, c1: f32, m: f32) -> Result<Self, String> {
+        if !(0.0..=1.0).contains(&c0) || !(0.0..=1.0).contains(&c1) {
+            return Err(format!("Clipping points must be in [0, 1], got c0={} c1={}", c0, c1));
+        }
+        if c0 >= c1 {
+            return Err(format!("Shadows clip ({}) must be below highlights clip ({})", c0, c1));
+        }
+        if m <= 0.0 || m >= 1.0 {
+            return Err(format!("Midtones balance must be in (0, 1), got {}", m));
+        }
+        Ok(Self { c0, c1, m })
+    }
+
+    fn interpolate_mtf(table: &[f32], x: f32) -> f32 {
+        let scaled = x * (table.len() - 1) as f32;
+        let idx = scaled.floor() as usize;
+        let next = (idx + 1).min(table.len() - 1);
+        let frac = scaled - idx as f32;
+        table[idx] * (1.0 - frac) + table[next] * frac
+    }
+
+    fn generate_mtf_lookup_table(&self, steps: usize) -> Vec<f32> {
+        // Generate values for each step in the range [0.0, 1.0]
+        (0..steps)
+            .map(|i| mtf(self.m, i as f32 / (steps as f32 - 1.0)))
+            .collect()
+    }
+
+    // Applies the transfer function to data already normalized to [0.0, 1.0]
+    pub fn apply(&self, data: &Array2<f32>) -> Array2<f32> {
+        let mut out = data.to_owned();
+        self.apply_inplace(&mut out);
+        out
+    }
+
+    pub fn apply_inplace(&self, data: &mut Array2<f32>) {
+        let table = self.generate_mtf_lookup_table(65536);
+        let (c0, c1) = (self.c0, self.c1);
+        let range = (c1 - c0).max(f32::EPSILON);
+
+        data.par_mapv_inplace(|x| {
+            if x <= c0 {
+                0.0
+            } else if x >= c1 {
+                1.0
+            } else {
+                Self::interpolate_mtf(&table, (x - c0) / range)
+            }
+        });
+    }
+}
+
+impl ChannelStretch {
+    pub fn channel(&self, index: usize) -> StretchParams {
+        match self {
+            ChannelStretch::Linked(params) => *params,
+            ChannelStretch::Unlinked(params) => params[index.min(2)],
+        }
+    }
+
+    // Applies the stretch to each channel in place. Mono images pass a single
+    // channel and always use the first set of parameters.
+    pub fn apply_inplace(&self, channels: &mut [Array2<f32>]) {
+        channels
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(i, channel)| self.channel(i).apply_inplace(channel));
+    }
+}
+
+impl Default for Stretch {
+    fn default() -> Self {
+        Self::new(0.25, -1.25)
+    }
+}
+
 impl Stretch {
     pub fn new(target_bkg: f32, shadows_clip: f32) -> Self {
         Self {
             target_bkg,
             shadows_clip,
+            highlights_clip: 1.0,
+        }
+    }
+
+    pub fn with_highlights_clip(mut self, highlights_clip: f32) -> Self {
+        self.highlights_clip = highlights_clip.clamp(0.0, 1.0);
+        self
+    }
+
+    fn params_from_stats(&self, median: f32, avg_dev: f32) -> StretchParams {
+        let c1 = self.highlights_clip;
+        let c0 = (median + self.shadows_clip * avg_dev).clamp(0.0, c1);
+        let range = (c1 - c0).max(f32::EPSILON);
+        let m = mtf(self.target_bkg, ((median - c0) / range).clamp(0.0, 1.0));
+        StretchParams { c0, c1, m }
+    }
+
+    // Auto-stretch parameters for a single channel normalized to [0.0, 1.0]
+    pub fn stretch_params(&self, data: &Array2<f32>) -> StretchParams {
+        let (median, avg_dev, _, _, _) = calc_channel_stats(data);
+        self.params_from_stats(median, avg_dev)
+    }
+
+    // Auto-stretch parameters computed independently for every channel
+    pub fn unlinked_params(&self, channels: &[Array2<f32>]) -> ChannelStretch {
+        let mut params = [StretchParams::default(); 3];
+        for (i, channel) in channels.iter().take(3).enumerate() {
+            params[i] = self.stretch_params(channel);
         }
+        if channels.len() == 1 {
+            params = [params[0]; 3];
+        }
+        ChannelStretch::Unlinked(params)
     }
 
-    // Midtones Transfer Function
+    // Common auto-stretch parameters for all channels, derived from the
+    // average median and deviation of the channels.
+    pub fn linked_params(&self, channels: &[Array2<f32>]) -> ChannelStretch {
+        if channels.is_empty() {
+            return ChannelStretch::Linked(StretchParams::default());
+        }
+        let n = channels.len() as f32;
+        let (median, avg_dev) = channels
+            .par_iter()
+            .map(|channel| {
+                let (median, avg_dev, _, _, _) = calc_channel_stats(channel);
+                (median, avg_dev)
+            })
+            .reduce(|| (0.0, 0.0), |a, b| (a.0 + b.0, a.1 + b.1));
+        ChannelStretch::Linked(self.params_from_stats(median / n, avg_dev / n))
+    }
 
-    //     MTF(m, x) = {
-    //         0                for x == 0,
-    //         1/2              for x == m,
-    //         1                for x == 1,
+    // Normalizes the data by its maximum and applies the auto-stretch
+    pub fn stretch(&self, data: &Array2<f32>) -> Array2<f32> {
+        let max_val = data.iter().copied().fold(f32::MIN, f32::max);
+        let mut normalized = normalize(data, max_val);
+        self.stretch_params(&normalized).apply_inplace(&mut normalized);
+        normalized
+    }
+}
 
-    //         (m - 1)x
-    //         --------------   otherwise.
-    //         (2m - 1)x - m
-    //     }
+// Arcsinh stretch (Lupton et al. 2004)
+//
+//     x' = x * asinh(beta * I) / (I * asinh(beta))
+//
+// where I is the mean of the channels at each pixel. All channels are scaled
+// by the same factor, so the color ratios of the data are kept, which helps
+// with faint nebulosity where an MTF stretch washes out the colors.
+//
+//     Args:
+//         beta: stretch strength, 0 leaves the data unchanged
+//         black_point: value subtracted before stretching, in [0.0, 1.0)
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ArcsinhStretch {
+    pub beta: f32,
+    pub black_point: f32,
+}
 
-    //     See the section "Midtones Balance" from
-    //     https://pixinsight.com/doc/tools/HistogramTransformation/HistogramTransformation.html
+impl ArcsinhStretch {
+    pub fn new(beta: f32, black_point: f32) -> Result<Self, String> {
+        if beta < 0.0 {
+            return Err(format!("Arcsinh stretch factor must be positive, got {}", beta));
+        }
+        if !(0.0..1.0).contains(&black_point) {
+            return Err(format!("Black point must be in [0, 1), got {}", black_point));
+        }
+        Ok(Self { beta, black_point })
+    }
 
-    //     Args:
-    //         m (float): midtones balance parameter
-    //                    a value below 0.5 darkens the midtones
-    //                    a value above 0.5 lightens the midtones
-    //         x (np.array): the data that we want to copy and transform.
+    fn factor(&self, intensity: f32) -> f32 {
+        if self.beta == 0.0 || intensity <= 0.0 {
+            1.0
+        } else {
+            (self.beta * intensity).asinh() / (intensity * self.beta.asinh())
+        }
+    }
 
+    // Applies the stretch to normalized channels in place. Mono images pass a
+    // single channel.
+    pub fn apply_inplace(&self, channels: &mut [Array2<f32>]) {
+        let bp = self.black_point;
+        let scale = 1.0 / (1.0 - bp);
+        for channel in channels.iter_mut() {
+            channel.par_mapv_inplace(|x| ((x - bp) * scale).max(0.0));
+        }
 
-    fn interpolate_mtf(table: &[f32], x: f32) -> f32 {
-        let scaled = x * (table.len() - 1) as f32;
-        let idx = scaled.floor() as usize;
-        let next = (idx + 1).min(table.len() - 1);
-        let frac = scaled - idx as f32;
-        table[idx] * (1.0 - frac) + table[next] * frac
+        if channels.len() < 3 {
+            for channel in channels.iter_mut() {
+                channel.par_mapv_inplace(|x| (x * self.factor(x)).min(1.0));
+            }
+            return;
+        }
+
+        let (r, rest) = channels.split_at_mut(1);
+        let (g, b) = rest.split_at_mut(1);
+        ndarray::Zip::from(&mut r[0])
+            .and(&mut g[0])
+            .and(&mut b[0])
+            .par_for_each(|r, g, b| {
+                let k = self.factor((*r + *g + *b) / 3.0);
+                let (sr, sg, sb) = (*r * k, *g * k, *b * k);
+                // Rescale instead of clipping so saturated pixels keep their hue
+                let max = sr.max(sg).max(sb);
+                let norm = if max > 1.0 { 1.0 / max } else { 1.0 };
+                *r = sr * norm;
+                *g = sg * norm;
+                *b = sb * norm;
+            });
     }
+}
 
-    fn generate_mtf_lookup_table(&self, m: f32, steps: usize) -> Vec<f32> {
-        let mut table = Vec::with_capacity(steps);
-    
-        // Generate values for each step in the range [0.0, 1.0]
-        for i in 0..steps {
-            let x = i as f32 / (steps as f32 - 1.0);  // Normalize to [0.0, 1.0]
-            table.push(mtf(m, x));  // Assuming `mtf(m, x)` is your existing function
+// Generalized Hyperbolic Stretch, as described by Payne & Cranfield and
+// implemented in Siril and the PixInsight GHS script.
+//
+//     Args:
+//         stretch_factor: ln(D + 1), 0 leaves the data unchanged
+//         local_intensity: b, concentrates the contrast around SP as it grows
+//         symmetry_point: SP, the intensity receiving the most contrast
+//         shadow_protection: LP, below it the transform is linear
+//         highlight_protection: HP, above it the transform is linear
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct GhsStretch {
+    pub stretch_factor: f32,
+    pub local_intensity: f32,
+    pub symmetry_point: f32,
+    pub shadow_protection: f32,
+    pub highlight_protection: f32,
+}
+
+// Coefficients of the four segments of the transform: linear below LP,
+// hyperbolic between LP and SP and between SP and HP, and linear above HP.
+#[derive(Debug, Default, Copy, Clone)]
+struct GhsCoefficients {
+    a1: f32,
+    b1: f32,
+    a2: f32,
+    b2: f32,
+    c2: f32,
+    d2: f32,
+    e2: f32,
+    a3: f32,
+    b3: f32,
+    c3: f32,
+    d3: f32,
+    e3: f32,
+    a4: f32,
+    b4: f32,
+}
+
+impl GhsStretch {
+    pub fn new(
+        stretch_factor: f32,
+        local_intensity: f32,
+        symmetry_point: f32,
+        shadow_protection: f32,
+        highlight_protection: f32,
+    ) -> Result<Self, String> {
+        if stretch_factor < 0.0 {
+            return Err(format!("Stretch factor must be positive, got {}", stretch_factor));
+        }
+        if !(-5.0..=15.0).contains(&local_intensity) {
+            return Err(format!("Local intensity must be in [-5, 15], got {}", local_intensity));
+        }
+        if !(0.0..=1.0).contains(&symmetry_point) {
+            return Err(format!("Symmetry point must be in [0, 1], got {}", symmetry_point));
+        }
+        if shadow_protection < 0.0 || shadow_protection > symmetry_point {
+            return Err(format!(
+                "Shadow protection must be in [0, SP={}], got {}",
+                symmetry_point, shadow_protection
+            ));
         }
-    
-        table
+        if highlight_protection < symmetry_point || highlight_protection > 1.0 {
+            return Err(format!(
+                "Highlight protection must be in [SP={}, 1], got {}",
+                symmetry_point, highlight_protection
+            ));
+        }
+        Ok(Self {
+            stretch_factor,
+            local_intensity,
+            symmetry_point,
+            shadow_protection,
+            highlight_protection,
+        })
     }
 
-    fn stretch_params(&self, data: &Array2<f32>) -> (f32, f32, f32) {
-        let (median, avg_dev, _, _, _) = calc_channel_stats(data);
+    fn coefficients(&self) -> GhsCoefficients {
+        let d = self.stretch_factor.exp() - 1.0;
+        let b = self.local_intensity;
+        let (lp, sp, hp) = (
+            self.shadow_protection,
+            self.symmetry_point,
+            self.highlight_protection,
+        );
+        let mut c = GhsCoefficients::default();
 
-        let c0 = (median + self.shadows_clip * avg_dev).clamp(0.0, 1.0);
-        let m = mtf(self.target_bkg, median - c0);
-        (c0, 1.0, m)
+        if b == -1.0 {
+            let qlp = -(d * (sp - lp)).ln_1p();
+            let q0 = qlp - d * lp / (1.0 + d * (sp - lp));
+            let qwp = (d * (hp - sp)).ln_1p();
+            let q1 = qwp + d * (1.0 - hp) / (1.0 + d * (hp - sp));
+            let q = 1.0 / (q1 - q0);
+            c.b1 = d / (1.0 + d * (sp - lp)) * q;
+            c.a2 = -q0 * q;
+            c.b2 = -q;
+            c.c2 = 1.0 + d * sp;
+            c.d2 = -d;
+            c.a3 = -q0 * q;
+            c.b3 = q;
+            c.c3 = 1.0 - d * sp;
+            c.d3 = d;
+            c.a4 = (qwp - q0 - d * hp / (1.0 + d * (hp - sp))) * q;
+            c.b4 = q * d / (1.0 + d * (hp - sp));
+        } else if b < 0.0 {
+            let b = -b;
+            let qlp = (1.0 - (1.0 + d * b * (sp - lp)).powf((b - 1.0) / b)) / (b - 1.0);
+            let q0 = qlp - d * lp * (1.0 + d * b * (sp - lp)).powf(-1.0 / b);
+            let qwp = ((1.0 + d * b * (hp - sp)).powf((b - 1.0) / b) - 1.0) / (b - 1.0);
+            let q1 = qwp + d * (1.0 - hp) * (1.0 + d * b * (hp - sp)).powf(-1.0 / b);
+            let q = 1.0 / (q1 - q0);
+            c.b1 = d * (1.0 + d * b * (sp - lp)).powf(-1.0 / b) * q;
+            c.a2 = (1.0 / (b - 1.0) - q0) * q;
+            c.b2 = -q / (b - 1.0);
+            c.c2 = 1.0 + d * b * sp;
+            c.d2 = -d * b;
+            c.e2 = (b - 1.0) / b;
+            c.a3 = (-1.0 / (b - 1.0) - q0) * q;
+            c.b3 = q / (b - 1.0);
+            c.c3 = 1.0 - d * b * sp;
+            c.d3 = d * b;
+            c.e3 = (b - 1.0) / b;
+            c.a4 = (qwp - q0 - d * hp * (1.0 + d * b * (hp - sp)).powf(-1.0 / b)) * q;
+            c.b4 = d * (1.0 + d * b * (hp - sp)).powf(-1.0 / b) * q;
+        } else if b == 0.0 {
+            let qlp = (-d * (sp - lp)).exp();
+            let q0 = qlp - d * lp * (-d * (sp - lp)).exp();
+            let qwp = 2.0 - (-d * (hp - sp)).exp();
+            let q1 = qwp + d * (1.0 - hp) * (-d * (hp - sp)).exp();
+            let q = 1.0 / (q1 - q0);
+            c.a1 = 0.0;
+            c.b1 = d * (-d * (sp - lp)).exp() * q;
+            c.a2 = -q0 * q;
+            c.b2 = q;
+            c.c2 = -d * sp;
+            c.d2 = d;
+            c.a3 = (2.0 - q0) * q;
+            c.b3 = -q;
+            c.c3 = d * sp;
+            c.d3 = -d;
+            c.a4 = (qwp - q0 - d * hp * (-d * (hp - sp)).exp()) * q;
+            c.b4 = d * (-d * (hp - sp)).exp() * q;
+        } else {
+            let qlp = (1.0 + d * b * (sp - lp)).powf(-1.0 / b);
+            let q0 = qlp - d * lp * (1.0 + d * b * (sp - lp)).powf(-(1.0 + b) / b);
+            let qwp = 2.0 - (1.0 + d * b * (hp - sp)).powf(-1.0 / b);
+            let q1 = qwp + d * (1.0 - hp) * (1.0 + d * b * (hp - sp)).powf(-(1.0 + b) / b);
+            let q = 1.0 / (q1 - q0);
+            c.b1 = d * (1.0 + d * b * (sp - lp)).powf(-(1.0 + b) / b) * q;
+            c.a2 = -q0 * q;
+            c.b2 = q;
+            c.c2 = 1.0 + d * b * sp;
+            c.d2 = -d * b;
+            c.e2 = -1.0 / b;
+            c.a3 = (2.0 - q0) * q;
+            c.b3 = -q;
+            c.c3 = 1.0 - d * b * sp;
+            c.d3 = d * b;
+            c.e3 = -1.0 / b;
+            c.a4 = (qwp - q0 - d * hp * (1.0 + d * b * (hp - sp)).powf(-(b + 1.0) / b)) * q;
+            c.b4 = d * (1.0 + d * b * (hp - sp)).powf(-(b + 1.0) / b) * q;
+        }
+        c
     }
 
-    pub fn stretch(&self, data: &Array2<f32>) -> Array2<f32> {
-        let max_val = data.iter().copied().fold(f32::MIN, f32::max);
-        let mut flat: Vec<f32> = data.iter().map(|x| x / max_val).collect();
-        let reshaped = Array2::from_shape_vec(data.raw_dim(), flat.clone()).unwrap();
-        let (c0, _, m) = self.stretch_params(&reshaped);
-        let table = self.generate_mtf_lookup_table(m, 65536);
-        let inv_c0 = 1.0 - c0;
-    
-        let len = flat.len();
-        let chunks = len / SIMD_WIDTH;
-    
-        for i in 0..chunks {
-            let idx = i * SIMD_WIDTH;
-            let chunk = f32x8::from_slice(&flat[idx..idx + SIMD_WIDTH]);
-            let mask = chunk.simd_lt(f32x8::splat(c0));
-            let adj = ((chunk - f32x8::splat(c0)) / f32x8::splat(inv_c0))
-                .simd_max(f32x8::splat(0.0)).simd_min(f32x8::splat(1.0));
-    
-            let mut interpolated = [0.0; 8];
-            for j in 0..SIMD_WIDTH {
-                interpolated[j] = Self::interpolate_mtf(&table, adj[j]);
+    fn transform(&self, c: &GhsCoefficients, x: f32) -> f32 {
+        let b = self.local_intensity;
+        let (lp, sp, hp) = (
+            self.shadow_protection,
+            self.symmetry_point,
+            self.highlight_protection,
+        );
+        let x = x.clamp(0.0, 1.0);
+
+        let y = if x < lp {
+            c.a1 + c.b1 * x
+        } else if x < sp {
+            if b == -1.0 {
+                c.a2 + c.b2 * (c.c2 + c.d2 * x).ln()
+            } else if b == 0.0 {
+                c.a2 + c.b2 * (c.c2 + c.d2 * x).exp()
+            } else {
+                c.a2 + c.b2 * (c.c2 + c.d2 * x).powf(c.e2)
             }
-            let result = mask.select(f32x8::splat(0.0), f32x8::from_array(interpolated));
-            result.copy_to_slice(&mut flat[idx..idx + SIMD_WIDTH]);
-        }
-    
-        // Tail
-        for i in chunks * SIMD_WIDTH..len {
-            let x = flat[i];
-            flat[i] = if x < c0 {
-                0.0
+        } else if x < hp {
+            if b == -1.0 {
+                c.a3 + c.b3 * (c.c3 + c.d3 * x).ln()
+            } else if b == 0.0 {
+                c.a3 + c.b3 * (c.c3 + c.d3 * x).exp()
             } else {
-                Self::interpolate_mtf(&table, ((x - c0) / inv_c0).clamp(0.0, 1.0))
-            };
+                c.a3 + c.b3 * (c.c3 + c.d3 * x).powf(c.e3)
+            }
+        } else {
+            c.a4 + c.b4 * x
+        };
+        y.clamp(0.0, 1.0)
+    }
+
+    pub fn apply_inplace(&self, channels: &mut [Array2<f32>]) {
+        if self.stretch_factor == 0.0 {
+            return;
+        }
+        let c = self.coefficients();
+        for channel in channels.iter_mut() {
+            channel.par_mapv_inplace(|x| self.transform(&c, x));
         }
-    
-        Array2::from_shape_vec(data.raw_dim(), flat).unwrap()
     }
+}
 
-}
\ No newline at end of file
+// Contrast Limited Adaptive Histogram Equalization
+//
+//     Args:
+//         tiles: number of tiles along each axis of the image
+//         clip_limit: maximum height of a tile histogram bin, as a multiple
+//                     of the average bin height. Lower values limit the
+//                     noise amplification on the background.
+//         bins: number of histogram bins per tile
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ClaheStretch {
+    pub tiles: usize,
+    pub clip_limit: f32,
+    pub bins: usize,
+}
+
+impl Default for ClaheStretch {
+    fn default() -> Self {
+        Self { tiles: 8, clip_limit: 2.0, bins: 256 }
+    }
+}
+
+impl ClaheStretch {
+    pub fn new(tiles: usize, clip_limit: f32, bins: usize) -> Result<Self, String> {
+        if tiles == 0 || bins < 2 {
+            return Err("CLAHE needs at least one tile and two bins".to_string());
+        }
+        if clip_limit < 1.0 {
+            return Err(format!("Clip limit must be at least 1.0, got {}", clip_limit));
+        }
+        Ok(Self { tiles, clip_limit, bins })
+    }
+
+    fn bin(&self, x: f32) -> usize {
+        ((x.clamp(0.0, 1.0) * (self.bins - 1) as f32).round() as usize).min(self.bins - 1)
+    }
+
+    // Equalization curve of a single tile, with the clipped excess
+    // redistributed evenly across all the bins.
+    fn tile_mapping(&self, tile: ndarray::ArrayView2<f32>) -> Vec<f32> {
+        let mut hist = vec![0f32; self.bins];
+        for &x in tile.iter() {
+            hist[self.bin(x)] += 1.0;
+        }
+
+        let total = tile.len() as f32;
+        if total == 0.0 {
+            return (0..self.bins).map(|i| i as f32 / (self.bins - 1) as f32).collect();
+        }
+
+        let limit = (self.clip_limit * total / self.bins as f32).max(1.0);
+        let mut excess = 0.0;
+        for h in hist.iter_mut() {
+            if *h > limit {
+                excess += *h - limit;
+                *h = limit;
+            }
+        }
+        let share = excess / self.bins as f32;
+
+        let mut cdf = Vec::with_capacity(self.bins);
+        let mut acc = 0.0;
+        for h in hist {
+            acc += h + share;
+            cdf.push((acc / total).min(1.0));
+        }
+        cdf
+    }
+
+    fn apply_channel(&self, data: &mut Array2<f32>) {
+        let (h, w) = data.dim();
+        if h == 0 || w == 0 {
+            return;
+        }
+        let tiles_y = self.tiles.min(h);
+        let tiles_x = self.tiles.min(w);
+        let tile_h = h.div_ceil(tiles_y);
+        let tile_w = w.div_ceil(tiles_x);
+
+        let mappings: Vec<Vec<f32>> = (0..tiles_y * tiles_x)
+            .into_par_iter()
+            .map(|t| {
+                let (ty, tx) = (t / tiles_x, t % tiles_x);
+                let y0 = (ty * tile_h).min(h);
+                let x0 = (tx * tile_w).min(w);
+                let y1 = (y0 + tile_h).min(h);
+                let x1 = (x0 + tile_w).min(w);
+                self.tile_mapping(data.slice(ndarray::s![y0..y1, x0..x1]))
+            })
+            .collect();
+
+        // Position of a pixel relative to the tile centers: the two tiles to
+        // blend and the weight of the second one.
+        let neighbors = |pos: usize, size: usize, count: usize| -> (usize, usize, f32) {
+            let t = (pos as f32 + 0.5) / size as f32 - 0.5;
+            if t <= 0.0 {
+                (0, 0, 0.0)
+            } else if t >= (count - 1) as f32 {
+                (count - 1, count - 1, 0.0)
+            } else {
+                let t0 = t.floor() as usize;
+                (t0, t0 + 1, t - t0 as f32)
+            }
+        };
+
+        data.axis_iter_mut(ndarray::Axis(0))
+            .into_par_iter()
+            .enumerate()
+            .for_each(|(y, mut row)| {
+                let (ty0, ty1, fy) = neighbors(y, tile_h, tiles_y);
+                for (x, v) in row.iter_mut().enumerate() {
+                    let (tx0, tx1, fx) = neighbors(x, tile_w, tiles_x);
+                    let bin = self.bin(*v);
+                    let top = mappings[ty0 * tiles_x + tx0][bin] * (1.0 - fx)
+                        + mappings[ty0 * tiles_x + tx1][bin] * fx;
+                    let bottom = mappings[ty1 * tiles_x + tx0][bin] * (1.0 - fx)
+                        + mappings[ty1 * tiles_x + tx1][bin] * fx;
+                    *v = top * (1.0 - fy) + bottom * fy;
+                }
+            });
+    }
+
+    pub fn apply_inplace(&self, channels: &mut [Array2<f32>]) {
+        for channel in channels.iter_mut() {
+            self.apply_channel(channel);
+        }
+    }
+}
+
+// Any of the supported stretches, as sent by the frontend
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub enum StretchAlgorithm {
+    Mtf { stf: ChannelStretch },
+    Arcsinh(ArcsinhStretch),
+    Ghs(GhsStretch),
+    Clahe(ClaheStretch),
+}
+
+impl StretchAlgorithm {
+    // Whether the channels must share a common normalization. Only unlinked
+    // MTF stretches scale each channel on its own.
+    pub fn is_linked(&self) -> bool {
+        !matches!(self, StretchAlgorithm::Mtf { stf: ChannelStretch::Unlinked(_) })
+    }
+
+    // Applies the stretch to channels normalized to [0.0, 1.0]
+    pub fn apply_inplace(&self, channels: &mut [Array2<f32>]) {
+        match self {
+            StretchAlgorithm::Mtf { stf } => stf.apply_inplace(channels),
+            StretchAlgorithm::Arcsinh(s) => s.apply_inplace(channels),
+            StretchAlgorithm::Ghs(s) => s.apply_inplace(channels),
+            StretchAlgorithm::Clahe(s) => s.apply_inplace(channels),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array;
+
+    const EPSILON: f32 = 1e-4;
+
+    fn ramp(steps: usize) -> Array2<f32> {
+        Array::from_shape_fn((1, steps), |(_, x)| x as f32 / (steps - 1) as f32)
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < EPSILON, "expected {}, got {}", expected, actual);
+    }
+
+    fn assert_monotonic(data: &Array2<f32>) {
+        for pair in data.as_slice().unwrap().windows(2) {
+            assert!(pair[1] >= pair[0], "not monotonic: {} then {}", pair[0], pair[1]);
+        }
+    }
+
+    fn params_close(actual: StretchParams, c0: f32, c1: f32, m: f32) {
+        assert_close(actual.c0, c0);
+        assert_close(actual.c1, c1);
+        assert_close(actual.m, m);
+    }
+
+    fn row(values: &[f32]) -> Array2<f32> {
+        Array::from_shape_vec((1, values.len()), values.to_vec()).unwrap()
+    }
+
+    // Median 0.3 and 0.4, average deviation 0.12 for both
+    fn channels() -> [Array2<f32>; 2] {
+        [row(&[0.1, 0.2, 0.3, 0.4, 0.5]), row(&[0.6, 0.5, 0.4, 0.3, 0.2])]
+    }
+
+    #[test]
+    fn mtf_reference_values() {
+        assert_close(mtf(0.25, 0.5), 0.75);
+        assert_close(mtf(0.25, 0.25), 0.5);
+        assert_close(mtf(0.8, 0.2), 0.05882353);
+        assert_close(mtf(0.5, 0.37), 0.37);
+        assert_close(mtf(0.25, 0.0), 0.0);
+        assert_close(mtf(0.25, 1.0), 1.0);
+    }
+
+    #[test]
+    fn explicit_params_clip_and_stretch() {
+        let params = StretchParams::new(0.1, 0.9, 0.25).unwrap();
+        let out = params.apply(&row(&[0.0, 0.1, 0.3, 0.5, 0.9, 1.0]));
+        // (x - c0) / (c1 - c0) = 0.25 and 0.5, then the MTF
+        let expected = [0.0, 0.0, 0.5, 0.75, 1.0, 1.0];
+        for (actual, expected) in out.iter().zip(expected) {
+            assert_close(*actual, expected);
+        }
+        let out = StretchParams::default().apply(&ramp(101));
+        for (actual, expected) in out.iter().zip(ramp(101).iter()) {
+            assert_close(*actual, *expected);
+        }
+        assert!(StretchParams::new(0.5, 0.4, 0.5).is_err());
+        assert!(StretchParams::new(0.0, 1.0, 1.0).is_err());
+        assert!(StretchParams::new(-0.1, 1.0, 0.5).is_err());
+    }
+
+    #[test]
+    fn linked_and_unlinked_channels() {
+        let params = [
+            StretchParams::new(0.0, 1.0, 0.25).unwrap(),
+            StretchParams::new(0.0, 1.0, 0.5).unwrap(),
+            StretchParams::new(0.0, 1.0, 0.8).unwrap(),
+        ];
+        let grey = || vec![row(&[0.5]), row(&[0.5]), row(&[0.5])];
+
+        let mut channels = grey();
+        ChannelStretch::Linked(params[0]).apply_inplace(&mut channels);
+        assert!(channels.iter().all(|c| (c[[0, 0]] - 0.75).abs() < EPSILON));
+
+        let mut channels = grey();
+        ChannelStretch::Unlinked(params).apply_inplace(&mut channels);
+        let values: Vec<f32> = channels.iter().map(|c| c[[0, 0]]).collect();
+        for (actual, expected) in values.into_iter().zip([0.75, 0.5, 0.2]) {
+            assert_close(actual, expected);
+        }
+
+        // Mono images use the first set, extra channels the last
+        let mut mono = [row(&[0.5])];
+        ChannelStretch::Unlinked(params).apply_inplace(&mut mono);
+        assert_close(mono[0][[0, 0]], 0.75);
+        assert_eq!(ChannelStretch::Unlinked(params).channel(5), params[2]);
+        assert_eq!(ChannelStretch::Linked(params[1]).channel(2), params[1]);
+    }
+
+    #[test]
+    fn auto_stretch_params() {
+        let stretch = Stretch::default();
+        // c0 = median - 1.25 * 0.12, m = mtf(0.25, (median - c0) / (1 - c0))
+        params_close(stretch.stretch_params(&channels()[0]), 0.15, 1.0, 0.391304);
+
+        let ChannelStretch::Unlinked(params) = stretch.unlinked_params(&channels()) else {
+            panic!("Expected unlinked params");
+        };
+        params_close(params[0], 0.15, 1.0, 0.391304);
+        params_close(params[1], 0.25, 1.0, 0.428571);
+        let mono = stretch.unlinked_params(&channels()[..1]);
+        assert_eq!(mono, ChannelStretch::Unlinked([params[0]; 3]));
+
+        // The average median 0.35 and deviation 0.12 of the channels
+        let ChannelStretch::Linked(params) = stretch.linked_params(&channels()) else {
+            panic!("Expected linked params");
+        };
+        params_close(params, 0.2, 1.0, 0.409091);
+        assert_eq!(stretch.linked_params(&[]), ChannelStretch::Linked(StretchParams::default()));
+    }
+
+    #[test]
+    fn auto_stretch_highlights_clip() {
+        let stretch = Stretch::default().with_highlights_clip(0.8);
+        // m = mtf(0.25, (0.3 - 0.15) / (0.8 - 0.15))
+        let params = stretch.stretch_params(&channels()[0]);
+        params_close(params, 0.15, 0.8, 0.473684);
+        let out = params.apply(&row(&[0.15, 0.3, 0.8, 0.9]));
+        let expected = [0.0, 0.25, 1.0, 1.0];
+        for (actual, expected) in out.iter().zip(expected) {
+            assert_close(*actual, expected);
+        }
+
+        let ChannelStretch::Linked(params) = stretch.linked_params(&channels()) else {
+            panic!("Expected linked params");
+        };
+        params_close(params, 0.2, 0.8, 0.5);
+        assert_eq!(Stretch::default().with_highlights_clip(1.5).highlights_clip, 1.0);
+        assert_eq!(Stretch::default().highlights_clip, 1.0);
+    }
+
+    fn arcsinh_mono(stretch: &ArcsinhStretch, x: f32) -> f32 {
+        let mut channels = [Array2::from_elem((1, 1), x)];
+        stretch.apply_inplace(&mut channels);
+        channels[0][[0, 0]]
+    }
+
+    #[test]
+    fn arcsinh_reference_values() {
+        let stretch = ArcsinhStretch::new(10.0, 0.0).unwrap();
+        assert_close(arcsinh_mono(&stretch, 0.0), 0.0);
+        assert_close(arcsinh_mono(&stretch, 1.0), 1.0);
+        // asinh(1) / asinh(10)
+        assert_close(arcsinh_mono(&stretch, 0.1), 0.293965);
+        // Out of range input is clipped
+        assert_close(arcsinh_mono(&stretch, 1.5), 1.0);
+        assert_close(arcsinh_mono(&stretch, -0.5), 0.0);
+
+        let mut channels = [ramp(1001)];
+        stretch.apply_inplace(&mut channels);
+        assert_monotonic(&channels[0]);
+
+        // beta 0 leaves the data unchanged
+        let identity = ArcsinhStretch::new(0.0, 0.0).unwrap();
+        assert_close(arcsinh_mono(&identity, 0.37), 0.37);
+    }
+
+    #[test]
+    fn arcsinh_black_point() {
+        let stretch = ArcsinhStretch::new(10.0, 0.2).unwrap();
+        assert_close(arcsinh_mono(&stretch, 0.1), 0.0);
+        assert_close(arcsinh_mono(&stretch, 0.2), 0.0);
+        // (0.6 - 0.2) / 0.8 = 0.5, then asinh(5) / asinh(10)
+        assert_close(arcsinh_mono(&stretch, 0.6), 0.771270);
+        assert_close(arcsinh_mono(&stretch, 1.0), 1.0);
+    }
+
+    #[test]
+    fn arcsinh_keeps_color_ratios() {
+        let stretch = ArcsinhStretch::new(50.0, 0.0).unwrap();
+        let mut channels = [
+            Array2::from_elem((1, 1), 0.02),
+            Array2::from_elem((1, 1), 0.04),
+            Array2::from_elem((1, 1), 0.08),
+        ];
+        stretch.apply_inplace(&mut channels);
+        let (r, g, b) = (channels[0][[0, 0]], channels[1][[0, 0]], channels[2][[0, 0]]);
+        assert!(r > 0.02);
+        assert_close(g / r, 2.0);
+        assert_close(b / r, 4.0);
+
+        // Saturated pixels are rescaled, not clipped per channel
+        let mut channels = [
+            Array2::from_elem((1, 1), 0.5),
+            Array2::from_elem((1, 1), 1.0),
+            Array2::from_elem((1, 1), 1.0),
+        ];
+        stretch.apply_inplace(&mut channels);
+        assert_close(channels[1][[0, 0]], 1.0);
+        assert_close(channels[0][[0, 0]] / channels[1][[0, 0]], 0.5);
+    }
+
+    fn ghs_at(stretch: &GhsStretch, x: f32) -> f32 {
+        stretch.transform(&stretch.coefficients(), x)
+    }
+
+    #[test]
+    fn ghs_reference_values() {
+        // D = 10, exponential (b = 0), symmetric around 0.5
+        let stretch = GhsStretch::new(11f32.ln(), 0.0, 0.5, 0.0, 1.0).unwrap();
+        assert_close(ghs_at(&stretch, 0.0), 0.0);
+        assert_close(ghs_at(&stretch, 1.0), 1.0);
+        assert_close(ghs_at(&stretch, 0.5), 0.5);
+        // (e^-2.5 - e^-5) / (2 - 2 e^-5)
+        assert_close(ghs_at(&stretch, 0.25), 0.037929);
+        // Point symmetry around SP
+        for x in [0.05, 0.1, 0.2, 0.3, 0.45] {
+            assert_close(ghs_at(&stretch, 0.5 - x) + ghs_at(&stretch, 0.5 + x), 1.0);
+        }
+
+        // D = 10, logarithmic (b = -1), SP = 0.25:
+        // ln(1 + D SP) / (ln(1 + D SP) + ln(1 + D (1 - SP)))
+        let stretch = GhsStretch::new(11f32.ln(), -1.0, 0.25, 0.0, 1.0).unwrap();
+        assert_close(ghs_at(&stretch, 0.0), 0.0);
+        assert_close(ghs_at(&stretch, 0.25), 0.369238);
+        assert_close(ghs_at(&stretch, 1.0), 1.0);
+    }
+
+    #[test]
+    fn ghs_is_monotonic_and_clipped() {
+        for b in [-2.0, -1.0, 0.0, 2.0, 8.0] {
+            let stretch = GhsStretch::new(3.0, b, 0.2, 0.05, 0.9).unwrap();
+            assert_close(ghs_at(&stretch, 0.0), 0.0);
+            assert_close(ghs_at(&stretch, 1.0), 1.0);
+            assert_close(ghs_at(&stretch, -0.3), 0.0);
+            assert_close(ghs_at(&stretch, 1.3), 1.0);
+
+            let mut channels = [ramp(1001)];
+            stretch.apply_inplace(&mut channels);
+            assert_monotonic(&channels[0]);
+            // Continuous where the segments meet
+            for edge in [0.05, 0.2, 0.9] {
+                assert!((ghs_at(&stretch, edge - 1e-4) - ghs_at(&stretch, edge + 1e-4)).abs() < 1e-2);
+            }
+        }
+
+        // A stretch factor of 0 leaves the data unchanged
+        let identity = GhsStretch::new(0.0, 0.0, 0.5, 0.0, 1.0).unwrap();
+        let mut channels = [ramp(11)];
+        identity.apply_inplace(&mut channels);
+        assert_eq!(channels[0], ramp(11));
+    }
+
+    #[test]
+    fn clahe_without_clipping_equalizes() {
+        // Every bin holds one pixel, so the clip limit is never reached and
+        // bin i maps to (i + 1) / bins
+        let stretch = ClaheStretch::new(1, 4.0, 256).unwrap();
+        let mut channels = [ramp(256)];
+        stretch.apply_inplace(&mut channels);
+        let out = &channels[0];
+        assert_close(out[[0, 0]], 1.0 / 256.0);
+        assert_close(out[[0, 127]], 128.0 / 256.0);
+        assert_close(out[[0, 255]], 1.0);
+        assert_monotonic(out);
+    }
+
+    #[test]
+    fn clahe_clip_limit_bounds_the_contrast() {
+        // Half the pixels black and half white. Plain equalization maps black
+        // to 0.5, a clip limit of 1 keeps 4 of the 128 black pixels in their
+        // bin and spreads the other 248 excess pixels over the 64 bins:
+        // (4 + 248 / 64) / 256
+        let data = Array::from_shape_fn((16, 16), |(_, x)| if x < 8 { 0.0 } else { 1.0 });
+        let equalize = ClaheStretch::new(1, 64.0, 64).unwrap();
+        let mut channels = [data.clone()];
+        equalize.apply_inplace(&mut channels);
+        assert_close(channels[0][[0, 0]], 0.5);
+        assert_close(channels[0][[0, 15]], 1.0);
+
+        let clipped = ClaheStretch::new(1, 1.0, 64).unwrap();
+        let mut channels = [data];
+        clipped.apply_inplace(&mut channels);
+        assert_close(channels[0][[0, 0]], 7.875 / 256.0);
+        assert_close(channels[0][[0, 15]], 1.0);
+
+        let stretch = ClaheStretch::new(4, 2.0, 64).unwrap();
+        let mut channels = [Array::from_shape_fn((32, 32), |(y, x)| ((x + y) % 7) as f32 / 6.0)];
+        stretch.apply_inplace(&mut channels);
+        assert!(channels[0].iter().all(|v| (0.0..=1.0).contains(v)));
+    }
+
+    #[test]
+    fn invalid_parameters_are_rejected() {
+        assert!(ArcsinhStretch::new(-1.0, 0.0).is_err());
+        assert!(ArcsinhStretch::new(1.0, 1.0).is_err());
+        assert!(GhsStretch::new(1.0, 0.0, 0.5, 0.6, 1.0).is_err());
+        assert!(GhsStretch::new(1.0, 0.0, 0.5, 0.0, 0.4).is_err());
+        assert!(ClaheStretch::new(0, 2.0, 256).is_err());
+        assert!(ClaheStretch::new(8, 0.5, 256).is_err());
+    }
+}
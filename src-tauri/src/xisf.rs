@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use flate2::read::ZlibDecoder;
+use ndarray::{Array2, Array3};
+
+use crate::debayer::BayerPattern;
+use crate::metadata::ImageMetadata;
+use crate::rawimage::RawImage;
+
+// XISF 1.0 monolithic files: "XISF0100", the length of the XML header as a
+// little endian u32, 4 reserved bytes, the header and then the attached data
+// blocks. See https://pixinsight.com/doc/docs/XISF-1.0-spec/XISF-1.0-spec.html
+const SIGNATURE: &[u8; 8] = b"XISF0100";
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum SampleFormat {
+    UInt8,
+    UInt16,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl SampleFormat {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "UInt8" => Ok(Self::UInt8),
+            "UInt16" => Ok(Self::UInt16),
+            "UInt32" => Ok(Self::UInt32),
+            "Float32" => Ok(Self::Float32),
+            "Float64" => Ok(Self::Float64),
+            _ => Err(format!("Unsupported XISF sample format {}", value)),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Self::UInt8 => 1,
+            Self::UInt16 => 2,
+            Self::UInt32 | Self::Float32 => 4,
+            Self::Float64 => 8,
+        }
+    }
+}
+
+// Layout of the image, as described by the attributes of <Image>
+struct ImageHeader {
+    width: usize,
+    height: usize,
+    channels: usize,
+    format: SampleFormat,
+    big_endian: bool,
+    planar: bool,
+    // Range of floating point samples
+    bounds: (f64, f64),
+    position: u64,
+    size: usize,
+    // Codec, uncompressed size and item size for byte shuffling
+    compression: Option<(String, usize, usize)>,
+    bayer_pattern: BayerPattern,
+    keywords: HashMap<String, String>,
+}
+
+fn bayer_pattern(value: &str) -> BayerPattern {
+    match value.trim().trim_matches('\'').trim() {
+        "RGGB" => BayerPattern::RGGB,
+        "BGGR" => BayerPattern::BGGR,
+        "GRBG" => BayerPattern::GRBG,
+        "GBRG" => BayerPattern::GBRG,
+        _ => BayerPattern::NONE,
+    }
+}
+
+fn parse_header(xml: &str) -> Result<ImageHeader, String> {
+    let doc = roxmltree::Document::parse(xml).map_err(|e| e.to_string())?;
+    let image = doc
+        .descendants()
+        .find(|n| n.has_tag_name("Image"))
+        .ok_or_else(|| "No image in XISF file".to_string())?;
+    let attr = |name: &str| image.attribute(name);
+
+    let geometry: Vec<usize> = attr("geometry")
+        .ok_or_else(|| "XISF image without geometry".to_string())?
+        .split(':')
+        .map(|v| v.parse().map_err(|_| format!("Invalid XISF geometry {}", v)))
+        .collect::<Result<_, _>>()?;
+    if geometry.len() != 3 {
+        return Err("Only two dimensional XISF images are supported".to_string());
+    }
+
+    let location: Vec<&str> = attr("location").unwrap_or_default().split(':').collect();
+    let (position, size) = match location.as_slice() {
+        ["attachment", position, size] => (
+            position.parse().map_err(|_| "Invalid XISF attachment position".to_string())?,
+            size.parse().map_err(|_| "Invalid XISF attachment size".to_string())?,
+        ),
+        _ => return Err("Only attached XISF data blocks are supported".to_string()),
+    };
+
+    let compression = match attr("compression") {
+        Some(value) => {
+            let parts: Vec<&str> = value.split(':').collect();
+            let uncompressed = parts
+                .get(1)
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| format!("Invalid XISF compression {}", value))?;
+            let item_size = parts.get(2).and_then(|v| v.parse().ok()).unwrap_or(1);
+            Some((parts[0].to_string(), uncompressed, item_size))
+        }
+        None => None,
+    };
+
+    let bounds = attr("bounds")
+        .and_then(|b| {
+            let (lo, hi) = b.split_once(':')?;
+            Some((lo.parse().ok()?, hi.parse().ok()?))
+        })
+        .unwrap_or((0.0, 1.0));
+
+    let keywords: HashMap<String, String> = image
+        .children()
+        .filter(|n| n.has_tag_name("FITSKeyword"))
+        .filter_map(|n| Some((n.attribute("name")?.to_string(), n.attribute("value")?.to_string())))
+        .collect();
+
+    let bayer_pattern = image
+        .children()
+        .find(|n| n.has_tag_name("ColorFilterArray"))
+        .and_then(|n| n.attribute("pattern"))
+        .or_else(|| keywords.get("BAYERPAT").map(|v| v.as_str()))
+        .map(bayer_pattern)
+        .unwrap_or(BayerPattern::NONE);
+
+    Ok(ImageHeader {
+        width: geometry[0],
+        height: geometry[1],
+        channels: geometry[2],
+        format: SampleFormat::parse(attr("sampleFormat").unwrap_or_default())?,
+        big_endian: attr("byteOrder") == Some("big"),
+        planar: attr("pixelStorage").is_none_or(|s| s == "Planar"),
+        bounds,
+        position,
+        size,
+        compression,
+        bayer_pattern,
+        keywords,
+    })
+}
+
+// Reverses the byte shuffling of the "+sh" codecs: the first byte of every
+// item, then every second byte, and so on
+fn unshuffle(data: &[u8], item_size: usize) -> Vec<u8> {
+    if item_size <= 1 {
+        return data.to_vec();
+    }
+    let count = data.len() / item_size;
+    let mut out = vec![0u8; data.len()];
+    for i in 0..count {
+        for b in 0..item_size {
+            out[i * item_size + b] = data[b * count + i];
+        }
+    }
+    let tail = count * item_size;
+    out[tail..].copy_from_slice(&data[tail..]);
+    out
+}
+
+fn decompress(data: Vec<u8>, compression: &Option<(String, usize, usize)>) -> Result<Vec<u8>, String> {
+    let Some((codec, uncompressed, item_size)) = compression else {
+        return Ok(data);
+    };
+    let (zlib, shuffled) = match codec.as_str() {
+        "zlib" => (true, false),
+        "zlib+sh" => (true, true),
+        _ => return Err(format!("Unsupported XISF compression {}", codec)),
+    };
+    let mut out = Vec::with_capacity(*uncompressed);
+    if zlib {
+        ZlibDecoder::new(data.as_slice())
+            .read_to_end(&mut out)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(if shuffled { unshuffle(&out, *item_size) } else { out })
+}
+
+// Samples converted to the 16 bit range used by RawImage. Floating point data
+// is rescaled from its bounds; integers are kept as they are.
+fn samples(data: &[u8], header: &ImageHeader) -> Vec<i32> {
+    let (lo, hi) = header.bounds;
+    let scale = if hi > lo { u16::MAX as f64 / (hi - lo) } else { 0.0 };
+    let float = |v: f64| ((v - lo) * scale).round().clamp(0.0, u16::MAX as f64) as i32;
+    data.chunks_exact(header.format.size())
+        .map(|b| match (header.format, header.big_endian) {
+            (SampleFormat::UInt8, _) => b[0] as i32,
+            (SampleFormat::UInt16, false) => u16::from_le_bytes([b[0], b[1]]) as i32,
+            (SampleFormat::UInt16, true) => u16::from_be_bytes([b[0], b[1]]) as i32,
+            (SampleFormat::UInt32, false) => u32::from_le_bytes(b.try_into().unwrap()).min(i32::MAX as u32) as i32,
+            (SampleFormat::UInt32, true) => u32::from_be_bytes(b.try_into().unwrap()).min(i32::MAX as u32) as i32,
+            (SampleFormat::Float32, false) => float(f32::from_le_bytes(b.try_into().unwrap()) as f64),
+            (SampleFormat::Float32, true) => float(f32::from_be_bytes(b.try_into().unwrap()) as f64),
+            (SampleFormat::Float64, false) => float(f64::from_le_bytes(b.try_into().unwrap())),
+            (SampleFormat::Float64, true) => float(f64::from_be_bytes(b.try_into().unwrap())),
+        })
+        .collect()
+}
+
+pub fn read_xisf(path: &Path) -> Result<RawImage, String> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+
+    let mut preamble = [0u8; 16];
+    reader.read_exact(&mut preamble).map_err(|e| e.to_string())?;
+    if &preamble[..8] != SIGNATURE {
+        return Err("Not a XISF file".to_string());
+    }
+    let header_length = u32::from_le_bytes(preamble[8..12].try_into().unwrap()) as usize;
+    let mut xml = vec![0u8; header_length];
+    reader.read_exact(&mut xml).map_err(|e| e.to_string())?;
+    let xml = String::from_utf8_lossy(&xml);
+    let header = parse_header(xml.trim_end_matches('\0'))?;
+
+    let mut data = vec![0u8; header.size];
+    reader.seek(SeekFrom::Start(header.position)).map_err(|e| e.to_string())?;
+    reader.read_exact(&mut data).map_err(|e| e.to_string())?;
+    let data = decompress(data, &header.compression)?;
+
+    let (w, h, n) = (header.width, header.height, header.channels);
+    let values = samples(&data, &header);
+    if values.len() < w * h * n {
+        return Err("XISF data block is too short".to_string());
+    }
+
+    let mut image = match n {
+        1 => {
+            let plane = Array2::from_shape_vec((h, w), values[..w * h].to_vec()).map_err(|e| e.to_string())?;
+            RawImage::new(plane, header.bayer_pattern)
+        }
+        3 => {
+            let rgb = if header.planar {
+                Array3::from_shape_fn((h, w, 3), |(y, x, c)| values[c * w * h + y * w + x])
+            } else {
+                Array3::from_shape_vec((h, w, 3), values[..w * h * 3].to_vec()).map_err(|e| e.to_string())?
+            };
+            RawImage::from_rgb(rgb)
+        }
+        _ => return Err(format!("Unsupported number of XISF channels {}", n)),
+    };
+    // Float samples are scaled to the 16 bit range by `samples`
+    image.saturation_level = match header.format {
+        SampleFormat::UInt8 => u8::MAX as i32,
+        SampleFormat::UInt32 => i32::MAX,
+        _ => u16::MAX as i32,
+    };
+    image.metadata = ImageMetadata::from_keywords(|k| header.keywords.get(k).cloned());
+    Ok(image)
+}
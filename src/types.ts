@@ -41,6 +41,8 @@ export class Settings {
     public selectedSiteIdx?: number;
     public verifyTimeMatch: boolean;
     public connections?: Connection[] = [];
+    // Probed directly by ASIAIR discovery, on top of the broadcasts
+    public asiairHosts: string[] = [];
 
     constructor() {
         this.checkUpdate = true;
@@ -48,6 +50,7 @@ export class Settings {
         this.selectedSiteIdx = undefined;
         this.sites = [];
         this.connections = [];
+        this.asiairHosts = [];
     }
 
     async loadSettings(store: Store) {
@@ -56,6 +59,7 @@ export class Settings {
         this.selectedSiteIdx = await store.get<number>("selectedSiteIdx") ?? undefined;
         this.sites = await store.get<Site[]>("sites") ?? [];
         this.connections = await store.get<Connection[]>("connections") ?? [];
+        this.asiairHosts = await store.get<string[]>("asiairHosts") ?? [];
     }
 
     async saveSettings(store: Store) {
@@ -67,6 +71,63 @@ export class Settings {
         }
         await store.set("sites", this.sites);
         await store.set("connections", this.connections);
+        await store.set("asiairHosts", this.asiairHosts);
         await store.save();
     }
 }
+
+// ASIAIR plans, as read with asiair_get_plan. Fields not listed here are kept
+// on the objects and written back with the plan.
+export type FrameType = 'light' | 'dark' | 'flat' | 'bias';
+
+export interface ExposureSequence {
+    type: FrameType;
+    filter?: string;
+    // Microseconds
+    exposure: number;
+    gain: number;
+    bin: number;
+    count: number;
+    done?: number;
+    [key: string]: any;
+}
+
+export interface PlanTarget {
+    name: string;
+    // Hours
+    ra: number;
+    // Degrees
+    dec: number;
+    rotation?: number;
+    // Local time, "HH:MM"
+    window?: { start: string; end: string; [key: string]: any };
+    enabled?: boolean;
+    sequences: ExposureSequence[];
+    [key: string]: any;
+}
+
+export interface Plan {
+    name: string;
+    targets: PlanTarget[];
+    [key: string]: any;
+}
+
+export interface AutorunState {
+    state: 'idle' | 'running' | 'stopped' | 'complete' | 'failed';
+    plan: string | null;
+    target: string | null;
+    sequence: number | null;
+    done: number;
+    total: number;
+    message: string | null;
+}
+
+// Histogram as returned by get_image_histogram and sent with fits_image_updated.
+// `values` are already scaled (linear or log) and normalized to [0, 1].
+export interface Histogram {
+    bins: number;
+    scale: 'linear' | 'log';
+    stretched: boolean;
+    counts: number[][];
+    values: number[][];
+}